@@ -0,0 +1,83 @@
+//! Tracks the downloads a client asked to abort. Gateways record an abort as
+//! soon as the command arrives, so that a download already in flight is
+//! cancelled before its package is installed.
+
+use chan::Sender;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use datatype::UpdateRequestId;
+use http_client::{HttpClient, HttpRequest, HttpResponse};
+
+
+#[derive(Clone, Default)]
+pub struct Aborts {
+    // maps each aborted update to whether its download was already cancelled
+    ids: Arc<Mutex<HashMap<UpdateRequestId, bool>>>,
+}
+
+impl Aborts {
+    pub fn new() -> Aborts {
+        Aborts::default()
+    }
+
+    pub fn request(&self, id: &UpdateRequestId) {
+        self.ids.lock().unwrap().entry(id.clone()).or_insert(false);
+    }
+
+    pub fn is_requested(&self, id: &UpdateRequestId) -> bool {
+        self.ids.lock().unwrap().contains_key(id)
+    }
+
+    /// Record that the download of an aborted update has been cancelled.
+    pub fn cancelled(&self, id: &UpdateRequestId) {
+        self.ids.lock().unwrap().insert(id.clone(), true);
+    }
+
+    /// Forget an abort request, returning whether its download was already
+    /// cancelled.
+    pub fn finish(&self, id: &UpdateRequestId) -> bool {
+        self.ids.lock().unwrap().remove(id).unwrap_or(false)
+    }
+}
+
+
+/// Lets the downloads sent through it stop reading as soon as their update is
+/// aborted, rather than once they complete.
+pub struct AbortableClient<'c> {
+    pub client: &'c HttpClient,
+    pub aborts: &'c Aborts,
+}
+
+impl<'c> HttpClient for AbortableClient<'c> {
+    fn chan_request(&self, mut req: HttpRequest, resp_tx: Sender<HttpResponse>) {
+        req.progress = req.progress.take().map(|progress| progress.with_aborts(self.aborts.clone()));
+        self.client.chan_request(req, resp_tx)
+    }
+
+    fn is_testing(&self) -> bool {
+        self.client.is_testing()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    #[test]
+    fn tracks_cancelled_downloads() {
+        let aborts = Aborts::new();
+        let id     = "1".to_string();
+        assert!(!aborts.is_requested(&id));
+
+        aborts.request(&id);
+        assert!(aborts.is_requested(&id));
+        aborts.cancelled(&id);
+        aborts.request(&id);
+        assert!(aborts.finish(&id));
+        assert!(!aborts.is_requested(&id));
+        assert!(!aborts.finish(&id));
+    }
+}
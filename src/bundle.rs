@@ -0,0 +1,301 @@
+//! Offline update bundles, for installing updates without a connection to
+//! the server (e.g. from a USB stick in a workshop).
+//!
+//! A bundle is a directory holding a signed `bundle.json` manifest and the
+//! packages it lists:
+//!
+//! ```json
+//! {"signed": {"bundle_id": "2017-03-workshop",
+//!             "updates": [{"update_id": "1", "file": "app.deb", "length": 1024, "sha256": "..."}]},
+//!  "signatures": [{"keyid": "<hex ed25519 public key>", "sig": "<hex signature>"}]}
+//! ```
+//!
+//! The signature covers the `signed` object as serialized by rustc_serialize,
+//! and must come from one of the configured `public_keys`.
+//!
+//! Packages are copied off the bundle's media before they are checked, so
+//! that the files installed are the ones that were checked.
+
+use chan::Sender;
+use crypto::digest::Digest;
+use crypto::ed25519;
+use crypto::sha2::Sha256;
+use rustc_serialize::{Decodable, json};
+use rustc_serialize::hex::FromHex;
+use rustc_serialize::json::Json;
+use std::collections::HashSet;
+use std::fs;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use datatype::{BundleConfig, Command, Error, UpdateRequestId};
+use interpreter::Global;
+
+
+/// The name of the manifest at the root of each bundle.
+pub const MANIFEST: &'static str = "bundle.json";
+
+#[derive(RustcDecodable, Debug)]
+struct Signature {
+    keyid: String,
+    sig:   String,
+}
+
+#[derive(RustcDecodable, Debug)]
+struct Manifest {
+    bundle_id: String,
+    updates:   Vec<BundleUpdate>,
+}
+
+/// An update in a bundle, with the package that installs it.
+#[derive(RustcDecodable, Debug, Clone, PartialEq, Eq)]
+pub struct BundleUpdate {
+    pub update_id: UpdateRequestId,
+    pub file:      String,
+    pub length:    u64,
+    pub sha256:    String,
+}
+
+/// A bundle whose manifest signature and packages have been verified.
+#[derive(Debug)]
+pub struct Bundle {
+    pub id:      String,
+    pub dir:     PathBuf,
+    pub updates: Vec<BundleUpdate>,
+}
+
+impl Bundle {
+    /// Open the bundle in `dir`, verifying its manifest.
+    pub fn open(dir: &Path, config: &BundleConfig) -> Result<Bundle, Error> {
+        let mut raw = String::new();
+        try!(try!(File::open(dir.join(MANIFEST))).read_to_string(&mut raw));
+        let json   = try!(Json::from_str(&raw).map_err(|err| bundle_error(format!("invalid manifest: {}", err))));
+        let signed = try!(json.find("signed").cloned().ok_or(bundle_error("manifest has no signed object")));
+        let signatures: Vec<Signature> = try!(decode(try!(json.find("signatures").cloned()
+                                                          .ok_or(bundle_error("manifest has no signatures")))));
+
+        let message = signed.to_string();
+        if !signatures.iter().any(|sig| config.public_keys.contains(&sig.keyid) && verifies(sig, message.as_bytes())) {
+            return Err(bundle_error(format!("{:?} is not signed by a trusted key", dir)));
+        }
+
+        let manifest: Manifest = try!(decode(signed));
+        try!(check_name("bundle_id", &manifest.bundle_id));
+        for update in &manifest.updates {
+            try!(check_name("update_id", &update.update_id));
+            try!(check_name("file", &update.file));
+        }
+
+        Ok(Bundle { id: manifest.bundle_id, dir: dir.to_path_buf(), updates: manifest.updates })
+    }
+
+    /// Copy the packages of `updates` into `packages_dir` and check the copies
+    /// against the manifest, returning their paths. When any copy doesn't
+    /// match, all of them are removed.
+    pub fn copy_packages(&self, updates: &[BundleUpdate], packages_dir: &Path) -> Result<Vec<PathBuf>, Error> {
+        try!(fs::create_dir_all(packages_dir));
+        let mut copies = Vec::new();
+        for update in updates {
+            let copy = packages_dir.join(format!("{}-{}-{}", self.id, update.update_id, update.file));
+            copies.push(copy.clone());
+            let copied = fs::copy(self.dir.join(&update.file), &copy).map_err(Error::from)
+                .and_then(|_| check_package(&copy, update));
+            if let Err(err) = copied {
+                for copy in &copies {
+                    let _ = fs::remove_file(copy);
+                }
+                return Err(err);
+            }
+        }
+        Ok(copies)
+    }
+}
+
+
+/// Keeps track of the imported bundles under `results_dir`.
+pub struct BundleStore {
+    dir: PathBuf,
+}
+
+impl BundleStore {
+    pub fn new(config: &BundleConfig) -> BundleStore {
+        BundleStore { dir: PathBuf::from(&config.results_dir) }
+    }
+
+    pub fn is_imported(&self, bundle_id: &str) -> bool {
+        self.dir.join("imported").join(bundle_id).exists()
+    }
+
+    pub fn mark_imported(&self, bundle_id: &str) -> Result<(), Error> {
+        try!(fs::create_dir_all(self.dir.join("imported")));
+        try!(File::create(self.dir.join("imported").join(bundle_id)));
+        Ok(())
+    }
+
+    /// Whether an update of a bundle that wasn't fully imported has already
+    /// been installed.
+    pub fn is_installed(&self, bundle_id: &str, update_id: &str) -> bool {
+        self.dir.join("installed").join(bundle_id).join(update_id).exists()
+    }
+
+    pub fn mark_installed(&self, bundle_id: &str, update_id: &str) -> Result<(), Error> {
+        try!(fs::create_dir_all(self.dir.join("installed").join(bundle_id)));
+        try!(File::create(self.dir.join("installed").join(bundle_id).join(update_id)));
+        Ok(())
+    }
+}
+
+
+/// Look for bundles in `watch_dir` and the directories directly below it
+/// (such as mount points) every `scan_interval` seconds, asking the global
+/// interpreter to install each new one. A bundle is only picked up again
+/// after it has been removed.
+pub fn watch(config: &BundleConfig, gtx: Sender<Global>) {
+    let mut seen = HashSet::new();
+    loop {
+        let found = find_bundles(Path::new(&config.watch_dir));
+        seen.retain(|dir| found.contains(dir));
+        for dir in found {
+            if seen.insert(dir.clone()) {
+                info!("Found update bundle in {:?}", dir);
+                let command = Command::InstallBundle(format!("{}", dir.display()));
+                gtx.send(Global { command: command, response_tx: None });
+            }
+        }
+        thread::sleep(Duration::from_secs(config.scan_interval));
+    }
+}
+
+fn find_bundles(watch_dir: &Path) -> HashSet<PathBuf> {
+    let mut found = HashSet::new();
+    if watch_dir.join(MANIFEST).is_file() {
+        found.insert(watch_dir.to_path_buf());
+    }
+    if let Ok(entries) = fs::read_dir(watch_dir) {
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            if entry.path().join(MANIFEST).is_file() {
+                found.insert(entry.path());
+            }
+        }
+    }
+    found
+}
+
+
+fn verifies(signature: &Signature, message: &[u8]) -> bool {
+    match (signature.keyid.from_hex(), signature.sig.from_hex()) {
+        (Ok(ref public), Ok(ref sig)) if public.len() == 32 && sig.len() == 64 => ed25519::verify(message, public, sig),
+        _ => false
+    }
+}
+
+fn check_package(path: &Path, update: &BundleUpdate) -> Result<(), Error> {
+    let mut file   = try!(File::open(path));
+    let mut hasher = Sha256::new();
+    let mut buf    = [0; 65536];
+    let mut length = 0;
+    loop {
+        match try!(file.read(&mut buf)) {
+            0 => break,
+            n => {
+                hasher.input(&buf[..n]);
+                length += n as u64;
+            }
+        }
+    }
+    if length != update.length || hasher.result_str() != update.sha256.to_lowercase() {
+        Err(bundle_error(format!("{:?} doesn't match the manifest", path)))
+    } else {
+        Ok(())
+    }
+}
+
+// Names end up in paths, so they must stay inside their directory.
+fn check_name(field: &str, name: &str) -> Result<(), Error> {
+    if name.is_empty() || name.starts_with('.') || name.contains('/') {
+        Err(bundle_error(format!("invalid {}: {:?}", field, name)))
+    } else {
+        Ok(())
+    }
+}
+
+fn decode<T: Decodable>(json: Json) -> Result<T, Error> {
+    Ok(try!(T::decode(&mut json::Decoder::new(json))))
+}
+
+fn bundle_error<S: Into<String>>(msg: S) -> Error {
+    Error::BundleError(msg.into())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crypto::digest::Digest;
+    use crypto::ed25519;
+    use crypto::sha2::Sha256;
+    use rustc_serialize::hex::ToHex;
+    use rustc_serialize::json::Json;
+    use std::fs;
+    use std::fs::File;
+    use std::io::prelude::*;
+    use std::path::Path;
+
+    use super::*;
+    use datatype::BundleConfig;
+    use remote::dw::random_string;
+
+
+    fn write_bundle(dir: &str, secret: &[u8], public: &[u8], package: &[u8]) {
+        let mut hasher = Sha256::new();
+        hasher.input(package);
+        let signed = Json::from_str(&format!(r#"{{"bundle_id":"b1","updates":[{{"update_id":"u1","file":"app.deb",
+                                              "length":{},"sha256":"{}"}}]}}"#, package.len(), hasher.result_str())).unwrap();
+        let sig = ed25519::signature(signed.to_string().as_bytes(), secret).to_hex();
+        fs::create_dir_all(dir).unwrap();
+        File::create(format!("{}/app.deb", dir)).unwrap().write_all(package).unwrap();
+        File::create(format!("{}/{}", dir, MANIFEST)).unwrap()
+            .write_all(format!(r#"{{"signatures":[{{"keyid":"{}","sig":"{}"}}],"signed":{}}}"#,
+                               public.to_hex(), sig, signed).as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn verifies_and_records_bundles() {
+        let (secret, public) = ed25519::keypair(&[3; 32]);
+        let root   = format!("/tmp/sota-bundle-{}", random_string(8));
+        let config = BundleConfig {
+            watch_dir:     root.clone(),
+            public_keys:   vec![public.to_hex()],
+            scan_interval: 1,
+            results_dir:   format!("{}/results", root),
+        };
+        let dir = format!("{}/usb", root);
+        write_bundle(&dir, &secret, &public, b"package");
+
+        assert_eq!(find_bundles(Path::new(&root)).into_iter().collect::<Vec<_>>(), vec![Path::new(&dir).to_path_buf()]);
+        let bundle = Bundle::open(Path::new(&dir), &config).unwrap();
+        assert_eq!((bundle.id.as_str(), bundle.updates[0].update_id.as_str()), ("b1", "u1"));
+        let packages = Path::new(&root).join("packages");
+        let copies   = bundle.copy_packages(&bundle.updates, &packages).unwrap();
+        assert_eq!(copies, vec![packages.join("b1-u1-app.deb")]);
+
+        let store = BundleStore::new(&config);
+        assert!(!store.is_imported("b1"));
+        assert!(!store.is_installed("b1", "u1"));
+        store.mark_installed("b1", "u1").unwrap();
+        assert!(store.is_installed("b1", "u1"));
+        store.mark_imported("b1").unwrap();
+        assert!(store.is_imported("b1"));
+
+        // tampered packages and untrusted keys
+        File::create(format!("{}/app.deb", dir)).unwrap().write_all(b"tampered").unwrap();
+        assert!(bundle.copy_packages(&bundle.updates, &packages).is_err());
+        assert!(!packages.join("b1-u1-app.deb").exists());
+        let (other, other_public) = ed25519::keypair(&[4; 32]);
+        write_bundle(&dir, &other, &other_public, b"package");
+        assert!(Bundle::open(Path::new(&dir), &config).is_err());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
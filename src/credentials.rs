@@ -0,0 +1,198 @@
+//! Encryption at rest for the credentials file.
+//!
+//! The encryption key is derived from a machine-bound secret so that a copied
+//! disk image does not leak the credentials of the device it was taken from.
+
+use crypto::aead::{AeadDecryptor, AeadEncryptor};
+use crypto::aes::KeySize;
+use crypto::aes_gcm::AesGcm;
+use crypto::hmac::Hmac;
+use crypto::pbkdf2::pbkdf2;
+use crypto::sha2::Sha256;
+use rustc_serialize::{Decoder, Decodable};
+use rustc_serialize::base64::{FromBase64, ToBase64, STANDARD};
+use std::fs::File;
+use std::io::Read;
+use std::process::Command;
+use std::str::FromStr;
+
+use datatype::Error;
+
+
+/// Header prefixed to encrypted credentials files.
+const MAGIC: &'static str = "OTAENC1:";
+const ROUNDS: u32         = 10000;
+const SALT_LEN: usize     = 16;
+const NONCE_LEN: usize    = 12;
+const TAG_LEN: usize      = 16;
+
+
+/// A source of machine-bound secret material used to derive the credentials key.
+pub trait KeySource {
+    fn secret(&self) -> Result<Vec<u8>, Error>;
+}
+
+/// The configured `credentials_key`, either `file:<path>` to read the secret
+/// from a file or `keyring:<description>` to read a user key from the kernel
+/// keyring.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum CredentialsKey {
+    File(String),
+    Keyring(String),
+}
+
+impl KeySource for CredentialsKey {
+    fn secret(&self) -> Result<Vec<u8>, Error> {
+        let secret = match *self {
+            CredentialsKey::File(ref path) => {
+                let mut secret = Vec::new();
+                try!(try!(File::open(path)).read_to_end(&mut secret));
+                secret
+            }
+
+            CredentialsKey::Keyring(ref desc) => {
+                let output = try!(Command::new("keyctl").arg("pipe").arg(format!("%user:{}", desc)).output());
+                if !output.status.success() {
+                    let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+                    return Err(Error::CryptoError(format!("couldn't read keyring key {}: {}", desc, stderr)));
+                }
+                output.stdout
+            }
+        };
+
+        if secret.is_empty() {
+            Err(Error::CryptoError(format!("empty secret for credentials key: {:?}", self)))
+        } else {
+            Ok(secret)
+        }
+    }
+}
+
+impl FromStr for CredentialsKey {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<CredentialsKey, Error> {
+        match s.splitn(2, ':').collect::<Vec<_>>() {
+            ref parts if parts.len() == 2 && parts[0] == "file" && !parts[1].is_empty() => {
+                Ok(CredentialsKey::File(parts[1].to_string()))
+            }
+
+            ref parts if parts.len() == 2 && parts[0] == "keyring" && !parts[1].is_empty() => {
+                Ok(CredentialsKey::Keyring(parts[1].to_string()))
+            }
+
+            _ => Err(Error::ParseError(format!("unknown credentials key: {}", s)))
+        }
+    }
+}
+
+impl Decodable for CredentialsKey {
+    fn decode<D: Decoder>(d: &mut D) -> Result<CredentialsKey, D::Error> {
+        let s = try!(d.read_str());
+        s.parse::<CredentialsKey>().map_err(|err| d.error(&format!("{}", err)))
+    }
+}
+
+
+/// Returns true if the credentials file contents were written by `encrypt`.
+pub fn is_encrypted(text: &str) -> bool {
+    text.starts_with(MAGIC)
+}
+
+/// Encrypt `plain` with AES-256-GCM using a key derived from the `KeySource`.
+pub fn encrypt(key: &KeySource, plain: &[u8]) -> Result<String, Error> {
+    let salt  = try!(random_bytes(SALT_LEN));
+    let nonce = try!(random_bytes(NONCE_LEN));
+    let dkey  = try!(derive_key(key, &salt));
+
+    let mut cipher = vec![0u8; plain.len()];
+    let mut tag    = [0u8; TAG_LEN];
+    AesGcm::new(KeySize::KeySize256, &dkey, &nonce, MAGIC.as_bytes()).encrypt(plain, &mut cipher, &mut tag);
+
+    let mut data = Vec::with_capacity(SALT_LEN + NONCE_LEN + TAG_LEN + cipher.len());
+    data.extend_from_slice(&salt);
+    data.extend_from_slice(&nonce);
+    data.extend_from_slice(&tag);
+    data.extend_from_slice(&cipher);
+    Ok(format!("{}{}", MAGIC, data.to_base64(STANDARD)))
+}
+
+/// Decrypt the output of `encrypt`, failing if the key or data is wrong.
+pub fn decrypt(key: &KeySource, text: &str) -> Result<Vec<u8>, Error> {
+    if !is_encrypted(text) {
+        return Err(Error::CryptoError("credentials are not encrypted".to_string()));
+    }
+    let data = try!(text[MAGIC.len()..].trim().from_base64()
+                    .map_err(|err| Error::CryptoError(format!("invalid credentials encoding: {}", err))));
+    if data.len() < SALT_LEN + NONCE_LEN + TAG_LEN {
+        return Err(Error::CryptoError("encrypted credentials truncated".to_string()));
+    }
+
+    let (salt, rest)   = data.split_at(SALT_LEN);
+    let (nonce, rest)  = rest.split_at(NONCE_LEN);
+    let (tag, cipher)  = rest.split_at(TAG_LEN);
+    let dkey           = try!(derive_key(key, salt));
+    let mut plain      = vec![0u8; cipher.len()];
+
+    if AesGcm::new(KeySize::KeySize256, &dkey, nonce, MAGIC.as_bytes()).decrypt(cipher, &mut plain, tag) {
+        Ok(plain)
+    } else {
+        Err(Error::CryptoError("couldn't decrypt credentials: wrong key or corrupt file".to_string()))
+    }
+}
+
+fn derive_key(key: &KeySource, salt: &[u8]) -> Result<[u8; 32], Error> {
+    let secret   = try!(key.secret());
+    let mut mac  = Hmac::new(Sha256::new(), &secret);
+    let mut dkey = [0u8; 32];
+    pbkdf2(&mut mac, salt, ROUNDS, &mut dkey);
+    Ok(dkey)
+}
+
+fn random_bytes(n: usize) -> Result<Vec<u8>, Error> {
+    let mut bytes = vec![0u8; n];
+    try!(try!(File::open("/dev/urandom")).read_exact(&mut bytes));
+    Ok(bytes)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use datatype::Error;
+
+
+    struct StaticKey(&'static [u8]);
+
+    impl KeySource for StaticKey {
+        fn secret(&self) -> Result<Vec<u8>, Error> {
+            Ok(self.0.to_vec())
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let key       = StaticKey(b"machine secret");
+        let encrypted = encrypt(&key, b"client_id = \"id\"").unwrap();
+        assert!(is_encrypted(&encrypted));
+        assert!(!encrypted.contains("client_id"));
+        assert_eq!(decrypt(&key, &encrypted).unwrap(), b"client_id = \"id\"".to_vec());
+    }
+
+    #[test]
+    fn test_wrong_key() {
+        let encrypted = encrypt(&StaticKey(b"machine secret"), b"secret").unwrap();
+        assert_eq!(format!("{}", decrypt(&StaticKey(b"other machine"), &encrypted).unwrap_err()),
+                   "Crypto error: couldn't decrypt credentials: wrong key or corrupt file");
+    }
+
+    #[test]
+    fn test_parse_credentials_key() {
+        assert_eq!("file:/etc/ota/secret".parse::<CredentialsKey>().unwrap(),
+                   CredentialsKey::File("/etc/ota/secret".to_string()));
+        assert_eq!("keyring:ota".parse::<CredentialsKey>().unwrap(),
+                   CredentialsKey::Keyring("ota".to_string()));
+        assert!("file:".parse::<CredentialsKey>().is_err());
+        assert!("tpm:0".parse::<CredentialsKey>().is_err());
+    }
+}
@@ -3,10 +3,18 @@ use std::borrow::Cow;
 
 #[derive(RustcDecodable, Debug, PartialEq, Clone, Default)]
 pub struct AccessToken {
-    pub access_token: String,
-    pub token_type:   String,
-    pub expires_in:   i32,
-    pub scope:        Vec<String>
+    pub access_token:  String,
+    pub token_type:    String,
+    pub expires_in:    i32,
+    pub scope:         Vec<String>,
+    pub refresh_token: Option<String>,
+}
+
+impl AccessToken {
+    /// Returns the required scopes that were not granted to this token.
+    pub fn missing_scopes<'a>(&self, required: &'a [String]) -> Vec<&'a String> {
+        required.iter().filter(|scope| !self.scope.contains(scope)).collect()
+    }
 }
 
 impl<'a> Into<Cow<'a, AccessToken>> for AccessToken {
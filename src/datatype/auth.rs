@@ -8,6 +8,7 @@ pub enum Auth {
     None,
     Credentials(ClientId, ClientSecret),
     Token(AccessToken),
+    Certificate,
 }
 
 impl<'a> Into<Cow<'a, Auth>> for Auth {
@@ -4,20 +4,42 @@ use std::str::FromStr;
 use nom::{IResult, space, eof};
 use datatype::{ClientCredentials, ClientId, ClientSecret, Error, UpdateRequestId};
 use datatype::report::{UpdateReport, InstalledSoftware};
+use datatype::update_request::{DownloadComplete, GetInstalledSoftware};
 
 
 #[derive(RustcDecodable, RustcEncodable, PartialEq, Eq, Debug, Clone)]
 pub enum Command {
+    AbortDownload(UpdateRequestId),
     AcceptUpdates(Vec<UpdateRequestId>),
     UpdateReport(UpdateReport),
     Authenticate(Option<ClientCredentials>),
     GetPendingUpdates,
+    InstallBundle(String),
+    InstallDownload(DownloadComplete),
     ListInstalledPackages,
+    ListOutbox,
+    ReloadConfig,
+    SendInstalledSoftware(GetInstalledSoftware),
     Shutdown,
     UpdateInstalledPackages,
     ReportInstalledSoftware(InstalledSoftware),
 }
 
+impl Command {
+    /// Whether running the command a second time has no further effect, so
+    /// that it can be retried after the server rejected the access token.
+    pub fn is_idempotent(&self) -> bool {
+        match *self {
+            Command::GetPendingUpdates          |
+            Command::ListInstalledPackages      |
+            Command::SendInstalledSoftware(_)   |
+            Command::UpdateInstalledPackages    |
+            Command::ReportInstalledSoftware(_) => true,
+            _                                   => false
+        }
+    }
+}
+
 impl FromStr for Command {
     type Err = Error;
 
@@ -32,14 +54,22 @@ impl FromStr for Command {
 named!(command <(Command, Vec<&str>)>, chain!(
     space?
     ~ cmd: alt!(
-        alt_complete!(tag!("AcceptUpdate") | tag!("acc"))
+        alt_complete!(tag!("AbortDownload") | tag!("abort"))
+            => { |_| Command::AbortDownload(String::new()) }
+        | alt_complete!(tag!("AcceptUpdate") | tag!("acc"))
             => { |_| Command::AcceptUpdates(Vec::new()) }
         | alt_complete!(tag!("Authenticate") | tag!("auth"))
             => { |_| Command::Authenticate(None) }
         | alt_complete!(tag!("GetPendingUpdates") | tag!("pen"))
             => { |_| Command::GetPendingUpdates }
+        | alt_complete!(tag!("InstallBundle") | tag!("bundle"))
+            => { |_| Command::InstallBundle(String::new()) }
         | alt_complete!(tag!("ListInstalledPackages") | tag!("ls"))
             => { |_| Command::ListInstalledPackages }
+        | alt_complete!(tag!("ListOutbox") | tag!("outbox"))
+            => { |_| Command::ListOutbox }
+        | alt_complete!(tag!("ReloadConfig") | tag!("reload"))
+            => { |_| Command::ReloadConfig }
         | alt_complete!(tag!("Shutdown") | tag!("shutdown"))
             => { |_| Command::Shutdown }
         | alt_complete!(tag!("UpdateInstalledPackages") | tag!("up"))
@@ -66,6 +96,11 @@ named!(arguments <&[u8], Vec<&str> >, chain!(
 
 fn parse_arguments(cmd: Command, args: Vec<&str>) -> Result<Command, Error> {
     match cmd {
+        Command::AbortDownload(_) => match args.len() {
+            1 => Ok(Command::AbortDownload(args[0].to_string())),
+            _ => Err(Error::Command("usage: abort <id>".to_string())),
+        },
+
         Command::AcceptUpdates(_) => match args.len() {
             0 => Err(Error::Command("usage: acc [<id>]".to_string())),
             _ => Ok(Command::AcceptUpdates(args.iter().map(|arg| String::from(*arg)).collect())),
@@ -85,11 +120,30 @@ fn parse_arguments(cmd: Command, args: Vec<&str>) -> Result<Command, Error> {
             _ => Err(Error::Command(format!("unexpected pen args: {:?}", args))),
         },
 
+        Command::InstallBundle(_) => match args.len() {
+            1 => Ok(Command::InstallBundle(args[0].to_string())),
+            _ => Err(Error::Command("usage: bundle <dir>".to_string())),
+        },
+
+        Command::InstallDownload(_) => Err(Error::Command("InstallDownload is only sent internally".to_string())),
+
         Command::ListInstalledPackages => match args.len() {
             0 => Ok(Command::ListInstalledPackages),
             _ => Err(Error::Command(format!("unexpected ls args: {:?}", args))),
         },
 
+        Command::ListOutbox => match args.len() {
+            0 => Ok(Command::ListOutbox),
+            _ => Err(Error::Command(format!("unexpected outbox args: {:?}", args))),
+        },
+
+        Command::ReloadConfig => match args.len() {
+            0 => Ok(Command::ReloadConfig),
+            _ => Err(Error::Command(format!("unexpected reload args: {:?}", args))),
+        },
+
+        Command::SendInstalledSoftware(_) => Err(Error::Command("SendInstalledSoftware is only sent internally".to_string())),
+
         Command::Shutdown => match args.len() {
             0 => Ok(Command::Shutdown),
             _ => Err(Error::Command(format!("unexpected shutdown args: {:?}", args))),
@@ -142,6 +196,14 @@ mod tests {
         assert_eq!(arguments(&b";"[..]), IResult::Done(&b";"[..], Vec::new()));
     }
 
+    #[test]
+    fn abort_download_test() {
+        assert_eq!("abort 1".parse::<Command>().unwrap(), Command::AbortDownload("1".to_string()));
+        assert_eq!("AbortDownload this".parse::<Command>().unwrap(), Command::AbortDownload("this".to_string()));
+        assert!("abort".parse::<Command>().is_err());
+        assert!("abort one two".parse::<Command>().is_err());
+    }
+
     #[test]
     fn accept_update_test() {
         assert_eq!("acc 1".parse::<Command>().unwrap(), Command::AcceptUpdates(vec!["1".to_string()]));
@@ -170,6 +232,13 @@ mod tests {
         assert!("pen some".parse::<Command>().is_err());
     }
 
+    #[test]
+    fn install_bundle_test() {
+        assert_eq!("bundle /media/usb".parse::<Command>().unwrap(), Command::InstallBundle("/media/usb".to_string()));
+        assert_eq!("InstallBundle dir".parse::<Command>().unwrap(), Command::InstallBundle("dir".to_string()));
+        assert!("bundle".parse::<Command>().is_err());
+    }
+
     #[test]
     fn list_installed_test() {
         assert_eq!("ls".parse::<Command>().unwrap(), Command::ListInstalledPackages);
@@ -177,6 +246,20 @@ mod tests {
         assert!("ls some".parse::<Command>().is_err());
     }
 
+    #[test]
+    fn list_outbox_test() {
+        assert_eq!("outbox".parse::<Command>().unwrap(), Command::ListOutbox);
+        assert_eq!("ListOutbox".parse::<Command>().unwrap(), Command::ListOutbox);
+        assert!("outbox all".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn reload_config_test() {
+        assert_eq!("reload".parse::<Command>().unwrap(), Command::ReloadConfig);
+        assert_eq!("ReloadConfig".parse::<Command>().unwrap(), Command::ReloadConfig);
+        assert!("reload now".parse::<Command>().is_err());
+    }
+
     #[test]
     fn shutdown_test() {
         assert_eq!("shutdown".parse::<Command>().unwrap(), Command::Shutdown);
@@ -185,6 +268,14 @@ mod tests {
         assert!("Shutdown 1 2".parse::<Command>().is_err());
     }
 
+    #[test]
+    fn idempotent_test() {
+        assert!(Command::GetPendingUpdates.is_idempotent());
+        assert!(Command::UpdateInstalledPackages.is_idempotent());
+        assert!(!Command::AcceptUpdates(vec!["1".to_string()]).is_idempotent());
+        assert!(!Command::Shutdown.is_idempotent());
+    }
+
     #[test]
     fn update_installed_test() {
         assert_eq!("up".parse::<Command>().unwrap(), Command::UpdateInstalledPackages);
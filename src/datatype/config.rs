@@ -1,61 +1,654 @@
+use log::LogLevelFilter;
 use rustc_serialize::Decodable;
-use std::fs;
+use rustc_serialize::hex::FromHex;
+use std::{env, fs};
+use std::collections::BTreeMap;
 use std::fs::File;
 use std::io::ErrorKind;
-use std::os::unix::fs::PermissionsExt;
+use std::net::SocketAddr;
 use std::io::prelude::*;
 use std::path::Path;
+use tempfile::NamedTempFile;
 use toml;
-use toml::{Decoder, Parser, Table};
+use toml::{Decoder, Parser, Table, Value};
 
+use credentials;
+use credentials::{CredentialsKey, KeySource};
 use datatype::{Error, Url};
+use firmware::FirmwareInventory;
+use http_client::proxy::parse_proxy_url;
+use http_client::tls::parse_fingerprint;
+use network::{NetworkClass, NetworkState};
 use package_manager::PackageManager;
 
 
 #[derive(Default, PartialEq, Eq, Debug, Clone)]
 pub struct Config {
-    pub device:  DeviceConfig,
-    pub auth:    Option<AuthConfig>,
-    pub gateway: GatewayConfig,
-    pub ota:     OtaConfig,
+    pub device:    DeviceConfig,
+    pub auth:      Option<AuthConfig>,
+    pub gateway:   GatewayConfig,
+    pub ota:       OtaConfig,
+    pub tls:       Option<TlsConfig>,
+    pub provision: Option<ProvisionConfig>,
+    pub log:       Option<LogConfig>,
+    pub rvi:       Option<RviConfig>,
+    pub dbus:      Option<DBusConfig>,
+    pub secondary: BTreeMap<String, SecondaryConfig>,
+    pub uptane:    Option<UptaneConfig>,
+    pub push:      Option<PushConfig>,
+    pub mqtt:      Option<MqttConfig>,
+    pub bundle:    Option<BundleConfig>,
+    pub network:   Option<NetworkConfig>,
+    pub proxy:     Option<ProxyConfig>,
 }
 
+impl Config {
+    /// Provisioning is required when a `[provision]` section is present but
+    /// no credentials have been persisted yet.
+    pub fn needs_provisioning(&self) -> bool {
+        match (&self.provision, &self.auth) {
+            (&Some(_), &Some(ref auth)) => !Path::new(&auth.credentials_file).exists(),
+            _ => false
+        }
+    }
+
+    /// Names of the settings that differ in `new` but only take effect after
+    /// a restart, so can't be applied by a live reload.
+    pub fn restart_required(&self, new: &Config) -> Vec<&'static str> {
+        let mut changed = Vec::new();
+        if self.device != new.device {
+            changed.push("device");
+        }
+        if self.tls != new.tls {
+            changed.push("tls");
+        }
+        if self.provision != new.provision {
+            changed.push("provision");
+        }
+        if self.rvi != new.rvi {
+            changed.push("rvi");
+        }
+        if self.dbus != new.dbus {
+            changed.push("dbus");
+        }
+        if self.uptane != new.uptane {
+            changed.push("uptane");
+        }
+        if self.push != new.push {
+            changed.push("push");
+        }
+        if self.mqtt != new.mqtt {
+            changed.push("mqtt");
+        }
+        if self.proxy != new.proxy {
+            changed.push("proxy");
+        }
+        if self.bundle.as_ref().map(|b| &b.watch_dir) != new.bundle.as_ref().map(|b| &b.watch_dir) {
+            changed.push("bundle.watch_dir");
+        }
+        if self.network.as_ref().map(|n| &n.state) != new.network.as_ref().map(|n| &n.state) {
+            changed.push("network.state");
+        }
+        if self.gateway.http_server != new.gateway.http_server {
+            changed.push("gateway.http_server");
+        }
+        if self.gateway.websocket_server != new.gateway.websocket_server {
+            changed.push("gateway.websocket_server");
+        }
+
+        match (&self.auth, &new.auth) {
+            (&Some(ref old), &Some(ref new)) => {
+                if old.credentials_file != new.credentials_file {
+                    changed.push("auth.credentials_file");
+                }
+                if old.credentials_key != new.credentials_key {
+                    changed.push("auth.credentials_key");
+                }
+            }
+            (&None, &None) => (),
+            _ => changed.push("auth"),
+        }
+
+        changed
+    }
+}
+
+/// Built-in defaults, the lowest configuration layer.
+const DEFAULT_CONFIG: &'static str =
+    r#"
+    [device]
+    uuid = "123e4567-e89b-12d3-a456-426655440000"
+    vin = "V1234567890123456"
+
+    [gateway]
+    console = false
+    http = false
+    websocket = true
+    http_server = "127.0.0.1:8888"
+    websocket_server = "127.0.0.1:3012"
+
+    [ota]
+    server = "http://127.0.0.1:8080"
+    polling_interval = 10
+    busy_polling_interval = 2
+    max_polling_backoff = 3600
+    inventory_interval = 0
+    packages_dir = "/tmp/"
+    package_manager = "dpkg"
+    shutdown_timeout = 60
+    "#;
+
+/// Defaults for optional sections, applied only when the section is present.
+const OPTIONAL_DEFAULTS: &'static str =
+    r#"
+    [rvi]
+    client = "http://127.0.0.1:8901"
+    edge = "127.0.0.1:9080"
+    storage_dir = "/var/sota"
+    vin_match = 2
+
+    [dbus]
+    name = "org.genivi.SotaClient"
+    path = "/org/genivi/SotaClient"
+    interface = "org.genivi.SotaClient"
+    software_manager = "org.genivi.SoftwareLoadingManager"
+    software_manager_path = "/org/genivi/SoftwareLoadingManager"
+    timeout = 60
+
+    [uptane]
+    metadata_dir = "/var/sota/metadata"
+
+    [push]
+    retry_delay = 10
+    timeout = 60
+
+    [mqtt]
+    broker = "127.0.0.1:1883"
+    tls = false
+    topic_prefix = "devices"
+    keep_alive = 60
+    retry_delay = 10
+    timeout = 30
+
+    [bundle]
+    watch_dir = "/media"
+    scan_interval = 5
+    results_dir = "/var/sota/bundles"
+
+    [network]
+    check_interval = 5
+    allow_roaming = false
+
+    [proxy]
+    no_proxy = []
+    "#;
+
+/// Placeholder values giving the type of the optional keys that have no
+/// default, so that environment overrides of them are coerced correctly.
+const ENV_TYPES: &'static str =
+    r#"
+    [auth]
+    scope = []
+
+    [tls]
+    pinned_certs = []
+
+    [rvi]
+    timeout = 0
+
+    [bundle]
+    public_keys = []
+
+    [network]
+    max_rate = 0
+    metered_max_rate = 0
+    metered_max_size = 0
+    "#;
+
+/// Every config section, which the environment variables may create.
+const SECTIONS: &'static [&'static str] = &["device", "gateway", "ota", "auth", "tls", "provision", "log", "rvi",
+                                             "dbus", "secondary", "uptane", "push", "mqtt", "bundle", "network",
+                                             "proxy"];
+
+/// Sections holding a table per entry, which the environment can't address.
+const TABLE_SECTIONS: &'static [&'static str] = &["secondary"];
+
+/// Config keys whose values are hidden by `print_config`.
+const SECRET_KEYS: &'static [&'static str] = &["secret", "provision_key", "pkcs12_password", "password"];
+
+
 pub fn load_config(path: &str) -> Result<Config, Error> {
+    decode_config(try!(load_table(path, true)))
+}
+
+pub fn parse_config(toml: &str) -> Result<Config, Error> {
+    let mut table = try!(parse_table(DEFAULT_CONFIG));
+    merge_tables(&mut table, try!(parse_table(toml)));
+    decode_config(table)
+}
+
+/// Merge all configuration layers below the command line flags: the built-in
+/// defaults, the config file at `path`, any `*.toml` files in the `<path>.d`
+/// drop-in directory (in lexical order), then `OTA_<SECTION>_<KEY>` environment
+/// variables. It is an error for `path` to be missing when `required` is set.
+pub fn load_table(path: &str, required: bool) -> Result<Table, Error> {
     debug!("load_config: {}", path);
+    let mut table = try!(parse_table(DEFAULT_CONFIG));
+
     match File::open(path) {
         Ok(mut file) => {
             let mut text = String::new();
             try!(file.read_to_string(&mut text));
-            parse_config(&text)
+            merge_tables(&mut table, try!(parse_table(&text)));
+        }
+
+        Err(ref err) if err.kind() == ErrorKind::NotFound && !required => {
+            warn!("config file {} not found; using defaults and overrides only", path);
         }
 
         Err(ref err) if err.kind() == ErrorKind::NotFound => {
-            error!("config file {} not found; using default config...", path);
-            Ok(Config::default())
+            return Err(Error::ParseError(format!("config file not found: {}", path)));
+        }
+
+        Err(err) => return Err(Error::IoError(err)),
+    }
+
+    match fs::read_dir(format!("{}.d", path)) {
+        Ok(entries) => {
+            let mut paths = Vec::new();
+            for entry in entries {
+                let path = try!(entry).path();
+                if path.extension().map_or(false, |ext| ext == "toml") {
+                    paths.push(path);
+                }
+            }
+            paths.sort();
+
+            for path in paths {
+                debug!("load_config drop-in: {:?}", path);
+                let mut text = String::new();
+                try!(try!(File::open(&path)).read_to_string(&mut text));
+                merge_tables(&mut table, try!(parse_table(&text)));
+            }
         }
 
-        Err(err) => Err(Error::IoError(err)),
+        Err(ref err) if err.kind() == ErrorKind::NotFound => (),
+        Err(err) => return Err(Error::IoError(err)),
     }
+
+    apply_env(&mut table, env::vars());
+    Ok(table)
 }
 
-pub fn parse_config(toml: &str) -> Result<Config, Error> {
-    let table = try!(parse_table(toml));
+/// Recursively merge `overlay` into `base`, with `overlay` values taking precedence.
+pub fn merge_tables(base: &mut Table, overlay: Table) {
+    for (key, value) in overlay {
+        let replace = match (base.get_mut(&key), value) {
+            (Some(&mut Value::Table(ref mut inner)), Value::Table(table)) => {
+                merge_tables(inner, table);
+                None
+            }
+            (_, value) => Some(value)
+        };
+        if let Some(value) = replace {
+            base.insert(key, value);
+        }
+    }
+}
+
+/// Override config values from `OTA_<SECTION>_<KEY>` environment variables,
+/// e.g. `OTA_OTA_POLLING_INTERVAL=30`. A missing section is created when it's
+/// one of the config's sections, so unrelated `OTA_*` variables are ignored.
+/// Each value is coerced to the type of the key it overrides, and is otherwise
+/// kept as a string. Sections of named tables such as `[secondary.<name>]` can't
+/// be set this way and their variables are rejected.
+pub fn apply_env<I: Iterator<Item=(String, String)>>(table: &mut Table, vars: I) {
+    let types = env_types();
+    for (name, raw) in vars {
+        let path = match env_key(&name) {
+            Some(path) => path,
+            None       => continue
+        };
+
+        let section = path.split('.').next().unwrap_or("").to_string();
+        if TABLE_SECTIONS.contains(&section.as_str()) {
+            error!("ignoring environment variable {}: [{}] entries must be set in the config file", name, section);
+        } else if table.contains_key(&section) || SECTIONS.contains(&section.as_str()) {
+            debug!("config override from {}: {}", name, path);
+            let value = match types.lookup(&path) {
+                Some(template) => coerce_value(&raw, template),
+                None           => Value::String(raw)
+            };
+            set_key(table, &path, value);
+        } else {
+            trace!("ignoring environment variable {}", name);
+        }
+    }
+}
+
+// Every known key with a value of its type, taken from the built-in defaults.
+fn env_types() -> Value {
+    let mut types = Table::new();
+    for text in &[ENV_TYPES, OPTIONAL_DEFAULTS, DEFAULT_CONFIG] {
+        merge_tables(&mut types, parse_table(text).expect("invalid built-in config"));
+    }
+    Value::Table(types)
+}
+
+// Parse `raw` as a value of the same type as `template`, falling back to a string.
+fn coerce_value(raw: &str, template: &Value) -> Value {
+    let value = match *template {
+        Value::Integer(_) => raw.parse().ok().map(Value::Integer),
+        Value::Float(_)   => raw.parse().ok().map(Value::Float),
+        Value::Boolean(_) => raw.parse().ok().map(Value::Boolean),
+        Value::Array(_)   => match parse_value(raw) {
+            array @ Value::Array(_) => Some(array),
+            _ => None
+        },
+        _ => None
+    };
+    value.unwrap_or_else(|| Value::String(raw.to_string()))
+}
+
+fn env_key(name: &str) -> Option<String> {
+    match name {
+        "OTA_PLUS_CLIENT_HTTP_ADDR"      => return Some("gateway.http_server".to_string()),
+        "OTA_PLUS_CLIENT_WEBSOCKET_ADDR" => return Some("gateway.websocket_server".to_string()),
+        _ => ()
+    }
+
+    if !name.starts_with("OTA_") {
+        return None;
+    }
+    let rest = &name[4..];
+    rest.find('_').map(|n| format!("{}.{}", rest[..n].to_lowercase(), rest[n+1..].to_lowercase()))
+}
+
+/// Set a `section.key` config value, creating the section if missing.
+pub fn set_key(table: &mut Table, path: &str, value: Value) {
+    let mut parts = path.splitn(2, '.');
+    let section   = parts.next().unwrap_or("").to_string();
+    let key       = parts.next().unwrap_or("").to_string();
+
+    match *table.entry(section).or_insert_with(|| Value::Table(Table::new())) {
+        Value::Table(ref mut inner) => { inner.insert(key, value); }
+        _ => error!("can't set {}: not a config section", path)
+    }
+}
+
+/// Parse a raw string as a TOML value, falling back to a TOML string.
+pub fn parse_value(raw: &str) -> Value {
+    Parser::new(&format!("value = {}", raw)).parse()
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or(Value::String(raw.to_string()))
+}
+
+/// Render the merged config `table` as TOML, with the defaults of its optional
+/// sections and the credentials and device UUID of an existing credentials
+/// file. Nothing is written, so this is safe before bootstrap and provisioning.
+/// Secret values are redacted.
+pub fn print_config(table: &Table) -> Result<String, Error> {
+    let mut table = try!(with_defaults(table.clone()));
+    let stored = match table.get("auth") {
+        Some(_) => try!(stored_credentials(&try!(parse_section::<AuthConfig>(&table, "auth")))),
+        None    => None
+    };
+    if let Some(stored) = stored {
+        if stored.contains_key("device") {
+            let uuid = try!(parse_section::<DeviceFile>(&stored, "device")).uuid;
+            set_key(&mut table, "device.uuid", Value::String(uuid));
+        }
+        if stored.contains_key("auth") {
+            let credentials = try!(parse_section::<CredentialsFile>(&stored, "auth"));
+            set_key(&mut table, "auth.client_id", Value::String(credentials.client_id));
+            set_key(&mut table, "auth.secret", Value::String(credentials.secret));
+        } else {
+            table.remove("auth");
+        }
+    }
 
-    let auth_cfg = if table.contains_key("auth") {
-        let parsed: AuthConfig = try!(parse_section(&table, "auth"));
-        Some(try!(bootstrap_credentials(parsed)))
-    } else {
-        None
+    for (_, section) in table.iter_mut() {
+        if let Value::Table(ref mut inner) = *section {
+            for key in SECRET_KEYS.iter() {
+                if inner.contains_key(*key) {
+                    inner.insert(key.to_string(), Value::String("<redacted>".to_string()));
+                }
+            }
+        }
+    }
+    Ok(format!("{}", Value::Table(table)))
+}
+
+/// Fill in the defaults of the optional sections that are present.
+fn with_defaults(mut table: Table) -> Result<Table, Error> {
+    for (section, defaults) in try!(parse_table(OPTIONAL_DEFAULTS)) {
+        if let Some(value) = table.remove(&section) {
+            let merged = match (defaults, value) {
+                (Value::Table(mut defaults), Value::Table(value)) => {
+                    merge_tables(&mut defaults, value);
+                    Value::Table(defaults)
+                }
+                (_, value) => value
+            };
+            table.insert(section, merged);
+        }
+    }
+    Ok(table)
+}
+
+/// Decode and validate every section of the merged config table, reporting all
+/// errors at once rather than stopping at the first.
+pub fn decode_config(table: Table) -> Result<Config, Error> {
+    let table      = try!(with_defaults(table));
+    let mut errors = Vec::new();
+
+    let device:    Option<DeviceConfig>    = decode_section(&table, "device", true, &mut errors);
+    let gateway:   Option<GatewayConfig>   = decode_section(&table, "gateway", true, &mut errors);
+    let ota:       Option<OtaConfig>       = decode_section(&table, "ota", true, &mut errors);
+    let auth:      Option<AuthConfig>      = decode_section(&table, "auth", false, &mut errors);
+    let tls:       Option<TlsConfig>       = decode_section(&table, "tls", false, &mut errors);
+    let provision: Option<ProvisionConfig> = decode_section(&table, "provision", false, &mut errors);
+    let log:       Option<LogConfig>       = decode_section(&table, "log", false, &mut errors);
+    let rvi:       Option<RviConfig>       = decode_section(&table, "rvi", false, &mut errors);
+    let dbus:      Option<DBusConfig>      = decode_section(&table, "dbus", false, &mut errors);
+    let secondary: Option<BTreeMap<String, SecondaryConfig>> = decode_section(&table, "secondary", false, &mut errors);
+    let uptane:    Option<UptaneConfig>    = decode_section(&table, "uptane", false, &mut errors);
+    let push:      Option<PushConfig>      = decode_section(&table, "push", false, &mut errors);
+    let mqtt:      Option<MqttConfig>      = decode_section(&table, "mqtt", false, &mut errors);
+    let bundle:    Option<BundleConfig>    = decode_section(&table, "bundle", false, &mut errors);
+    let network:   Option<NetworkConfig>   = decode_section(&table, "network", false, &mut errors);
+    let proxy:     Option<ProxyConfig>     = decode_section(&table, "proxy", false, &mut errors);
+
+    if table.contains_key("provision") && !table.contains_key("auth") {
+        errors.push("provision: requires an auth section for the credentials_file".to_string());
+    }
+    if let Some(ref device) = device {
+        if device.uuid.is_empty() && provision.is_none() {
+            errors.push("device.uuid: must not be empty".to_string());
+        }
+    }
+    if let Some(ref gateway) = gateway {
+        if gateway.http_server.parse::<SocketAddr>().is_err() {
+            errors.push(format!("gateway.http_server: invalid socket address: {}", gateway.http_server));
+        }
+        if gateway.websocket_server.parse::<SocketAddr>().is_err() {
+            errors.push(format!("gateway.websocket_server: invalid socket address: {}", gateway.websocket_server));
+        }
+    }
+    if let Some(ref ota) = ota {
+        if ota.polling_interval == 0 {
+            errors.push("ota.polling_interval: must be greater than zero".to_string());
+        }
+        if ota.busy_polling_interval == 0 {
+            errors.push("ota.busy_polling_interval: must be greater than zero".to_string());
+        }
+        if ota.max_polling_backoff < ota.polling_interval {
+            errors.push("ota.max_polling_backoff: must not be less than polling_interval".to_string());
+        }
+        if ota.packages_dir.is_empty() {
+            errors.push("ota.packages_dir: must not be empty".to_string());
+        }
+        if ota.outbox_dir.as_ref().map(|dir| dir.is_empty()).unwrap_or(false) {
+            errors.push("ota.outbox_dir: must not be empty".to_string());
+        }
+    }
+    if let Some(ref tls) = tls {
+        if tls.cert_file.is_some() != tls.key_file.is_some() {
+            errors.push("tls.cert_file: must be set together with tls.key_file".to_string());
+        }
+        for pin in tls.pinned_certs.iter().flat_map(|pins| pins.iter()) {
+            if let Err(err) = parse_fingerprint(pin) {
+                errors.push(format!("tls.pinned_certs: {}", err));
+            }
+        }
+    }
+
+    if let Some(ref log) = log {
+        if log.level.parse::<LogLevelFilter>().is_err() {
+            errors.push(format!("log.level: unknown log level: {}", log.level));
+        }
+    }
+
+    if let Some(ref rvi) = rvi {
+        if rvi.edge.parse::<SocketAddr>().is_err() {
+            errors.push(format!("rvi.edge: invalid socket address: {}", rvi.edge));
+        }
+    }
+
+    if let Some(ref dbus) = dbus {
+        if dbus.timeout <= 0 {
+            errors.push("dbus.timeout: must be greater than zero".to_string());
+        }
+    }
+
+    if let Some(ref uptane) = uptane {
+        if uptane.hardware_id.is_empty() {
+            errors.push("uptane.hardware_id: must not be empty".to_string());
+        }
+    }
+
+    if let Some(ref push) = push {
+        match push.server.inner().scheme() {
+            "https" => (),
+            scheme  => errors.push(format!("push.server: expected an https:// URL, got scheme: {}", scheme))
+        }
+        if push.retry_delay == 0 || push.timeout == 0 {
+            errors.push("push: retry_delay and timeout must be greater than zero".to_string());
+        }
+    }
+
+    if let Some(ref mqtt) = mqtt {
+        let mut parts = mqtt.broker.rsplitn(2, ':');
+        match (parts.next().map(|port| port.parse::<u16>()), parts.next()) {
+            (Some(Ok(_)), Some(host)) if !host.is_empty() => (),
+            _ => errors.push(format!("mqtt.broker: expected host:port, got: {}", mqtt.broker))
+        }
+        if mqtt.topic_prefix.is_empty() {
+            errors.push("mqtt.topic_prefix: must not be empty".to_string());
+        }
+        if mqtt.keep_alive == 0 || mqtt.retry_delay == 0 || mqtt.timeout == 0 {
+            errors.push("mqtt: keep_alive, retry_delay and timeout must be greater than zero".to_string());
+        }
+        if mqtt.password.is_some() && mqtt.username.is_none() {
+            errors.push("mqtt.password: requires a username".to_string());
+        }
+        if rvi.is_some() {
+            errors.push("mqtt: can't be used together with rvi".to_string());
+        }
+    }
+
+    if let Some(ref bundle) = bundle {
+        if bundle.public_keys.is_empty() {
+            errors.push("bundle.public_keys: must not be empty".to_string());
+        }
+        for key in &bundle.public_keys {
+            match key.from_hex() {
+                Ok(ref bytes) if bytes.len() == 32 => (),
+                _ => errors.push(format!("bundle.public_keys: invalid ed25519 public key: {}", key))
+            }
+        }
+        if bundle.scan_interval == 0 {
+            errors.push("bundle.scan_interval: must be greater than zero".to_string());
+        }
+        if ota.as_ref().map(|ota| ota.outbox_dir.is_none()).unwrap_or(false) {
+            errors.push("bundle: requires an ota.outbox_dir to keep the install reports".to_string());
+        }
+    }
+
+    if let Some(ref network) = network {
+        if network.check_interval == 0 {
+            errors.push("network.check_interval: must be greater than zero".to_string());
+        }
+        if network.max_rate == Some(0) || network.metered_max_rate == Some(0) {
+            errors.push("network: max_rate and metered_max_rate must be greater than zero".to_string());
+        }
+    }
+
+    if let Some(ref proxy) = proxy {
+        for (key, url) in vec![("http", &proxy.http), ("https", &proxy.https)] {
+            if let Some(Err(err)) = url.as_ref().map(|url| parse_proxy_url(url)) {
+                errors.push(format!("proxy.{}: {}", key, err));
+            }
+        }
+    }
+
+    for (name, secondary) in secondary.iter().flat_map(|secondaries| secondaries.iter()) {
+        if secondary.socket.is_empty() {
+            errors.push(format!("secondary.{}.socket: must not be empty", name));
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(Error::InvalidConfig(errors));
+    }
+
+    let tls = tls.map(|tls| TlsConfig {
+        credentials_key: tls.credentials_key.clone().or(auth.as_ref().and_then(|auth| auth.credentials_key.clone())),
+        ..tls
+    });
+
+    let mut device_cfg = device.expect("device config");
+    let auth_cfg = match auth {
+        Some(auth) => try!(bootstrap_credentials(auth, &mut device_cfg, provision.is_none())),
+        None       => None
     };
 
     Ok(Config {
-        auth:    auth_cfg,
-        device:  try!(parse_section(&table, "device")),
-        ota:     try!(parse_section(&table, "ota")),
-        gateway: try!(parse_section(&table, "gateway")),
+        auth:      auth_cfg,
+        device:    device_cfg,
+        ota:       ota.expect("ota config"),
+        gateway:   gateway.expect("gateway config"),
+        tls:       tls,
+        provision: provision,
+        log:       log,
+        rvi:       rvi,
+        dbus:      dbus,
+        secondary: secondary.unwrap_or(BTreeMap::new()),
+        uptane:    uptane,
+        push:      push,
+        mqtt:      mqtt,
+        bundle:    bundle,
+        network:   network,
+        proxy:     proxy,
     })
 }
 
+fn decode_section<T: Decodable>(table: &Table, section: &str, required: bool, errors: &mut Vec<String>) -> Option<T> {
+    match table.get(section) {
+        Some(value) => {
+            let mut decoder = Decoder::new(value.clone());
+            T::decode(&mut decoder).map_err(|err| errors.push(format!("{}: {}", section, err))).ok()
+        }
+
+        None => {
+            if required {
+                errors.push(format!("{}: missing section", section));
+            }
+            None
+        }
+    }
+}
+
 fn parse_table(toml: &str) -> Result<Table, Error> {
     let mut parser = Parser::new(&toml);
     Ok(try!(parser.parse().ok_or_else(move || parser.errors)))
@@ -71,51 +664,127 @@ fn parse_section<T: Decodable>(table: &Table, section: &str) -> Result<T, Error>
 
 
 #[derive(RustcEncodable, RustcDecodable)]
-struct CredentialsFile {
+pub struct CredentialsFile {
     pub client_id: String,
     pub secret:    String,
 }
 
+#[derive(RustcEncodable, RustcDecodable)]
+struct DeviceFile {
+    pub uuid: String,
+}
+
 // Read AuthConfig values from the credentials file if it exists, or write the
-// current AuthConfig values to a new credentials file otherwise.
-fn bootstrap_credentials(auth_cfg: AuthConfig) -> Result<AuthConfig, Error> {
+// current AuthConfig values to a new credentials file otherwise. A credentials
+// file written by provisioning may also contain the registered device UUID, or
+// no auth section at all when the device was issued a client certificate.
+//
+// When a `credentials_key` is set the file is encrypted at rest, and an existing
+// plaintext file is migrated to the encrypted format.
+fn bootstrap_credentials(auth_cfg: AuthConfig, device_cfg: &mut DeviceConfig, write_missing: bool)
+                         -> Result<Option<AuthConfig>, Error> {
     let creds = auth_cfg.credentials_file.clone();
     let path = Path::new(&creds);
+    let cred_key = auth_cfg.credentials_key.clone();
+    let key      = cred_key.as_ref().map(|key| key as &KeySource);
     debug!("bootstrap_credentials: {:?}", path);
 
     let credentials = match File::open(path) {
         Ok(mut file) => {
             let mut text = String::new();
             try!(file.read_to_string(&mut text));
+            let text = match (credentials::is_encrypted(&text), key) {
+                (true, Some(key)) => try!(String::from_utf8(try!(credentials::decrypt(key, &text)))),
+                (true, None)      => return Err(Error::CryptoError("credentials file is encrypted but no credentials_key is set".to_string())),
+                (false, Some(key)) => {
+                    info!("Migrating plaintext credentials file {:?} to encrypted storage", path);
+                    try!(write_private_file(path, &try!(credentials::encrypt(key, text.as_bytes())).into_bytes()));
+                    text
+                }
+                (false, None) => text
+            };
             let table = try!(parse_table(&text));
+            if table.contains_key("device") {
+                device_cfg.uuid = try!(parse_section::<DeviceFile>(&table, "device")).uuid;
+            }
+            if !table.contains_key("auth") {
+                return Ok(None);
+            }
             try!(parse_section::<CredentialsFile>(&table, "auth"))
         }
 
         Err(ref err) if err.kind() == ErrorKind::NotFound => {
-            let mut table   = Table::new();
+            if !write_missing {
+                return Ok(Some(auth_cfg));
+            }
             let credentials = CredentialsFile { client_id: auth_cfg.client_id, secret: auth_cfg.secret };
-            table.insert("auth".to_string(), toml::encode(&credentials));
-
-            let dir = try!(path.parent().ok_or(Error::ParseError("Invalid credentials file path".to_string())));
-            try!(fs::create_dir_all(&dir));
-            let mut file  = try!(File::create(path));
-            let mut perms = try!(file.metadata()).permissions();
-            perms.set_mode(0o600);
-            try!(fs::set_permissions(path, perms));
-            try!(file.write_all(&toml::encode_str(&table).into_bytes()));
-
+            try!(write_credentials(path, Some(&credentials), None, key));
             credentials
         }
 
         Err(err) => return Err(Error::IoError(err))
     };
 
-    Ok(AuthConfig {
+    Ok(Some(AuthConfig {
         server:           auth_cfg.server,
         client_id:        credentials.client_id,
         secret:           credentials.secret,
         credentials_file: auth_cfg.credentials_file,
-    })
+        scope:            auth_cfg.scope,
+        credentials_key:  auth_cfg.credentials_key,
+    }))
+}
+
+// Read an existing credentials file without migrating or creating it.
+fn stored_credentials(auth_cfg: &AuthConfig) -> Result<Option<Table>, Error> {
+    let mut text = String::new();
+    match File::open(&auth_cfg.credentials_file) {
+        Ok(mut file) => { try!(file.read_to_string(&mut text)); }
+        Err(ref err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(Error::IoError(err))
+    }
+
+    if credentials::is_encrypted(&text) {
+        let key = try!(auth_cfg.credentials_key.as_ref().ok_or_else(|| {
+            Error::CryptoError("credentials file is encrypted but no credentials_key is set".to_string())
+        }));
+        text = try!(String::from_utf8(try!(credentials::decrypt(key as &KeySource, &text))));
+    }
+    Ok(Some(try!(parse_table(&text))))
+}
+
+/// Persist the client credentials and (optionally) device UUID to a new
+/// credentials file that is only readable by the current user, encrypting it
+/// when a key is provided.
+pub fn write_credentials(path: &Path, credentials: Option<&CredentialsFile>, uuid: Option<&str>,
+                         key: Option<&KeySource>) -> Result<(), Error> {
+    let mut table = Table::new();
+    if let Some(credentials) = credentials {
+        table.insert("auth".to_string(), toml::encode(credentials));
+    }
+    if let Some(uuid) = uuid {
+        table.insert("device".to_string(), toml::encode(&DeviceFile { uuid: uuid.to_string() }));
+    }
+
+    let text = toml::encode_str(&table);
+    match key {
+        Some(key) => write_private_file(path, &try!(credentials::encrypt(key, text.as_bytes())).into_bytes()),
+        None      => write_private_file(path, &text.into_bytes())
+    }
+}
+
+/// Replace the file at `path` with `data`, readable only by the current user,
+/// creating any missing parent directories. The data is written to a 0600
+/// temporary file in the same directory first and then renamed over `path`,
+/// so a crash never leaves a truncated file behind.
+pub fn write_private_file(path: &Path, data: &[u8]) -> Result<(), Error> {
+    let dir = try!(path.parent().ok_or(Error::ParseError(format!("Invalid file path: {:?}", path))));
+    try!(fs::create_dir_all(&dir));
+    let mut file = try!(NamedTempFile::new_in(&dir));
+    try!(file.write_all(data));
+    try!(file.sync_all());
+    try!(file.persist(path).map_err(|err| err.error));
+    Ok(())
 }
 
 
@@ -125,6 +794,8 @@ pub struct AuthConfig {
     pub client_id:        String,
     pub secret:           String,
     pub credentials_file: String,
+    pub scope:            Option<Vec<String>>,
+    pub credentials_key:  Option<CredentialsKey>,
 }
 
 impl Default for AuthConfig {
@@ -134,6 +805,8 @@ impl Default for AuthConfig {
             client_id:        "client-id".to_string(),
             secret:           "secret".to_string(),
             credentials_file: "/tmp/ats_credentials.toml".to_string(),
+            scope:            None,
+            credentials_key:  None,
         }
     }
 }
@@ -157,17 +830,21 @@ impl Default for DeviceConfig {
 
 #[derive(RustcDecodable, PartialEq, Eq, Debug, Clone)]
 pub struct GatewayConfig {
-    pub console:   bool,
-    pub http:      bool,
-    pub websocket: bool,
+    pub console:          bool,
+    pub http:             bool,
+    pub websocket:        bool,
+    pub http_server:      String,
+    pub websocket_server: String,
 }
 
 impl Default for GatewayConfig {
     fn default() -> GatewayConfig {
         GatewayConfig {
-            console:   false,
-            http:      false,
-            websocket: true,
+            console:          false,
+            http:             false,
+            websocket:        true,
+            http_server:      "127.0.0.1:8888".to_string(),
+            websocket_server: "127.0.0.1:3012".to_string(),
         }
     }
 }
@@ -175,19 +852,249 @@ impl Default for GatewayConfig {
 
 #[derive(RustcDecodable, PartialEq, Eq, Debug, Clone)]
 pub struct OtaConfig {
-    pub server:           Url,
-    pub polling_interval: u64,
-    pub packages_dir:     String,
-    pub package_manager:  PackageManager,
+    pub server:                Url,
+    pub polling_interval:      u64,
+    pub busy_polling_interval: u64,
+    pub max_polling_backoff:   u64,
+    pub inventory_interval:    u64,
+    pub packages_dir:          String,
+    pub package_manager:       PackageManager,
+    pub shutdown_timeout:      u64,
+    pub firmware:              Option<FirmwareInventory>,
+    pub outbox_dir:            Option<String>,
 }
 
 impl Default for OtaConfig {
     fn default() -> OtaConfig {
         OtaConfig {
-            server:           Url::parse("http://127.0.0.1:8080").unwrap(),
-            polling_interval: 10,
-            packages_dir:     "/tmp/".to_string(),
-            package_manager:  PackageManager::Dpkg,
+            server:                Url::parse("http://127.0.0.1:8080").unwrap(),
+            polling_interval:      10,
+            busy_polling_interval: 2,
+            max_polling_backoff:   3600,
+            inventory_interval:    0,
+            packages_dir:          "/tmp/".to_string(),
+            package_manager:       PackageManager::Dpkg,
+            shutdown_timeout:      60,
+            firmware:              None,
+            outbox_dir:            None,
+        }
+    }
+}
+
+
+#[derive(RustcDecodable, PartialEq, Eq, Debug, Clone)]
+pub struct LogConfig {
+    pub level: String,
+}
+
+impl Default for LogConfig {
+    fn default() -> LogConfig {
+        LogConfig { level: "info".to_string() }
+    }
+}
+
+
+#[derive(RustcDecodable, PartialEq, Eq, Debug, Clone)]
+pub struct RviConfig {
+    pub client:      Url,
+    pub edge:        String,
+    pub storage_dir: String,
+    pub timeout:     Option<i64>,
+    pub vin_match:   i32,
+}
+
+impl RviConfig {
+    /// The URL that RVI uses to reach the local service edge.
+    pub fn edge_url(&self) -> Result<Url, Error> {
+        Url::parse(&format!("http://{}", self.edge))
+    }
+}
+
+impl Default for RviConfig {
+    fn default() -> RviConfig {
+        RviConfig {
+            client:      Url::parse("http://127.0.0.1:8901").unwrap(),
+            edge:        "127.0.0.1:9080".to_string(),
+            storage_dir: "/var/sota".to_string(),
+            timeout:     None,
+            vin_match:   2,
+        }
+    }
+}
+
+
+#[derive(RustcDecodable, PartialEq, Eq, Debug, Clone)]
+pub struct DBusConfig {
+    pub name:                  String,
+    pub path:                  String,
+    pub interface:             String,
+    pub software_manager:      String,
+    pub software_manager_path: String,
+    pub timeout:               i32,
+}
+
+impl DBusConfig {
+    /// The timeout for calls to the software manager, in milliseconds.
+    pub fn timeout_ms(&self) -> i32 {
+        self.timeout.saturating_mul(1000)
+    }
+}
+
+impl Default for DBusConfig {
+    fn default() -> DBusConfig {
+        DBusConfig {
+            name:                  "org.genivi.SotaClient".to_string(),
+            path:                  "/org/genivi/SotaClient".to_string(),
+            interface:             "org.genivi.SotaClient".to_string(),
+            software_manager:      "org.genivi.SoftwareLoadingManager".to_string(),
+            software_manager_path: "/org/genivi/SoftwareLoadingManager".to_string(),
+            timeout:               60,
+        }
+    }
+}
+
+
+#[derive(RustcDecodable, PartialEq, Eq, Debug, Clone)]
+pub struct SecondaryConfig {
+    pub socket:      String,
+    pub timeout:     Option<u64>,
+    pub hardware_id: Option<String>,
+}
+
+
+#[derive(RustcDecodable, PartialEq, Eq, Debug, Clone)]
+pub struct UptaneConfig {
+    pub director_server: Url,
+    pub image_server:    Url,
+    pub metadata_dir:    String,
+    pub hardware_id:     String,
+}
+
+
+#[derive(RustcDecodable, PartialEq, Eq, Debug, Clone)]
+pub struct PushConfig {
+    pub server:      Url,
+    pub retry_delay: u64,
+    /// How long to wait for the answer to a long poll, in seconds.
+    pub timeout:     u64,
+}
+
+
+#[derive(RustcDecodable, PartialEq, Eq, Debug, Clone)]
+pub struct MqttConfig {
+    pub broker:       String,
+    pub tls:          bool,
+    pub client_id:    Option<String>,
+    pub topic_prefix: String,
+    pub keep_alive:   u16,
+    pub retry_delay:  u64,
+    pub timeout:      u64,
+    pub username:     Option<String>,
+    pub password:     Option<String>,
+}
+
+impl MqttConfig {
+    /// The topic under which the device publishes and subscribes.
+    pub fn device_topic(&self, device: &DeviceConfig) -> String {
+        format!("{}/{}", self.topic_prefix.trim_right_matches('/'), device.uuid)
+    }
+}
+
+
+#[derive(RustcDecodable, PartialEq, Eq, Debug, Clone)]
+pub struct BundleConfig {
+    pub watch_dir:     String,
+    pub public_keys:   Vec<String>,
+    pub scan_interval: u64,
+    pub results_dir:   String,
+}
+
+
+#[derive(RustcDecodable, PartialEq, Eq, Debug, Clone)]
+pub struct NetworkConfig {
+    pub state:            NetworkState,
+    pub check_interval:   u64,
+    pub max_rate:         Option<u64>,
+    pub metered_max_rate: Option<u64>,
+    pub metered_max_size: Option<u64>,
+    pub allow_roaming:    bool,
+}
+
+impl NetworkConfig {
+    /// Whether a download that has fetched `bytes` so far may continue on a
+    /// network of `class`. An unknown class counts as metered.
+    pub fn allows(&self, class: Option<NetworkClass>, bytes: u64) -> bool {
+        match class {
+            Some(NetworkClass::Unmetered) => true,
+            Some(NetworkClass::Roaming) if !self.allow_roaming => false,
+            _ => match self.metered_max_size {
+                Some(mb) => bytes <= mb * 1024 * 1024,
+                None     => true
+            }
+        }
+    }
+
+    /// The maximum download rate in bytes per second on a network of `class`.
+    pub fn rate_limit(&self, class: Option<NetworkClass>) -> Option<u64> {
+        match class {
+            Some(NetworkClass::Unmetered) => self.max_rate,
+            _ => self.metered_max_rate.or(self.max_rate)
+        }
+    }
+}
+
+
+#[derive(RustcDecodable, PartialEq, Eq, Debug, Clone)]
+pub struct ProxyConfig {
+    pub http:     Option<String>,
+    pub https:    Option<String>,
+    pub no_proxy: Vec<String>,
+}
+
+
+#[derive(RustcDecodable, PartialEq, Eq, Debug, Clone)]
+pub struct ProvisionConfig {
+    pub server:        Url,
+    pub provision_key: String,
+}
+
+impl Default for ProvisionConfig {
+    fn default() -> ProvisionConfig {
+        ProvisionConfig {
+            server:        Url::parse("http://127.0.0.1:8083").unwrap(),
+            provision_key: "provision-key".to_string(),
+        }
+    }
+}
+
+
+#[derive(RustcDecodable, Default, PartialEq, Eq, Debug, Clone)]
+pub struct TlsConfig {
+    pub ca_file:         Option<String>,
+    pub cert_file:       Option<String>,
+    pub key_file:        Option<String>,
+    pub pkcs12_file:     Option<String>,
+    pub pkcs12_password: Option<String>,
+    pub pinned_certs:    Option<Vec<String>>,
+    /// Decrypts a `key_file` issued at provisioning. Defaults to the
+    /// `auth.credentials_key`.
+    pub credentials_key: Option<CredentialsKey>,
+}
+
+impl TlsConfig {
+    pub fn has_client_cert(&self) -> bool {
+        (self.cert_file.is_some() && self.key_file.is_some()) || self.pkcs12_file.is_some()
+    }
+
+    /// The settings without the client certificate, for provisioning before
+    /// the certificate has been issued.
+    pub fn without_client_cert(&self) -> TlsConfig {
+        TlsConfig {
+            cert_file:       None,
+            key_file:        None,
+            pkcs12_file:     None,
+            pkcs12_password: None,
+            ..self.clone()
         }
     }
 }
@@ -195,7 +1102,15 @@ impl Default for OtaConfig {
 
 #[cfg(test)]
 mod tests {
+    use std::fs;
+    use std::fs::File;
+    use std::io::prelude::*;
+    use std::os::unix::fs::PermissionsExt;
+    use tempfile::NamedTempFile;
+    use toml::Value;
+
     use super::*;
+    use credentials;
 
 
     const AUTH_CONFIG: &'static str =
@@ -220,6 +1135,8 @@ mod tests {
         console = false
         http = false
         websocket = true
+        http_server = "127.0.0.1:8888"
+        websocket_server = "127.0.0.1:3012"
         "#;
 
     const OTA_CONFIG: &'static str =
@@ -229,6 +1146,16 @@ mod tests {
         polling_interval = 10
         packages_dir = "/tmp/"
         package_manager = "dpkg"
+        shutdown_timeout = 60
+        "#;
+
+    const TLS_CONFIG: &'static str =
+        r#"
+        [tls]
+        ca_file = "/etc/ota/ca.pem"
+        cert_file = "/etc/ota/client.pem"
+        key_file = "/etc/ota/client.key"
+        pinned_certs = ["AB:CD:EF"]
         "#;
 
     #[test]
@@ -244,7 +1171,263 @@ mod tests {
     }
 
     #[test]
-    fn bad_path_yields_default_config() {
-        assert_eq!(load_config("").unwrap(), Config::default())
+    fn parse_tls_config() {
+        let config = String::new() + DEVICE_CONFIG + GATEWAY_CONFIG + OTA_CONFIG + TLS_CONFIG;
+        let tls    = parse_config(&config).unwrap().tls.unwrap();
+        assert_eq!(tls.ca_file, Some("/etc/ota/ca.pem".to_string()));
+        assert_eq!(tls.pkcs12_file, None);
+        assert_eq!(tls.pinned_certs, Some(vec!["AB:CD:EF".to_string()]));
+        assert!(tls.has_client_cert());
+    }
+
+    #[test]
+    fn provision_requires_auth_section() {
+        let provision = r#"
+        [provision]
+        server = "http://127.0.0.1:8083"
+        provision_key = "key"
+        "#;
+        let config = String::new() + DEVICE_CONFIG + GATEWAY_CONFIG + OTA_CONFIG + provision;
+        assert_eq!(format!("{}", parse_config(&config).unwrap_err()),
+                   "Invalid config:\n  provision: requires an auth section for the credentials_file");
+    }
+
+    #[test]
+    fn migrate_plaintext_credentials() {
+        let mut key   = NamedTempFile::new().unwrap();
+        let mut creds = NamedTempFile::new().unwrap();
+        key.write_all(b"machine secret").unwrap();
+        creds.write_all(b"[auth]\nclient_id = \"id\"\nsecret = \"secret\"\n").unwrap();
+        let (key_file, creds_file) = (key.path().to_path_buf(), creds.path().to_path_buf());
+
+        let auth = format!(r#"
+        [auth]
+        server = "http://127.0.0.1:9000"
+        client_id = "client-id"
+        secret = "secret"
+        credentials_file = "{}"
+        credentials_key = "file:{}"
+        "#, creds_file.display(), key_file.display());
+        let config   = auth + DEVICE_CONFIG + GATEWAY_CONFIG + OTA_CONFIG;
+        let migrated = parse_config(&config).unwrap().auth.unwrap();
+        assert_eq!(migrated.client_id, "id".to_string());
+
+        let mut text = String::new();
+        File::open(&creds_file).unwrap().read_to_string(&mut text).unwrap();
+        assert!(credentials::is_encrypted(&text));
+        assert_eq!(fs::metadata(&creds_file).unwrap().permissions().mode() & 0o777, 0o600);
+        assert_eq!(parse_config(&config).unwrap().auth.unwrap(), migrated);
+
+        let tls = parse_config(&format!("{}[tls]\nca_file = \"/etc/ca.pem\"\n", config)).unwrap().tls.unwrap();
+        assert_eq!(tls.credentials_key, Some(CredentialsKey::File(key_file.display().to_string())));
+    }
+
+    #[test]
+    fn missing_sections_use_defaults() {
+        assert_eq!(parse_config("").unwrap(), Config::default());
+        let ota = parse_config("[ota]\npolling_interval = 30\n").unwrap().ota;
+        assert_eq!(ota.polling_interval, 30);
+        assert_eq!(ota.packages_dir, "/tmp/".to_string());
+    }
+
+    #[test]
+    fn all_errors_are_reported() {
+        let config = r#"
+        [gateway]
+        http_server = "nowhere"
+        [ota]
+        polling_interval = 0
+        "#;
+        match parse_config(config) {
+            Err(Error::InvalidConfig(errors)) => assert_eq!(errors, vec![
+                "gateway.http_server: invalid socket address: nowhere".to_string(),
+                "ota.polling_interval: must be greater than zero".to_string(),
+            ]),
+            other => panic!("unexpected result: {:?}", other)
+        }
+    }
+
+    #[test]
+    fn env_overrides_config_sections() {
+        let mut table = parse_table(DEFAULT_CONFIG).unwrap();
+        apply_env(&mut table, vec![
+            ("OTA_OTA_POLLING_INTERVAL".to_string(), "30".to_string()),
+            ("OTA_PLUS_CLIENT_HTTP_ADDR".to_string(), "0.0.0.0:80".to_string()),
+            ("OTA_LOG_LEVEL".to_string(), "debug".to_string()),
+            ("OTA_FOO_BAR".to_string(), "baz".to_string()),
+        ].into_iter());
+        let config = decode_config(table.clone()).unwrap();
+        assert_eq!(config.ota.polling_interval, 30);
+        assert_eq!(config.gateway.http_server, "0.0.0.0:80".to_string());
+        assert_eq!(config.log, Some(LogConfig { level: "debug".to_string() }));
+        assert!(!table.contains_key("foo"));
+    }
+
+    #[test]
+    fn env_values_take_the_type_of_their_key() {
+        let mut table = parse_table(DEFAULT_CONFIG).unwrap();
+        apply_env(&mut table, vec![
+            ("OTA_DEVICE_VIN".to_string(), "12345".to_string()),
+            ("OTA_DEVICE_UUID".to_string(), "42".to_string()),
+            ("OTA_GATEWAY_HTTP".to_string(), "true".to_string()),
+            ("OTA_RVI_TIMEOUT".to_string(), "30".to_string()),
+            ("OTA_AUTH_SCOPE".to_string(), "[\"read\"]".to_string()),
+            ("OTA_LOG_LEVEL".to_string(), "1".to_string()),
+        ].into_iter());
+        let scope = Value::Table(table.clone()).lookup("auth.scope").cloned();
+        assert_eq!(scope, Some(Value::Array(vec![Value::String("read".to_string())])));
+        table.remove("auth");
+        let config = decode_config(table).unwrap();
+        assert_eq!(config.device.vin, "12345".to_string());
+        assert_eq!(config.device.uuid, "42".to_string());
+        assert_eq!(config.gateway.http, true);
+        assert_eq!(config.rvi.unwrap().timeout, Some(30));
+        assert_eq!(config.log, Some(LogConfig { level: "1".to_string() }));
+    }
+
+    #[test]
+    fn env_rejects_named_table_sections() {
+        let mut table = parse_table(DEFAULT_CONFIG).unwrap();
+        apply_env(&mut table, vec![("OTA_SECONDARY_ECU1".to_string(), "x".to_string())].into_iter());
+        assert!(!table.contains_key("secondary"));
+        assert!(decode_config(table).unwrap().secondary.is_empty());
+    }
+
+    #[test]
+    fn print_config_shows_the_stored_credentials() {
+        let file  = NamedTempFile::new().unwrap();
+        let creds = file.path().with_extension("toml");
+        let mut table = parse_table(DEFAULT_CONFIG).unwrap();
+        merge_tables(&mut table, parse_table(AUTH_CONFIG).unwrap());
+        merge_tables(&mut table, parse_table("[push]\nserver = \"wss://127.0.0.1\"\n").unwrap());
+        set_key(&mut table, "auth.credentials_file", Value::String(creds.display().to_string()));
+
+        let text = print_config(&table).unwrap();
+        assert!(text.contains("retry_delay = 10"));
+        assert!(text.contains("<redacted>"));
+        assert!(!creds.exists());
+
+        let stored = CredentialsFile { client_id: "stored-id".to_string(), secret: "stored-secret".to_string() };
+        write_credentials(&creds, Some(&stored), Some("stored-uuid"), None).unwrap();
+        let text = print_config(&table).unwrap();
+        assert!(text.contains("stored-id"));
+        assert!(text.contains("stored-uuid"));
+        assert!(!text.contains("stored-secret"));
+
+        write_credentials(&creds, None, Some("stored-uuid"), None).unwrap();
+        assert!(!print_config(&table).unwrap().contains("[auth]"));
+        fs::remove_file(&creds).unwrap();
+    }
+
+    #[test]
+    fn rvi_section_defaults() {
+        assert_eq!(parse_config("[rvi]\n").unwrap().rvi, Some(RviConfig::default()));
+        let rvi = parse_config("[rvi]\nedge = \"0.0.0.0:9999\"\ntimeout = 30\n").unwrap().rvi.unwrap();
+        assert_eq!(rvi.edge, "0.0.0.0:9999".to_string());
+        assert_eq!(rvi.timeout, Some(30));
+        assert_eq!(rvi.vin_match, 2);
+    }
+
+    #[test]
+    fn push_section() {
+        let config = parse_config("[push]\nserver = \"https://ota.example.com/push\"\n").unwrap();
+        let push   = config.push.unwrap();
+        assert_eq!(push.retry_delay, 10);
+        assert_eq!(push.timeout, 60);
+        assert!(parse_config("[push]\nserver = \"https://ota.example.com/push\"\ntimeout = 0\n").is_err());
+        assert!(parse_config("[push]\nserver = \"http://ota.example.com/push\"\n").is_err());
+        assert!(parse_config("[push]\nserver = \"wss://ota.example.com/push\"\n").is_err());
+    }
+
+    #[test]
+    fn mqtt_section() {
+        let config = parse_config("[mqtt]\nbroker = \"127.0.0.1:8883\"\ntls = true\n").unwrap();
+        let mqtt   = config.mqtt.unwrap();
+        assert_eq!((mqtt.keep_alive, mqtt.topic_prefix.as_str()), (60, "devices"));
+        assert!(parse_config("[mqtt]\nbroker = \"broker\"\n").is_err());
+        assert!(parse_config("[mqtt]\nusername = \"\"\nkeep_alive = 0\n").is_err());
+        assert!(parse_config("[mqtt]\n[rvi]\n").is_err());
+    }
+
+    #[test]
+    fn bundle_section() {
+        let key    = "3b6a27bcceb6a42d62a3a8d02a6f0d73653215771de243a63ac048a18b59da29";
+        let bundle = format!("[bundle]\npublic_keys = [\"{}\"]\n", key);
+        let config = parse_config(&format!("{}[ota]\noutbox_dir = \"/var/sota/outbox\"\n", bundle)).unwrap();
+        assert_eq!(config.bundle.unwrap().watch_dir, "/media".to_string());
+        assert!(parse_config(&bundle).is_err());
+        assert!(parse_config("[bundle]\npublic_keys = [\"abcd\"]\n[ota]\noutbox_dir = \"/tmp\"\n").is_err());
+        assert!(parse_config("[bundle]\npublic_keys = []\n[ota]\noutbox_dir = \"/tmp\"\n").is_err());
+    }
+
+    #[test]
+    fn network_section() {
+        let config = parse_config("[network]\nstate = \"file:/run/network\"\nmetered_max_size = 10\nmetered_max_rate = 1000\n").unwrap();
+        let network = config.network.unwrap();
+        assert_eq!(network.state, NetworkState::File { path: "/run/network".to_string() });
+        assert!(network.allows(Some(NetworkClass::Unmetered), 20 * 1024 * 1024));
+        assert!(network.allows(Some(NetworkClass::Metered), 1024));
+        assert!(!network.allows(Some(NetworkClass::Metered), 20 * 1024 * 1024));
+        assert!(!network.allows(None, 20 * 1024 * 1024));
+        assert!(!network.allows(Some(NetworkClass::Roaming), 1024));
+        assert_eq!((network.rate_limit(Some(NetworkClass::Unmetered)), network.rate_limit(None)), (None, Some(1000)));
+        assert!(parse_config("[network]\nstate = \"wifi\"\n").is_err());
+        assert!(parse_config("[network]\nstate = \"cmd:nmcli\"\nmax_rate = 0\n").is_err());
+    }
+
+    #[test]
+    fn proxy_section() {
+        let config = parse_config("[proxy]\nhttps = \"proxy.corp:3128\"\n").unwrap();
+        assert_eq!(config.proxy, Some(ProxyConfig { http: None, https: Some("proxy.corp:3128".to_string()), no_proxy: Vec::new() }));
+        assert!(parse_config("[proxy]\nhttps = \"https://proxy.corp\"\n").is_err());
+    }
+
+    #[test]
+    fn uptane_section() {
+        let config = parse_config("[uptane]\ndirector_server = \"http://director\"\nimage_server = \"http://images\"\nhardware_id = \"tcu\"\n").unwrap();
+        assert_eq!(config.uptane.unwrap().metadata_dir, "/var/sota/metadata".to_string());
+        assert!(parse_config("[uptane]\nhardware_id = \"tcu\"\n").is_err());
+    }
+
+    #[test]
+    fn secondary_sections() {
+        let config = parse_config("[secondary.brakes]\nsocket = \"/run/brakes.sock\"\n").unwrap();
+        assert_eq!(config.secondary.get("brakes"), Some(&SecondaryConfig { socket: "/run/brakes.sock".to_string(), timeout: None, hardware_id: None }));
+        assert!(parse_config("[secondary.doors]\nsocket = \"\"\n").is_err());
+    }
+
+    #[test]
+    fn firmware_inventory() {
+        let config = parse_config("[ota]\nfirmware = \"dir:/var/lib/firmware\"\n").unwrap();
+        assert_eq!(config.ota.firmware, Some(FirmwareInventory::Directory { path: "/var/lib/firmware".to_string() }));
+        assert!(parse_config("[ota]\nfirmware = \"flash\"\n").is_err());
+    }
+
+    #[test]
+    fn dbus_section_defaults() {
+        assert_eq!(parse_config("[dbus]\n").unwrap().dbus, Some(DBusConfig::default()));
+        let dbus = parse_config("[dbus]\nname = \"org.example.Client\"\ntimeout = 5\n").unwrap().dbus.unwrap();
+        assert_eq!(dbus.name, "org.example.Client".to_string());
+        assert_eq!(dbus.timeout_ms(), 5000);
+        assert!(parse_config("[dbus]\ntimeout = 0\n").is_err());
+    }
+
+    #[test]
+    fn restart_required_changes() {
+        let old = Config::default();
+        let mut new = Config::default();
+        new.ota.polling_interval = 60;
+        new.gateway.http = true;
+        assert!(old.restart_required(&new).is_empty());
+
+        new.device.vin = "changed".to_string();
+        new.gateway.http_server = "0.0.0.0:80".to_string();
+        assert_eq!(old.restart_required(&new), vec!["device", "gateway.http_server"]);
+    }
+
+    #[test]
+    fn bad_path_is_an_error() {
+        assert!(load_config("").is_err());
+        assert_eq!(load_table("", false).unwrap(), parse_table(DEFAULT_CONFIG).unwrap());
     }
 }
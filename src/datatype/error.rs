@@ -1,3 +1,4 @@
+use dbus::Error as DBusError;
 use hyper::error::Error as HyperError;
 use hyper::client::ClientError as HyperClientError;
 use std::convert::From;
@@ -19,22 +20,35 @@ use super::super::interpreter::Global;
 #[derive(Debug)]
 pub enum Error {
     AuthorizationError(String),
+    BundleError(String),
     ClientError(String),
     Command(String),
+    CryptoError(String),
+    DBusError(String),
+    DownloadAborted(String),
     FromUtf8Error(FromUtf8Error),
     HyperError(HyperError),
     HyperClientError(HyperClientError<AuthHandler>),
+    InvalidConfig(Vec<String>),
     IoError(IoError),
     JsonDecoderError(JsonDecoderError),
     JsonEncoderError(JsonEncoderError),
+    MqttError(String),
     PoisonError(String),
     PackageError(String),
     ParseError(String),
+    ProxyError(String),
+    RangeIgnored(Vec<u8>),
     RecvError(RecvError),
+    RequestRejected(String),
+    RetryAfter(u64),
+    RviError(String),
     SendErrorEvent(SendError<Event>),
     SendErrorGlobal(SendError<Global>),
+    TlsError(String),
     TomlParserErrors(Vec<TomlParserError>),
     TomlDecodeError(TomlDecodeError),
+    UptaneError(String),
     UrlParseError(UrlParseError),
     WebsocketError(WebsocketError),
 }
@@ -69,6 +83,12 @@ impl From<Vec<TomlParserError>> for Error {
     }
 }
 
+impl From<DBusError> for Error {
+    fn from(e: DBusError) -> Error {
+        Error::DBusError(format!("{}: {}", e.name().unwrap_or("unknown"), e.message().unwrap_or("")))
+    }
+}
+
 impl From<HyperClientError<AuthHandler>> for Error {
     fn from(e: HyperClientError<AuthHandler>) -> Error {
         Error::HyperClientError(e)
@@ -107,21 +127,34 @@ impl Display for Error {
         let inner: String = match *self {
             Error::ClientError(ref s)        => format!("Http client error: {}", s.clone()),
             Error::AuthorizationError(ref s) => format!("Http client authorization error: {}", s.clone()),
+            Error::BundleError(ref s)        => format!("Bundle error: {}", s.clone()),
             Error::Command(ref e)            => format!("Unknown Command: {}", e.clone()),
+            Error::CryptoError(ref s)        => format!("Crypto error: {}", s.clone()),
+            Error::DBusError(ref s)          => format!("D-Bus error: {}", s.clone()),
+            Error::DownloadAborted(ref s)    => format!("Download of {} aborted", s.clone()),
             Error::FromUtf8Error(ref e)      => format!("From utf8 error: {}", e.clone()),
             Error::HyperError(ref e)         => format!("Hyper error: {}", e.clone()),
             Error::HyperClientError(ref e)   => format!("Hyper client error: {}", e.clone()),
+            Error::InvalidConfig(ref e)      => format!("Invalid config:\n  {}", e.join("\n  ")),
             Error::IoError(ref e)            => format!("IO error: {}", e.clone()),
             Error::JsonDecoderError(ref e)   => format!("Failed to decode JSON: {}", e.clone()),
             Error::JsonEncoderError(ref e)   => format!("Failed to encode JSON: {}", e.clone()),
+            Error::MqttError(ref s)          => format!("MQTT error: {}", s.clone()),
             Error::PoisonError(ref e)        => format!("Poison error, {}", e.clone()),
             Error::PackageError(ref s)       => s.clone(),
             Error::ParseError(ref s)         => s.clone(),
+            Error::ProxyError(ref s)         => format!("Proxy error: {}", s.clone()),
+            Error::RangeIgnored(ref body)    => format!("Range ignored, got the whole body of {} bytes", body.len()),
             Error::RecvError(ref s)          => format!("Recv error: {}", s.clone()),
+            Error::RequestRejected(ref s)    => format!("Request rejected: {}", s.clone()),
+            Error::RetryAfter(secs)          => format!("Server unavailable, retry after {}s", secs),
+            Error::RviError(ref s)           => format!("RVI error: {}", s.clone()),
             Error::SendErrorEvent(ref s)     => format!("Send error for Event: {}", s.clone()),
             Error::SendErrorGlobal(ref s)    => format!("Send error for Global: {}", s.clone()),
+            Error::TlsError(ref s)           => format!("TLS error: {}", s.clone()),
             Error::TomlDecodeError(ref e)    => format!("Toml decode error: {}", e.clone()),
             Error::TomlParserErrors(ref e)   => format!("Toml parser errors: {:?}", e.clone()),
+            Error::UptaneError(ref s)        => format!("Uptane error: {}", s.clone()),
             Error::UrlParseError(ref s)      => format!("Url parse error: {}", s.clone()),
             Error::WebsocketError(ref e)     => format!("Websocket Error{:?}", e.clone()),
         };
@@ -2,6 +2,7 @@ use std::string::ToString;
 
 use datatype::{UpdateRequestId, UpdateState, Package};
 use datatype::update_request::{UpdateAvailable, DownloadComplete, GetInstalledSoftware};
+use outbox::OutboxEntry;
 
 
 #[derive(RustcEncodable, RustcDecodable, Debug, Clone, PartialEq, Eq)]
@@ -11,11 +12,17 @@ pub enum Event {
     NotAuthenticated,
     UpdateAvailable(UpdateAvailable),
     DownloadComplete(DownloadComplete),
+    DownloadProgress { id: UpdateRequestId, bytes: u64, total: Option<u64>, rate: u64 },
+    /// The server asked for the download of an update to be aborted.
+    AbortRequested(UpdateRequestId),
     GetInstalledSoftware(GetInstalledSoftware),
     UpdateStateChanged(UpdateRequestId, UpdateState),
+    InstallProgress { id: UpdateRequestId, percent: u8, step: String },
     UpdateErrored(UpdateRequestId, String),
     Error(String),
     FoundInstalledPackages(Vec<Package>),
+    FoundPendingUpdates(Vec<UpdateRequestId>),
+    FoundOutbox(Vec<OutboxEntry>),
 }
 
 impl ToString for Event {
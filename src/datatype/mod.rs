@@ -2,13 +2,14 @@ pub use self::access_token::AccessToken;
 pub use self::auth::Auth;
 pub use self::client_credentials::{ClientId, ClientSecret, ClientCredentials};
 pub use self::command::Command;
-pub use self::config::{Config, AuthConfig, GatewayConfig, OtaConfig};
+pub use self::config::{Config, AuthConfig, BundleConfig, DBusConfig, GatewayConfig, LogConfig, MqttConfig, NetworkConfig, OtaConfig,
+                       ProvisionConfig, ProxyConfig, PushConfig, RviConfig, SecondaryConfig, TlsConfig, UptaneConfig};
 pub use self::error::Error;
 pub use self::event::Event;
 pub use self::method::Method;
 pub use self::package::Package;
 pub use self::report::{UpdateReport, UpdateReportWithDevice, UpdateResultCode};
-pub use self::update_request::{UpdateRequestId, UpdateState, PendingUpdateRequest};
+pub use self::update_request::{UpdateRequestId, UpdateState, PendingUpdateRequest, PendingUpdates};
 pub use self::url::Url;
 
 pub type UpdateId = UpdateRequestId;
@@ -1,5 +1,7 @@
 use rustc_serialize::{Encodable, Encoder};
-use super::UpdateRequestId;
+use time;
+
+use super::{Package, UpdateRequestId, UpdateState};
 
 #[derive(RustcEncodable, Clone, Debug)]
 pub struct UpdateReportWithDevice<'a, 'b> {
@@ -137,6 +139,29 @@ pub struct InstalledPackage {
     pub last_modified: u64
 }
 
+impl From<Package> for InstalledPackage {
+    fn from(pkg: Package) -> InstalledPackage {
+        InstalledPackage {
+            package_id: format!("{}-{}", pkg.name, pkg.version),
+            name: pkg.name,
+            description: String::new(),
+            last_modified: 0
+        }
+    }
+}
+
+impl<'a> From<&'a InstalledPackage> for Package {
+    fn from(pkg: &'a InstalledPackage) -> Package {
+        let prefix = format!("{}-", pkg.name);
+        let version = if pkg.package_id.starts_with(&prefix) {
+            pkg.package_id[prefix.len()..].to_string()
+        } else {
+            pkg.package_id.clone()
+        };
+        Package { name: pkg.name.clone(), version: version }
+    }
+}
+
 #[derive(RustcDecodable, RustcEncodable, Clone, Debug, PartialEq, Eq)]
 pub struct InstalledPackages(pub Vec<InstalledPackage>);
 
@@ -154,6 +179,25 @@ impl InstalledSoftware {
         }
     }
 }
+
+/// A change in the state of an update, with the UTC time it happened.
+#[derive(RustcDecodable, RustcEncodable, Clone, Debug, PartialEq, Eq)]
+pub struct UpdateEvent {
+    pub update_id: UpdateRequestId,
+    pub state:     UpdateState,
+    pub timestamp: String,
+}
+
+impl UpdateEvent {
+    pub fn new(update_id: UpdateRequestId, state: UpdateState) -> UpdateEvent {
+        UpdateEvent {
+            update_id: update_id,
+            state:     state,
+            timestamp: format!("{}", time::now_utc().rfc3339()),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use rustc_serialize::json;
@@ -30,6 +30,15 @@ pub enum UpdateState {
     Installing,
     Installed,
     Failed,
+    Cancelled,
+}
+
+/// The pending updates, with the server's hint of how many seconds to wait
+/// before polling again.
+#[derive(Clone, PartialEq, Eq, Debug, Default, RustcEncodable, RustcDecodable)]
+pub struct PendingUpdates {
+    pub updates:       Vec<PendingUpdateRequest>,
+    pub poll_interval: Option<u64>,
 }
 
 #[derive(Clone, PartialEq, Eq, Debug, RustcEncodable, RustcDecodable)]
@@ -38,5 +47,7 @@ pub struct PendingUpdateRequest {
     pub requestId: UpdateRequestId,
     pub installPos: i32,
     pub packageId: Package,
-    pub createdAt: String
+    pub createdAt: String,
+    /// The secondary ECUs to install the update on, rather than the primary.
+    pub targets: Option<Vec<String>>
 }
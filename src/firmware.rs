@@ -0,0 +1,218 @@
+use rustc_serialize::{Decoder, Decodable};
+use std::fs;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::Path;
+use std::process::Command;
+use std::str::FromStr;
+use std::time::UNIX_EPOCH;
+use toml;
+
+use datatype::Error;
+use datatype::report::InstalledFirmware;
+
+
+/// Where to find the firmware versions of the vehicle's ECU modules.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum FirmwareInventory {
+    /// A TOML file with a `[[firmware]]` table per module.
+    Manifest { path: String },
+    /// A directory with a file per module, holding its firmware version.
+    Directory { path: String },
+    /// A command that prints a `module firmware_id [last_modified]` line per module.
+    Command { command: String },
+}
+
+impl FirmwareInventory {
+    pub fn installed_firmware(&self) -> Result<Vec<InstalledFirmware>, Error> {
+        match *self {
+            FirmwareInventory::Manifest { ref path }   => manifest_firmware(path),
+            FirmwareInventory::Directory { ref path }  => directory_firmware(path),
+            FirmwareInventory::Command { ref command } => command_firmware(command),
+        }
+    }
+}
+
+impl FromStr for FirmwareInventory {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<FirmwareInventory, Error> {
+        match s.splitn(2, ':').collect::<Vec<_>>() {
+            ref parts if parts.len() == 2 && !parts[1].is_empty() => {
+                let value = parts[1].to_string();
+                match parts[0] {
+                    "manifest" => Ok(FirmwareInventory::Manifest { path: value }),
+                    "dir"      => Ok(FirmwareInventory::Directory { path: value }),
+                    "cmd"      => Ok(FirmwareInventory::Command { command: value }),
+                    _          => Err(Error::ParseError(format!("unknown firmware inventory: {}", s)))
+                }
+            }
+            _ => Err(Error::ParseError(format!("unknown firmware inventory: {}", s)))
+        }
+    }
+}
+
+impl Decodable for FirmwareInventory {
+    fn decode<D: Decoder>(d: &mut D) -> Result<FirmwareInventory, D::Error> {
+        d.read_str().and_then(|s| s.parse::<FirmwareInventory>().map_err(|err| d.error(&format!("{}", err))))
+    }
+}
+
+
+#[derive(RustcDecodable)]
+struct Manifest {
+    firmware: Vec<ManifestEntry>,
+}
+
+#[derive(RustcDecodable)]
+struct ManifestEntry {
+    module:        String,
+    firmware_id:   String,
+    last_modified: Option<u64>,
+}
+
+// Modules without a `last_modified` time take that of the manifest.
+fn manifest_firmware(path: &str) -> Result<Vec<InstalledFirmware>, Error> {
+    let mut text = String::new();
+    try!(try!(File::open(path)).read_to_string(&mut text));
+    let value    = try!(text.parse::<toml::Value>());
+    let manifest = try!(Manifest::decode(&mut toml::Decoder::new(value)));
+    let modified = try!(modified_secs(Path::new(path)));
+
+    Ok(manifest.firmware.into_iter().map(|entry| InstalledFirmware {
+        module:        entry.module,
+        firmware_id:   entry.firmware_id,
+        last_modified: entry.last_modified.unwrap_or(modified),
+    }).collect())
+}
+
+fn directory_firmware(path: &str) -> Result<Vec<InstalledFirmware>, Error> {
+    let mut firmware = Vec::new();
+    for entry in try!(fs::read_dir(path)) {
+        let path = try!(entry).path();
+        if !path.is_file() {
+            continue;
+        }
+        let module = try!(path.file_name().and_then(|name| name.to_str()).ok_or_else(|| {
+            Error::ParseError(format!("Firmware module name is not valid UTF-8: {:?}", path))
+        }));
+
+        let mut version = String::new();
+        try!(try!(File::open(&path)).read_to_string(&mut version));
+        firmware.push(InstalledFirmware {
+            module:        module.to_string(),
+            firmware_id:   version.trim().to_string(),
+            last_modified: try!(modified_secs(&path)),
+        });
+    }
+    firmware.sort_by(|a, b| a.module.cmp(&b.module));
+    Ok(firmware)
+}
+
+fn command_firmware(command: &str) -> Result<Vec<InstalledFirmware>, Error> {
+    let output = try!(Command::new("sh").arg("-c").arg(command).output()
+        .map_err(|e| Error::PackageError(format!("Error fetching firmware: {}", e))));
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+        return Err(Error::PackageError(format!("Firmware command failed: {}", stderr)));
+    }
+
+    try!(String::from_utf8(output.stdout))
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(parse_firmware)
+        .collect()
+}
+
+pub fn parse_firmware(line: &str) -> Result<InstalledFirmware, Error> {
+    let parts = line.split_whitespace().collect::<Vec<_>>();
+    let last_modified = match parts.get(2) {
+        Some(time) => try!(time.parse::<u64>().map_err(|_| {
+            Error::ParseError(format!("Couldn't parse firmware time: {}", line))
+        })),
+        None => 0
+    };
+
+    match parts.len() {
+        2 | 3 => Ok(InstalledFirmware {
+            module:        parts[0].to_string(),
+            firmware_id:   parts[1].to_string(),
+            last_modified: last_modified,
+        }),
+        _ => Err(Error::ParseError(format!("Couldn't parse firmware: {}", line)))
+    }
+}
+
+fn modified_secs(path: &Path) -> Result<u64, Error> {
+    let modified = try!(try!(fs::metadata(path)).modified());
+    Ok(modified.duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or(0))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::fs::File;
+    use std::io::prelude::*;
+    use tempfile::NamedTempFile;
+
+    use super::*;
+    use datatype::report::InstalledFirmware;
+    use remote::dw::random_string;
+
+
+    fn firmware(module: &str, id: &str, time: u64) -> InstalledFirmware {
+        InstalledFirmware { module: module.to_string(), firmware_id: id.to_string(), last_modified: time }
+    }
+
+    #[test]
+    fn parses_inventory_sources() {
+        assert_eq!("dir:/var/lib/firmware".parse::<FirmwareInventory>().unwrap(),
+                   FirmwareInventory::Directory { path: "/var/lib/firmware".to_string() });
+        assert_eq!("cmd:list-ecus --all".parse::<FirmwareInventory>().unwrap(),
+                   FirmwareInventory::Command { command: "list-ecus --all".to_string() });
+        assert!("manifest:".parse::<FirmwareInventory>().is_err());
+        assert!("/etc/firmware.toml".parse::<FirmwareInventory>().is_err());
+    }
+
+    #[test]
+    fn reads_a_manifest() {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(br#"
+            [[firmware]]
+            module = "ecu1"
+            firmware_id = "1.0"
+            last_modified = 10
+
+            [[firmware]]
+            module = "ecu2"
+            firmware_id = "2.0"
+            "#).unwrap();
+        let path = file.path().to_str().unwrap().to_string();
+
+        let found = FirmwareInventory::Manifest { path: path }.installed_firmware().unwrap();
+        assert_eq!(found[0], firmware("ecu1", "1.0", 10));
+        assert_eq!(found[1].firmware_id, "2.0".to_string());
+        assert!(found[1].last_modified > 0);
+    }
+
+    #[test]
+    fn reads_a_directory() {
+        let dir = format!("/tmp/sota-firmware-{}", random_string(8));
+        fs::create_dir_all(&dir).unwrap();
+        File::create(format!("{}/ecu2", dir)).unwrap().write_all(b"2.0\n").unwrap();
+        File::create(format!("{}/ecu1", dir)).unwrap().write_all(b"1.0").unwrap();
+
+        let found = FirmwareInventory::Directory { path: dir.clone() }.installed_firmware().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(found.iter().map(|fw| fw.firmware_id.clone()).collect::<Vec<_>>(), vec!["1.0", "2.0"]);
+    }
+
+    #[test]
+    fn runs_a_command() {
+        let cmd = FirmwareInventory::Command { command: "printf 'ecu1 1.0 5\\necu2 2.0\\n'".to_string() };
+        assert_eq!(cmd.installed_firmware().unwrap(), vec![firmware("ecu1", "1.0", 5), firmware("ecu2", "2.0", 0)]);
+        assert!(FirmwareInventory::Command { command: "false".to_string() }.installed_firmware().is_err());
+        assert!(parse_firmware("ecu1").is_err());
+    }
+}
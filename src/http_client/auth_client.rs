@@ -2,50 +2,113 @@ use chan::Sender;
 use hyper;
 use hyper::{Encoder, Decoder, Next};
 use hyper::client::{Client, Handler, HttpsConnector, Request, Response};
-use hyper::header::{Authorization, Basic, Bearer, ContentLength, ContentType, Location};
+use hyper::header::{Authorization, Basic, Bearer, ContentLength, ContentType, Headers, Location};
 use hyper::mime::{Attr, Mime, TopLevel, SubLevel, Value};
-use hyper::net::{HttpStream, HttpsStream, OpensslStream, Openssl};
+use hyper::net::{HttpStream, HttpsStream, OpensslStream};
 use hyper::status::StatusCode;
-use std::{io, mem};
-use std::io::{ErrorKind, Write};
+use std::mem;
+use std::io::{ErrorKind, Read, Write};
+use std::thread;
 use std::time::Duration;
 use time;
 
-use datatype::{Auth, Error};
+use datatype::{Auth, Config, Error, Url};
 use http_client::{HttpClient, HttpRequest, HttpResponse};
+use http_client::proxy;
+use http_client::proxy::Proxy;
+use http_client::tls::Tls;
+
+
+/// How long to wait for a response by default.
+const DEFAULT_TIMEOUT_SECS: u64 = 20;
 
 
 #[derive(Clone)]
 pub struct AuthClient {
-    auth:   Auth,
-    client: Client<AuthHandler>,
+    auth:    Auth,
+    tls:     Tls,
+    proxy:   Option<Proxy>,
+    timeout: Duration,
+    client:  Client<AuthHandler>,
 }
 
 impl AuthClient {
     pub fn new(auth: Auth) -> AuthClient {
+        AuthClient::with_ssl(auth, Tls::default(), None)
+    }
+
+    /// Create a new client using the TLS settings from the `[tls]` config
+    /// section, and the proxies from the `[proxy]` section or environment.
+    pub fn from_config(auth: Auth, config: &Config) -> Result<AuthClient, Error> {
+        let proxy = try!(Proxy::from_config(config.proxy.as_ref()));
+        match (auth, config.tls.as_ref()) {
+            (Auth::Certificate, None) => {
+                Err(Error::TlsError("certificate authentication requires a [tls] config section".to_string()))
+            }
+
+            (Auth::Certificate, Some(cfg)) if !cfg.has_client_cert() => {
+                Err(Error::TlsError("certificate authentication requires a client certificate".to_string()))
+            }
+
+            (auth, Some(cfg)) => Ok(AuthClient::with_ssl(auth, try!(Tls::from_config(cfg)), proxy)),
+            (auth, None)      => Ok(AuthClient::with_ssl(auth, Tls::default(), proxy)),
+        }
+    }
+
+    /// Create a client that sends its requests through `proxy`, unless its
+    /// `no_proxy` list excludes them.
+    ///
+    /// Proxied requests are sent by a blocking client on a thread of their
+    /// own, so that a slow request such as a long poll doesn't hold up the
+    /// others. Both clients check any pinned certificates on each connection.
+    pub fn with_ssl(auth: Auth, tls: Tls, proxy: Option<Proxy>) -> AuthClient {
         let client = Client::<AuthHandler>::configure()
             .keep_alive(true)
             .max_sockets(1024)
-            .connector(HttpsConnector::new(Openssl::default()))
+            .connector(HttpsConnector::new(tls.ssl.clone()))
             .build()
             .expect("unable to create a new hyper Client");
 
         AuthClient {
-            auth:   auth,
-            client: client,
+            auth:    auth,
+            tls:     tls,
+            proxy:   proxy,
+            timeout: Duration::from_secs(DEFAULT_TIMEOUT_SECS),
+            client:  client,
         }
     }
+
+    /// Wait up to `timeout` for each response, such as for a long poll that
+    /// the server holds for longer than the default 20 seconds.
+    pub fn with_timeout(mut self, timeout: Duration) -> AuthClient {
+        self.timeout = timeout;
+        self
+    }
+
+    fn is_blocking(&self, url: &Url) -> bool {
+        self.proxy.as_ref().map(|proxy| proxy.for_url(url).is_some()).unwrap_or(false)
+    }
 }
 
 impl HttpClient for AuthClient {
     fn chan_request(&self, req: HttpRequest, resp_tx: Sender<HttpResponse>) {
         debug!("send_request_to: {:?}", req.url);
+        if self.is_blocking(&req.url) {
+            let (auth, tls, proxy, timeout) = (self.auth.clone(), self.tls.clone(), self.proxy.clone(), self.timeout);
+            thread::spawn(move || resp_tx.send(proxy::send(proxy.as_ref(), &auth, &tls, timeout, req)));
+            return;
+        }
+
         let _ = self.client.request(req.url.inner(), AuthHandler {
             auth:     self.auth.clone(),
+            tls:      self.tls.clone(),
+            proxy:    self.proxy.clone(),
             req:      req,
-            timeout:  Duration::from_secs(20),
+            timeout:  self.timeout,
             started:  None,
             written:  0,
+            total:    None,
+            whole:    false,
             response: Vec::new(),
             resp_tx:  resp_tx.clone(),
         }).map_err(|err| resp_tx.send(Err(Error::from(err))));
@@ -56,10 +119,14 @@ impl HttpClient for AuthClient {
 // FIXME: uncomment when yocto is at 1.8.0: #[derive(Debug)]
 pub struct AuthHandler {
     auth:     Auth,
+    tls:      Tls,
+    proxy:    Option<Proxy>,
     req:      HttpRequest,
     timeout:  Duration,
     started:  Option<u64>,
     written:  usize,
+    total:    Option<u64>,
+    whole:    bool,
     response: Vec<u8>,
     resp_tx:  Sender<HttpResponse>,
 }
@@ -72,21 +139,29 @@ impl ::std::fmt::Debug for AuthHandler {
 }
 
 impl AuthHandler {
-    fn redirect_request(&self, resp: Response) {
+    fn respond(&self, body: Vec<u8>) -> HttpResponse {
+        if self.whole { Err(Error::RangeIgnored(body)) } else { Ok(body) }
+    }
+
+    fn redirect_request(&mut self, resp: Response) {
         match resp.headers().get::<Location>() {
             Some(&Location(ref loc)) => match self.req.url.join(loc) {
                 Ok(url) => {
                     debug!("redirecting to {:?}", url);
-                    // drop Authentication Header on redirect
-                    let client = AuthClient::new(Auth::None);
+                    // drop Authentication Header on redirect, but keep the TLS settings
+                    let client = AuthClient::with_ssl(Auth::None, self.tls.clone(), self.proxy.clone())
+                        .with_timeout(self.timeout);
                     let body   = match self.req.body {
                         Some(ref data) => Some(data.clone()),
                         None           => None
                     };
                     let resp_rx = client.send_request(HttpRequest {
-                        url:    url,
-                        method: self.req.method.clone(),
-                        body:   body,
+                        url:             url,
+                        method:          self.req.method.clone(),
+                        body:            body,
+                        idempotency_key: self.req.idempotency_key.clone(),
+                        progress:        self.req.progress.take(),
+                        range:           self.req.range,
                     });
                     match resp_rx.recv().expect("no redirect_request response") {
                         Ok(data) => self.resp_tx.send(Ok(data)),
@@ -108,41 +183,87 @@ impl AuthHandler {
 
 pub type Stream = HttpsStream<OpensslStream<HttpStream>>;
 
-impl Handler<Stream> for AuthHandler {
-    fn on_request(&mut self, req: &mut Request) -> Next {
-        req.set_method(self.req.method.clone().into());
-        info!("on_request: {} {}", req.method(), req.uri());
-        let mut headers = req.headers_mut();
-        self.started    = Some(time::precise_time_ns());
+// The seconds to wait given by a `Retry-After` header. HTTP dates are ignored.
+fn retry_after(resp: &Response) -> Option<u64> {
+    resp.headers().get_raw("Retry-After")
+        .and_then(|values| values.first())
+        .and_then(|value| parse_retry_after(value))
+}
 
-        match self.auth {
-            Auth::None => {
-                headers.set(ContentType(Mime(TopLevel::Application, SubLevel::Json,
-                                             vec![(Attr::Charset, Value::Utf8)])));
-            }
+pub fn parse_retry_after(value: &[u8]) -> Option<u64> {
+    String::from_utf8(value.to_vec()).ok().and_then(|value| value.trim().parse::<u64>().ok())
+}
 
-            Auth::Credentials(_, _) if self.req.body.is_some() => {
-                panic!("no request body expected for Auth::Credentials");
-            }
+/// Set the headers for `auth` and the request, adding the default form body
+/// for client credentials.
+pub fn set_headers(auth: &Auth, req: &mut HttpRequest, headers: &mut Headers) {
+    match *auth {
+        Auth::None | Auth::Certificate => {
+            headers.set(ContentType(Mime(TopLevel::Application, SubLevel::Json,
+                                         vec![(Attr::Charset, Value::Utf8)])));
+        }
 
-            Auth::Credentials(ref id, ref secret) => {
-                headers.set(Authorization(Basic { username: id.0.clone(),
-                                                  password: Some(secret.0.clone()) }));
-                headers.set(ContentType(Mime(TopLevel::Application, SubLevel::WwwFormUrlEncoded,
-                                             vec![(Attr::Charset, Value::Utf8)])));
-                self.req.body = Some(br#"grant_type=client_credentials"#.to_vec());
+        Auth::Credentials(ref id, ref secret) => {
+            headers.set(Authorization(Basic { username: id.0.clone(),
+                                              password: Some(secret.0.clone()) }));
+            headers.set(ContentType(Mime(TopLevel::Application, SubLevel::WwwFormUrlEncoded,
+                                         vec![(Attr::Charset, Value::Utf8)])));
+            // other grant types (e.g. refresh_token) provide their own form body
+            if req.body.is_none() {
+                req.body = Some(br#"grant_type=client_credentials"#.to_vec());
             }
+        }
 
-            Auth::Token(ref token) => {
-                headers.set(Authorization(Bearer { token: token.access_token.clone() }));
-                headers.set(ContentType(Mime(TopLevel::Application, SubLevel::Json,
-                                             vec![(Attr::Charset, Value::Utf8)])));
-            }
-        };
+        Auth::Token(ref token) => {
+            headers.set(Authorization(Bearer { token: token.access_token.clone() }));
+            headers.set(ContentType(Mime(TopLevel::Application, SubLevel::Json,
+                                         vec![(Attr::Charset, Value::Utf8)])));
+        }
+    };
+
+    if let Some(ref key) = req.idempotency_key {
+        headers.set_raw("Idempotency-Key", vec![key.clone().into_bytes()]);
+    }
+    if let Some((first, last)) = req.range {
+        headers.set_raw("Range", vec![format!("bytes={}-{}", first, last).into_bytes()]);
+    }
+}
+
+/// Whether a successful response with `status` is the whole body rather than
+/// the part requested, which a server that ignores `Range` sends instead.
+pub fn ignores_range(req: &HttpRequest, status: &StatusCode) -> bool {
+    req.range.is_some() && *status != StatusCode::PartialContent
+}
+
+/// The error for a response that is neither successful nor a redirect.
+pub fn status_error(status: &StatusCode, retry_after: Option<u64>) -> Error {
+    if *status == StatusCode::Unauthorized || *status == StatusCode::Forbidden {
+        error!("on_response: {}", status);
+        Error::AuthorizationError(format!("{}", status.to_u16()))
+    } else if let Some(secs) = retry_after {
+        error!("on_response: {}, retry after {}s", status, secs);
+        Error::RetryAfter(secs)
+    } else if status.is_client_error() && *status != StatusCode::RequestTimeout && *status != StatusCode::TooManyRequests {
+        // sending the same request again won't help
+        error!("on_response: {}", status);
+        Error::RequestRejected(format!("{}", status.to_u16()))
+    } else {
+        let msg = format!("failed response status: {}", status);
+        error!("{}", msg);
+        Error::ClientError(msg)
+    }
+}
+
+impl Handler<Stream> for AuthHandler {
+    fn on_request(&mut self, req: &mut Request) -> Next {
+        req.set_method(self.req.method.clone().into());
+        info!("on_request: {} {}", req.method(), req.uri());
+        self.started = Some(time::precise_time_ns());
+        set_headers(&self.auth, &mut self.req, req.headers_mut());
 
         match self.req.body {
             Some(ref body) => {
-                headers.set(ContentLength(body.len() as u64));
+                req.headers_mut().set(ContentLength(body.len() as u64));
                 Next::write()
             }
 
@@ -186,50 +307,63 @@ impl Handler<Stream> for AuthHandler {
         debug!("on_response latency: {}ms", (latency / 1e6) as u32);
 
         if resp.status().is_success() {
+            self.whole = ignores_range(&self.req, resp.status());
             if let Some(len) = resp.headers().get::<ContentLength>() {
+                self.total = Some(**len);
                 if **len > 0 {
                     return Next::read();
                 }
             }
-            self.resp_tx.send(Ok(Vec::new()));
+            let resp = self.respond(Vec::new());
+            self.resp_tx.send(resp);
             Next::end()
         } else if resp.status().is_redirection() {
             self.redirect_request(resp);
             Next::end()
-        } else if resp.status() == &StatusCode::Forbidden {
-            error!("on_response: 403 Forbidden");
-            self.resp_tx.send(Err(Error::AuthorizationError("403".to_string())));
-            Next::end()
         } else {
-            let msg = format!("failed response status: {}", resp.status());
-            error!("{}", msg);
-            self.resp_tx.send(Err(Error::ClientError(msg)));
+            self.resp_tx.send(Err(status_error(resp.status(), retry_after(&resp))));
             Next::end()
         }
     }
 
+    // Read until the decoder would block, reporting the progress of what was
+    // read before waiting for more.
     fn on_response_readable(&mut self, decoder: &mut Decoder<Stream>) -> Next {
-        match io::copy(decoder, &mut self.response) {
-            Ok(0) => {
-                debug!("on_response_readable bytes read: {:?}", self.response.len());
-                self.resp_tx.send(Ok(mem::replace(&mut self.response, Vec::new())));
-                Next::end()
-            }
+        let mut buf = [0; 8192];
+        loop {
+            match decoder.read(&mut buf) {
+                Ok(0) => {
+                    debug!("on_response_readable bytes read: {:?}", self.response.len());
+                    if let Some(ref mut progress) = self.req.progress {
+                        progress.update(self.response.len() as u64, self.total, true);
+                    }
+                    let resp = self.respond(mem::replace(&mut self.response, Vec::new()));
+                    self.resp_tx.send(resp);
+                    return Next::end();
+                }
 
-            Ok(n) => {
-                trace!("{} more response bytes read", n);
-                Next::read()
-            }
+                Ok(n) => {
+                    trace!("{} more response bytes read", n);
+                    self.response.extend_from_slice(&buf[..n]);
+                    if let Some(Err(err)) = self.req.progress.as_ref().map(|progress| progress.check_aborted()) {
+                        self.resp_tx.send(Err(err));
+                        return Next::end();
+                    }
+                }
 
-            Err(ref err) if err.kind() == ErrorKind::WouldBlock => {
-                trace!("retry on_response_readable");
-                Next::read()
-            }
+                Err(ref err) if err.kind() == ErrorKind::WouldBlock => {
+                    trace!("retry on_response_readable");
+                    if let Some(ref mut progress) = self.req.progress {
+                        progress.update(self.response.len() as u64, self.total, false);
+                    }
+                    return Next::read();
+                }
 
-            Err(err) => {
-                error!("unable to read response body: {}", err);
-                self.resp_tx.send(Err(Error::from(err)));
-                Next::end()
+                Err(err) => {
+                    error!("unable to read response body: {}", err);
+                    self.resp_tx.send(Err(Error::from(err)));
+                    return Next::end();
+                }
             }
         }
     }
@@ -244,20 +378,28 @@ impl Handler<Stream> for AuthHandler {
 
 #[cfg(test)]
 mod tests {
+    use chan;
     use rustc_serialize::json::Json;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::Duration;
 
     use super::*;
-    use datatype::{Auth, Method, Url};
-    use http_client::{HttpClient, HttpRequest};
+    use datatype::{Auth, Event, Method, Url};
+    use http_client::{HttpClient, HttpRequest, Progress};
 
 
     #[test]
     fn test_send_get_request() {
         let client = AuthClient::new(Auth::None);
         let req = HttpRequest {
-            method: Method::Get,
-            url:    Url::parse("http://eu.httpbin.org/bytes/16?seed=123").unwrap(),
-            body:   None,
+            method:          Method::Get,
+            url:             Url::parse("http://eu.httpbin.org/bytes/16?seed=123").unwrap(),
+            body:            None,
+            idempotency_key: None,
+            progress:        None,
+            range:           None,
         };
 
         let resp_rx = client.send_request(req);
@@ -269,9 +411,12 @@ mod tests {
     fn test_send_post_request() {
         let client = AuthClient::new(Auth::None);
         let req = HttpRequest {
-            method: Method::Post,
-            url:    Url::parse("https://eu.httpbin.org/post").unwrap(),
-            body:   Some(br#"foo"#.to_vec()),
+            method:          Method::Post,
+            url:             Url::parse("https://eu.httpbin.org/post").unwrap(),
+            body:            Some(br#"foo"#.to_vec()),
+            idempotency_key: None,
+            progress:        None,
+            range:           None,
         };
 
         let resp_rx = client.send_request(req);
@@ -282,4 +427,70 @@ mod tests {
         let data    = obj.get("data").unwrap().as_string().unwrap();
         assert_eq!(data, "foo");
     }
+
+    #[test]
+    fn reports_progress_while_reading() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address  = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            for line in BufReader::new(stream.try_clone().unwrap()).lines() {
+                if line.unwrap().is_empty() {
+                    break;
+                }
+            }
+            stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 8\r\n\r\nabcd").unwrap();
+            thread::sleep(Duration::from_millis(700));
+            stream.write_all(b"efgh").unwrap();
+        });
+
+        let (etx, erx) = chan::async::<Event>();
+        let client     = AuthClient::new(Auth::None);
+        let req = HttpRequest {
+            method:          Method::Get,
+            url:             Url::parse(&format!("http://{}/download", address)).unwrap(),
+            body:            None,
+            idempotency_key: None,
+            progress:        Some(Progress::new("1".to_string(), etx)),
+            range:           None,
+        };
+        assert_eq!(client.send_request(req).recv().unwrap().unwrap(), b"abcdefgh".to_vec());
+
+        let bytes = (0..2).map(|_| match erx.recv() {
+            Some(Event::DownloadProgress { bytes, .. }) => bytes,
+            event => panic!("unexpected event: {:?}", event)
+        }).collect::<Vec<_>>();
+        assert_eq!(bytes, vec![4, 8]);
+    }
+
+    #[test]
+    fn waits_for_the_configured_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address  = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                for line in BufReader::new(stream.try_clone().unwrap()).lines() {
+                    if line.unwrap().is_empty() {
+                        break;
+                    }
+                }
+                thread::sleep(Duration::from_millis(1000));
+                let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok");
+            }
+        });
+
+        let get = |timeout: Duration| {
+            AuthClient::new(Auth::None).with_timeout(timeout).send_request(HttpRequest {
+                method:          Method::Get,
+                url:             Url::parse(&format!("http://{}/push", address)).unwrap(),
+                body:            None,
+                idempotency_key: None,
+                progress:        None,
+                range:           None,
+            }).recv().unwrap()
+        };
+        assert!(get(Duration::from_millis(200)).is_err());
+        assert_eq!(get(Duration::from_secs(5)).unwrap(), b"ok".to_vec());
+    }
 }
@@ -1,7 +1,10 @@
 use chan;
 use chan::{Sender, Receiver};
+use std::fmt;
+use std::time::{Duration, Instant};
 
-use datatype::{Error, Method, Url};
+use aborts::Aborts;
+use datatype::{Error, Event, Method, UpdateRequestId, Url};
 
 
 pub trait HttpClient {
@@ -18,9 +21,103 @@ pub trait HttpClient {
 
 #[derive(Debug)]
 pub struct HttpRequest {
-    pub method: Method,
-    pub url:    Url,
-    pub body:   Option<Vec<u8>>
+    pub method:          Method,
+    pub url:             Url,
+    pub body:            Option<Vec<u8>>,
+    /// Sent as an `Idempotency-Key` header, so the server can drop a request
+    /// that is retried after it was already handled.
+    pub idempotency_key: Option<String>,
+    /// Reports how much of the response body has been read.
+    pub progress:        Option<Progress>,
+    /// The first and last byte of the response body to request.
+    pub range:           Option<(u64, u64)>,
 }
 
 pub type HttpResponse = Result<Vec<u8>, Error>;
+
+
+/// The minimum time between two progress events for the same download.
+pub const PROGRESS_INTERVAL_MS: u64 = 500;
+
+/// Sends an `Event::DownloadProgress` for the update `id` as the response
+/// body is read, at most every `PROGRESS_INTERVAL_MS`. The reader stops once
+/// the update is aborted.
+pub struct Progress {
+    id:      UpdateRequestId,
+    etx:     Sender<Event>,
+    aborts:  Aborts,
+    started: Instant,
+    last:    Option<Instant>,
+}
+
+impl Progress {
+    pub fn new(id: UpdateRequestId, etx: Sender<Event>) -> Progress {
+        Progress { id: id, etx: etx, aborts: Aborts::new(), started: Instant::now(), last: None }
+    }
+
+    /// Let the download be interrupted by an abort of its update.
+    pub fn with_aborts(mut self, aborts: Aborts) -> Progress {
+        self.aborts = aborts;
+        self
+    }
+
+    /// Fail once the update has been aborted.
+    pub fn check_aborted(&self) -> Result<(), Error> {
+        if self.aborts.is_requested(&self.id) {
+            Err(Error::DownloadAborted(self.id.clone()))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Report `bytes` read of `total`, unless reported too recently. The
+    /// last update of a download is always reported.
+    pub fn update(&mut self, bytes: u64, total: Option<u64>, done: bool) {
+        let now = Instant::now();
+        match self.last {
+            Some(last) if !done && now.duration_since(last) < Duration::from_millis(PROGRESS_INTERVAL_MS) => return,
+            _ => self.last = Some(now)
+        }
+
+        let elapsed = now.duration_since(self.started);
+        let millis  = elapsed.as_secs() * 1000 + elapsed.subsec_nanos() as u64 / 1_000_000;
+        self.etx.send(Event::DownloadProgress {
+            id:    self.id.clone(),
+            bytes: bytes,
+            total: total,
+            rate:  if millis > 0 { bytes * 1000 / millis } else { 0 },
+        });
+    }
+}
+
+impl fmt::Debug for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Progress({})", self.id)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use chan;
+
+    use super::*;
+    use datatype::Event;
+
+
+    #[test]
+    fn progress_is_rate_limited() {
+        let (etx, erx)   = chan::async::<Event>();
+        let mut progress = Progress::new("1".to_string(), etx);
+        progress.update(10, Some(30), false);
+        progress.update(20, Some(30), false);
+        progress.update(30, Some(30), true);
+        drop(progress);
+
+        let bytes = erx.iter().map(|event| match event {
+            Event::DownloadProgress { bytes, total, .. } => (bytes, total),
+            event => panic!("unexpected event: {:?}", event)
+        }).collect::<Vec<_>>();
+        assert_eq!(bytes, vec![(10, Some(30)), (30, Some(30))]);
+    }
+}
@@ -1,7 +1,9 @@
 pub use self::auth_client::{AuthClient, AuthHandler};
-pub use self::http_client::{HttpClient, HttpRequest, HttpResponse};
+pub use self::http_client::{HttpClient, HttpRequest, HttpResponse, Progress};
 pub use self::test_client::TestHttpClient;
 
 pub mod auth_client;
 pub mod http_client;
+pub mod proxy;
 pub mod test_client;
+pub mod tls;
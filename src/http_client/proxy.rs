@@ -0,0 +1,484 @@
+//! Sending requests through an HTTP proxy: plain HTTP requests are forwarded
+//! by the proxy, while HTTPS requests are tunneled with `CONNECT`.
+//!
+//! Requests are sent as HTTP/1.0 on a fresh connection, so that the response
+//! is never chunked and its body ends with the connection.
+
+use hyper::header::Headers;
+use hyper::method::Method as HyperMethod;
+use hyper::status::StatusCode;
+use rustc_serialize::base64::{ToBase64, STANDARD};
+use std::env;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use datatype::{Auth, Error, ProxyConfig, Url};
+use http_client::{AuthClient, HttpClient, HttpRequest, HttpResponse};
+use http_client::auth_client::{ignores_range, parse_retry_after, set_headers, status_error};
+use http_client::tls;
+use http_client::tls::Tls;
+
+
+/// The proxies for HTTP and HTTPS requests, and the hosts to reach directly.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Proxy {
+    pub http:     Option<Url>,
+    pub https:    Option<Url>,
+    pub no_proxy: Vec<String>,
+}
+
+impl Proxy {
+    /// The proxies from the `[proxy]` section, or else from the `HTTP_PROXY`,
+    /// `HTTPS_PROXY` and `NO_PROXY` environment variables (or their lowercase
+    /// forms). Returns `None` when no proxy is set.
+    pub fn from_config(config: Option<&ProxyConfig>) -> Result<Option<Proxy>, Error> {
+        let proxy = match config {
+            Some(cfg) => Proxy {
+                http:     try!(optional_url(cfg.http.clone())),
+                https:    try!(optional_url(cfg.https.clone())),
+                no_proxy: cfg.no_proxy.clone(),
+            },
+
+            None => Proxy {
+                http:     try!(optional_url(env_var("HTTP_PROXY"))),
+                https:    try!(optional_url(env_var("HTTPS_PROXY"))),
+                no_proxy: env_var("NO_PROXY").map(|hosts| split_hosts(&hosts)).unwrap_or(Vec::new()),
+            }
+        };
+
+        if proxy.http.is_none() && proxy.https.is_none() {
+            Ok(None)
+        } else {
+            Ok(Some(proxy))
+        }
+    }
+
+    /// The proxy to send a request for `url` through, if any.
+    pub fn for_url(&self, url: &Url) -> Option<&Url> {
+        let proxy = match url.0.scheme() {
+            "http"  => self.http.as_ref(),
+            "https" => self.https.as_ref(),
+            _       => None
+        };
+        match url.0.host_str() {
+            Some(host) if !self.bypasses(host) => proxy,
+            _ => None
+        }
+    }
+
+    // A `no_proxy` entry matches its host and any subdomain, and `*` matches
+    // all hosts.
+    fn bypasses(&self, host: &str) -> bool {
+        let host = host.to_lowercase();
+        self.no_proxy.iter().any(|entry| {
+            let entry = entry.trim().trim_left_matches('.').to_lowercase();
+            !entry.is_empty() && (entry == "*" || host == entry || host.ends_with(&format!(".{}", entry)))
+        })
+    }
+}
+
+/// Parse a proxy address, which may leave out the `http://` scheme.
+pub fn parse_proxy_url(url: &str) -> Result<Url, Error> {
+    let url = if url.contains("://") { url.to_string() } else { format!("http://{}", url) };
+    let url = try!(Url::parse(&url));
+    if url.0.scheme() != "http" || url.0.host_str().is_none() {
+        return Err(Error::ProxyError(format!("expected an http://host:port proxy, got: {}", url.to_string())));
+    }
+    Ok(url)
+}
+
+/// Split a comma-separated `NO_PROXY` list.
+pub fn split_hosts(hosts: &str) -> Vec<String> {
+    hosts.split(',').map(|host| host.trim().to_string()).filter(|host| !host.is_empty()).collect()
+}
+
+fn optional_url(url: Option<String>) -> Result<Option<Url>, Error> {
+    match url {
+        Some(url) => parse_proxy_url(&url).map(Some),
+        None      => Ok(None)
+    }
+}
+
+fn env_var(name: &str) -> Option<String> {
+    env::var(name).or(env::var(name.to_lowercase())).ok().and_then(|value| {
+        if value.trim().is_empty() { None } else { Some(value.trim().to_string()) }
+    })
+}
+
+
+/// Send a request through the proxy for its URL, or straight to the server
+/// when there is none, blocking until the whole response is read. Redirects
+/// are followed without the `Authorization`. HTTPS servers are verified as
+/// for direct requests, and also checked against any pinned certificates.
+pub fn send(proxy: Option<&Proxy>, auth: &Auth, tls: &Tls, timeout: Duration, mut req: HttpRequest) -> HttpResponse {
+    let proxy_url = proxy.and_then(|proxy| proxy.for_url(&req.url).cloned());
+    let target    = req.url.0.clone();
+    let host      = try!(target.host_str().map(|host| host.to_string())
+                         .ok_or(Error::ProxyError(format!("no host in {}", target))));
+    let port      = target.port_or_known_default().unwrap_or(80);
+
+    let mut tcp = match proxy_url {
+        Some(ref url) => try!(TcpStream::connect((url.0.host_str().unwrap_or(""), url.0.port_or_known_default().unwrap_or(80)))),
+        None          => try!(TcpStream::connect((host.as_str(), port)))
+    };
+    try!(tcp.set_read_timeout(Some(timeout)));
+    try!(tcp.set_write_timeout(Some(timeout)));
+
+    let mut headers = Headers::new();
+    headers.set_raw("Host", vec![format!("{}:{}", host, port).into_bytes()]);
+    headers.set_raw("Connection", vec![b"close".to_vec()]);
+    set_headers(auth, &mut req, &mut headers);
+    if let Some(ref body) = req.body {
+        headers.set_raw("Content-Length", vec![format!("{}", body.len()).into_bytes()]);
+    }
+
+    let path = match target.query() {
+        Some(query) => format!("{}?{}", target.path(), query),
+        None        => target.path().to_string()
+    };
+    let (status, resp_headers, body) = match (target.scheme(), proxy_url.as_ref()) {
+        ("https", proxy_url) => {
+            if let Some(proxy_url) = proxy_url {
+                try!(connect_tunnel(&mut tcp, proxy_url, &host, port));
+            }
+            let mut stream = try!(tls::connect(tls, tcp, &host));
+            try!(exchange(&mut stream, &path, headers, &mut req))
+        }
+
+        (_, Some(proxy_url)) => {
+            if let Some(credentials) = proxy_authorization(proxy_url) {
+                headers.set_raw("Proxy-Authorization", vec![credentials.into_bytes()]);
+            }
+            try!(exchange(&mut tcp, &target.to_string(), headers, &mut req))
+        }
+
+        (_, None) => try!(exchange(&mut tcp, &path, headers, &mut req))
+    };
+
+    match proxy_url {
+        Some(ref url) => info!("on_response status: {} (via proxy {})", status, url.to_string()),
+        None          => info!("on_response status: {}", status)
+    }
+    if status == StatusCode::ProxyAuthenticationRequired {
+        let proxy = proxy_url.map(|url| url.to_string()).unwrap_or(String::new());
+        Err(Error::ProxyError(format!("proxy {} refused the credentials", proxy)))
+    } else if status.is_success() && ignores_range(&req, &status) {
+        Err(Error::RangeIgnored(body))
+    } else if status.is_success() {
+        Ok(body)
+    } else if status.is_redirection() {
+        let location = try!(find_header(&resp_headers, "Location").ok_or_else(|| {
+            Error::ClientError("redirection without Location header".to_string())
+        }));
+        let url = try!(req.url.join(&location));
+        debug!("redirecting to {:?}", url);
+        let client = AuthClient::with_ssl(Auth::None, tls.clone(), proxy.cloned()).with_timeout(timeout);
+        let resp_rx = client.send_request(HttpRequest {
+            url:             url,
+            method:          req.method.clone(),
+            body:            req.body.clone(),
+            idempotency_key: req.idempotency_key.clone(),
+            progress:        req.progress.take(),
+            range:           req.range,
+        });
+        resp_rx.recv().expect("no redirect_request response")
+    } else {
+        let retry_after = find_header(&resp_headers, "Retry-After").and_then(|value| parse_retry_after(value.as_bytes()));
+        Err(status_error(&status, retry_after))
+    }
+}
+
+fn proxy_authorization(proxy_url: &Url) -> Option<String> {
+    match (proxy_url.0.username(), proxy_url.0.password()) {
+        ("", None) => None,
+        (user, pass) => {
+            let credentials = format!("{}:{}", user, pass.unwrap_or(""));
+            Some(format!("Basic {}", credentials.as_bytes().to_base64(STANDARD)))
+        }
+    }
+}
+
+// Ask the proxy for a tunnel to `host:port`, leaving the stream at the start
+// of the tunnel.
+fn connect_tunnel(tcp: &mut TcpStream, proxy_url: &Url, host: &str, port: u16) -> Result<(), Error> {
+    let mut request = format!("CONNECT {0}:{1} HTTP/1.1\r\nHost: {0}:{1}\r\n", host, port);
+    if let Some(auth) = proxy_authorization(proxy_url) {
+        request.push_str(&format!("Proxy-Authorization: {}\r\n", auth));
+    }
+    request.push_str("\r\n");
+    try!(tcp.write_all(request.as_bytes()));
+
+    // read byte by byte so that nothing of the tunnel is buffered away
+    let mut head = Vec::new();
+    let mut byte = [0; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        if try!(tcp.read(&mut byte)) == 0 {
+            return Err(Error::ProxyError("connection closed during CONNECT".to_string()));
+        }
+        head.push(byte[0]);
+    }
+
+    let head   = String::from_utf8_lossy(&head).into_owned();
+    let status = try!(parse_status(head.lines().next().unwrap_or("")));
+    if status.is_success() {
+        Ok(())
+    } else if status == StatusCode::ProxyAuthenticationRequired {
+        Err(Error::ProxyError(format!("proxy {} refused the credentials", proxy_url.to_string())))
+    } else {
+        Err(Error::ProxyError(format!("CONNECT to {}:{} refused: {}", host, port, status)))
+    }
+}
+
+// Write the request and read the whole response, reporting the progress of
+// the body.
+fn exchange<S: Read + Write>(stream: &mut S, target: &str, headers: Headers, req: &mut HttpRequest)
+                             -> Result<(StatusCode, Vec<(String, String)>, Vec<u8>), Error> {
+    let method: HyperMethod = req.method.clone().into();
+    try!(write!(stream, "{} {} HTTP/1.0\r\n{}\r\n", method, target, headers));
+    if let Some(ref body) = req.body {
+        try!(stream.write_all(body));
+    }
+    try!(stream.flush());
+
+    // skip any interim 1xx responses
+    let mut reader = BufReader::new(stream);
+    let mut head   = try!(read_head(&mut reader));
+    while head.0.is_informational() {
+        head = try!(read_head(&mut reader));
+    }
+    let (status, resp_headers) = head;
+
+    let total    = find_header(&resp_headers, "Content-Length").and_then(|len| len.parse::<u64>().ok());
+    let mut body = Vec::new();
+    try!(read_body(&mut reader, total, &mut body, total, req));
+    if let Some(ref mut progress) = req.progress {
+        progress.update(body.len() as u64, total, true);
+    }
+
+    Ok((status, resp_headers, body))
+}
+
+fn read_head<R: BufRead>(reader: &mut R) -> Result<(StatusCode, Vec<(String, String)>), Error> {
+    let mut line = String::new();
+    try!(reader.read_line(&mut line));
+    let status = try!(parse_status(&line));
+
+    let mut headers = Vec::new();
+    loop {
+        line.clear();
+        if try!(reader.read_line(&mut line)) == 0 || line.trim().is_empty() {
+            break;
+        }
+        let mut parts = line.splitn(2, ':');
+        let name      = parts.next().unwrap_or("").trim().to_string();
+        let value     = parts.next().unwrap_or("").trim().to_string();
+        headers.push((name, value));
+    }
+    Ok((status, headers))
+}
+
+// Read `len` bytes, or up to the end of the stream.
+fn read_body<R: Read>(reader: &mut R, len: Option<u64>, body: &mut Vec<u8>, total: Option<u64>,
+                      req: &mut HttpRequest) -> Result<(), Error> {
+    let mut buf  = [0; 8192];
+    let mut left = len;
+    while left != Some(0) {
+        let want = left.map(|left| if left < buf.len() as u64 { left as usize } else { buf.len() }).unwrap_or(buf.len());
+        let n    = try!(reader.read(&mut buf[..want]));
+        if n == 0 {
+            if left.is_some() {
+                return Err(Error::ProxyError("response body ended early".to_string()));
+            }
+            break;
+        }
+        body.extend_from_slice(&buf[..n]);
+        left = left.map(|left| left - n as u64);
+
+        if let Some(ref mut progress) = req.progress {
+            try!(progress.check_aborted());
+            progress.update(body.len() as u64, total, false);
+        }
+    }
+    Ok(())
+}
+
+fn parse_status(line: &str) -> Result<StatusCode, Error> {
+    match line.split_whitespace().nth(1).and_then(|code| code.parse::<u16>().ok()) {
+        Some(code) => Ok(StatusCode::from_u16(code)),
+        None       => Err(Error::ProxyError(format!("invalid status line: {}", line.trim())))
+    }
+}
+
+fn find_header(headers: &[(String, String)], name: &str) -> Option<String> {
+    headers.iter().find(|&&(ref key, _)| key.to_lowercase() == name.to_lowercase()).map(|&(_, ref value)| value.clone())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use chan;
+    use chan::Receiver;
+    use openssl::ssl::{SslContext, SslMethod, SslStream};
+    use openssl::x509::X509FileType;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use super::*;
+    use datatype::{Auth, Error, Method, TlsConfig, Url};
+    use http_client::{AuthClient, HttpClient, HttpRequest};
+    use http_client::tls::Tls;
+
+
+    const CERT: &'static str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/certs/localhost.crt");
+    const KEY:  &'static str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/certs/localhost.key");
+
+    fn proxy(url: &str, no_proxy: &[&str]) -> Proxy {
+        Proxy {
+            http:     Some(parse_proxy_url(url).unwrap()),
+            https:    Some(parse_proxy_url(url).unwrap()),
+            no_proxy: no_proxy.iter().map(|host| host.to_string()).collect(),
+        }
+    }
+
+    fn get(url: &str) -> HttpRequest {
+        HttpRequest {
+            method:          Method::Get,
+            url:             Url::parse(url).unwrap(),
+            body:            None,
+            idempotency_key: None,
+            progress:        None,
+            range:           None,
+        }
+    }
+
+    fn read_lines<R: Read>(reader: R) -> Vec<String> {
+        let mut lines = Vec::new();
+        for line in BufReader::new(reader).lines() {
+            let line = line.unwrap();
+            if line.is_empty() {
+                break;
+            }
+            lines.push(line);
+        }
+        lines
+    }
+
+    // Accept a CONNECT and then act as the HTTPS server at the end of the
+    // tunnel, returning the CONNECT and the tunneled request.
+    fn serve_tunnel() -> (String, Receiver<(Vec<String>, Vec<String>)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address  = format!("http://{}", listener.local_addr().unwrap());
+        let (req_tx, req_rx) = chan::async::<(Vec<String>, Vec<String>)>();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut head = Vec::new();
+            let mut byte = [0; 1];
+            while !head.ends_with(b"\r\n\r\n") {
+                stream.read(&mut byte).unwrap();
+                head.push(byte[0]);
+            }
+            let connect = String::from_utf8(head).unwrap().lines().map(|line| line.to_string()).collect();
+            stream.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n").unwrap();
+
+            let mut ctx = SslContext::new(SslMethod::Sslv23).unwrap();
+            ctx.set_certificate_file(CERT, X509FileType::PEM).unwrap();
+            ctx.set_private_key_file(KEY, X509FileType::PEM).unwrap();
+            if let Ok(mut tls) = SslStream::accept(&ctx, stream) {
+                let lines = read_lines(&mut tls);
+                tls.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok").unwrap();
+                req_tx.send((connect, lines));
+            }
+        });
+        (address, req_rx)
+    }
+
+    fn trusting_test_cert() -> Tls {
+        let mut cfg = TlsConfig::default();
+        cfg.ca_file = Some(CERT.to_string());
+        Tls::from_config(&cfg).unwrap()
+    }
+
+    #[test]
+    fn matches_no_proxy_hosts() {
+        let proxy = proxy("proxy.corp:3128", &["localhost", ".internal.corp", "10.0.0.1"]);
+        let url   = |s: &str| Url::parse(s).unwrap();
+        assert_eq!(proxy.for_url(&url("https://ota.example.com/api")).map(|url| url.to_string()),
+                   Some("http://proxy.corp:3128/".to_string()));
+        assert!(proxy.for_url(&url("http://localhost:8080/")).is_none());
+        assert!(proxy.for_url(&url("https://ota.internal.corp/")).is_none());
+        assert!(proxy.for_url(&url("https://internal.corp/")).is_none());
+        assert!(proxy.for_url(&url("http://10.0.0.1/")).is_none());
+        assert!(parse_proxy_url("https://proxy.corp:3128").is_err());
+        assert_eq!(split_hosts(" a.com, ,b.com"), vec!["a.com".to_string(), "b.com".to_string()]);
+    }
+
+    #[test]
+    fn forwards_plain_http_requests() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address  = format!("http://user:pass@{}", listener.local_addr().unwrap());
+        let (req_tx, req_rx) = chan::async::<Vec<String>>();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let lines = read_lines(stream.try_clone().unwrap());
+            stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\n\r\nok").unwrap();
+            req_tx.send(lines);
+        });
+
+        let req  = get("http://ota.example.com/api/v1/updates");
+        let body = send(Some(&proxy(&address, &[])), &Auth::None, &Tls::default(), Duration::from_secs(20), req).unwrap();
+        assert_eq!(body, b"ok".to_vec());
+
+        let lines = req_rx.recv().unwrap();
+        assert_eq!(lines[0], "GET http://ota.example.com/api/v1/updates HTTP/1.0".to_string());
+        assert!(lines.contains(&"Proxy-Authorization: Basic dXNlcjpwYXNz".to_string()));
+        assert!(lines.contains(&"Host: ota.example.com:80".to_string()));
+    }
+
+    #[test]
+    fn slow_requests_dont_hold_up_others() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address  = format!("http://{}", listener.local_addr().unwrap());
+        thread::spawn(move || for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            thread::spawn(move || {
+                let lines = read_lines(stream.try_clone().unwrap());
+                if lines[0].contains("/poll") {
+                    thread::sleep(Duration::from_secs(5));
+                }
+                let _ = stream.write_all(b"HTTP/1.1 200 OK\r\n\r\nok");
+            });
+        });
+
+        let client  = AuthClient::with_ssl(Auth::None, Tls::default(), Some(proxy(&address, &[])));
+        let started = Instant::now();
+        let _poll   = client.send_request(get("http://ota.example.com/poll"));
+        let updates = client.send_request(get("http://ota.example.com/updates"));
+        assert_eq!(updates.recv().unwrap().unwrap(), b"ok".to_vec());
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn tunnels_https_requests() {
+        let (address, req_rx) = serve_tunnel();
+        let req  = get("https://localhost:8443/api/v1/updates?limit=1");
+        let body = send(Some(&proxy(&address, &[])), &Auth::None, &trusting_test_cert(), Duration::from_secs(20), req).unwrap();
+        assert_eq!(body, b"ok".to_vec());
+
+        let (connect, lines) = req_rx.recv().unwrap();
+        assert_eq!(connect[0], "CONNECT localhost:8443 HTTP/1.1".to_string());
+        assert_eq!(lines[0], "GET /api/v1/updates?limit=1 HTTP/1.0".to_string());
+    }
+
+    #[test]
+    fn verifies_the_tunneled_host() {
+        let (address, _) = serve_tunnel();
+        let req = get("https://127.0.0.1:8443/api/v1/updates");
+        match send(Some(&proxy(&address, &[])), &Auth::None, &trusting_test_cert(), Duration::from_secs(20), req) {
+            Err(Error::TlsError(_)) => (),
+            other => panic!("expected a TLS error, got: {:?}", other)
+        }
+    }
+}
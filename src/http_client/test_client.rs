@@ -6,7 +6,7 @@ use datatype::Error;
 
 
 pub struct TestHttpClient {
-    replies: RefCell<Vec<String>>
+    replies: RefCell<Vec<HttpResponse>>
 }
 
 impl TestHttpClient {
@@ -15,6 +15,11 @@ impl TestHttpClient {
     }
 
     pub fn from(replies: Vec<String>) -> TestHttpClient {
+        TestHttpClient::from_responses(replies.into_iter().map(|body| Ok(body.into_bytes())).collect())
+    }
+
+    /// Reply with each response in turn, starting from the last one.
+    pub fn from_responses(replies: Vec<HttpResponse>) -> TestHttpClient {
         TestHttpClient { replies: RefCell::new(replies) }
     }
 }
@@ -22,7 +27,7 @@ impl TestHttpClient {
 impl HttpClient for TestHttpClient {
     fn chan_request(&self, req: HttpRequest, resp_tx: Sender<HttpResponse>) {
         match self.replies.borrow_mut().pop() {
-            Some(body) => resp_tx.send(Ok(body.as_bytes().to_vec())),
+            Some(resp) => resp_tx.send(resp),
             None       => resp_tx.send(Err(Error::ClientError(req.url.to_string())))
         }
     }
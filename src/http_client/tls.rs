@@ -0,0 +1,306 @@
+use hyper::net::Openssl;
+use openssl::crypto::hash::Type as HashType;
+use openssl::crypto::pkey::PKey;
+use openssl::ssl::{Ssl, SslContext, SslMethod, SslStream, SSL_VERIFY_PEER};
+use openssl::x509::{X509, X509FileType, X509StoreContext};
+use openssl_verify;
+use rustc_serialize::hex::FromHex;
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::process::Command;
+use std::sync::Arc;
+
+use credentials;
+use credentials::CredentialsKey;
+use datatype::{Error, TlsConfig};
+
+
+/// The OpenSSL context for the `[tls]` settings, and the SHA-256
+/// fingerprints that the server's own certificate must match when any are
+/// pinned.
+#[derive(Clone, Default)]
+pub struct Tls {
+    pub ssl:  Openssl,
+    pub pins: Vec<Vec<u8>>,
+}
+
+impl Tls {
+    pub fn from_config(cfg: &TlsConfig) -> Result<Tls, Error> {
+        let pins = match cfg.pinned_certs {
+            Some(ref pinned) => try!(pinned.iter().map(|pin| parse_fingerprint(pin)).collect::<Result<Vec<_>, _>>()),
+            None             => Vec::new()
+        };
+        Ok(Tls { ssl: try!(openssl(cfg, &pins)), pins: pins })
+    }
+}
+
+/// Build an OpenSSL context from the `[tls]` config section, loading any
+/// custom CA bundle and client certificate. The server's certificate is
+/// checked against any `pins` while it is verified.
+pub fn openssl(cfg: &TlsConfig, pins: &[Vec<u8>]) -> Result<Openssl, Error> {
+    let mut ctx = try!(SslContext::new(SslMethod::Sslv23).map_err(tls_error));
+    try!(ctx.set_default_verify_paths().map_err(tls_error));
+
+    if let Some(ref ca_file) = cfg.ca_file {
+        debug!("loading CA bundle from {}", ca_file);
+        try!(ctx.set_CA_file(ca_file).map_err(tls_error));
+    }
+
+    match (&cfg.cert_file, &cfg.key_file, &cfg.pkcs12_file) {
+        (&None, &None, &None) => (),
+
+        (&Some(ref cert_file), &Some(ref key_file), &None) => {
+            debug!("loading client certificate from {}", cert_file);
+            try!(ctx.set_certificate_file(cert_file, X509FileType::PEM).map_err(tls_error));
+            let pem = try!(read_private_key(key_file, cfg.credentials_key.as_ref()));
+            let key = try!(PKey::private_key_from_pem(&mut &pem[..]).map_err(tls_error));
+            try!(ctx.set_private_key(&key).map_err(tls_error));
+            try!(ctx.check_private_key().map_err(tls_error));
+        }
+
+        (&None, &None, &Some(ref pkcs12_file)) => {
+            debug!("loading client certificate from {}", pkcs12_file);
+            let password = cfg.pkcs12_password.clone().unwrap_or(String::new());
+            let client   = try!(pkcs12_to_pem(pkcs12_file, &password, "-clcerts"));
+            let cert     = try!(X509::from_pem(&mut &client[..]).map_err(tls_error));
+            let key      = try!(PKey::private_key_from_pem(&mut &client[..]).map_err(tls_error));
+            try!(ctx.set_certificate(&cert).map_err(tls_error));
+            try!(ctx.set_private_key(&key).map_err(tls_error));
+            let chain = try!(pkcs12_to_pem(pkcs12_file, &password, "-cacerts"));
+            for pem in split_pem_certs(&chain) {
+                let cert = try!(X509::from_pem(&mut pem.as_bytes()).map_err(tls_error));
+                try!(ctx.add_extra_chain_cert(&cert).map_err(tls_error));
+            }
+            try!(ctx.check_private_key().map_err(tls_error));
+        }
+
+        _ => return Err(Error::TlsError("expected either cert_file and key_file, or pkcs12_file".to_string()))
+    }
+
+    if !pins.is_empty() {
+        let pins = pins.to_vec();
+        ctx.set_verify_callback(SSL_VERIFY_PEER, move |preverified, x509_ctx| {
+            preverified && matches_pin(&pins, x509_ctx)
+        });
+    }
+
+    Ok(Openssl { context: Arc::new(ctx) })
+}
+
+/// Open a TLS session over `stream`, verifying the server's certificate chain,
+/// that it was issued for `host`, and that it matches a pinned fingerprint.
+pub fn connect<S: Read + Write>(tls: &Tls, stream: S, host: &str) -> Result<SslStream<S>, Error> {
+    let mut session = try!(Ssl::new(&tls.ssl.context).map_err(tls_error));
+    try!(session.set_hostname(host).map_err(tls_error));
+    let domain = host.to_string();
+    session.set_verify_callback(SSL_VERIFY_PEER, move |preverified, x509_ctx| {
+        openssl_verify::verify_callback(&domain, preverified, x509_ctx)
+    });
+    let stream = try!(SslStream::connect(session, stream).map_err(tls_error));
+    if !tls.pins.is_empty() {
+        try!(check_pin(&tls.pins, stream.ssl().peer_certificate()));
+    }
+    Ok(stream)
+}
+
+// The verify callback of the context, used by the async client. A session
+// with its own callback, as in `connect`, checks the pin after the handshake.
+fn matches_pin(pins: &[Vec<u8>], x509_ctx: &X509StoreContext) -> bool {
+    if x509_ctx.error_depth() > 0 {
+        return true;
+    }
+    check_pin(pins, x509_ctx.get_current_cert()).map_err(|err| error!("{}", err)).is_ok()
+}
+
+// Only the server's own (leaf) certificate is pinned; intermediates are
+// checked by the usual chain verification.
+fn check_pin(pins: &[Vec<u8>], cert: Option<X509>) -> Result<(), Error> {
+    match cert.and_then(|cert| cert.fingerprint(HashType::SHA256)) {
+        Some(ref fingerprint) if pins.contains(fingerprint) => Ok(()),
+        Some(_) => Err(Error::TlsError("server certificate does not match any pinned fingerprint".to_string())),
+        None    => Err(Error::TlsError("unable to fingerprint server certificate".to_string()))
+    }
+}
+
+// The OpenSSL version the client is built against can't parse PKCS#12, so
+// the `openssl` tool converts it to PEM, with the password passed through
+// its environment rather than its arguments.
+fn pkcs12_to_pem(path: &str, password: &str, certs: &str) -> Result<Vec<u8>, Error> {
+    try!(File::open(path));
+    let output = try!(Command::new("openssl")
+                      .args(&["pkcs12", "-in", path, "-nodes", certs, "-passin", "env:PKCS12_PASSWORD"])
+                      .env("PKCS12_PASSWORD", password)
+                      .output());
+    if output.status.success() {
+        Ok(output.stdout)
+    } else {
+        Err(Error::TlsError(format!("unable to read {}: {}", path, String::from_utf8_lossy(&output.stderr).trim())))
+    }
+}
+
+fn split_pem_certs(pem: &[u8]) -> Vec<String> {
+    const END: &'static str = "-----END CERTIFICATE-----";
+    let text = String::from_utf8_lossy(pem);
+    text.split(END)
+        .filter_map(|part| part.find("-----BEGIN CERTIFICATE-----").map(|start| format!("{}{}\n", &part[start..], END)))
+        .collect()
+}
+
+/// Read a PEM private key, decrypting it when it was written encrypted at
+/// provisioning.
+fn read_private_key(path: &str, key: Option<&CredentialsKey>) -> Result<Vec<u8>, Error> {
+    let mut text = String::new();
+    try!(try!(File::open(path)).read_to_string(&mut text));
+    match (credentials::is_encrypted(&text), key) {
+        (true, Some(key)) => credentials::decrypt(key, &text),
+        (true, None)      => Err(Error::CryptoError(format!("{} is encrypted but no credentials_key is set", path))),
+        (false, _)        => Ok(text.into_bytes())
+    }
+}
+
+/// Parse a SHA-256 certificate fingerprint, with or without colon separators.
+pub fn parse_fingerprint(pin: &str) -> Result<Vec<u8>, Error> {
+    let hex = pin.replace(":", "");
+    match hex.from_hex() {
+        Ok(ref bytes) if bytes.len() == 32 => Ok(bytes.clone()),
+        _ => Err(Error::TlsError(format!("invalid SHA-256 fingerprint: {}", pin)))
+    }
+}
+
+fn tls_error<E: Display>(err: E) -> Error {
+    Error::TlsError(format!("{}", err))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use openssl::ssl::{SslContext, SslMethod, SslStream};
+    use openssl::x509::X509FileType;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    use super::*;
+    use datatype::{Auth, Method, TlsConfig, Url};
+    use http_client::{AuthClient, HttpClient, HttpRequest};
+
+
+    const CERT: &'static str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/certs/localhost.crt");
+    const KEY:  &'static str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/certs/localhost.key");
+    const PIN:  &'static str = "55:C2:71:0F:D4:E6:D1:DF:9D:9E:BB:81:10:2D:66:19:28:96:02:E3:82:93:B4:22:A7:67:16:5C:50:C9:FF:4A";
+
+    // Serve one TLS connection with the test certificate, echoing a byte.
+    fn serve() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address  = format!("{}", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut ctx = SslContext::new(SslMethod::Sslv23).unwrap();
+            ctx.set_certificate_file(CERT, X509FileType::PEM).unwrap();
+            ctx.set_private_key_file(KEY, X509FileType::PEM).unwrap();
+            if let Ok(mut tls) = SslStream::accept(&ctx, stream) {
+                let mut byte = [0; 1];
+                let _ = tls.read_exact(&mut byte).and_then(|_| tls.write_all(&byte));
+            }
+        });
+        address
+    }
+
+    // Answer one HTTPS request with the test certificate, returning the port.
+    fn serve_https() -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port     = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut ctx = SslContext::new(SslMethod::Sslv23).unwrap();
+            ctx.set_certificate_file(CERT, X509FileType::PEM).unwrap();
+            ctx.set_private_key_file(KEY, X509FileType::PEM).unwrap();
+            if let Ok(mut tls) = SslStream::accept(&ctx, stream) {
+                let mut head = Vec::new();
+                let mut byte = [0; 1];
+                while !head.ends_with(b"\r\n\r\n") && tls.read_exact(&mut byte).is_ok() {
+                    head.push(byte[0]);
+                }
+                let _ = tls.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok");
+            }
+        });
+        port
+    }
+
+    fn get(port: u16) -> HttpRequest {
+        HttpRequest {
+            method:          Method::Get,
+            url:             Url::parse(&format!("https://localhost:{}/", port)).unwrap(),
+            body:            None,
+            idempotency_key: None,
+            progress:        None,
+            range:           None,
+        }
+    }
+
+    fn tls(pins: &[&str]) -> Tls {
+        let mut cfg      = TlsConfig::default();
+        cfg.ca_file      = Some(CERT.to_string());
+        cfg.pinned_certs = Some(pins.iter().map(|pin| pin.to_string()).collect());
+        Tls::from_config(&cfg).unwrap()
+    }
+
+
+    #[test]
+    fn test_parse_fingerprint() {
+        let pin = "AB:CD:EF:01:23:45:67:89:AB:CD:EF:01:23:45:67:89:AB:CD:EF:01:23:45:67:89:AB:CD:EF:01:23:45:67:89";
+        assert_eq!(parse_fingerprint(pin).unwrap().len(), 32);
+        assert_eq!(parse_fingerprint(pin).unwrap(), parse_fingerprint(&pin.replace(":", "")).unwrap());
+        assert!(parse_fingerprint("AB:CD").is_err());
+        assert!(parse_fingerprint("not hex").is_err());
+    }
+
+    #[test]
+    fn test_incomplete_client_certificate() {
+        let mut cfg   = TlsConfig::default();
+        cfg.cert_file = Some("/tmp/client.pem".to_string());
+        assert_eq!(format!("{}", openssl(&cfg, &[]).unwrap_err()),
+                   "TLS error: expected either cert_file and key_file, or pkcs12_file");
+    }
+
+    #[test]
+    fn test_missing_pkcs12_file() {
+        let mut cfg     = TlsConfig::default();
+        cfg.pkcs12_file = Some("/nonexistent/client.p12".to_string());
+        assert_eq!(format!("{}", openssl(&cfg, &[]).unwrap_err()),
+                   "IO error: No such file or directory (os error 2)");
+    }
+
+    #[test]
+    fn connects_to_a_pinned_server() {
+        let mut stream = connect(&tls(&[PIN]), TcpStream::connect(serve().as_str()).unwrap(), "localhost").unwrap();
+        stream.write_all(b"x").unwrap();
+        let mut byte = [0; 1];
+        stream.read_exact(&mut byte).unwrap();
+        assert_eq!(&byte, b"x");
+    }
+
+    #[test]
+    fn refuses_an_unpinned_server() {
+        let other = "AB:CD:EF:01:23:45:67:89:AB:CD:EF:01:23:45:67:89:AB:CD:EF:01:23:45:67:89:AB:CD:EF:01:23:45:67:89";
+        let tcp   = TcpStream::connect(serve().as_str()).unwrap();
+        assert_eq!(format!("{}", connect(&tls(&[other]), tcp, "localhost").unwrap_err()),
+                   "TLS error: server certificate does not match any pinned fingerprint");
+    }
+
+    #[test]
+    fn pins_async_connections() {
+        let other  = "AB:CD:EF:01:23:45:67:89:AB:CD:EF:01:23:45:67:89:AB:CD:EF:01:23:45:67:89:AB:CD:EF:01:23:45:67:89";
+        let pinned = AuthClient::with_ssl(Auth::None, tls(&[PIN]), None);
+        assert_eq!(pinned.send_request(get(serve_https())).recv().unwrap().unwrap(), b"ok".to_vec());
+        let unpinned = AuthClient::with_ssl(Auth::None, tls(&[other]), None);
+        assert!(unpinned.send_request(get(serve_https())).recv().unwrap().is_err());
+    }
+
+    #[test]
+    fn refuses_the_wrong_host() {
+        let tcp = TcpStream::connect(serve().as_str()).unwrap();
+        assert!(connect(&tls(&[PIN]), tcp, "example.com").is_err());
+    }
+}
@@ -8,6 +8,7 @@ use std::string::ToString;
 use std::sync::{Arc, Mutex};
 
 use super::gateway::{Gateway, Interpret};
+use datatype::GatewayConfig;
 
 
 pub struct Console;
@@ -17,7 +18,7 @@ impl<C, E> Gateway<C, E> for Console
           E: ToString + Send + Clone + Debug + 'static,
           <C as FromStr>::Err: Debug,
 {
-    fn new(itx: Sender<Interpret<C, E>>) -> Result<Self, String> {
+    fn new(itx: Sender<Interpret<C, E>>, _: &GatewayConfig) -> Result<Self, String> {
         let (etx, erx) = chan::sync::<E>(0);
         let etx        = Arc::new(Mutex::new(etx));
 
@@ -4,6 +4,8 @@ use std::thread;
 use std::fmt::Debug;
 use std::sync::{Arc, Mutex};
 
+use datatype::GatewayConfig;
+
 
 #[derive(Clone, Debug)]
 pub struct Interpret<C, E>
@@ -14,24 +16,31 @@ pub struct Interpret<C, E>
     pub response_tx: Option<Arc<Mutex<Sender<E>>>>,
 }
 
+/// Closes a running gateway.
+pub type GatewayClose = Box<Fn() + Send>;
+
 pub trait Gateway<C, E>: Sized + Send + Sync + 'static
     where C: Send + Clone + Debug + 'static,
           E: Send + Clone + Debug + 'static,
 {
-    fn new(itx: Sender<Interpret<C, E>>) -> Result<Self, String>;
+    fn new(itx: Sender<Interpret<C, E>>, cfg: &GatewayConfig) -> Result<Self, String>;
 
-    fn run(itx: Sender<Interpret<C, E>>, erx: Receiver<E>) {
-        let gateway = Self::new(itx).unwrap_or_else(|err| {
+    fn run(itx: Sender<Interpret<C, E>>, erx: Receiver<E>, cfg: &GatewayConfig) -> GatewayClose {
+        let gateway = Arc::new(Self::new(itx, cfg).unwrap_or_else(|err| {
             error!("couldn't start gateway: {}", err);
             std::process::exit(1);
-        });
+        }));
 
+        let pulsed = gateway.clone();
         thread::spawn(move || {
             loop {
-                gateway.pulse(erx.recv().expect("all gateway event transmitters are closed"));
+                pulsed.pulse(erx.recv().expect("all gateway event transmitters are closed"));
             }
         });
+        Box::new(move || gateway.close())
     }
 
     fn pulse(&self, _: E) {} // ignore global events by default
+
+    fn close(&self) {} // nothing to close by default
 }
@@ -4,36 +4,46 @@ use hyper::{Decoder, Encoder, Next, StatusCode};
 use hyper::header::{ContentLength, ContentType};
 use hyper::mime::{Attr, Mime, TopLevel, SubLevel, Value};
 use hyper::net::HttpStream;
-use hyper::server::{Handler, Server, Request, Response};
+use hyper::server::{Handler, Listening, Server, Request, Response};
 use rustc_serialize::{json, Decodable, Encodable};
-use std::{env, io, mem, thread};
+use std::{io, mem, thread};
 use std::fmt::Debug;
 use std::io::{ErrorKind, Write};
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
 use super::gateway::{Gateway, Interpret};
+use datatype::GatewayConfig;
 
 
-pub struct Http;
+pub struct Http {
+    listening: Mutex<Option<Listening>>,
+}
 
 impl<C, E> Gateway<C, E> for Http
     where C: Decodable + Send + Clone + Debug + 'static,
           E: Encodable + Send + Clone + Debug + 'static
 {
-    fn new(itx: Sender<Interpret<C, E>>) -> Result<Self, String> {
+    fn new(itx: Sender<Interpret<C, E>>, cfg: &GatewayConfig) -> Result<Self, String> {
         let itx    = Arc::new(Mutex::new(itx));
-        let addr   = env::var("OTA_PLUS_CLIENT_HTTP_ADDR").unwrap_or("127.0.0.1:8888".to_string());
+        let addr   = try!(cfg.http_server.parse().map_err(|err| format!("invalid http_server address: {}", err)));
 
-        let server = match Server::http(&addr.parse().unwrap()) {
+        let server = match Server::http(&addr) {
             Ok(server) => server,
             Err(err)   => return Err(format!("couldn't start http server: {}", err))
         };
-        let (addr, server) = server.handle(move |_| HttpHandler::new(itx.clone())).unwrap();
+        let (listening, server) = server.handle(move |_| HttpHandler::new(itx.clone())).unwrap();
         thread::spawn(move || server.run());
 
-        info!("Listening on http://{}", addr);
-        Ok(Http)
+        info!("Listening on http://{}", listening);
+        Ok(Http { listening: Mutex::new(Some(listening)) })
+    }
+
+    fn close(&self) {
+        if let Some(listening) = self.listening.lock().unwrap().take() {
+            info!("Closing the http listener on {}", listening);
+            listening.close();
+        }
     }
 }
 
@@ -195,7 +205,7 @@ mod tests {
 
     use super::*;
     use super::super::gateway::Gateway;
-    use super::super::super::datatype::{Auth, Command, Event, Method, Url};
+    use super::super::super::datatype::{Auth, Command, Event, GatewayConfig, Method, Url};
     use super::super::super::http_client::{AuthClient, HttpClient, HttpRequest};
     use super::super::super::interpreter::Global;
 
@@ -203,7 +213,7 @@ mod tests {
     fn http_connections() {
         let (etx, erx) = chan::sync::<Event>(0);
         let (gtx, grx) = chan::sync::<Global>(0);
-        Http::run(gtx, erx);
+        Http::run(gtx, erx, &GatewayConfig::default());
 
         thread::spawn(move || {
             let _ = etx; // move into this scope
@@ -228,9 +238,12 @@ mod tests {
                     let req_body = json::encode(&cmd).unwrap();
 
                     let req = HttpRequest {
-                        method: Method::Post,
-                        url:    Url::parse("http://127.0.0.1:8888").unwrap(),
-                        body:   Some(req_body.into_bytes()),
+                        method:          Method::Post,
+                        url:             Url::parse("http://127.0.0.1:8888").unwrap(),
+                        body:            Some(req_body.into_bytes()),
+                        idempotency_key: None,
+                        progress:        None,
+                        range:           None,
                     };
                     let resp_rx = client.send_request(req);
                     let resp    = resp_rx.recv().unwrap().unwrap();
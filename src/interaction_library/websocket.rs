@@ -1,49 +1,53 @@
 use chan;
 use chan::Sender;
 use rustc_serialize::{json, Decodable, Encodable};
-use std::{env, thread};
+use std::thread;
 use std::collections::HashMap;
 use std::fmt::Debug;
 use std::sync::{Arc, Mutex};
 use ws;
-use ws::{listen, CloseCode, Handler, Handshake, Message, Sender as WsSender};
+use ws::{CloseCode, Handler, Handshake, Message, Sender as WsSender, WebSocket};
 use ws::util::Token;
 
 use super::gateway::{Gateway, Interpret};
-use datatype::Error;
+use datatype::{Error, GatewayConfig};
 
 
 pub struct Websocket {
-    clients: Arc<Mutex<HashMap<Token, WsSender>>>
+    clients:  Arc<Mutex<HashMap<Token, WsSender>>>,
+    listener: Mutex<WsSender>,
 }
 
 impl<C, E> Gateway<C, E> for Websocket
     where C: Decodable + Send + Clone + Debug + 'static,
           E: Encodable + Send + Clone + Debug + 'static,
 {
-    fn new(itx: Sender<Interpret<C, E>>) -> Result<Self, String> {
+    fn new(itx: Sender<Interpret<C, E>>, cfg: &GatewayConfig) -> Result<Self, String> {
         let clients = Arc::new(Mutex::new(HashMap::new()));
-        let addr    = env::var("OTA_PLUS_CLIENT_WEBSOCKET_ADDR").unwrap_or("127.0.0.1:3012".to_string());
+        let addr    = cfg.websocket_server.clone();
 
         let handler_clients = clients.clone();
+        let socket = try!(WebSocket::new(move |out| {
+            WebsocketHandler {
+                out:     out,
+                itx:     itx.clone(),
+                clients: handler_clients.clone()
+            }
+        }).map_err(|err| format!("couldn't open websocket listener: {}", err)));
+        let listener = Mutex::new(socket.broadcaster());
+
         let (start_tx, start_rx) = chan::sync::<Result<(), ws::Error>>(0);
         thread::spawn(move || {
             info!("Opening websocket listener on {}", addr);
-            start_tx.send(listen(&addr as &str, |out| {
-                WebsocketHandler {
-                    out:     out,
-                    itx:     itx.clone(),
-                    clients: handler_clients.clone()
-                }
-            }));
+            start_tx.send(socket.listen(&addr as &str).map(|_| ()));
         });
 
         let tick = chan::tick_ms(1000); // FIXME: ugly hack for blocking call
         chan_select! {
-            tick.recv()                => return Ok(Websocket{ clients: clients }),
+            tick.recv()                => return Ok(Websocket { clients: clients, listener: listener }),
             start_rx.recv() -> outcome => match outcome {
                 Some(outcome) => match outcome {
-                    Ok(_)    => return Ok(Websocket{ clients: clients }),
+                    Ok(_)    => return Ok(Websocket { clients: clients, listener: listener }),
                     Err(err) => return Err(format!("couldn't open websocket listener: {}", err))
                 },
                 None => panic!("expected websocket start outcome")
@@ -57,6 +61,11 @@ impl<C, E> Gateway<C, E> for Websocket
             let _ = out.send(Message::Text(json.clone()));
         }
     }
+
+    fn close(&self) {
+        info!("Closing the websocket listener");
+        let _ = self.listener.lock().unwrap().shutdown().map_err(|err| error!("Couldn't close the websocket listener: {}", err));
+    }
 }
 
 
@@ -143,14 +152,14 @@ mod tests {
 
     use super::*;
     use super::super::gateway::Gateway;
-    use super::super::super::datatype::{Command, Event};
+    use super::super::super::datatype::{Command, Event, GatewayConfig};
     use super::super::super::interpreter::Global;
 
     #[test]
     fn websocket_connections() {
         let (etx, erx) = chan::sync::<Event>(0);
         let (gtx, grx) = chan::sync::<Global>(0);
-        Websocket::run(gtx, erx);
+        Websocket::run(gtx, erx, &GatewayConfig::default());
 
         thread::spawn(move || {
             let _ = etx; // move into this scope
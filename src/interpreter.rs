@@ -2,14 +2,28 @@ use chan;
 use chan::{Sender, Receiver};
 use std;
 use std::borrow::Cow;
+use std::cmp;
+use std::fs;
+use std::path::{Path, PathBuf};
+use time;
 
-use datatype::{AccessToken, Auth, ClientId, ClientSecret, Command, Config,
-               Error, Event, UpdateState, UpdateRequestId};
+use aborts::{AbortableClient, Aborts};
+use bundle::{Bundle, BundleStore};
+use datatype::{AccessToken, Auth, AuthConfig, ClientId, ClientSecret, Command, Config, Error, Event,
+               UpdateReport, UpdateResultCode, UpdateState, UpdateRequestId};
 use datatype::Command::*;
+use datatype::report::{InstalledFirmware, InstalledPackage, InstalledSoftware, OperationResult, OperationResults, UpdateEvent};
 use http_client::{AuthClient, HttpClient};
 use interaction_library::gateway::Interpret;
+use network::{Network, ThrottledClient};
+use oauth2;
 use oauth2::authenticate;
-use ota_plus::OTA;
+use outbox::{MAX_ATTEMPTS, Outbox, Upstream};
+use polling::Polling;
+use secondary::{SecondaryLoader, TargetStore};
+use shutdown::Shutdown;
+use transport::Transport;
+use uptane;
 
 
 pub trait Interpreter<I: 'static, O> {
@@ -23,7 +37,11 @@ pub trait Interpreter<I: 'static, O> {
 }
 
 
-pub struct EventInterpreter;
+pub struct EventInterpreter {
+    /// Accept the updates RVI announces, which aren't listed as pending by
+    /// the server. Other announcements are checked against the server.
+    pub accept_available: bool,
+}
 
 impl Interpreter<Event, Command> for EventInterpreter {
     fn interpret(&mut self, event: Event, ctx: &Sender<Command>) {
@@ -38,17 +56,27 @@ impl Interpreter<Event, Command> for EventInterpreter {
                 ctx.send(Command::Authenticate(None));
             }
 
-            /* TODO: Handle PackageManger events
-            Event::DownloadComplete => {
-                env.config.ota.package_manager.install_package(p);
-                ctx.send(Command::UpdateReport())
+            Event::UpdateAvailable(avail) => {
+                if avail.request_confirmation {
+                    info!("Update {} is waiting for confirmation", avail.update_id);
+                } else if self.accept_available {
+                    ctx.send(Command::AcceptUpdates(vec![avail.update_id]));
+                } else {
+                    ctx.send(Command::GetPendingUpdates);
+                }
+            }
+
+            Event::DownloadComplete(dl) => {
+                ctx.send(Command::InstallDownload(dl));
+            }
+
+            Event::AbortRequested(id) => {
+                ctx.send(Command::AbortDownload(id));
             }
 
-            Event::GetInstalledSoftware => {
-                env.config.ota.package_manager.installed_packages();
-                ctx.send(Command::InstalledSoftware())
+            Event::GetInstalledSoftware(get) => {
+                ctx.send(Command::SendInstalledSoftware(get));
             }
-            */
 
             _ => ()
         }
@@ -68,29 +96,107 @@ impl Interpreter<Command, Global> for CommandInterpreter {
 
 pub type Global = Interpret<Command, Event>;
 
+/// Re-reads the configuration on `Command::ReloadConfig` and passes each
+/// accepted config on to the threads that apply it outside the interpreter.
+pub struct ConfigReloader {
+    load:   Box<Fn() -> Result<Config, Error> + Send>,
+    notify: Sender<Config>,
+}
+
+impl ConfigReloader {
+    pub fn new(load: Box<Fn() -> Result<Config, Error> + Send>, notify: Sender<Config>) -> ConfigReloader {
+        ConfigReloader { load: load, notify: notify }
+    }
+}
+
 pub struct GlobalInterpreter<'t> {
-    pub config:      Config,
-    pub token:       Option<Cow<'t, AccessToken>>,
-    pub http_client: Box<HttpClient>,
-    pub loopback_tx: Sender<Global>,
+    pub aborts:       Aborts,
+    pub config:       Config,
+    pub token:        Option<Cow<'t, AccessToken>>,
+    pub token_expiry: Option<i64>,
+    pub http_client:  Box<HttpClient>,
+    pub inventory:    Option<InstalledSoftware>,
+    pub loopback_tx:  Sender<Global>,
+    pub network:      Network,
+    pub polling:      Polling,
+    pub reloader:     Option<ConfigReloader>,
+    pub shutdown:     Shutdown,
+    pub transport:    Box<Transport>,
 }
 
 impl<'t> Interpreter<Global, Event> for GlobalInterpreter<'t> {
     fn interpret(&mut self, global: Global, etx: &Sender<Event>) {
         info!("Global interpreter started: {:?}", global.command);
 
+        let _work = match self.shutdown.begin() {
+            Some(work) => work,
+            None if global.command == Shutdown => {
+                info!("Flushing the outbox before shutting down");
+                self.flush_outbox();
+                etx.send(Event::Ok);
+                if let Some(ref tx) = global.response_tx {
+                    tx.lock().unwrap().send(Event::Ok);
+                }
+                return;
+            }
+            None => {
+                info!("Shutting down, refusing: {:?}", global.command);
+                let ev = Event::Error("client is shutting down".to_string());
+                etx.send(ev.clone());
+                if let Some(ref tx) = global.response_tx {
+                    tx.lock().unwrap().send(ev);
+                }
+                return;
+            }
+        };
+
+        if self.token_expiring() {
+            info!("Access token about to expire, renewing...");
+            let _ = self.renew_token().map_err(|err| error!("Couldn't renew access token: {}", err));
+        }
+
+        if global.command == GetPendingUpdates {
+            self.polling.poll_started();
+        }
+
         let (multi_tx, multi_rx) = chan::async::<Event>();
-        let outcome = match (&self.token, self.config.auth.is_none()) {
-            (&Some(_), _) | (_, true) => {
-                self.authenticated(global.command.clone(), multi_tx)
+        let mut outcome = if global.command == ReloadConfig {
+            self.reload_config(multi_tx)
+        } else {
+            match (&self.token, self.config.auth.is_none()) {
+                (&Some(_), _) | (_, true) => {
+                    self.authenticated(global.command.clone(), multi_tx)
+                }
+                _ => self.unauthenticated(global.command.clone(), multi_tx)
             }
-            _ => self.unauthenticated(global.command.clone(), multi_tx)
         };
+        let mut events = multi_rx.into_iter().collect::<Vec<_>>();
+
+        // Only commands that can safely run twice are retried, as the
+        // rejected request may have been one of several it made.
+        let rejected = match outcome {
+            Err(Error::AuthorizationError(_)) => self.token.is_some() && self.config.auth.is_some(),
+            _                                 => false
+        };
+        if rejected {
+            info!("Access token rejected, re-authenticating: {:?}", global.command);
+            outcome = match self.renew_token() {
+                Ok(_) if global.command.is_idempotent() => {
+                    info!("Retrying with the new access token: {:?}", global.command);
+                    let (retry_tx, retry_rx) = chan::async::<Event>();
+                    let retried = self.authenticated(global.command.clone(), retry_tx);
+                    events.extend(retry_rx);
+                    retried
+                }
+                Ok(_)    => outcome,
+                Err(err) => self.discard_token().and(Err(err))
+            };
+        }
 
         let mut response_ev: Option<Event> = None;
         match outcome {
             Ok(_) => {
-                for ev in multi_rx {
+                for ev in events {
                     etx.send(ev.clone());
                     response_ev = Some(ev);
                 }
@@ -98,6 +204,9 @@ impl<'t> Interpreter<Global, Event> for GlobalInterpreter<'t> {
             }
 
             Err(Error::AuthorizationError(_)) => {
+                for ev in events {
+                    etx.send(ev);
+                }
                 let ev = Event::NotAuthenticated;
                 etx.send(ev.clone());
                 response_ev = Some(ev);
@@ -122,54 +231,152 @@ impl<'t> Interpreter<Global, Event> for GlobalInterpreter<'t> {
 }
 
 impl<'t> GlobalInterpreter<'t> {
-    fn authenticated(&self, cmd: Command, etx: Sender<Event>) -> Result<(), Error> {
-        let mut ota = OTA::new(&self.config, self.http_client.as_ref());
-
+    fn authenticated(&mut self, cmd: Command, etx: Sender<Event>) -> Result<(), Error> {
         // always send at least one Event response
         match cmd {
+            AbortDownload(id) => {
+                if self.aborts.finish(&id) {
+                    info!("Download of {} was already cancelled", id);
+                    etx.send(Event::Ok);
+                } else if self.polling.is_in_flight(&id) {
+                    try!(self.cancel_download(&id, &etx));
+                    self.aborts.finish(&id);
+                } else {
+                    info!("Ignoring abort of unknown download {}", id);
+                    etx.send(Event::Error(format!("No download of {} to abort", id)));
+                }
+            }
+
             AcceptUpdates(ids) => {
-                for id in ids {
+                // updates are released however the loop ends, unless their
+                // download carries on in the background
+                let updates = ids.iter().map(|id| self.polling.track_update(id)).collect::<Vec<_>>();
+                for (id, update) in ids.into_iter().zip(updates) {
                     info!("Accepting ID: {}", id);
-                    etx.send(Event::UpdateStateChanged(id.clone(), UpdateState::Downloading));
-                    let report = try!(ota.install_package_update(&id, &etx));
-                    try!(ota.send_install_report(&report));
-                    info!("Install Report for {}: {:?}", id, report);
-                    try!(ota.update_installed_packages())
+                    if self.aborts.is_requested(&id) {
+                        try!(self.cancel_download(&id, &etx));
+                        continue;
+                    }
+                    self.state_changed(&id, UpdateState::Downloading, &etx);
+                    let report = match self.download_update(&id, &etx) {
+                        Ok(Some(_)) if self.aborts.is_requested(&id) => {
+                            try!(self.cancel_download(&id, &etx));
+                            continue;
+                        }
+
+                        Ok(Some(path)) => {
+                            let err_str  = format!("Path is not valid UTF-8: {:?}", path);
+                            let pkg_path = try!(path.to_str().ok_or(Error::ParseError(err_str)));
+                            info!("Downloaded to {:?}. Installing...", pkg_path);
+                            self.install_download(&id, pkg_path, &etx)
+                        }
+
+                        Ok(None) => {
+                            info!("Download of {} started", id);
+                            update.keep();
+                            continue;
+                        }
+
+                        Err(_) if self.aborts.is_requested(&id) => {
+                            try!(self.cancel_download(&id, &etx));
+                            continue;
+                        }
+
+                        Err(err) => {
+                            etx.send(Event::UpdateErrored(id.clone(), format!("{:?}", err)));
+                            let failed = format!("Download failed: {:?}", err);
+                            UpdateReport::single(id.clone(), UpdateResultCode::GENERAL_ERROR, failed)
+                        }
+                    };
+                    drop(update);
+                    try!(self.send_report(&report));
+                    let sw = try!(self.installed_software(true, true));
+                    try!(self.send_installed(&sw));
                 }
             }
 
             Authenticate(_) => etx.send(Event::Ok),
 
             GetPendingUpdates => {
-                let mut updates = try!(ota.get_package_updates());
-                if updates.len() > 0 {
-                    updates.sort_by_key(|u| u.installPos);
-                    info!("New package updates available: {:?}", updates);
-                    let ids: Vec<UpdateRequestId> = updates.iter().map(|u| u.requestId.clone()).collect();
-                    self.loopback_tx.send(Global { command: Command::AcceptUpdates(ids), response_tx: None });
+                let pending = match self.transport.get_pending_updates(&self.config, self.http_client.as_ref()) {
+                    Ok(pending) => pending,
+                    Err(err)    => {
+                        self.polling.failed(&err);
+                        return Err(err);
+                    }
+                };
+                self.polling.succeeded(pending.poll_interval);
+                self.flush_outbox();
+                let mut updates = pending.updates;
+                updates.sort_by_key(|u| u.installPos);
+                let ids: Vec<UpdateRequestId> = updates.iter().map(|u| u.requestId.clone()).collect();
+                let store = TargetStore::new(&self.config.ota.packages_dir);
+                for update in updates.iter() {
+                    match update.targets {
+                        Some(ref targets) => try!(store.save(&update.requestId, targets)),
+                        None              => store.remove(&update.requestId)
+                    }
+                }
+                etx.send(Event::FoundPendingUpdates(ids.clone()));
+                let new_ids: Vec<UpdateRequestId> = ids.into_iter().filter(|id| !self.polling.is_in_flight(id)).collect();
+                if new_ids.len() > 0 {
+                    info!("New package updates available: {:?}", new_ids);
+                    for id in new_ids.iter() {
+                        self.polling.update_started(id);
+                    }
+                    self.loopback_tx.send(Global { command: Command::AcceptUpdates(new_ids), response_tx: None });
                 }
                 etx.send(Event::Ok);
             }
 
+            InstallBundle(dir) => try!(self.install_bundle(&dir, &etx)),
+
+            InstallDownload(dl) => {
+                let _update = self.polling.track_update(&dl.update_id);
+                if self.aborts.is_requested(&dl.update_id) {
+                    try!(self.cancel_download(&dl.update_id, &etx));
+                    return Ok(());
+                }
+                info!("Installing downloaded update: {}", dl.update_id);
+                let report = self.install_download(&dl.update_id, &dl.update_image, &etx);
+                try!(self.send_report(&report));
+            }
+
             ListInstalledPackages => {
                 let pkgs = try!(self.config.ota.package_manager.installed_packages());
                 etx.send(Event::FoundInstalledPackages(pkgs));
             }
 
-            Shutdown => std::process::exit(0),
+            ListOutbox => try!(self.list_outbox(&etx)),
+
+            ReloadConfig => unreachable!("handled by interpret"),
+
+            SendInstalledSoftware(get) => {
+                let sw = try!(self.installed_software(get.include_packages, get.include_module_firmware));
+                try!(self.send_installed(&sw));
+                etx.send(Event::Ok);
+            }
+
+            Shutdown => {
+                self.shutdown.request();
+                etx.send(Event::Ok);
+            }
 
             UpdateInstalledPackages => {
-                try!(ota.update_installed_packages());
+                let sw = try!(self.installed_software(true, true));
+                try!(self.send_installed(&sw));
                 etx.send(Event::Ok);
                 info!("Posted installed packages to the server.")
             }
 
-            UpdateReport(_) => {
-                // TODO: Implement feature
+            UpdateReport(report) => {
+                try!(self.send_report(&report));
+                etx.send(Event::Ok);
             }
 
-            ReportInstalledSoftware(_) => {
-                // TODO: Implement feature
+            ReportInstalledSoftware(sw) => {
+                try!(self.send_installed(&sw));
+                etx.send(Event::Ok);
             }
         }
 
@@ -179,33 +386,429 @@ impl<'t> GlobalInterpreter<'t> {
     fn unauthenticated(&mut self, cmd: Command, etx: Sender<Event>) -> Result<(), Error> {
         match cmd {
             Authenticate(_) => {
-                let config = self.config.auth.clone().expect("trying to authenticate without auth config");
-                let auth   = Auth::Credentials(ClientId(config.client_id), ClientSecret(config.secret));
-                let server = config.server.join("/token").unwrap();
-                self.set_client(auth);
-                let token = try!(authenticate(server, self.http_client.as_ref()));
-                self.set_client(Auth::Token(token.clone()));
-                self.token = Some(token.into());
+                try!(self.renew_token());
                 etx.send(Event::Authenticated);
             }
 
-            AcceptUpdates(_)      |
+            InstallBundle(dir) => try!(self.install_bundle(&dir, &etx)),
+
+            ListOutbox => try!(self.list_outbox(&etx)),
+
+            AcceptUpdates(ids) => {
+                for id in ids.iter() {
+                    self.polling.update_finished(id);
+                }
+                etx.send(Event::NotAuthenticated);
+            }
+
+            InstallDownload(dl) => {
+                self.polling.update_finished(&dl.update_id);
+                etx.send(Event::NotAuthenticated);
+            }
+
+            AbortDownload(_)      |
             GetPendingUpdates     |
             ListInstalledPackages |
+            SendInstalledSoftware(_) |
             UpdateReport(_) |
             ReportInstalledSoftware(_) |
             UpdateInstalledPackages => etx.send(Event::NotAuthenticated),
 
-            Shutdown => std::process::exit(0),
+            ReloadConfig => unreachable!("handled by interpret"),
+
+            Shutdown => {
+                self.shutdown.request();
+                etx.send(Event::Ok);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Download an update, throttled by the network policy when there is one.
+    // An abort stops the download between reads.
+    fn download_update(&mut self, id: &UpdateRequestId, etx: &Sender<Event>) -> Result<Option<PathBuf>, Error> {
+        match self.config.network {
+            Some(ref network) => {
+                let throttled = ThrottledClient { client: self.http_client.as_ref(), network: &self.network, config: network };
+                let client    = AbortableClient { client: &throttled, aborts: &self.aborts };
+                self.transport.download_update(&self.config, &client, id, etx)
+            }
+
+            None => {
+                let client = AbortableClient { client: self.http_client.as_ref(), aborts: &self.aborts };
+                self.transport.download_update(&self.config, &client, id, etx)
+            }
+        }
+    }
+
+    // Install a downloaded update, over any transport, once its image matches
+    // the verified Uptane targets for the hardware it is installed on.
+    fn install_download(&mut self, id: &UpdateRequestId, path: &str, etx: &Sender<Event>) -> UpdateReport {
+        if let Err(err) = uptane::verify_update(&self.config, self.http_client.as_ref(), id, Path::new(path)) {
+            TargetStore::new(&self.config.ota.packages_dir).remove(id);
+            etx.send(Event::UpdateErrored(id.clone(), format!("{}", err)));
+            return UpdateReport::single(id.clone(), UpdateResultCode::VALIDATION_FAILED, format!("{}", err));
+        }
+        self.install_update(id, path, etx)
+    }
+
+    // Install a downloaded update on its target secondaries, or on this host
+    // when it has none.
+    fn install_update(&self, id: &UpdateRequestId, path: &str, etx: &Sender<Event>) -> UpdateReport {
+        let store  = TargetStore::new(&self.config.ota.packages_dir);
+        let report = match store.load(id) {
+            Some(ref targets) if !targets.is_empty() => self.install_secondaries(id, targets, path, etx),
+            _ => self.install_package(id, path, etx)
+        };
+        store.remove(id);
+        report
+    }
+
+    // Hand the image to the loader of each target, reporting a result per
+    // secondary.
+    fn install_secondaries(&self, id: &UpdateRequestId, targets: &[String], path: &str, etx: &Sender<Event>)
+                           -> UpdateReport {
+        self.state_changed(id, UpdateState::Installing, etx);
+        let results = targets.iter().map(|module| {
+            info!("Installing {} on secondary {}", id, module);
+            let (code, output) = match self.config.secondary.get(module) {
+                Some(secondary) => secondary.loader(module).install(id, Path::new(path)).unwrap_or_else(|err| {
+                    (UpdateResultCode::GENERAL_ERROR, format!("{}", err))
+                }),
+                None => (UpdateResultCode::NOT_FOUND, format!("Unknown secondary: {}", module))
+            };
+            OperationResult { id: module.clone(), result_code: code, result_text: output }
+        }).collect::<Vec<_>>();
+
+        let failed = results.iter()
+            .filter(|result| result.result_code != UpdateResultCode::OK)
+            .map(|result| format!("{}: {:?}: {}", result.id, result.result_code, result.result_text))
+            .collect::<Vec<_>>();
+        if failed.is_empty() {
+            self.state_changed(id, UpdateState::Installed, etx);
+        } else {
+            etx.send(Event::UpdateErrored(id.clone(), failed.join(", ")));
+        }
+        UpdateReport::new(id.clone(), OperationResults(results))
+    }
+
+    // Install a downloaded package and build the report for its update.
+    fn install_package(&self, id: &UpdateRequestId, path: &str, etx: &Sender<Event>) -> UpdateReport {
+        self.state_changed(id, UpdateState::Installing, etx);
+        let progress = |percent: u8, step: &str| {
+            etx.send(Event::InstallProgress { id: id.clone(), percent: percent, step: step.to_string() })
+        };
+        match self.config.ota.package_manager.install_package(path, &progress) {
+            Ok((code, output)) => {
+                self.state_changed(id, UpdateState::Installed, etx);
+                UpdateReport::single(id.clone(), code, output)
+            }
+
+            Err((code, output)) => {
+                etx.send(Event::UpdateErrored(id.clone(), format!("{:?}: {:?}", code, output)));
+                UpdateReport::single(id.clone(), code, output)
+            }
+        }
+    }
+
+    // Install the updates of a bundle that hasn't been imported before. The
+    // reports wait in the outbox until they reach the server.
+    fn install_bundle(&mut self, dir: &str, etx: &Sender<Event>) -> Result<(), Error> {
+        let config = try!(self.config.bundle.clone().ok_or(Error::BundleError("no [bundle] section".to_string())));
+        if self.config.ota.outbox_dir.is_none() {
+            return Err(Error::BundleError("bundles need an ota.outbox_dir to keep their reports".to_string()));
+        }
+        let bundle = try!(Bundle::open(Path::new(dir), &config));
+        let store  = BundleStore::new(&config);
+        if store.is_imported(&bundle.id) {
+            info!("Bundle {} was already imported", bundle.id);
+            etx.send(Event::Ok);
+            return Ok(());
+        }
+
+        let updates = bundle.updates.iter()
+            .filter(|update| !store.is_installed(&bundle.id, &update.update_id))
+            .cloned()
+            .collect::<Vec<_>>();
+        let packages = try!(bundle.copy_packages(&updates, Path::new(&self.config.ota.packages_dir)));
+        for (update, path) in updates.iter().zip(packages) {
+            let err_str  = format!("Path is not valid UTF-8: {:?}", path);
+            let pkg_path = try!(path.to_str().ok_or(Error::ParseError(err_str)));
+            info!("Installing {} from bundle {}", update.update_id, bundle.id);
+            let report = self.install_update(&update.update_id, pkg_path, etx);
+            let _      = fs::remove_file(&path);
+            try!(self.send_report(&report));
+            if report.operation_results.iter().all(|result| result.result_code == UpdateResultCode::OK) {
+                try!(store.mark_installed(&bundle.id, &update.update_id));
+            }
+        }
+        try!(store.mark_imported(&bundle.id));
+        etx.send(Event::Ok);
+        Ok(())
+    }
+
+    // Remove what was downloaded for an aborted update and tell the server.
+    fn cancel_download(&mut self, id: &UpdateRequestId, etx: &Sender<Event>) -> Result<(), Error> {
+        info!("Aborting download of {}", id);
+        try!(self.transport.abort_download(&self.config, self.http_client.as_ref(), id));
+        TargetStore::new(&self.config.ota.packages_dir).remove(id);
+        let report = UpdateReport::single(id.clone(), UpdateResultCode::USER_DECLINED, "Download aborted".to_string());
+        try!(self.send_report(&report));
+        self.aborts.cancelled(id);
+        self.polling.update_finished(id);
+        self.state_changed(id, UpdateState::Cancelled, etx);
+        Ok(())
+    }
+
+    fn send_report(&mut self, report: &UpdateReport) -> Result<(), Error> {
+        info!("Install Report for {}: {:?}", report.update_id, report);
+        self.send_upstream(Upstream::UpdateReport(report.clone()))
+    }
+
+    // Without an outbox a failed inventory is only logged, as the next sync
+    // sends it again.
+    fn send_installed(&mut self, sw: &InstalledSoftware) -> Result<(), Error> {
+        self.inventory = Some(sw.clone());
+        let message = Upstream::InstalledSoftware(sw.clone());
+        if self.config.ota.outbox_dir.is_some() {
+            return self.send_upstream(message);
+        }
+        let _ = self.deliver(&message, None).map_err(|err| error!("Couldn't send the installed software: {}", err));
+        Ok(())
+    }
+
+    // Announce a change in the state of an update, queueing it for the server
+    // when there is an outbox. It is delivered with the next flush.
+    fn state_changed(&self, id: &UpdateRequestId, state: UpdateState, etx: &Sender<Event>) {
+        if let Some(ref dir) = self.config.ota.outbox_dir {
+            let event = UpdateEvent::new(id.clone(), state.clone());
+            if let Err(err) = Outbox::new(dir).enqueue(Upstream::UpdateEvent(event)) {
+                error!("Couldn't queue the {:?} event of {}: {}", state, id, err);
+            }
+        }
+        etx.send(Event::UpdateStateChanged(id.clone(), state));
+    }
+
+    // Queue a message in the outbox and deliver everything queued, so that
+    // only a failure to queue it is an error. Without an outbox the message
+    // is sent directly.
+    fn send_upstream(&mut self, message: Upstream) -> Result<(), Error> {
+        let outbox = match self.config.ota.outbox_dir.clone() {
+            Some(dir) => Outbox::new(&dir),
+            None      => return self.deliver(&message, None)
+        };
+        try!(outbox.enqueue(message));
+        self.flush_outbox();
+        Ok(())
+    }
+
+    // Deliver the queued messages in order, stopping when the server can't be
+    // reached so that later messages don't overtake the failed one. Messages
+    // the server rejects are skipped, and set aside after `MAX_ATTEMPTS`.
+    fn flush_outbox(&mut self) {
+        let outbox = match self.config.ota.outbox_dir {
+            Some(ref dir) => Outbox::new(dir),
+            None          => return
+        };
+        let entries = match outbox.entries() {
+            Ok(entries) => entries,
+            Err(err)    => {
+                error!("Couldn't read the outbox: {}", err);
+                return;
+            }
+        };
+
+        let queued = entries.len();
+        for entry in entries {
+            match self.deliver(&entry.message, Some(entry.key.as_str())) {
+                Ok(_) => {
+                    if let Err(err) = outbox.delivered(&entry) {
+                        error!("Couldn't remove delivered outbox entry {}: {}", entry.seq, err);
+                    }
+                }
+
+                Err(err @ Error::RequestRejected(_)) => {
+                    let saved = if entry.attempts + 1 >= MAX_ATTEMPTS {
+                        error!("Giving up on outbox entry {} after {} attempts: {}", entry.seq, MAX_ATTEMPTS, err);
+                        outbox.dead_letter(&entry, &err)
+                    } else {
+                        error!("Outbox entry {} was rejected: {}", entry.seq, err);
+                        outbox.failed(&entry, &err)
+                    };
+                    if let Err(err) = saved {
+                        error!("Couldn't update outbox entry {}: {}", entry.seq, err);
+                    }
+                }
+
+                Err(err) => {
+                    info!("Keeping {} outbox messages until the server is reachable: {}", queued, err);
+                    if let Err(err) = outbox.failed(&entry, &err) {
+                        error!("Couldn't update outbox entry {}: {}", entry.seq, err);
+                    }
+                    return;
+                }
+            }
+        }
+    }
+
+    fn deliver(&mut self, message: &Upstream, key: Option<&str>) -> Result<(), Error> {
+        let client = self.http_client.as_ref();
+        match *message {
+            Upstream::UpdateReport(ref report) => self.transport.send_update_report(&self.config, client, report, key),
+            Upstream::InstalledSoftware(ref sw) => self.transport.send_installed_software(&self.config, client, sw, key),
+            Upstream::UpdateEvent(ref event)    => self.transport.send_update_event(&self.config, client, event, key),
+        }
+    }
+
+    fn list_outbox(&self, etx: &Sender<Event>) -> Result<(), Error> {
+        let entries = match self.config.ota.outbox_dir {
+            Some(ref dir) => try!(Outbox::new(dir).entries()),
+            None          => Vec::new()
+        };
+        etx.send(Event::FoundOutbox(entries));
+        Ok(())
+    }
+
+    // The part that isn't asked for is taken from the last inventory sent, or
+    // read as well when there is none, as each report replaces the whole
+    // inventory on the server. A firmware inventory or secondary that can't
+    // be read is left out rather than holding back the package inventory.
+    fn installed_software(&self, packages: bool, firmware: bool) -> Result<InstalledSoftware, Error> {
+        let known = self.inventory.as_ref();
+        let pkgs = match known {
+            Some(sw) if !packages => sw.packages.clone(),
+            _ => try!(self.config.ota.package_manager.installed_packages())
+                     .into_iter().map(InstalledPackage::from).collect()
+        };
+        let modules = match known {
+            Some(sw) if !firmware => sw.firmware.clone(),
+            _ => self.installed_firmware()
+        };
+        Ok(InstalledSoftware { packages: pkgs, firmware: modules })
+    }
+
+    fn installed_firmware(&self) -> Vec<InstalledFirmware> {
+        let mut modules = match self.config.ota.firmware {
+            Some(ref inventory) => inventory.installed_firmware().unwrap_or_else(|err| {
+                error!("Couldn't read the firmware inventory: {}", err);
+                Vec::new()
+            }),
+            None => Vec::new()
+        };
+        for (module, secondary) in self.config.secondary.iter() {
+            match secondary.loader(module).installed_firmware() {
+                Ok(installed) => modules.push(installed),
+                Err(err)      => error!("Couldn't read the firmware of secondary {}: {}", module, err)
+            }
+        }
+        modules
+    }
+
+    // Load the config again and switch to it if every change can be applied
+    // live. Changes to the auth config discard the current access token.
+    fn reload_config(&mut self, etx: Sender<Event>) -> Result<(), Error> {
+        let config = match self.reloader {
+            Some(ref reloader) => try!((reloader.load)()),
+            None               => return Err(Error::Command("config reload is not enabled".to_string()))
+        };
+
+        let restart = self.config.restart_required(&config);
+        if !restart.is_empty() {
+            let errors = restart.iter().map(|name| format!("{}: changing requires a restart", name)).collect();
+            return Err(Error::InvalidConfig(errors));
         }
 
+        let auth_changed = self.config.auth != config.auth;
+        self.config = config.clone();
+        if let Some(ref reloader) = self.reloader {
+            reloader.notify.send(config);
+        }
+        if auth_changed && self.config.auth.is_some() {
+            info!("Auth config changed, discarding access token");
+            try!(self.discard_token());
+            etx.send(Event::NotAuthenticated);
+        } else {
+            etx.send(Event::Ok);
+        }
+        info!("Config reloaded");
         Ok(())
     }
 
-    fn set_client(&mut self, auth: Auth) {
+    // Fetch a new access token. The current token is kept until a new one
+    // arrives, as a renewal may fail while the token is still valid.
+    fn renew_token(&mut self) -> Result<(), Error> {
+        let config = self.config.auth.clone().expect("trying to authenticate without auth config");
+        try!(self.set_client(Auth::Credentials(ClientId(config.client_id.clone()), ClientSecret(config.secret.clone()))));
+
+        match self.request_token(&config) {
+            Ok(token) => {
+                self.token_expiry = if token.expires_in > 0 {
+                    Some(time::get_time().sec + token.expires_in as i64)
+                } else {
+                    None
+                };
+                try!(self.set_client(Auth::Token(token.clone())));
+                self.token = Some(token.into());
+                Ok(())
+            }
+
+            Err(err) => {
+                if let Some(token) = self.token.clone() {
+                    try!(self.set_client(Auth::Token(token.into_owned())));
+                }
+                Err(err)
+            }
+        }
+    }
+
+    // Use the refresh_token grant when available, falling back to the
+    // client_credentials grant otherwise.
+    fn request_token(&self, config: &AuthConfig) -> Result<AccessToken, Error> {
+        let refresh_token = self.token.as_ref().and_then(|token| token.refresh_token.clone());
+        let server        = try!(config.server.join("/token"));
+        let token = match refresh_token {
+            Some(ref refresh_token) => {
+                match oauth2::refresh(server.clone(), refresh_token, self.http_client.as_ref()) {
+                    Ok(token) => token,
+                    Err(err)  => {
+                        info!("Refresh grant failed, requesting a new token: {}", err);
+                        try!(authenticate(server, self.http_client.as_ref()))
+                    }
+                }
+            }
+
+            None => try!(authenticate(server, self.http_client.as_ref()))
+        };
+
+        if let Some(ref scope) = config.scope {
+            try!(oauth2::check_scope(&token, scope));
+        }
+        Ok(token)
+    }
+
+    // Forget a token that the server no longer accepts.
+    fn discard_token(&mut self) -> Result<(), Error> {
+        self.token        = None;
+        self.token_expiry = None;
+        self.set_client(Auth::None)
+    }
+
+    fn token_expiring(&self) -> bool {
+        match (&self.token, self.token_expiry) {
+            (&Some(ref token), Some(expiry)) => {
+                let margin = cmp::min(oauth2::REFRESH_MARGIN, token.expires_in as i64 / 2);
+                expiry - time::get_time().sec <= margin
+            }
+            _ => false
+        }
+    }
+
+    fn set_client(&mut self, auth: Auth) -> Result<(), Error> {
         if !self.http_client.is_testing() {
-            self.http_client = Box::new(AuthClient::new(auth));
+            self.http_client = Box::new(try!(AuthClient::from_config(auth, &self.config)));
         }
+        Ok(())
     }
 }
 
@@ -214,28 +817,69 @@ impl<'t> GlobalInterpreter<'t> {
 mod tests {
     use chan;
     use chan::{Sender, Receiver};
+    use std::fs;
     use std::thread;
 
     use super::*;
-    use datatype::{AccessToken, Command, Config, Event, UpdateState};
+    use datatype::{config, AccessToken, AuthConfig, Command, Config, Error, Event, Package,
+                   PendingUpdateRequest, SecondaryConfig, UpdateReport, UpdateResultCode, UpdateState};
+    use datatype::report::{InstalledPackage, InstalledSoftware};
+    use datatype::update_request::{GetInstalledSoftware, UpdateAvailable};
+    use firmware::FirmwareInventory;
     use http_client::test_client::TestHttpClient;
     use package_manager::PackageManager;
+    use outbox::{Outbox, Upstream};
     use package_manager::tpm::assert_rx;
+    use remote::dw::random_string;
+    use secondary::test_loader;
+    use transport::{OtaTransport, Transport, TestTransport};
 
 
     fn new_interpreter(replies: Vec<String>, pkg_mgr: PackageManager) -> (Sender<Command>, Receiver<Event>) {
+        new_interpreter_with_expiry(replies, pkg_mgr, None)
+    }
+
+    fn new_interpreter_with_expiry(replies: Vec<String>, pkg_mgr: PackageManager, expiry: Option<i64>)
+                                   -> (Sender<Command>, Receiver<Event>) {
+        start_interpreter(Box::new(OtaTransport), TestHttpClient::from(replies), pkg_mgr, expiry)
+    }
+
+    // An interpreter with the default config and no access token, which each
+    // test then adjusts.
+    fn test_interpreter(transport: Box<Transport>, client: TestHttpClient, gtx: Sender<Global>)
+                        -> GlobalInterpreter<'static> {
+        GlobalInterpreter {
+            aborts:       Aborts::new(),
+            config:       Config::default(),
+            token:        None,
+            token_expiry: None,
+            http_client:  Box::new(client),
+            inventory:    None,
+            loopback_tx:  gtx,
+            network:      Network::new(),
+            polling:      Polling::new(),
+            reloader:     None,
+            shutdown:     Shutdown::new(),
+            transport:    transport,
+        }
+    }
+
+    fn start_interpreter(transport: Box<Transport>, client: TestHttpClient, pkg_mgr: PackageManager,
+                         expiry: Option<i64>) -> (Sender<Command>, Receiver<Event>) {
         let (etx, erx) = chan::sync::<Event>(0);
         let (ctx, crx) = chan::sync::<Command>(0);
         let (gtx, _)   = chan::sync::<Global>(0);
 
         thread::spawn(move || {
-            let mut wi = GlobalInterpreter {
-                config:      Config::default(),
-                token:       Some(AccessToken::default().into()),
-                http_client: Box::new(TestHttpClient::from(replies)),
-                loopback_tx: gtx,
-            };
+            let mut token    = AccessToken::default();
+            token.expires_in = 10;
+            let mut wi = test_interpreter(transport, client, gtx);
+            wi.token        = Some(token.into());
+            wi.token_expiry = expiry;
             wi.config.ota.package_manager = pkg_mgr;
+            if expiry.is_some() {
+                wi.config.auth = Some(AuthConfig::default());
+            }
 
             loop {
                 match crx.recv() {
@@ -248,6 +892,22 @@ mod tests {
         (ctx, erx)
     }
 
+    #[test]
+    fn only_rvi_announcements_are_accepted() {
+        let avail = UpdateAvailable {
+            update_id:            "upd1".to_string(),
+            signature:            "".to_string(),
+            description:          "".to_string(),
+            request_confirmation: false,
+            size:                 0,
+        };
+        let (ctx, crx) = chan::async::<Command>();
+        EventInterpreter { accept_available: true }.interpret(Event::UpdateAvailable(avail.clone()), &ctx);
+        assert_eq!(crx.recv(), Some(Command::AcceptUpdates(vec!["upd1".to_string()])));
+        EventInterpreter { accept_available: false }.interpret(Event::UpdateAvailable(avail), &ctx);
+        assert_eq!(crx.recv(), Some(Command::GetPendingUpdates));
+    }
+
     #[test]
     fn already_authenticated() {
         let replies    = Vec::new();
@@ -284,4 +944,307 @@ mod tests {
         ctx.send(Command::AcceptUpdates(vec!["1".to_string()]));
         assert_rx(erx, &[Event::Error("IO error: No such file or directory (os error 2)".to_owned())]);
     }
+
+    #[test]
+    fn failed_updates_are_released() {
+        let (etx, _) = chan::async::<Event>();
+        let (gtx, _) = chan::async::<Global>();
+        let client   = TestHttpClient::from(vec!["[]".to_string(); 10]);
+        let mut wi   = test_interpreter(Box::new(OtaTransport), client, gtx);
+        wi.config.ota.package_manager = PackageManager::new_file(false);
+
+        wi.interpret(Global { command: Command::AcceptUpdates(vec!["1".to_string(), "2".to_string()]), response_tx: None }, &etx);
+        assert!(!wi.polling.is_in_flight(&"1".to_string()));
+        assert!(!wi.polling.is_in_flight(&"2".to_string()));
+    }
+
+    #[test]
+    fn unauthenticated_updates_are_released() {
+        let (etx, erx) = chan::async::<Event>();
+        let (gtx, _)   = chan::async::<Global>();
+        let mut wi     = test_interpreter(Box::new(OtaTransport), TestHttpClient::new(), gtx);
+        wi.config.auth = Some(AuthConfig::default());
+
+        wi.polling.update_started(&"1".to_string());
+        wi.interpret(Global { command: Command::AcceptUpdates(vec!["1".to_string()]), response_tx: None }, &etx);
+        assert_rx(erx, &[Event::NotAuthenticated]);
+        assert!(!wi.polling.is_in_flight(&"1".to_string()));
+    }
+
+    #[test]
+    fn accept_updates_with_test_transport() {
+        let transport = TestTransport::from(vec![PendingUpdateRequest {
+            requestId:  "1".to_string(),
+            installPos: 0,
+            packageId:  Package { name: "fake-pkg".to_string(), version: "0.1.1".to_string() },
+            createdAt:  "2010-01-01".to_string(),
+            targets:    None,
+        }]);
+        let (reports, inventory) = (transport.reports.clone(), transport.inventory.clone());
+        let pkg_mgr    = PackageManager::new_file(true);
+        let (ctx, erx) = start_interpreter(Box::new(transport), TestHttpClient::new(), pkg_mgr, None);
+
+        ctx.send(Command::AcceptUpdates(vec!["1".to_string()]));
+        assert_rx(erx, &[
+            Event::UpdateStateChanged("1".to_string(), UpdateState::Downloading),
+            Event::UpdateStateChanged("1".to_string(), UpdateState::Installing),
+            Event::UpdateStateChanged("1".to_string(), UpdateState::Installed),
+        ]);
+        let reports = reports.lock().unwrap();
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].operation_results[0].result_code, UpdateResultCode::OK);
+        let inventory = inventory.lock().unwrap();
+        assert_eq!(inventory[0].packages[0].package_id, "fake-pkg-0.1.1");
+    }
+
+    #[test]
+    fn abort_download() {
+        let (etx, erx) = chan::async::<Event>();
+        let (gtx, _)   = chan::async::<Global>();
+        let transport  = TestTransport::new();
+        let (reports, aborted) = (transport.reports.clone(), transport.aborted.clone());
+        let mut wi = test_interpreter(Box::new(transport), TestHttpClient::new(), gtx);
+
+        // unknown downloads are left alone
+        wi.interpret(Global { command: Command::AbortDownload("2".to_string()), response_tx: None }, &etx);
+        assert!(aborted.lock().unwrap().is_empty());
+
+        wi.polling.update_started(&"1".to_string());
+        wi.interpret(Global { command: Command::AbortDownload("1".to_string()), response_tx: None }, &etx);
+        assert_rx(erx, &[
+            Event::Error("No download of 2 to abort".to_string()),
+            Event::UpdateStateChanged("1".to_string(), UpdateState::Cancelled),
+        ]);
+        assert_eq!(*aborted.lock().unwrap(), vec!["1".to_string()]);
+        assert!(!wi.polling.is_in_flight(&"1".to_string()));
+        let reports = reports.lock().unwrap();
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].operation_results[0].result_code, UpdateResultCode::USER_DECLINED);
+    }
+
+    #[test]
+    fn send_installed_firmware_only() {
+        let (etx, erx) = chan::async::<Event>();
+        let (gtx, _)   = chan::async::<Global>();
+        let transport  = TestTransport::new();
+        let inventory  = transport.inventory.clone();
+        let mut wi = test_interpreter(Box::new(transport), TestHttpClient::new(), gtx);
+        wi.config.ota.firmware = Some(FirmwareInventory::Command { command: "echo ecu1 1.0 5".to_string() });
+        let known = InstalledPackage::from(Package { name: "known".to_string(), version: "1".to_string() });
+        wi.inventory = Some(InstalledSoftware { packages: vec![known.clone()], firmware: Vec::new() });
+
+        let get = GetInstalledSoftware { include_packages: false, include_module_firmware: true };
+        wi.interpret(Global { command: Command::SendInstalledSoftware(get), response_tx: None }, &etx);
+        assert_rx(erx, &[Event::Ok]);
+        let inventory = inventory.lock().unwrap();
+        assert_eq!(inventory[0].packages, vec![known]);
+        assert_eq!(inventory[0].firmware[0].firmware_id, "1.0".to_string());
+    }
+
+    #[test]
+    fn install_on_secondaries() {
+        let (etx, erx) = chan::async::<Event>();
+        let (gtx, _)   = chan::async::<Global>();
+        let transport  = TestTransport::from(vec![PendingUpdateRequest {
+            requestId:  "1".to_string(),
+            installPos: 0,
+            packageId:  Package { name: "brakes-fw".to_string(), version: "2.1".to_string() },
+            createdAt:  "2010-01-01".to_string(),
+            targets:    Some(vec!["brakes".to_string(), "doors".to_string()]),
+        }]);
+        let (reports, inventory) = (transport.reports.clone(), transport.inventory.clone());
+        let mut wi = test_interpreter(Box::new(transport), TestHttpClient::new(), gtx);
+        wi.config.ota.package_manager = PackageManager::new_file(true);
+        wi.config.ota.packages_dir    = format!("/tmp/sota-packages-{}", random_string(8));
+        let socket = test_loader::serve("OK flashed", "2.1");
+        wi.config.secondary.insert("brakes".to_string(), SecondaryConfig { socket: socket, timeout: Some(5), hardware_id: None });
+
+        wi.interpret(Global { command: Command::GetPendingUpdates, response_tx: None }, &etx);
+        let store = TargetStore::new(&wi.config.ota.packages_dir);
+        assert_eq!(store.load(&"1".to_string()), Some(vec!["brakes".to_string(), "doors".to_string()]));
+        wi.interpret(Global { command: Command::AcceptUpdates(vec!["1".to_string()]), response_tx: None }, &etx);
+        assert_eq!(store.load(&"1".to_string()), None);
+        assert_rx(erx, &[
+            Event::FoundPendingUpdates(vec!["1".to_string()]),
+            Event::Ok,
+            Event::UpdateStateChanged("1".to_string(), UpdateState::Downloading),
+            Event::UpdateStateChanged("1".to_string(), UpdateState::Installing),
+            Event::UpdateErrored("1".to_string(), "doors: NOT_FOUND: Unknown secondary: doors".to_string()),
+        ]);
+
+        let reports = reports.lock().unwrap();
+        let results = &reports[0].operation_results;
+        assert_eq!((results[0].id.as_str(), &results[0].result_code), ("brakes", &UpdateResultCode::OK));
+        assert_eq!((results[1].id.as_str(), &results[1].result_code), ("doors", &UpdateResultCode::NOT_FOUND));
+        let inventory = inventory.lock().unwrap();
+        assert_eq!(inventory[0].firmware[0].module, "brakes".to_string());
+    }
+
+    #[test]
+    fn skips_updates_in_flight() {
+        let (etx, _)     = chan::async::<Event>();
+        let (gtx, grx)   = chan::async::<Global>();
+        let transport    = TestTransport::from(vec![PendingUpdateRequest {
+            requestId:  "1".to_string(),
+            installPos: 0,
+            packageId:  Package { name: "brakes-fw".to_string(), version: "2.1".to_string() },
+            createdAt:  "2010-01-01".to_string(),
+            targets:    None,
+        }]);
+        let mut wi = test_interpreter(Box::new(transport), TestHttpClient::new(), gtx);
+
+        wi.interpret(Global { command: Command::GetPendingUpdates, response_tx: None }, &etx);
+        assert_eq!(grx.recv().map(|global| global.command), Some(Command::AcceptUpdates(vec!["1".to_string()])));
+        assert!(wi.polling.is_in_flight(&"1".to_string()));
+        wi.interpret(Global { command: Command::GetPendingUpdates, response_tx: None }, &etx);
+        chan_select! {
+            default => {},
+            grx.recv() -> global => panic!("accepted again: {:?}", global.map(|global| global.command)),
+        }
+    }
+
+    #[test]
+    fn reload_config() {
+        let (etx, erx)       = chan::sync::<Event>(0);
+        let (gtx, _)         = chan::sync::<Global>(0);
+        let (notify, reload) = chan::async::<Config>();
+
+        thread::spawn(move || {
+            let load = Box::new(|| config::parse_config("[ota]\npolling_interval = 60\n[device]\nvin = \"new\"\n"));
+            let mut wi = test_interpreter(Box::new(OtaTransport), TestHttpClient::new(), gtx);
+            wi.reloader = Some(ConfigReloader::new(load, notify));
+
+            wi.interpret(Global { command: Command::ReloadConfig, response_tx: None }, &etx);
+            wi.config.device.vin = "new".to_string();
+            wi.interpret(Global { command: Command::ReloadConfig, response_tx: None }, &etx);
+        });
+
+        assert_rx(erx, &[
+            Event::Error("Invalid config:\n  device: changing requires a restart".to_string()),
+            Event::Ok,
+        ]);
+        assert_eq!(reload.recv().unwrap().ota.polling_interval, 60);
+    }
+
+    #[test]
+    fn shutdown_refuses_new_commands() {
+        let replies    = Vec::new();
+        let pkg_mgr    = PackageManager::new_file(true);
+        let (ctx, erx) = new_interpreter(replies, pkg_mgr);
+
+        ctx.send(Command::Shutdown);
+        ctx.send(Command::ListInstalledPackages);
+        assert_rx(erx, &[Event::Ok, Event::Error("client is shutting down".to_string())]);
+    }
+
+    #[test]
+    fn shutdown_flushes_the_outbox() {
+        let (etx, erx) = chan::async::<Event>();
+        let (gtx, _)   = chan::sync::<Global>(0);
+        let dir        = format!("/tmp/sota-outbox-{}", random_string(8));
+        let transport  = TestTransport::new();
+        let reports    = transport.reports.clone();
+        let mut wi     = test_interpreter(Box::new(transport), TestHttpClient::new(), gtx);
+        wi.config.ota.outbox_dir = Some(dir.clone());
+
+        let report = UpdateReport::single("1".to_string(), UpdateResultCode::OK, "".to_string());
+        Outbox::new(&dir).enqueue(Upstream::UpdateReport(report.clone())).unwrap();
+        // the first requests the shutdown, the second flushes the outbox
+        for _ in 0..2 {
+            wi.interpret(Global { command: Command::Shutdown, response_tx: None }, &etx);
+            assert_eq!(erx.recv(), Some(Event::Ok));
+        }
+        assert_eq!(*reports.lock().unwrap(), vec![report]);
+        assert!(Outbox::new(&dir).entries().unwrap().is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn expiring_token_is_renewed() {
+        let token      = r#"{"access_token": "new", "token_type": "type", "expires_in": 3600, "scope": []}"#;
+        let replies    = vec!["[]".to_string(), token.to_string()];
+        let pkg_mgr    = PackageManager::new_file(true);
+        let (ctx, erx) = new_interpreter_with_expiry(replies, pkg_mgr, Some(0));
+
+        ctx.send(Command::GetPendingUpdates);
+        assert_rx(erx, &[Event::FoundPendingUpdates(Vec::new()), Event::Ok]);
+    }
+
+    #[test]
+    fn rejected_messages_dont_hold_up_the_outbox() {
+        let rejected   = || Err(Error::RequestRejected("400".to_string()));
+        let client     = TestHttpClient::from_responses(vec![Ok(Vec::new()), rejected(), rejected()]);
+        let (etx, erx) = chan::async::<Event>();
+        let (gtx, _)   = chan::sync::<Global>(0);
+        let dir        = format!("/tmp/sota-outbox-{}", random_string(8));
+        let mut wi     = test_interpreter(Box::new(OtaTransport), client, gtx);
+        wi.config.ota.outbox_dir = Some(dir.clone());
+
+        let reports = vec!["1", "2"].into_iter()
+            .map(|id| UpdateReport::single(id.to_string(), UpdateResultCode::OK, "".to_string()))
+            .collect::<Vec<_>>();
+        for report in &reports {
+            wi.interpret(Global { command: Command::UpdateReport(report.clone()), response_tx: None }, &etx);
+            assert_eq!(erx.recv(), Some(Event::Ok));
+        }
+
+        wi.interpret(Global { command: Command::ListOutbox, response_tx: None }, &etx);
+        match erx.recv() {
+            Some(Event::FoundOutbox(entries)) => {
+                assert_eq!(entries.len(), 1);
+                assert_eq!(entries[0].attempts, 2);
+                assert_eq!(entries[0].message, Upstream::UpdateReport(reports[0].clone()));
+            }
+            event => panic!("expected the outbox, got {:?}", event)
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn failed_renewal_keeps_the_token() {
+        let replies    = vec!["[]".to_string(), "not a token".to_string()];
+        let pkg_mgr    = PackageManager::new_file(true);
+        let (ctx, erx) = new_interpreter_with_expiry(replies, pkg_mgr, Some(0));
+
+        ctx.send(Command::GetPendingUpdates);
+        assert_rx(erx, &[Event::FoundPendingUpdates(Vec::new()), Event::Ok]);
+    }
+
+    #[test]
+    fn rejected_request_is_retried_with_a_new_token() {
+        let token   = r#"{"access_token": "new", "token_type": "type", "expires_in": 3600, "scope": []}"#;
+        let client  = TestHttpClient::from_responses(vec![
+            Ok(b"[]".to_vec()),
+            Ok(token.as_bytes().to_vec()),
+            Err(Error::AuthorizationError("401".to_string())),
+        ]);
+        let pkg_mgr    = PackageManager::new_file(true);
+        let expiry     = Some(time::get_time().sec + 3600);
+        let (ctx, erx) = start_interpreter(Box::new(OtaTransport), client, pkg_mgr, expiry);
+
+        ctx.send(Command::GetPendingUpdates);
+        assert_rx(erx, &[Event::FoundPendingUpdates(Vec::new()), Event::Ok]);
+    }
+
+    #[test]
+    fn rejected_installs_are_not_retried() {
+        let token   = r#"{"access_token": "new", "token_type": "type", "expires_in": 3600, "scope": []}"#;
+        let client  = TestHttpClient::from_responses(vec![
+            Ok(token.as_bytes().to_vec()),
+            Err(Error::AuthorizationError("401".to_string())),
+            Ok(b"[]".to_vec()),
+        ]);
+        let pkg_mgr    = PackageManager::new_file(true);
+        let expiry     = Some(time::get_time().sec + 3600);
+        let (ctx, erx) = start_interpreter(Box::new(OtaTransport), client, pkg_mgr, expiry);
+
+        // the report is rejected after the install, which mustn't run again
+        ctx.send(Command::AcceptUpdates(vec!["1".to_string()]));
+        assert_rx(erx, &[
+            Event::UpdateStateChanged("1".to_string(), UpdateState::Downloading),
+            Event::UpdateStateChanged("1".to_string(), UpdateState::Installing),
+            Event::UpdateStateChanged("1".to_string(), UpdateState::Installed),
+            Event::NotAuthenticated,
+        ]);
+    }
 }
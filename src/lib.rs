@@ -5,6 +5,9 @@ extern crate dbus;
 extern crate hyper;
 #[macro_use] extern crate nom; // use before log to avoid error!() macro conflict
 #[macro_use] extern crate log;
+extern crate openssl;
+extern crate openssl_verify;
+extern crate rand;
 extern crate rustc_serialize;
 extern crate tempfile;
 extern crate time;
@@ -12,10 +15,26 @@ extern crate toml;
 extern crate url;
 extern crate ws;
 
+pub mod aborts;
+pub mod bundle;
 pub mod oauth2;
+pub mod outbox;
+pub mod credentials;
 pub mod datatype;
+pub mod firmware;
 pub mod http_client;
 pub mod interaction_library;
 pub mod interpreter;
+pub mod mqtt;
+pub mod network;
 pub mod ota_plus;
 pub mod package_manager;
+pub mod polling;
+pub mod provision;
+pub mod push;
+pub mod remote;
+pub mod secondary;
+pub mod shutdown;
+pub mod swm;
+pub mod transport;
+pub mod uptane;
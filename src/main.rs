@@ -8,39 +8,126 @@ extern crate hyper;
 #[macro_use] extern crate log;
 extern crate rustc_serialize;
 extern crate time;
+extern crate toml;
 
 use chan::{Sender, Receiver};
 use chan_signal::Signal;
 use env_logger::LogBuilder;
-use getopts::Options;
-use log::LogRecord;
-use std::env;
-use std::time::Duration;
-
-use libotaplus::datatype::{config, Auth, Command, Config, Event, Url};
+use getopts::{Matches, Options};
+use log::{Log, LogLevelFilter, LogMetadata, LogRecord, MaxLogLevelFilter};
+use std::{env, io, thread};
+use std::io::Write;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use toml::{Table, Value};
+
+use libotaplus::aborts::Aborts;
+use libotaplus::bundle;
+use libotaplus::datatype::{config, Auth, Command, Config, DBusConfig, Error, Event, GatewayConfig, OtaConfig, Url};
 use libotaplus::http_client::AuthClient;
 use libotaplus::interaction_library::{Console, Gateway, Http, Websocket};
+use libotaplus::interaction_library::gateway::GatewayClose;
 use libotaplus::interaction_library::broadcast::Broadcast;
-use libotaplus::interpreter::{EventInterpreter, CommandInterpreter, Interpreter,
+use libotaplus::interpreter::{EventInterpreter, CommandInterpreter, ConfigReloader, Interpreter,
                               Global, GlobalInterpreter};
+use libotaplus::network;
+use libotaplus::network::Network;
 use libotaplus::package_manager::PackageManager;
+use libotaplus::polling;
+use libotaplus::polling::Polling;
+use libotaplus::provision;
+use libotaplus::push;
+use libotaplus::push::Notifier;
+use libotaplus::remote::rvi::ServiceEdge;
+use libotaplus::remote::svc::{RemoteServices, ServiceHandler};
+use libotaplus::shutdown::Shutdown;
+use libotaplus::swm::interpreter::SwmEventInterpreter;
+use libotaplus::swm::sc::SotaC;
+use libotaplus::transport::{MqttTransport, OtaTransport, RviTransport, Transport};
+
+
+/// Exit status when a shutdown timed out before the work in flight finished.
+const EXIT_ABANDONED: i32 = 2;
 
 
-fn spawn_signal_handler(signals: Receiver<Signal>) {
+fn spawn_signal_handler(signals: Receiver<Signal>, ctx: Sender<Command>, shutdown: Shutdown) {
     loop {
         match signals.recv() {
-            Some(Signal::INT)  => std::process::exit(0),
-            Some(Signal::TERM) => std::process::exit(0),
-            _                  => ()
+            Some(Signal::INT) | Some(Signal::TERM) => {
+                if shutdown.is_requested() {
+                    warn!("Second shutdown signal received, exiting now");
+                    std::process::exit(EXIT_ABANDONED);
+                }
+                shutdown.request();
+            }
+            Some(Signal::HUP) => ctx.send(Command::ReloadConfig),
+            _                 => ()
         }
     }
 }
 
-fn spawn_update_poller(interval: u64, ctx: Sender<Command>) {
-    let tick = chan::tick(Duration::from_secs(interval));
+fn spawn_shutdown_handler(shutdown: Shutdown, timeout: Arc<AtomicUsize>, gtx: Sender<Global>,
+                          gateways: Arc<Mutex<Gateways>>) {
+    shutdown.wait_requested();
+    let timeout  = timeout.load(Ordering::SeqCst) as u64;
+    let deadline = Instant::now() + Duration::from_secs(timeout);
+    info!("Shutting down, waiting up to {}s for work in flight...", timeout);
+    let finished = shutdown.wait_idle(Duration::from_secs(timeout)) && flush_outbox(&gtx, deadline);
+    gateways.lock().unwrap().close();
+
+    if finished {
+        info!("Shutdown complete");
+        std::process::exit(0);
+    } else {
+        error!("Shutdown timed out, abandoning work in flight");
+        std::process::exit(EXIT_ABANDONED);
+    }
+}
+
+// Ask the interpreter for a last delivery of the outbox, returning `false`
+// if it didn't finish before the deadline.
+fn flush_outbox(gtx: &Sender<Global>, deadline: Instant) -> bool {
+    let now = Instant::now();
+    if now >= deadline {
+        return false;
+    }
+    let remaining  = deadline - now;
+    let (etx, erx) = chan::async::<Event>();
+    gtx.send(Global { command: Command::Shutdown, response_tx: Some(Arc::new(Mutex::new(etx))) });
+
+    let timeout = chan::after(remaining);
+    chan_select! {
+        erx.recv()     => true,
+        timeout.recv() => false,
+    }
+}
+
+fn spawn_update_poller(polling: Polling, config: Arc<RwLock<OtaConfig>>, ctx: Sender<Command>) {
+    loop {
+        let delay = polling.next_delay(&config.read().unwrap(), polling::jitter());
+        debug!("Polling for updates again in {}s", delay);
+        thread::sleep(Duration::from_secs(delay));
+        if polling.queue_poll() {
+            ctx.send(Command::GetPendingUpdates);
+        } else {
+            debug!("Skipping the poll while the last one is waiting");
+        }
+    }
+}
+
+// Reports the installed software every `inventory_interval` seconds, give or
+// take 10%. An interval of 0 only reports it at startup and after installs.
+fn spawn_inventory_sync(config: Arc<RwLock<OtaConfig>>, ctx: Sender<Command>) {
     loop {
-        let _ = tick.recv();
-        ctx.send(Command::GetPendingUpdates);
+        let interval = config.read().unwrap().inventory_interval;
+        if interval == 0 {
+            thread::sleep(Duration::from_secs(60));
+            continue;
+        }
+        let delay = interval as f64 * (0.9 + polling::jitter() / 5.0);
+        thread::sleep(Duration::from_secs(delay.round() as u64));
+        ctx.send(Command::UpdateInstalledPackages);
     }
 }
 
@@ -50,82 +137,369 @@ fn perform_initial_sync(ctx: &Sender<Command>) {
 }
 
 fn main() {
-    setup_logging();
-    let config = build_config();
+    let log_control = setup_logging();
+    let matches     = parse_args();
+    let table       = config_table(&matches).unwrap_or_else(|err| exit!("{}", err));
+    if matches.opt_present("print-config") {
+        print!("{}", config::print_config(&table).unwrap_or_else(|err| exit!("{}", err)));
+        std::process::exit(0);
+    }
+    let mut config  = config::decode_config(table).unwrap_or_else(|err| exit!("{}", err));
+    log_control.set_level(log_level(&config));
+
+    if config.needs_provisioning() {
+        info!("No credentials found, provisioning device...");
+        // the client certificate is only written by the provisioning
+        let mut prov_config = config.clone();
+        prov_config.tls     = config.tls.as_ref().map(|tls| tls.without_client_cert());
+        let client = AuthClient::from_config(Auth::None, &prov_config)
+            .unwrap_or_else(|err| exit!("Invalid TLS or proxy config: {}", err));
+        provision::provision(&mut config, &client).unwrap_or_else(|err| exit!("Provisioning failed: {}", err));
+    }
 
     let (etx, erx) = chan::async::<Event>();
     let (ctx, crx) = chan::async::<Command>();
     let (gtx, grx) = chan::async::<Global>();
-
+    let (reload_tx, reload_rx) = chan::async::<Config>();
+
+    let auth = match config.tls {
+        Some(ref tls) if tls.has_client_cert() && config.auth.is_none() => Auth::Certificate,
+        _ => Auth::None
+    };
+    let http_client = AuthClient::from_config(auth, &config)
+        .unwrap_or_else(|err| exit!("Invalid TLS or proxy config: {}", err));
+    let load_config = Box::new(move || config_table(&matches).and_then(config::decode_config));
+
+    let aborts        = Aborts::new();
+    let shutdown      = Shutdown::new();
     let mut broadcast = Broadcast::new(erx);
     perform_initial_sync(&ctx);
 
     crossbeam::scope(|scope| {
         // Must subscribe to the signal before spawning ANY other threads
-        let signals = chan_signal::notify(&[Signal::INT, Signal::TERM]);
-        scope.spawn(move || spawn_signal_handler(signals));
+        let signals    = chan_signal::notify(&[Signal::INT, Signal::TERM, Signal::HUP]);
+        let signal_ctx = ctx.clone();
+        let signal_end = shutdown.clone();
+        scope.spawn(move || spawn_signal_handler(signals, signal_ctx, signal_end));
 
-        let poll_tick = config.ota.polling_interval;
-        let poll_ctx  = ctx.clone();
-        scope.spawn(move || spawn_update_poller(poll_tick, poll_ctx));
+        let end_timeout = Arc::new(AtomicUsize::new(config.ota.shutdown_timeout as usize));
 
-        if config.gateway.http {
-            let http_gtx = gtx.clone();
-            let http_sub = broadcast.subscribe();
-            scope.spawn(move || Http::run(http_gtx, http_sub));
+        let polling   = Polling::new();
+        let poll_cfg  = Arc::new(RwLock::new(config.ota.clone()));
+        let poll_ctx  = ctx.clone();
+        let poll_read = poll_cfg.clone();
+        let poll_with = polling.clone();
+        scope.spawn(move || spawn_update_poller(poll_with, poll_read, poll_ctx));
+
+        if let Some(push) = config.push.clone() {
+            let push_client = http_client.clone().with_timeout(Duration::from_secs(push.timeout));
+            let notifier    = Notifier { gtx: gtx.clone(), polling: polling.clone(), aborts: aborts.clone() };
+            scope.spawn(move || push::run(&push, &push_client, notifier));
         }
 
-        if config.gateway.console {
-            let console_gtx = gtx.clone();
-            let console_sub = broadcast.subscribe();
-            scope.spawn(move || Console::run(console_gtx, console_sub));
+        let network = Network::new();
+        if let Some(net) = config.network.clone() {
+            let watched = network.clone();
+            scope.spawn(move || network::watch(&net, watched));
         }
 
-        if config.gateway.websocket {
-            let ws_gtx = gtx.clone();
-            let ws_sub = broadcast.subscribe();
-            scope.spawn(move || Websocket::run(ws_gtx, ws_sub));
+        if let Some(bundle) = config.bundle.clone() {
+            let bundle_gtx = gtx.clone();
+            scope.spawn(move || bundle::watch(&bundle, bundle_gtx));
         }
 
+        let sync_ctx  = ctx.clone();
+        let sync_read = poll_cfg.clone();
+        scope.spawn(move || spawn_inventory_sync(sync_read, sync_ctx));
+
+        let transport: Box<Transport> = match config.rvi.clone() {
+            Some(rvi) => {
+                let svcs     = Arc::new(Mutex::new(RemoteServices::new(rvi.client.clone())));
+                let handler  = ServiceHandler::new(etx.clone(), svcs.clone(), rvi.clone(), polling.clone(), shutdown.clone());
+                let xfers    = handler.transfers();
+                let edge_url = rvi.edge_url().unwrap_or_else(|err| exit!("Invalid RVI edge address: {}", err));
+                let edge     = ServiceEdge::new(rvi.client.clone(), edge_url, handler);
+                edge.start().unwrap_or_else(|err| exit!("Couldn't start the RVI edge: {}", err));
+                Box::new(RviTransport::new(svcs, xfers))
+            }
+
+            None if config.mqtt.is_some() => {
+                let mqtt = MqttTransport::new(&config, etx.clone(), polling.clone())
+                    .unwrap_or_else(|err| exit!("Couldn't start the MQTT transport: {}", err));
+                Box::new(mqtt)
+            }
+
+            None => Box::new(OtaTransport)
+        };
+
+        let gateways = Gateways {
+            console:   GatewaySwitch::new(Box::new(<Console as Gateway<Command, Event>>::run), broadcast.subscribe(), gtx.clone(), shutdown.clone(), aborts.clone()),
+            http:      GatewaySwitch::new(Box::new(<Http as Gateway<Command, Event>>::run), broadcast.subscribe(), gtx.clone(), shutdown.clone(), aborts.clone()),
+            websocket: GatewaySwitch::new(Box::new(<Websocket as Gateway<Command, Event>>::run), broadcast.subscribe(), gtx.clone(), shutdown.clone(), aborts.clone()),
+            dbus:      config.dbus.clone().map(|cfg| {
+                GatewaySwitch::new(dbus_gateway(cfg), broadcast.subscribe(), gtx.clone(), shutdown.clone(), aborts.clone())
+            }),
+        };
+        let gateways = Arc::new(Mutex::new(gateways));
+        gateways.lock().unwrap().apply(&config.gateway);
+
+        let end_wait     = shutdown.clone();
+        let end_read     = end_timeout.clone();
+        let end_gtx      = gtx.clone();
+        let end_gateways = gateways.clone();
+        scope.spawn(move || spawn_shutdown_handler(end_wait, end_read, end_gtx, end_gateways));
+
+        scope.spawn(move || {
+            for config in reload_rx {
+                info!("Applying reloaded config");
+                *poll_cfg.write().unwrap() = config.ota.clone();
+                end_timeout.store(config.ota.shutdown_timeout as usize, Ordering::SeqCst);
+                gateways.lock().unwrap().apply(&config.gateway);
+                log_control.set_level(log_level(&config));
+            }
+        });
+
         let event_sub = broadcast.subscribe();
         let event_ctx = ctx.clone();
-        scope.spawn(move || EventInterpreter.run(event_sub, event_ctx));
+        match config.dbus.clone() {
+            Some(cfg) => {
+                let client_config = config.clone();
+                let swm_client    = http_client.clone();
+                scope.spawn(move || SwmEventInterpreter {
+                    config:        cfg,
+                    client_config: client_config,
+                    http_client:   Box::new(swm_client),
+                }.run(event_sub, event_ctx))
+            }
+            None      => {
+                let accept_available = config.rvi.is_some();
+                scope.spawn(move || EventInterpreter { accept_available: accept_available }.run(event_sub, event_ctx))
+            }
+        };
 
         let cmd_gtx = gtx.clone();
         scope.spawn(move || CommandInterpreter.run(crx, cmd_gtx));
 
         scope.spawn(move || GlobalInterpreter {
-            config:      config,
-            token:       None,
-            http_client: Box::new(AuthClient::new(Auth::None)),
-            loopback_tx: gtx,
+            aborts:       aborts,
+            config:       config,
+            token:        None,
+            token_expiry: None,
+            http_client:  Box::new(http_client),
+            inventory:    None,
+            loopback_tx:  gtx,
+            network:      network,
+            polling:      polling,
+            reloader:     Some(ConfigReloader::new(load_config, reload_tx)),
+            shutdown:     shutdown,
+            transport:    transport,
         }.run(grx, etx));
 
         scope.spawn(move || broadcast.start());
     });
 }
 
-fn setup_logging() {
-    let name    = option_env!("SERVICE_NAME").unwrap_or("ota-plus-client");
-    let version = option_env!("SERVICE_VERSION").unwrap_or("?");
 
+type GatewayRun = Box<Fn(Sender<Global>, Receiver<Event>, &GatewayConfig) -> GatewayClose + Send>;
+
+/// Lets a gateway be enabled and disabled by a config reload. Events and
+/// commands only pass through while it is enabled, and the gateway itself is
+/// started the first time it is enabled. Commands are refused once a shutdown
+/// has been requested, and aborts are recorded as soon as they arrive so they
+/// can cancel a download that is already running.
+struct GatewaySwitch {
+    enabled: Arc<AtomicBool>,
+    run:     GatewayRun,
+    close:   Option<GatewayClose>,
+    pending: Option<(Sender<Global>, Receiver<Event>)>,
+}
+
+impl GatewaySwitch {
+    fn new(run: GatewayRun, sub: Receiver<Event>, gtx: Sender<Global>, shutdown: Shutdown, aborts: Aborts)
+           -> GatewaySwitch {
+        let enabled    = Arc::new(AtomicBool::new(false));
+        let (etx, erx) = chan::async::<Event>();
+        let (itx, irx) = chan::async::<Global>();
+
+        let events_enabled = enabled.clone();
+        thread::spawn(move || {
+            for event in sub {
+                if events_enabled.load(Ordering::SeqCst) {
+                    etx.send(event);
+                }
+            }
+        });
+
+        let commands_enabled = enabled.clone();
+        thread::spawn(move || {
+            for global in irx {
+                let refusal = if shutdown.is_requested() {
+                    Some("client is shutting down")
+                } else if !commands_enabled.load(Ordering::SeqCst) {
+                    Some("gateway is disabled")
+                } else {
+                    None
+                };
+
+                if let (None, &Command::AbortDownload(ref id)) = (refusal, &global.command) {
+                    aborts.request(id);
+                }
+                match (refusal, global.response_tx.clone()) {
+                    (None, _)             => gtx.send(global),
+                    (Some(msg), Some(tx)) => tx.lock().unwrap().send(Event::Error(msg.to_string())),
+                    (Some(_), None)       => ()
+                }
+            }
+        });
+
+        GatewaySwitch { enabled: enabled, run: run, close: None, pending: Some((itx, erx)) }
+    }
+
+    fn set(&mut self, enable: bool, cfg: &GatewayConfig) {
+        if enable {
+            if let Some((itx, erx)) = self.pending.take() {
+                self.close = Some((self.run)(itx, erx, cfg));
+            }
+        }
+        self.enabled.store(enable, Ordering::SeqCst);
+    }
+
+    /// Stop passing events and commands and close the gateway if it started.
+    fn close(&mut self) {
+        self.enabled.store(false, Ordering::SeqCst);
+        if let Some(close) = self.close.take() {
+            close();
+        }
+    }
+}
+
+struct Gateways {
+    console:   GatewaySwitch,
+    http:      GatewaySwitch,
+    websocket: GatewaySwitch,
+    dbus:      Option<GatewaySwitch>,
+}
+
+impl Gateways {
+    fn apply(&mut self, cfg: &GatewayConfig) {
+        self.console.set(cfg.console, cfg);
+        self.http.set(cfg.http, cfg);
+        self.websocket.set(cfg.websocket, cfg);
+        if let Some(ref mut dbus) = self.dbus {
+            dbus.set(true, cfg);
+        }
+    }
+
+    fn close(&mut self) {
+        self.console.close();
+        self.http.close();
+        self.websocket.close();
+        if let Some(ref mut dbus) = self.dbus {
+            dbus.close();
+        }
+    }
+}
+
+/// Runs the D-Bus gateway, which accepts commands from the software loading
+/// manager and exports the client status. Events reach the manager itself
+/// through the `SwmEventInterpreter`. Closing it releases the bus name and
+/// waits for its thread to stop.
+fn dbus_gateway(cfg: DBusConfig) -> GatewayRun {
+    Box::new(move |itx: Sender<Global>, erx: Receiver<Event>, _: &GatewayConfig| {
+        let cfg     = cfg.clone();
+        let stop    = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let handle  = thread::spawn(move || {
+            let _ = SotaC::new(cfg, itx).start(erx, &stopped)
+                .map_err(|err| error!("D-Bus gateway stopped: {}", err));
+        });
+        let handle = Mutex::new(Some(handle));
+        Box::new(move || {
+            stop.store(true, Ordering::SeqCst);
+            if let Some(handle) = handle.lock().unwrap().take() {
+                let _ = handle.join();
+            }
+        })
+    })
+}
+
+
+/// Wraps the env_logger so the `[log] level` setting can override the
+/// `RUST_LOG` filter at runtime.
+struct ReloadableLogger {
+    inner: env_logger::Logger,
+    level: Arc<RwLock<Option<LogLevelFilter>>>,
+}
+
+impl Log for ReloadableLogger {
+    fn enabled(&self, metadata: &LogMetadata) -> bool {
+        match *self.level.read().unwrap() {
+            Some(level) => metadata.level() <= level,
+            None        => self.inner.enabled(metadata)
+        }
+    }
+
+    fn log(&self, record: &LogRecord) {
+        match *self.level.read().unwrap() {
+            Some(level) => if record.level() <= level {
+                let _ = writeln!(&mut io::stderr(), "{}", format_record(record));
+            },
+            None => self.inner.log(record)
+        }
+    }
+}
+
+struct LogControl {
+    level:   Arc<RwLock<Option<LogLevelFilter>>>,
+    max:     MaxLogLevelFilter,
+    default: LogLevelFilter,
+}
+
+impl LogControl {
+    fn set_level(&self, level: Option<LogLevelFilter>) {
+        *self.level.write().unwrap() = level;
+        self.max.set(level.unwrap_or(self.default));
+    }
+}
+
+fn log_level(config: &Config) -> Option<LogLevelFilter> {
+    config.log.as_ref().and_then(|log| log.level.parse().ok())
+}
+
+fn format_record(record: &LogRecord) -> String {
+    let name      = option_env!("SERVICE_NAME").unwrap_or("ota-plus-client");
+    let version   = option_env!("SERVICE_VERSION").unwrap_or("?");
+    let timestamp = format!("{}", time::now_utc().rfc3339());
+    format!("{}:{} @ {}: {} - {}", name, version, timestamp, record.level(), record.args())
+}
+
+fn setup_logging() -> LogControl {
     let mut builder = LogBuilder::new();
-    builder.format(move |record: &LogRecord| {
-        let timestamp = format!("{}", time::now_utc().rfc3339());
-        format!("{}:{} @ {}: {} - {}", name, version, timestamp, record.level(), record.args())
-    });
+    builder.format(format_record);
     let _ = env::var("RUST_LOG").map(|level| builder.parse(&level));
-    builder.init().expect("env_logger::init() called twice, blame the programmers.");
+
+    let inner   = builder.build();
+    let default = inner.filter();
+    let level   = Arc::new(RwLock::new(None));
+    let mut max = None;
+    log::set_logger(|filter| {
+        filter.set(default);
+        max = Some(filter);
+        Box::new(ReloadableLogger { inner: inner, level: level.clone() })
+    }).expect("log::set_logger() called twice, blame the programmers.");
+
+    LogControl { level: level, max: max.expect("max log level filter"), default: default }
 }
 
 
-fn build_config() -> Config {
+fn parse_args() -> Matches {
     let args     = env::args().collect::<Vec<String>>();
     let program  = args[0].clone();
     let mut opts = Options::new();
 
     opts.optflag("h", "help", "print this help menu");
     opts.optopt("", "config", "change config path", "PATH");
+    opts.optflag("", "print-config", "print the effective configuration and exit");
 
     opts.optopt("", "auth-server", "change the auth server URL", "URL");
     opts.optopt("", "auth-client-id", "change auth client id", "ID");
@@ -146,40 +520,49 @@ fn build_config() -> Config {
     if matches.opt_present("h") {
         exit!("{}", opts.usage(&format!("Usage: {} [options]", program)));
     }
+    matches
+}
 
+// Merge the config layers with the command line flags on top. This is also
+// run again on each config reload.
+fn config_table(matches: &Matches) -> Result<Table, Error> {
+    let explicit    = matches.opt_present("config") || env::var("OTA_PLUS_CLIENT_CFG").is_ok();
     let config_file = matches.opt_str("config").unwrap_or_else(|| {
         env::var("OTA_PLUS_CLIENT_CFG").unwrap_or("/opt/ats/ota/etc/ota.toml".to_string())
     });
-    let mut config  = config::load_config(&config_file).unwrap_or_else(|err| exit!("{}", err));
-
-    config.auth.as_mut().map(|auth_cfg| {
-        matches.opt_str("auth-client-id").map(|id| auth_cfg.client_id = id);
-        matches.opt_str("auth-secret").map(|secret| auth_cfg.secret = secret);
-        matches.opt_str("auth-server").map(|text| {
-            auth_cfg.server = Url::parse(&text).unwrap_or_else(|err| exit!("Invalid auth-server URL: {}", err));
-        });
-    });
+    let mut table   = try!(config::load_table(&config_file, explicit));
+
+    if table.contains_key("auth") {
+        matches.opt_str("auth-client-id").map(|id| config::set_key(&mut table, "auth.client_id", Value::String(id)));
+        matches.opt_str("auth-secret").map(|secret| config::set_key(&mut table, "auth.secret", Value::String(secret)));
+        if let Some(text) = matches.opt_str("auth-server") {
+            try!(Url::parse(&text).map_err(|err| Error::ParseError(format!("Invalid auth-server URL: {}", err))));
+            config::set_key(&mut table, "auth.server", Value::String(text));
+        }
+    }
 
-    matches.opt_str("device-uuid").map(|uuid| config.device.uuid = uuid);
-    matches.opt_str("device-vin").map(|vin| config.device.vin = vin);
+    matches.opt_str("device-uuid").map(|uuid| config::set_key(&mut table, "device.uuid", Value::String(uuid)));
+    matches.opt_str("device-vin").map(|vin| config::set_key(&mut table, "device.vin", Value::String(vin)));
 
     if matches.opt_present("console") {
-        config.gateway.console = true;
+        config::set_key(&mut table, "gateway.console", Value::Boolean(true));
     }
     if matches.opt_present("http") {
-        config.gateway.http = true;
+        config::set_key(&mut table, "gateway.http", Value::Boolean(true));
     }
     if matches.opt_present("no-websocket") {
-        config.gateway.websocket = false;
+        config::set_key(&mut table, "gateway.websocket", Value::Boolean(false));
     }
 
-    matches.opt_str("ota-packages-dir").map(|path| config.ota.packages_dir = path);
-    matches.opt_str("ota-server").map(|text| {
-        config.ota.server  = Url::parse(&text).unwrap_or_else(|err| exit!("Invalid ota-server URL: {}", err));
-    });
-    matches.opt_str("ota-package-manager").map(|text| {
-        config.ota.package_manager = text.parse::<PackageManager>().unwrap_or_else(|err| exit!("Invalid package manager: {}", err));
-    });
+    matches.opt_str("ota-packages-dir").map(|path| config::set_key(&mut table, "ota.packages_dir", Value::String(path)));
+    if let Some(text) = matches.opt_str("ota-server") {
+        try!(Url::parse(&text).map_err(|err| Error::ParseError(format!("Invalid ota-server URL: {}", err))));
+        config::set_key(&mut table, "ota.server", Value::String(text));
+    }
+    if let Some(text) = matches.opt_str("ota-package-manager") {
+        try!(text.parse::<PackageManager>().map_err(|err| Error::ParseError(format!("Invalid package manager: {}", err))));
+        config::set_key(&mut table, "ota.package_manager", Value::String(text));
+    }
 
-    config
+    Ok(table)
 }
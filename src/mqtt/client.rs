@@ -0,0 +1,366 @@
+use chan;
+use chan::{Receiver, Sender};
+use openssl::ssl::SslStream;
+use std::collections::BTreeMap;
+use std::io;
+use std::io::{ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use datatype::{Error, MqttConfig, TlsConfig};
+use http_client::tls;
+use http_client::tls::Tls;
+use mqtt::packet::{Connect, Packet, Publish};
+
+
+/// How long to wait for the start of each packet before servicing the
+/// outgoing queue and keepalive.
+const POLL_MS: u64 = 100;
+
+/// What the connection reports back to its owner.
+#[derive(Debug)]
+pub enum Incoming {
+    Connected,
+    Disconnected,
+    Message(Publish),
+}
+
+struct Outgoing {
+    publish: Publish,
+    acked:   Option<Sender<()>>,
+    expired: Arc<AtomicBool>,
+}
+
+impl Outgoing {
+    fn new(publish: Publish, acked: Option<Sender<()>>) -> Outgoing {
+        Outgoing { publish: publish, acked: acked, expired: Arc::new(AtomicBool::new(false)) }
+    }
+
+    fn is_expired(&self) -> bool {
+        self.expired.load(Ordering::SeqCst)
+    }
+}
+
+
+/// A handle to a persistent MQTT session, kept alive by a background thread
+/// that reconnects `retry_delay` seconds after losing the broker.
+///
+/// The session is not cleaned on reconnect, so the broker holds QoS 1
+/// messages for the device while it is offline, and unacknowledged publishes
+/// are sent again until the caller gives up on them. The broker publishes a retained `offline` to
+/// `<topic>/status` when the client disappears, which the client replaces
+/// with `online` once connected.
+#[derive(Clone)]
+pub struct Mqtt {
+    outgoing: Sender<Outgoing>,
+    timeout:  Duration,
+}
+
+impl Mqtt {
+    /// Start the session, subscribing to `subscribe` with QoS 1.
+    pub fn start(config: &MqttConfig, tls_cfg: Option<&TlsConfig>, client_id: &str, topic: &str, subscribe: &str)
+                 -> Result<(Mqtt, Receiver<Incoming>), Error> {
+        let context = if config.tls {
+            Some(try!(Tls::from_config(tls_cfg.unwrap_or(&TlsConfig::default()))))
+        } else {
+            None
+        };
+
+        let (out_tx, out_rx) = chan::async::<Outgoing>();
+        let (in_tx, in_rx)   = chan::async::<Incoming>();
+        let session = Session {
+            config:    config.clone(),
+            context:   context,
+            connect:   Connect {
+                client_id:     client_id.to_string(),
+                keep_alive:    config.keep_alive,
+                clean_session: false,
+                will:          Some(Publish::new(&format!("{}/status", topic), b"offline".to_vec(), 1, true)),
+                username:      config.username.clone(),
+                password:      config.password.clone(),
+            },
+            subscribe: subscribe.to_string(),
+            inflight:  BTreeMap::new(),
+            next_id:   0,
+        };
+        thread::spawn(move || session.run(out_rx, in_tx));
+
+        Ok((Mqtt { outgoing: out_tx, timeout: Duration::from_secs(config.timeout) }, in_rx))
+    }
+
+    /// Publish with QoS 1, waiting until the broker acknowledges it. A publish
+    /// that times out is dropped rather than sent again after reconnecting,
+    /// though the broker may still deliver a copy it already received.
+    pub fn publish(&self, topic: &str, payload: Vec<u8>) -> Result<(), Error> {
+        let (ack_tx, ack_rx) = chan::sync::<()>(1);
+        let out     = Outgoing::new(Publish::new(topic, payload, 1, false), Some(ack_tx));
+        let expired = out.expired.clone();
+        self.outgoing.send(out);
+
+        let timeout = chan::after(self.timeout);
+        chan_select! {
+            ack_rx.recv()  => Ok(()),
+            timeout.recv() => {
+                expired.store(true, Ordering::SeqCst);
+                Err(Error::MqttError(format!("no acknowledgement for publish to {}", topic)))
+            }
+        }
+    }
+}
+
+
+trait Stream: Read + Write + Send {
+    fn set_timeout(&self, timeout: Duration) -> io::Result<()>;
+}
+
+impl Stream for TcpStream {
+    fn set_timeout(&self, timeout: Duration) -> io::Result<()> {
+        self.set_read_timeout(Some(timeout))
+    }
+}
+
+impl Stream for SslStream<TcpStream> {
+    fn set_timeout(&self, timeout: Duration) -> io::Result<()> {
+        self.get_ref().set_read_timeout(Some(timeout))
+    }
+}
+
+
+struct Session {
+    config:    MqttConfig,
+    context:   Option<Tls>,
+    connect:   Connect,
+    subscribe: String,
+    inflight:  BTreeMap<u16, Outgoing>,
+    next_id:   u16,
+}
+
+impl Session {
+    fn run(mut self, out_rx: Receiver<Outgoing>, in_tx: Sender<Incoming>) {
+        loop {
+            if let Err(err) = self.session(&out_rx, &in_tx) {
+                error!("MQTT connection to {} lost: {}", self.config.broker, err);
+            } else {
+                info!("MQTT session closed");
+                return;
+            }
+            in_tx.send(Incoming::Disconnected);
+            thread::sleep(Duration::from_secs(self.config.retry_delay));
+        }
+    }
+
+    /// Run one connection to the broker, returning `Ok` once all handles to
+    /// the session are dropped.
+    fn session(&mut self, out_rx: &Receiver<Outgoing>, in_tx: &Sender<Incoming>) -> Result<(), Error> {
+        let mut stream = try!(self.open());
+        try!(send(&mut stream, Packet::Connect(self.connect.clone())));
+        match try!(self.wait_packet(&mut stream)) {
+            Packet::ConnAck { code: 0, .. } => (),
+            Packet::ConnAck { code, .. }    => return Err(Error::MqttError(format!("connection refused with code {}", code))),
+            packet => return Err(Error::MqttError(format!("expected CONNACK, got {:?}", packet)))
+        }
+
+        let status = self.connect.will.as_ref().map(|will| will.topic.clone()).expect("last will");
+        let online = Outgoing::new(Publish::new(&status, b"online".to_vec(), 1, true), None);
+        try!(self.publish(&mut stream, online));
+        let subscribe = Packet::Subscribe { id: self.packet_id(), topics: vec![(self.subscribe.clone(), 1)] };
+        try!(send(&mut stream, subscribe));
+        self.drop_expired();
+        for (_, out) in &mut self.inflight {
+            out.publish.dup = true;
+            try!(send(&mut stream, Packet::Publish(out.publish.clone())));
+        }
+        info!("Connected to MQTT broker {}", self.config.broker);
+        in_tx.send(Incoming::Connected);
+
+        let keep_alive    = Duration::from_secs(self.config.keep_alive as u64);
+        let mut last_sent = Instant::now();
+        let mut ping_sent = None;
+        loop {
+            while let Some(out) = next_outgoing(out_rx) {
+                match out {
+                    Some(ref out) if out.is_expired() => continue,
+                    Some(out) => try!(self.publish(&mut stream, out)),
+                    None      => return self.close(&mut stream)
+                }
+                last_sent = Instant::now();
+            }
+            self.drop_expired();
+
+            match ping_sent {
+                Some(sent) if Instant::now().duration_since(sent) > Duration::from_secs(self.config.timeout) => {
+                    return Err(Error::MqttError("no response to keepalive ping".to_string()));
+                }
+                None if Instant::now().duration_since(last_sent) >= keep_alive => {
+                    try!(send(&mut stream, Packet::PingReq));
+                    last_sent = Instant::now();
+                    ping_sent = Some(last_sent);
+                }
+                _ => ()
+            }
+
+            match try!(self.poll_packet(&mut stream)) {
+                Some(Packet::Publish(publish)) => {
+                    if let Some(id) = publish.id {
+                        try!(send(&mut stream, Packet::PubAck(id)));
+                    }
+                    in_tx.send(Incoming::Message(publish));
+                }
+
+                Some(Packet::PubAck(id)) => {
+                    if let Some(Outgoing { acked: Some(ack_tx), .. }) = self.inflight.remove(&id) {
+                        ack_tx.send(());
+                    }
+                }
+
+                Some(Packet::SubAck { codes, .. }) => {
+                    if codes.iter().any(|code| *code == 0x80) {
+                        return Err(Error::MqttError(format!("subscription to {} refused", self.subscribe)));
+                    }
+                }
+
+                Some(Packet::PingResp) => ping_sent = None,
+                Some(packet)           => debug!("ignoring MQTT packet: {:?}", packet),
+                None                   => ()
+            }
+        }
+    }
+
+    // A clean disconnect doesn't trigger the last will, so publish it first.
+    fn close(&self, stream: &mut Box<Stream>) -> Result<(), Error> {
+        if let Some(ref will) = self.connect.will {
+            try!(send(stream, Packet::Publish(Publish::new(&will.topic, will.payload.clone(), 0, true))));
+        }
+        send(stream, Packet::Disconnect)
+    }
+
+    fn open(&self) -> Result<Box<Stream>, Error> {
+        let tcp = try!(TcpStream::connect(self.config.broker.as_str()));
+        try!(tcp.set_write_timeout(Some(Duration::from_secs(self.config.timeout))));
+        match self.context {
+            Some(ref context) => {
+                let host = self.config.broker.rsplitn(2, ':').last().unwrap_or("");
+                Ok(Box::new(try!(tls::connect(context, tcp, host))))
+            }
+
+            None => Ok(Box::new(tcp))
+        }
+    }
+
+    // Forget the publishes whose callers stopped waiting for them.
+    fn drop_expired(&mut self) {
+        let expired = self.inflight.iter()
+            .filter(|&(_, out)| out.is_expired())
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        for id in expired {
+            debug!("dropping expired MQTT publish {}", id);
+            self.inflight.remove(&id);
+        }
+    }
+
+    fn packet_id(&mut self) -> u16 {
+        loop {
+            self.next_id = self.next_id.wrapping_add(1);
+            if self.next_id != 0 && !self.inflight.contains_key(&self.next_id) {
+                return self.next_id;
+            }
+        }
+    }
+
+    fn publish(&mut self, stream: &mut Box<Stream>, mut out: Outgoing) -> Result<(), Error> {
+        let id = self.packet_id();
+        out.publish.id = Some(id);
+        let packet = Packet::Publish(out.publish.clone());
+        self.inflight.insert(id, out);
+        send(stream, packet)
+    }
+
+    fn poll_packet(&self, stream: &mut Box<Stream>) -> Result<Option<Packet>, Error> {
+        let mut header = [0; 1];
+        try!(stream.set_timeout(Duration::from_millis(POLL_MS)));
+        match stream.read(&mut header) {
+            Ok(0) => Err(Error::MqttError("connection closed by the broker".to_string())),
+            Ok(_) => {
+                try!(stream.set_timeout(Duration::from_secs(self.config.timeout)));
+                Packet::read_rest(header[0], stream).map(Some)
+            }
+            Err(ref err) if err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::TimedOut => Ok(None),
+            Err(err) => Err(Error::IoError(err))
+        }
+    }
+
+    fn wait_packet(&self, stream: &mut Box<Stream>) -> Result<Packet, Error> {
+        try!(stream.set_timeout(Duration::from_secs(self.config.timeout)));
+        Packet::read(stream)
+    }
+}
+
+fn send(stream: &mut Box<Stream>, packet: Packet) -> Result<(), Error> {
+    trace!("sending MQTT packet: {:?}", packet);
+    try!(stream.write_all(&packet.encode()));
+    Ok(try!(stream.flush()))
+}
+
+/// Take the next queued publish without blocking. The inner `None` means all
+/// handles to the session were dropped.
+fn next_outgoing(out_rx: &Receiver<Outgoing>) -> Option<Option<Outgoing>> {
+    let mut next = None;
+    chan_select! {
+        default => (),
+        out_rx.recv() -> out => next = Some(out),
+    }
+    next
+}
+
+
+#[cfg(test)]
+pub mod test_broker {
+    use chan;
+    use chan::{Receiver, Sender};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::io::Write;
+
+    use mqtt::packet::{Packet, Publish};
+
+
+    /// Accept one client, acknowledging everything it sends. Returns the
+    /// broker address, the packets received, and a channel to publish to the
+    /// client.
+    pub fn serve() -> (String, Receiver<Packet>, Sender<Publish>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address  = format!("{}", listener.local_addr().unwrap());
+        let (recv_tx, recv_rx) = chan::async::<Packet>();
+        let (pub_tx, pub_rx)   = chan::async::<Publish>();
+
+        thread::spawn(move || {
+            let (mut reader, _) = listener.accept().unwrap();
+            let writer = Arc::new(Mutex::new(reader.try_clone().unwrap()));
+            let pub_writer = writer.clone();
+            thread::spawn(move || for (id, mut publish) in pub_rx.iter().enumerate() {
+                publish.id = Some(id as u16 + 1000);
+                pub_writer.lock().unwrap().write_all(&Packet::Publish(publish).encode()).unwrap();
+            });
+
+            while let Ok(packet) = Packet::read(&mut reader) {
+                let reply = match packet {
+                    Packet::Connect(_)               => Some(Packet::ConnAck { session_present: false, code: 0 }),
+                    Packet::Publish(ref publish)     => publish.id.map(Packet::PubAck),
+                    Packet::Subscribe { id, .. }     => Some(Packet::SubAck { id: id, codes: vec![1] }),
+                    Packet::PingReq                  => Some(Packet::PingResp),
+                    _                                => None
+                };
+                if let Some(reply) = reply {
+                    writer.lock().unwrap().write_all(&reply.encode()).unwrap();
+                }
+                recv_tx.send(packet);
+            }
+        });
+        (address, recv_rx, pub_tx)
+    }
+}
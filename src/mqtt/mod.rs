@@ -0,0 +1,4 @@
+pub use self::client::{Incoming, Mqtt};
+
+pub mod client;
+pub mod packet;
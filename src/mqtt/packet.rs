@@ -0,0 +1,318 @@
+//! Encoding and decoding of the MQTT 3.1.1 control packets.
+
+use std::io::Read;
+
+use datatype::Error;
+
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Packet {
+    Connect(Connect),
+    ConnAck { session_present: bool, code: u8 },
+    Publish(Publish),
+    PubAck(u16),
+    Subscribe { id: u16, topics: Vec<(String, u8)> },
+    SubAck { id: u16, codes: Vec<u8> },
+    PingReq,
+    PingResp,
+    Disconnect,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Connect {
+    pub client_id:     String,
+    pub keep_alive:    u16,
+    pub clean_session: bool,
+    pub will:          Option<Publish>,
+    pub username:      Option<String>,
+    pub password:      Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Publish {
+    pub topic:   String,
+    pub payload: Vec<u8>,
+    pub qos:     u8,
+    pub retain:  bool,
+    pub dup:     bool,
+    pub id:      Option<u16>,
+}
+
+impl Publish {
+    pub fn new(topic: &str, payload: Vec<u8>, qos: u8, retain: bool) -> Publish {
+        Publish { topic: topic.to_string(), payload: payload, qos: qos, retain: retain, dup: false, id: None }
+    }
+}
+
+impl Packet {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut body = Vec::new();
+        let header = match *self {
+            Packet::Connect(ref connect) => {
+                let mut flags = if connect.clean_session { 0x02 } else { 0 };
+                if let Some(ref will) = connect.will {
+                    flags |= 0x04 | (will.qos << 3) | if will.retain { 0x20 } else { 0 };
+                }
+                if connect.password.is_some() { flags |= 0x40; }
+                if connect.username.is_some() { flags |= 0x80; }
+
+                put_str(&mut body, "MQTT");
+                body.push(4);
+                body.push(flags);
+                put_u16(&mut body, connect.keep_alive);
+                put_str(&mut body, &connect.client_id);
+                if let Some(ref will) = connect.will {
+                    put_str(&mut body, &will.topic);
+                    put_bytes(&mut body, &will.payload);
+                }
+                if let Some(ref username) = connect.username { put_str(&mut body, username); }
+                if let Some(ref password) = connect.password { put_str(&mut body, password); }
+                0x10
+            }
+
+            Packet::ConnAck { session_present, code } => {
+                body.push(if session_present { 1 } else { 0 });
+                body.push(code);
+                0x20
+            }
+
+            Packet::Publish(ref publish) => {
+                put_str(&mut body, &publish.topic);
+                if let Some(id) = publish.id {
+                    put_u16(&mut body, id);
+                }
+                body.extend_from_slice(&publish.payload);
+                0x30 | if publish.dup { 0x08 } else { 0 } | (publish.qos << 1) | if publish.retain { 1 } else { 0 }
+            }
+
+            Packet::PubAck(id) => {
+                put_u16(&mut body, id);
+                0x40
+            }
+
+            Packet::Subscribe { id, ref topics } => {
+                put_u16(&mut body, id);
+                for &(ref topic, qos) in topics {
+                    put_str(&mut body, topic);
+                    body.push(qos);
+                }
+                0x82
+            }
+
+            Packet::SubAck { id, ref codes } => {
+                put_u16(&mut body, id);
+                body.extend_from_slice(codes);
+                0x90
+            }
+
+            Packet::PingReq    => 0xc0,
+            Packet::PingResp   => 0xd0,
+            Packet::Disconnect => 0xe0,
+        };
+
+        let mut packet = vec![header];
+        let mut len    = body.len();
+        loop {
+            let byte = (len % 128) as u8;
+            len /= 128;
+            packet.push(if len > 0 { byte | 0x80 } else { byte });
+            if len == 0 {
+                break;
+            }
+        }
+        packet.extend(body);
+        packet
+    }
+
+    /// Read the rest of a packet, given its first byte.
+    pub fn read_rest<R: Read>(header: u8, reader: &mut R) -> Result<Packet, Error> {
+        let mut len = 0;
+        for shift in 0..4 {
+            let byte = try!(read_byte(reader));
+            len += ((byte & 0x7f) as usize) << (7 * shift);
+            if byte & 0x80 == 0 {
+                break;
+            } else if shift == 3 {
+                return Err(Error::MqttError("remaining length is too long".to_string()));
+            }
+        }
+
+        let mut body = vec![0; len];
+        try!(reader.read_exact(&mut body));
+        let mut body = Body { data: &body, pos: 0 };
+
+        let packet = match header >> 4 {
+            1 => {
+                if try!(body.string()) != "MQTT" || try!(body.byte()) != 4 {
+                    return Err(Error::MqttError("unsupported protocol".to_string()));
+                }
+                let flags      = try!(body.byte());
+                let keep_alive = try!(body.u16());
+                let client_id  = try!(body.string());
+                let will = if flags & 0x04 != 0 {
+                    let topic   = try!(body.string());
+                    let payload = try!(body.bytes());
+                    Some(Publish::new(&topic, payload, (flags >> 3) & 0x03, flags & 0x20 != 0))
+                } else {
+                    None
+                };
+                let username = if flags & 0x80 != 0 { Some(try!(body.string())) } else { None };
+                let password = if flags & 0x40 != 0 { Some(try!(body.string())) } else { None };
+                Packet::Connect(Connect {
+                    client_id:     client_id,
+                    keep_alive:    keep_alive,
+                    clean_session: flags & 0x02 != 0,
+                    will:          will,
+                    username:      username,
+                    password:      password,
+                })
+            }
+
+            2 => {
+                let flags = try!(body.byte());
+                Packet::ConnAck { session_present: flags & 1 != 0, code: try!(body.byte()) }
+            }
+
+            3 => {
+                let qos   = (header >> 1) & 0x03;
+                let topic = try!(body.string());
+                let id    = if qos > 0 { Some(try!(body.u16())) } else { None };
+                Packet::Publish(Publish {
+                    topic:   topic,
+                    payload: body.rest(),
+                    qos:     qos,
+                    retain:  header & 1 != 0,
+                    dup:     header & 0x08 != 0,
+                    id:      id,
+                })
+            }
+
+            4 => Packet::PubAck(try!(body.u16())),
+
+            8 => {
+                let id = try!(body.u16());
+                let mut topics = Vec::new();
+                while !body.is_empty() {
+                    let topic = try!(body.string());
+                    topics.push((topic, try!(body.byte())));
+                }
+                Packet::Subscribe { id: id, topics: topics }
+            }
+
+            9 => {
+                let id = try!(body.u16());
+                Packet::SubAck { id: id, codes: body.rest() }
+            }
+
+            12 => Packet::PingReq,
+            13 => Packet::PingResp,
+            14 => Packet::Disconnect,
+            kind => return Err(Error::MqttError(format!("unsupported packet type: {}", kind)))
+        };
+        Ok(packet)
+    }
+
+    pub fn read<R: Read>(reader: &mut R) -> Result<Packet, Error> {
+        let header = try!(read_byte(reader));
+        Packet::read_rest(header, reader)
+    }
+}
+
+
+fn read_byte<R: Read>(reader: &mut R) -> Result<u8, Error> {
+    let mut byte = [0; 1];
+    try!(reader.read_exact(&mut byte));
+    Ok(byte[0])
+}
+
+fn put_u16(buf: &mut Vec<u8>, n: u16) {
+    buf.push((n >> 8) as u8);
+    buf.push(n as u8);
+}
+
+fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    put_u16(buf, bytes.len() as u16);
+    buf.extend_from_slice(bytes);
+}
+
+fn put_str(buf: &mut Vec<u8>, s: &str) {
+    put_bytes(buf, s.as_bytes());
+}
+
+struct Body<'a> {
+    data: &'a [u8],
+    pos:  usize,
+}
+
+impl<'a> Body<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], Error> {
+        if self.pos + n > self.data.len() {
+            return Err(Error::MqttError("packet is too short".to_string()));
+        }
+        let slice = &self.data[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(slice)
+    }
+
+    fn byte(&mut self) -> Result<u8, Error> {
+        Ok(try!(self.take(1))[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, Error> {
+        let bytes = try!(self.take(2));
+        Ok(((bytes[0] as u16) << 8) | bytes[1] as u16)
+    }
+
+    fn bytes(&mut self) -> Result<Vec<u8>, Error> {
+        let len = try!(self.u16()) as usize;
+        Ok(try!(self.take(len)).to_vec())
+    }
+
+    fn string(&mut self) -> Result<String, Error> {
+        Ok(try!(String::from_utf8(try!(self.bytes()))))
+    }
+
+    fn rest(&mut self) -> Vec<u8> {
+        let rest = self.data[self.pos..].to_vec();
+        self.pos = self.data.len();
+        rest
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    fn round_trip(packet: Packet) {
+        let bytes = packet.encode();
+        assert_eq!(Packet::read(&mut &bytes[..]).unwrap(), packet);
+    }
+
+    #[test]
+    fn packets_round_trip() {
+        round_trip(Packet::Connect(Connect {
+            client_id:     "device".to_string(),
+            keep_alive:    60,
+            clean_session: false,
+            will:          Some(Publish::new("devices/1/status", b"offline".to_vec(), 1, true)),
+            username:      Some("user".to_string()),
+            password:      None,
+        }));
+        round_trip(Packet::ConnAck { session_present: true, code: 0 });
+        let mut publish = Publish::new("devices/1/reports", vec![7; 300], 1, false);
+        publish.id = Some(9);
+        round_trip(Packet::Publish(publish));
+        round_trip(Packet::Subscribe { id: 2, topics: vec![("devices/1/updates".to_string(), 1)] });
+        round_trip(Packet::SubAck { id: 2, codes: vec![1] });
+        round_trip(Packet::PingReq);
+
+        assert_eq!(Packet::PubAck(258).encode(), vec![0x40, 2, 1, 2]);
+        assert!(Packet::read(&mut &[0x40, 2, 1][..]).is_err());
+    }
+}
@@ -0,0 +1,423 @@
+//! The class of network the vehicle is connected through, and the limits it
+//! puts on downloads.
+
+use chan::Sender;
+use dbus::{BusType, Connection, Message, MessageItem};
+use rustc_serialize::{Decoder, Decodable};
+use std::{cmp, fmt};
+use std::fs::File;
+use std::io::prelude::*;
+use std::process::Command;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use datatype::{Error, Method, NetworkConfig};
+use http_client::{HttpClient, HttpRequest, HttpResponse};
+
+
+/// How the vehicle is connected, from the cost of the data.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum NetworkClass {
+    /// E.g. Wi-Fi or ethernet in a garage.
+    Unmetered,
+    /// E.g. a cellular connection.
+    Metered,
+    /// A cellular connection abroad.
+    Roaming,
+}
+
+impl FromStr for NetworkClass {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<NetworkClass, Error> {
+        match s.trim().to_lowercase().as_str() {
+            "unmetered" | "wifi" | "ethernet" => Ok(NetworkClass::Unmetered),
+            "metered" | "cellular"            => Ok(NetworkClass::Metered),
+            "roaming"                         => Ok(NetworkClass::Roaming),
+            _ => Err(Error::ParseError(format!("unknown network class: {}", s.trim())))
+        }
+    }
+}
+
+
+/// Where to read the current network class from.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum NetworkState {
+    /// A file holding the class, e.g. written by a connection manager hook.
+    File { path: String },
+    /// A command that prints the class.
+    Command { command: String },
+    /// A string property of a D-Bus object on the system bus.
+    DBus { dest: String, path: String, interface: String, property: String },
+}
+
+impl NetworkState {
+    pub fn network_class(&self) -> Result<NetworkClass, Error> {
+        match *self {
+            NetworkState::File { ref path } => {
+                let mut text = String::new();
+                try!(try!(File::open(path)).read_to_string(&mut text));
+                text.parse()
+            }
+
+            NetworkState::Command { ref command } => {
+                let output = try!(Command::new("sh").arg("-c").arg(command).output());
+                if !output.status.success() {
+                    return Err(Error::ParseError(format!("network state command failed: {}", command)));
+                }
+                try!(String::from_utf8(output.stdout)).parse()
+            }
+
+            NetworkState::DBus { ref dest, ref path, ref interface, ref property } => {
+                let mut message = try!(Message::new_method_call(dest, path, "org.freedesktop.DBus.Properties", "Get")
+                                       .map_err(Error::DBusError));
+                message.append_items(&[MessageItem::Str(interface.clone()), MessageItem::Str(property.clone())]);
+                let conn  = try!(Connection::get_private(BusType::System));
+                let reply = try!(conn.send_with_reply_and_block(message, 5000));
+                match reply.get_items().into_iter().next() {
+                    Some(MessageItem::Variant(item)) => match *item {
+                        MessageItem::Str(ref class) => class.parse(),
+                        ref item => Err(Error::DBusError(format!("{} is not a string: {:?}", property, item)))
+                    },
+                    item => Err(Error::DBusError(format!("unexpected reply for {}: {:?}", property, item)))
+                }
+            }
+        }
+    }
+}
+
+impl FromStr for NetworkState {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<NetworkState, Error> {
+        match s.splitn(2, ':').collect::<Vec<_>>() {
+            ref parts if parts.len() == 2 && !parts[1].is_empty() => {
+                let value = parts[1].to_string();
+                match parts[0] {
+                    "file" => Ok(NetworkState::File { path: value }),
+                    "cmd"  => Ok(NetworkState::Command { command: value }),
+                    "dbus" => match value.split(':').collect::<Vec<_>>() {
+                        ref dbus if dbus.len() == 4 && dbus.iter().all(|part| !part.is_empty()) => Ok(NetworkState::DBus {
+                            dest:      dbus[0].to_string(),
+                            path:      dbus[1].to_string(),
+                            interface: dbus[2].to_string(),
+                            property:  dbus[3].to_string(),
+                        }),
+                        _ => Err(Error::ParseError(format!("expected dbus:<dest>:<path>:<interface>:<property>, got: {}", s)))
+                    },
+                    _ => Err(Error::ParseError(format!("unknown network state: {}", s)))
+                }
+            }
+            _ => Err(Error::ParseError(format!("unknown network state: {}", s)))
+        }
+    }
+}
+
+impl Decodable for NetworkState {
+    fn decode<D: Decoder>(d: &mut D) -> Result<NetworkState, D::Error> {
+        d.read_str().and_then(|s| s.parse::<NetworkState>().map_err(|err| d.error(&format!("{}", err))))
+    }
+}
+
+
+/// The current network class, shared between the watcher and the downloads.
+/// `None` until the class is known.
+#[derive(Clone, Default)]
+pub struct Network {
+    class: Arc<Mutex<Option<NetworkClass>>>,
+}
+
+impl Network {
+    pub fn new() -> Network {
+        Network::default()
+    }
+
+    pub fn class(&self) -> Option<NetworkClass> {
+        *self.class.lock().unwrap()
+    }
+
+    pub fn set_class(&self, class: Option<NetworkClass>) {
+        *self.class.lock().unwrap() = class;
+    }
+}
+
+/// Read the network class every `check_interval` seconds. A class that can't
+/// be read is treated as unknown.
+pub fn watch(config: &NetworkConfig, network: Network) {
+    loop {
+        let class = config.state.network_class()
+            .map_err(|err| error!("Couldn't read the network class: {}", err))
+            .ok();
+        if class != network.class() {
+            info!("Network class changed to {:?}", class);
+            network.set_class(class);
+        }
+        thread::sleep(Duration::from_secs(config.check_interval));
+    }
+}
+
+
+/// The size of each part of a download without a rate limit.
+pub const PART_SIZE: u64 = 4 * 1024 * 1024;
+
+/// Decides before each part of a download is requested whether it may start
+/// and how large it may be, waiting while the network's policy doesn't allow
+/// the download and keeping it under the network's rate limit.
+pub struct Throttle {
+    network: Network,
+    config:  NetworkConfig,
+    started: Instant,
+    base:    u64,
+}
+
+impl Throttle {
+    pub fn new(network: Network, config: NetworkConfig) -> Throttle {
+        Throttle { network: network, config: config, started: Instant::now(), base: 0 }
+    }
+
+    /// Wait until the part after the first `bytes` of the download may be
+    /// requested, returning its size: a second's worth at the rate limit.
+    pub fn next_part(&mut self, bytes: u64) -> u64 {
+        if !self.config.allows(self.network.class(), bytes) {
+            info!("Pausing download on a {:?} network", self.network.class());
+            while !self.config.allows(self.network.class(), bytes) {
+                thread::sleep(Duration::from_secs(self.config.check_interval));
+            }
+            info!("Resuming download on a {:?} network", self.network.class());
+            self.started = Instant::now();
+            self.base    = bytes;
+        }
+
+        match self.config.rate_limit(self.network.class()) {
+            Some(rate) => {
+                let elapsed = self.started.elapsed();
+                let elapsed = elapsed.as_secs() * 1000 + elapsed.subsec_nanos() as u64 / 1_000_000;
+                let due     = (bytes - self.base) * 1000 / rate;
+                if due > elapsed {
+                    thread::sleep(Duration::from_millis(due - elapsed));
+                }
+                cmp::max(rate, 1)
+            }
+
+            None => PART_SIZE
+        }
+    }
+}
+
+impl fmt::Debug for Throttle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Throttle({:?})", self.network.class())
+    }
+}
+
+
+/// Throttles the downloads sent through it, which are fetched as a series of
+/// `Range` requests in the caller's thread. Between parts a download pauses
+/// without holding a connection, and later resumes where it stopped. A server
+/// that ignores `Range` sends the whole body at once. Other requests are
+/// passed on as they are.
+pub struct ThrottledClient<'c> {
+    pub client:  &'c HttpClient,
+    pub network: &'c Network,
+    pub config:  &'c NetworkConfig,
+}
+
+impl<'c> ThrottledClient<'c> {
+    fn download(&self, mut req: HttpRequest) -> HttpResponse {
+        let mut throttle = Throttle::new(self.network.clone(), self.config.clone());
+        let mut progress = req.progress.take();
+        let mut body     = Vec::new();
+        loop {
+            if let Some(ref progress) = progress {
+                try!(progress.check_aborted());
+            }
+            let offset = body.len() as u64;
+            let size   = throttle.next_part(offset);
+            let resp   = self.client.send_request(HttpRequest {
+                method:          req.method.clone(),
+                url:             req.url.clone(),
+                body:            None,
+                idempotency_key: req.idempotency_key.clone(),
+                progress:        None,
+                range:           Some((offset, offset + size - 1)),
+            }).recv().expect("no throttled download response received");
+
+            let (part, whole) = match resp {
+                Ok(part) => (part, false),
+                Err(Error::RangeIgnored(part)) => (part, true),
+                // the last part ended exactly where the previous one did
+                Err(Error::RequestRejected(ref code)) if code == "416" && offset > 0 => (Vec::new(), false),
+                Err(err) => return Err(err)
+            };
+            let read = part.len() as u64;
+            if whole {
+                body = part;
+            } else if read > size {
+                return Err(Error::ClientError(format!("got {} bytes for a range of {}", read, size)));
+            } else {
+                body.extend_from_slice(&part);
+            }
+            let done = whole || read < size;
+
+            let total = if done { Some(body.len() as u64) } else { None };
+            if let Some(ref mut progress) = progress {
+                progress.update(body.len() as u64, total, done);
+            }
+            if done {
+                return Ok(body);
+            }
+        }
+    }
+}
+
+impl<'c> HttpClient for ThrottledClient<'c> {
+    fn chan_request(&self, req: HttpRequest, resp_tx: Sender<HttpResponse>) {
+        match req.method {
+            Method::Get if req.range.is_none() => resp_tx.send(self.download(req)),
+            _ => self.client.chan_request(req, resp_tx)
+        }
+    }
+
+    fn is_testing(&self) -> bool {
+        self.client.is_testing()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use chan;
+    use chan::Sender;
+    use std::cell::RefCell;
+    use std::cmp;
+    use std::fs::File;
+    use std::io::prelude::*;
+    use std::time::{Duration, Instant};
+    use tempfile::NamedTempFile;
+
+    use super::*;
+    use aborts::{AbortableClient, Aborts};
+    use datatype::{Error, Event, Method, Url};
+    use datatype::config::parse_config;
+    use http_client::{HttpClient, HttpRequest, HttpResponse, Progress};
+
+
+    #[test]
+    fn reads_the_network_class() {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(b"wifi\n").unwrap();
+        let path  = format!("{}", file.path().display());
+        let state = format!("file:{}", path).parse::<NetworkState>().unwrap();
+        assert_eq!(state.network_class().unwrap(), NetworkClass::Unmetered);
+        File::create(&path).unwrap().write_all(b"cellular").unwrap();
+        assert_eq!(state.network_class().unwrap(), NetworkClass::Metered);
+
+        let cmd = "cmd:echo roaming".parse::<NetworkState>().unwrap();
+        assert_eq!(cmd.network_class().unwrap(), NetworkClass::Roaming);
+        assert!("dbus:org.example:/network".parse::<NetworkState>().is_err());
+        assert!("file:".parse::<NetworkState>().is_err());
+    }
+
+    #[test]
+    fn throttles_to_the_rate_limit() {
+        let config  = parse_config("[network]\nstate = \"file:/dev/null\"\nmax_rate = 10000\n").unwrap();
+        let network = Network::new();
+        network.set_class(Some(NetworkClass::Unmetered));
+        let mut throttle = Throttle::new(network, config.network.unwrap());
+        let started = Instant::now();
+        assert_eq!(throttle.next_part(0), 10000);
+        assert_eq!(throttle.next_part(2000), 10000);
+        assert!(started.elapsed() >= Duration::from_millis(150));
+    }
+
+    // Serves `body`, honouring the requested ranges unless `whole`.
+    struct RangeClient {
+        body:   Vec<u8>,
+        whole:  bool,
+        ranges: RefCell<Vec<(u64, u64)>>,
+    }
+
+    impl HttpClient for RangeClient {
+        fn chan_request(&self, req: HttpRequest, resp_tx: Sender<HttpResponse>) {
+            let (first, last) = req.range.expect("a range");
+            self.ranges.borrow_mut().push((first, last));
+            if self.whole {
+                return resp_tx.send(Err(Error::RangeIgnored(self.body.clone())));
+            }
+            let len   = self.body.len() as u64;
+            let start = cmp::min(first, len) as usize;
+            let end   = cmp::min(last + 1, len) as usize;
+            resp_tx.send(Ok(self.body[start..end].to_vec()));
+        }
+    }
+
+    #[test]
+    fn downloads_in_ranges() {
+        let config  = parse_config("[network]\nstate = \"file:/dev/null\"\nmax_rate = 10\n").unwrap();
+        let network = Network::new();
+        network.set_class(Some(NetworkClass::Unmetered));
+        let client = RangeClient { body: b"0123456789abcde".to_vec(), whole: false, ranges: RefCell::new(Vec::new()) };
+        let body   = {
+            let throttled = ThrottledClient { client: &client, network: &network, config: config.network.as_ref().unwrap() };
+            throttled.send_request(HttpRequest {
+                method:          Method::Get,
+                url:             Url::parse("http://127.0.0.1:8080/download").unwrap(),
+                body:            None,
+                idempotency_key: None,
+                progress:        None,
+                range:           None,
+            }).recv().unwrap().unwrap()
+        };
+        assert_eq!(body, b"0123456789abcde".to_vec());
+        assert_eq!(*client.ranges.borrow(), vec![(0, 9), (10, 19)]);
+    }
+
+    #[test]
+    fn takes_the_whole_body_when_ranges_are_ignored() {
+        let config  = parse_config("[network]\nstate = \"file:/dev/null\"\nmax_rate = 10\n").unwrap();
+        let network = Network::new();
+        network.set_class(Some(NetworkClass::Unmetered));
+        // exactly the size of the first part
+        let client = RangeClient { body: b"0123456789".to_vec(), whole: true, ranges: RefCell::new(Vec::new()) };
+        let body   = {
+            let throttled = ThrottledClient { client: &client, network: &network, config: config.network.as_ref().unwrap() };
+            throttled.send_request(HttpRequest {
+                method:          Method::Get,
+                url:             Url::parse("http://127.0.0.1:8080/download").unwrap(),
+                body:            None,
+                idempotency_key: None,
+                progress:        None,
+                range:           None,
+            }).recv().unwrap().unwrap()
+        };
+        assert_eq!(body, b"0123456789".to_vec());
+        assert_eq!(*client.ranges.borrow(), vec![(0, 9)]);
+    }
+
+    #[test]
+    fn stops_aborted_downloads() {
+        let config  = parse_config("[network]\nstate = \"file:/dev/null\"\nmax_rate = 10\n").unwrap();
+        let network = Network::new();
+        network.set_class(Some(NetworkClass::Unmetered));
+        let aborts  = Aborts::new();
+        aborts.request(&"1".to_string());
+        let (etx, _) = chan::async::<Event>();
+        let client  = RangeClient { body: b"0123456789abcde".to_vec(), whole: false, ranges: RefCell::new(Vec::new()) };
+        let resp    = {
+            let throttled = ThrottledClient { client: &client, network: &network, config: config.network.as_ref().unwrap() };
+            let abortable = AbortableClient { client: &throttled, aborts: &aborts };
+            abortable.send_request(HttpRequest {
+                method:          Method::Get,
+                url:             Url::parse("http://127.0.0.1:8080/download").unwrap(),
+                body:            None,
+                idempotency_key: None,
+                progress:        Some(Progress::new("1".to_string(), etx)),
+                range:           None,
+            }).recv().unwrap()
+        };
+        assert_eq!(format!("{}", resp.unwrap_err()), "Download of 1 aborted".to_string());
+        assert!(client.ranges.borrow().is_empty());
+    }
+}
@@ -1,12 +1,23 @@
 use rustc_serialize::json;
+use url::form_urlencoded;
 
 use datatype::{AccessToken, Error, Method, Url};
 use http_client::{HttpClient, HttpRequest};
 
 
+/// Seconds before expiry at which an access token is proactively renewed.
+pub const REFRESH_MARGIN: i64 = 60;
+
 pub fn authenticate(server: Url, client: &HttpClient) -> Result<AccessToken, Error> {
     debug!("authenticate()");
-    let resp_rx = client.send_request(HttpRequest{ method: Method::Post, url: server, body: None });
+    let resp_rx = client.send_request(HttpRequest {
+        method:          Method::Post,
+        url:             server,
+        body:            None,
+        idempotency_key: None,
+        progress:        None,
+        range:           None,
+    });
     let resp    = resp_rx.recv().expect("no authenticate response received");
     let data    = try!(resp);
     let body    = try!(String::from_utf8(data));
@@ -14,6 +25,36 @@ pub fn authenticate(server: Url, client: &HttpClient) -> Result<AccessToken, Err
     Ok(try!(json::decode(&body)))
 }
 
+pub fn refresh(server: Url, refresh_token: &str, client: &HttpClient) -> Result<AccessToken, Error> {
+    debug!("refresh()");
+    let form = form_urlencoded::Serializer::new(String::new())
+        .append_pair("grant_type", "refresh_token")
+        .append_pair("refresh_token", refresh_token)
+        .finish();
+    let resp_rx = client.send_request(HttpRequest {
+        method:          Method::Post,
+        url:             server,
+        body:            Some(form.into_bytes()),
+        idempotency_key: None,
+        progress:        None,
+        range:           None,
+    });
+    let resp    = resp_rx.recv().expect("no refresh response received");
+    let data    = try!(resp);
+    let body    = try!(String::from_utf8(data));
+    debug!("refresh, body: `{}`", body);
+    Ok(try!(json::decode(&body)))
+}
+
+pub fn check_scope(token: &AccessToken, required: &[String]) -> Result<(), Error> {
+    let missing = token.missing_scopes(required);
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::AuthorizationError(format!("access token missing scopes: {:?}", missing)))
+    }
+}
+
 
 #[cfg(test)]
 mod tests {
@@ -26,17 +67,21 @@ mod tests {
         Url::parse("http://localhost:8000").unwrap()
     }
 
+    fn test_token() -> AccessToken {
+        AccessToken {
+            access_token:  "token".to_string(),
+            token_type:    "type".to_string(),
+            expires_in:    10,
+            scope:         vec!["scope".to_string()],
+            refresh_token: None,
+        }
+    }
+
     #[test]
     fn test_authenticate() {
         let token  = r#"{"access_token": "token", "token_type": "type", "expires_in": 10, "scope": ["scope"]}"#;
         let client = TestHttpClient::from(vec![token.to_string()]);
-        let expect = AccessToken {
-            access_token: "token".to_string(),
-            token_type:   "type".to_string(),
-            expires_in:   10,
-            scope:        vec!["scope".to_string()]
-        };
-        assert_eq!(expect, authenticate(test_server(), &client).unwrap());
+        assert_eq!(test_token(), authenticate(test_server(), &client).unwrap());
     }
 
     #[test]
@@ -54,4 +99,22 @@ mod tests {
         let expect = r#"Failed to decode JSON: MissingFieldError("access_token")"#;
         assert_eq!(expect, format!("{}", authenticate(test_server(), &client).unwrap_err()));
     }
+
+    #[test]
+    fn test_refresh() {
+        let token  = r#"{"access_token": "token", "token_type": "type", "expires_in": 10, "scope": ["scope"], "refresh_token": "again"}"#;
+        let client = TestHttpClient::from(vec![token.to_string()]);
+        let mut expect = test_token();
+        expect.refresh_token = Some("again".to_string());
+        assert_eq!(expect, refresh(test_server(), "old", &client).unwrap());
+    }
+
+    #[test]
+    fn test_check_scope() {
+        let token = test_token();
+        assert!(check_scope(&token, &[]).is_ok());
+        assert!(check_scope(&token, &["scope".to_string()]).is_ok());
+        assert_eq!(r#"Http client authorization error: access token missing scopes: ["other"]"#,
+                   format!("{}", check_scope(&token, &["scope".to_string(), "other".to_string()]).unwrap_err()));
+    }
 }
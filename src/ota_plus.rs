@@ -4,10 +4,10 @@ use std::fs::File;
 use std::io;
 use std::path::PathBuf;
 
-use datatype::{Config, Error, Event, Method, PendingUpdateRequest,
-               UpdateRequestId, UpdateReport, UpdateReportWithDevice,
-               UpdateResultCode, UpdateState, Url};
-use http_client::{HttpClient, HttpRequest};
+use datatype::{Config, Error, Event, Method, Package, PendingUpdateRequest, PendingUpdates,
+               UpdateRequestId, UpdateReport, UpdateReportWithDevice, Url};
+use datatype::report::{InstalledSoftware, UpdateEvent};
+use http_client::{HttpClient, HttpRequest, Progress};
 
 
 pub struct OTA<'c, 'h> {
@@ -29,90 +29,85 @@ impl<'c, 'h> OTA<'c, 'h> {
         self.config.ota.server.join(&endpoint).unwrap()
     }
 
-    pub fn get_package_updates(&mut self) -> Result<Vec<PendingUpdateRequest>, Error> {
+    /// Fetch the pending updates, served either as a list or as an object with
+    /// the `updates` and a `poll_interval` hint.
+    pub fn get_package_updates(&mut self) -> Result<PendingUpdates, Error> {
         debug!("getting package updates");
         let resp_rx = self.client.send_request(HttpRequest {
-            method: Method::Get,
-            url:    self.update_endpoint(""),
-            body:   None,
+            method:          Method::Get,
+            url:             self.update_endpoint(""),
+            body:            None,
+            idempotency_key: None,
+            progress:        None,
+            range:           None,
         });
         let resp = resp_rx.recv().expect("no get_package_updates response received");
         let data = try!(resp);
         let text = try!(String::from_utf8(data));
-        Ok(try!(json::decode::<Vec<PendingUpdateRequest>>(&text)))
+        if text.trim_left().starts_with('[') {
+            let updates = try!(json::decode::<Vec<PendingUpdateRequest>>(&text));
+            Ok(PendingUpdates { updates: updates, poll_interval: None })
+        } else {
+            Ok(try!(json::decode::<PendingUpdates>(&text)))
+        }
     }
 
-    pub fn download_package_update(&mut self, id: &UpdateRequestId) -> Result<PathBuf, Error> {
+    /// Download the package of an update, sending `Event::DownloadProgress`
+    /// events to `etx` along the way.
+    pub fn download_package_update(&mut self, id: &UpdateRequestId, etx: &Sender<Event>) -> Result<PathBuf, Error> {
         debug!("downloading package update");
         let resp_rx = self.client.send_request(HttpRequest {
-            method: Method::Get,
-            url:    self.update_endpoint(&format!("{}/download", id)),
-            body:   None,
+            method:          Method::Get,
+            url:             self.update_endpoint(&format!("{}/download", id)),
+            body:            None,
+            idempotency_key: None,
+            progress:        Some(Progress::new(id.clone(), etx.clone())),
+            range:           None,
         });
 
+        let path     = self.package_path(id);
+        let resp     = resp_rx.recv().expect("no download_package_update response received");
+        let data     = try!(resp);
+        let mut file = try!(File::create(path.as_path()));
+        let _        = io::copy(&mut &*data, &mut file);
+        Ok(path)
+    }
+
+    /// The path that the package of an update is downloaded to.
+    pub fn package_path(&self, id: &UpdateRequestId) -> PathBuf {
         let mut path = PathBuf::new();
         path.push(&self.config.ota.packages_dir);
         path.push(id);
         // TODO: Use Content-Disposition filename from request?
         // TODO: Do not invoke package_manager
         path.set_extension(self.config.ota.package_manager.extension());
-
-        let resp     = resp_rx.recv().expect("no download_package_update response received");
-        let data     = try!(resp);
-        let mut file = try!(File::create(path.as_path()));
-        let _        = io::copy(&mut &*data, &mut file);
-        Ok(path)
+        path
     }
 
-    pub fn install_package_update(&mut self, id: &UpdateRequestId, etx: &Sender<Event>)
-                                  -> Result<UpdateReport, Error> {
-        debug!("installing package update");
-        match self.download_package_update(id) {
-            Ok(path) => {
-                let err_str  = format!("Path is not valid UTF-8: {:?}", path);
-                let pkg_path = try!(path.to_str().ok_or(Error::ParseError(err_str)));
-                info!("Downloaded to {:?}. Installing...", pkg_path);
-
-                // TODO: Fire DownloadComplete event, handle async UpdateReport command
-                // TODO: Do not invoke package_manager
-                let _ = etx.send(Event::UpdateStateChanged(id.clone(), UpdateState::Installing));
-                match self.config.ota.package_manager.install_package(pkg_path) {
-                    Ok((code, output)) => {
-                        let _ = etx.send(Event::UpdateStateChanged(id.clone(), UpdateState::Installed));
-                        Ok(UpdateReport::single(id.clone(), code, output))
-                    }
-
-                    Err((code, output)) => {
-                        let err_str = format!("{:?}: {:?}", code, output);
-                        let _ = etx.send(Event::UpdateErrored(id.clone(), err_str));
-                        Ok(UpdateReport::single(id.clone(), code, output))
-                    }
-                }
-            }
-
-            Err(err) => {
-                etx.send(Event::UpdateErrored(id.clone(), format!("{:?}", err)));
-                let failed = format!("Download failed: {:?}", err);
-                Ok(UpdateReport::single(id.clone(), UpdateResultCode::GENERAL_ERROR, failed))
-            }
+    /// Send the installed packages to `installed` as a list of packages, and
+    /// any firmware to `installed_firmware`.
+    pub fn send_installed_software(&mut self, sw: &InstalledSoftware) -> Result<(), Error> {
+        debug!("sending installed software");
+        let pkgs = sw.packages.iter().map(Package::from).collect::<Vec<_>>();
+        try!(self.put_installed("installed", try!(json::encode(&pkgs))));
+        if !sw.firmware.is_empty() {
+            try!(self.put_installed("installed_firmware", try!(json::encode(&sw.firmware))));
         }
+        Ok(())
     }
 
-    pub fn update_installed_packages(&mut self) -> Result<(), Error> {
-        debug!("updating installed packages");
-        // TODO: Fire GetInstalledSoftware event, handle async InstalledSoftware command
-        // TODO: Do not invoke package_manager
-        let pkgs = try!(self.config.ota.package_manager.installed_packages());
-        let body = try!(json::encode(&pkgs));
-        debug!("installed packages: {}", body);
-
+    fn put_installed(&mut self, path: &str, body: String) -> Result<(), Error> {
+        debug!("{}: {}", path, body);
         let resp_rx = self.client.send_request(HttpRequest {
-            method: Method::Put,
-            url:    self.update_endpoint("installed"),
-            body:   Some(body.into_bytes()),
+            method:          Method::Put,
+            url:             self.update_endpoint(path),
+            body:            Some(body.into_bytes()),
+            idempotency_key: None,
+            progress:        None,
+            range:           None,
         });
-        let _ = resp_rx.recv().expect("no update_installed_packages response received")
-                       .map_err(|err| error!("update_installed_packages failed: {}", err));
+        let resp = resp_rx.recv().expect("no send_installed_software response received");
+        let _    = try!(resp);
         Ok(())
     }
 
@@ -121,14 +116,32 @@ impl<'c, 'h> OTA<'c, 'h> {
         let vin_report = UpdateReportWithDevice::new(&self.config.device.uuid, &report);
         let body       = try!(json::encode(&vin_report));
         let resp_rx    = self.client.send_request(HttpRequest {
-            method: Method::Post,
-            url:    self.update_endpoint(&format!("{}", report.update_id)),
-            body:   Some(body.into_bytes()),
+            method:          Method::Post,
+            url:             self.update_endpoint(&format!("{}", report.update_id)),
+            body:            Some(body.into_bytes()),
+            idempotency_key: None,
+            progress:        None,
+            range:           None,
         });
         let resp = resp_rx.recv().expect("no send_install_report response received");
         let _    = try!(resp);
         Ok(())
     }
+
+    pub fn send_update_event(&mut self, event: &UpdateEvent) -> Result<(), Error> {
+        debug!("sending update event: {:?}", event);
+        let resp_rx = self.client.send_request(HttpRequest {
+            method:          Method::Post,
+            url:             self.update_endpoint("events"),
+            body:            Some(try!(json::encode(event)).into_bytes()),
+            idempotency_key: None,
+            progress:        None,
+            range:           None,
+        });
+        let resp = resp_rx.recv().expect("no send_update_event response received");
+        let _    = try!(resp);
+        Ok(())
+    }
 }
 
 
@@ -138,10 +151,9 @@ mod tests {
     use rustc_serialize::json;
 
     use super::*;
-    use datatype::{Config, Event, Package, PendingUpdateRequest, UpdateResultCode, UpdateState};
+    use datatype::{Config, Package, PendingUpdateRequest, PendingUpdates};
+    use datatype::report::{InstalledFirmware, InstalledPackage, InstalledSoftware};
     use http_client::TestHttpClient;
-    use package_manager::PackageManager;
-    use package_manager::tpm::assert_rx;
 
 
     #[test]
@@ -153,7 +165,8 @@ mod tests {
                 name: "fake-pkg".to_string(),
                 version: "0.1.1".to_string()
             },
-            createdAt: "2010-01-01".to_string()
+            createdAt: "2010-01-01".to_string(),
+            targets: None
         };
 
         let json    = format!("[{}]", json::encode(&pending_update).unwrap());
@@ -162,82 +175,42 @@ mod tests {
             client: &mut TestHttpClient::from(vec![json.to_string()]),
         };
 
-        let updates: Vec<PendingUpdateRequest> = ota.get_package_updates().unwrap();
+        let updates: Vec<PendingUpdateRequest> = ota.get_package_updates().unwrap().updates;
         let ids: Vec<String> = updates.iter().map(|p| p.requestId.clone()).collect();
         assert_eq!(ids, vec!["someid".to_string()])
     }
 
     #[test]
-    fn bad_client_download_package_update() {
+    fn test_get_package_updates_with_hint() {
         let mut ota = OTA {
             config: &Config::default(),
-            client: &mut TestHttpClient::new(),
+            client: &mut TestHttpClient::from(vec![r#"{"updates":[],"poll_interval":300}"#.to_string()]),
         };
-        let expect  = "Http client error: http://127.0.0.1:8080/api/v1/vehicle_updates/123e4567-e89b-12d3-a456-426655440000/0/download";
-        assert_eq!(expect, format!("{}", ota.download_package_update(&"0".to_string()).unwrap_err()));
+        assert_eq!(ota.get_package_updates().unwrap(), PendingUpdates { updates: Vec::new(), poll_interval: Some(300) });
     }
 
     #[test]
-    fn test_install_package_update_0() {
-        let mut ota = OTA {
-            config: &Config::default(),
-            client: &mut TestHttpClient::new(),
-        };
-        let (tx, rx) = chan::async();
-        let report   = ota.install_package_update(&"0".to_string(), &tx);
-        assert_eq!(report.unwrap().operation_results.pop().unwrap().result_code,
-                   UpdateResultCode::GENERAL_ERROR);
-
-        let expect = r#"ClientError("http://127.0.0.1:8080/api/v1/vehicle_updates/123e4567-e89b-12d3-a456-426655440000/0/download")"#;
-        assert_rx(rx, &[
-            Event::UpdateErrored("0".to_string(), String::from(expect))
-        ]);
+    fn sends_firmware_separately() {
+        let pkg    = Package { name: "fake-pkg".to_string(), version: "0.1.1".to_string() };
+        let mut sw = InstalledSoftware { packages: vec![InstalledPackage::from(pkg.clone())], firmware: Vec::new() };
+        assert_eq!(Package::from(&sw.packages[0]), pkg);
+        let mut ota = OTA { config: &Config::default(), client: &mut TestHttpClient::from(vec!["".to_string()]) };
+        assert!(ota.send_installed_software(&sw).is_ok());
+
+        sw.firmware = vec![InstalledFirmware { module: "ecu1".to_string(), firmware_id: "1.0".to_string(), last_modified: 5 }];
+        let mut ota = OTA { config: &Config::default(), client: &mut TestHttpClient::from(vec!["".to_string()]) };
+        let err = format!("{}", ota.send_installed_software(&sw).unwrap_err());
+        assert!(err.ends_with("/installed_firmware"));
     }
 
     #[test]
-    fn test_install_package_update_1() {
-        let mut config             = Config::default();
-        config.ota.packages_dir    = "/tmp/".to_string();
-        config.ota.package_manager = PackageManager::new_file(false);
-
-        let mut ota = OTA {
-            config: &config,
-            client: &mut TestHttpClient::from(vec!["".to_string()]),
-        };
-        let (tx, rx) = chan::async();
-        let report   = ota.install_package_update(&"0".to_string(), &tx);
-        assert_eq!(report.unwrap().operation_results.pop().unwrap().result_code,
-                   UpdateResultCode::INSTALL_FAILED);
-
-        assert_rx(rx, &[
-            Event::UpdateStateChanged("0".to_string(), UpdateState::Installing),
-            // XXX: Not very helpful message?
-            Event::UpdateErrored("0".to_string(), r#"INSTALL_FAILED: "failed""#.to_string())
-        ]);
-    }
-
-    #[test]
-    fn test_install_package_update_2() {
-        let mut config = Config::default();
-        config.ota.packages_dir    = "/tmp/".to_string();
-        config.ota.package_manager = PackageManager::new_file(true);
-
-        let replies = vec![
-            "[]".to_string(),
-            "package data".to_string(),
-        ];
+    fn bad_client_download_package_update() {
         let mut ota = OTA {
-            config: &config,
-            client: &mut TestHttpClient::from(replies),
+            config: &Config::default(),
+            client: &mut TestHttpClient::new(),
         };
-        let (tx, rx) = chan::async();
-        let report   = ota.install_package_update(&"0".to_string(), &tx);
-        assert_eq!(report.unwrap().operation_results.pop().unwrap().result_code,
-                   UpdateResultCode::OK);
-
-        assert_rx(rx, &[
-            Event::UpdateStateChanged("0".to_string(), UpdateState::Installing),
-            Event::UpdateStateChanged("0".to_string(), UpdateState::Installed)
-        ]);
+        let (tx, _) = chan::async();
+        let expect  = "Http client error: http://127.0.0.1:8080/api/v1/vehicle_updates/123e4567-e89b-12d3-a456-426655440000/0/download";
+        assert_eq!(expect, format!("{}", ota.download_package_update(&"0".to_string(), &tx).unwrap_err()));
     }
 }
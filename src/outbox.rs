@@ -0,0 +1,207 @@
+//! A persistent queue for the messages sent upstream, so that reports made
+//! while the server is unreachable are delivered once it is back.
+
+use chan::Sender;
+use rustc_serialize::json;
+use std::fs;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::PathBuf;
+
+use datatype::{Error, UpdateReport};
+use datatype::report::{InstalledSoftware, UpdateEvent};
+use http_client::{HttpClient, HttpRequest, HttpResponse};
+use provision::generate_uuid;
+
+
+/// How often a message the server rejects is sent before it is set aside.
+pub const MAX_ATTEMPTS: u32 = 10;
+
+/// A message for the server.
+#[derive(RustcEncodable, RustcDecodable, Debug, Clone, PartialEq, Eq)]
+pub enum Upstream {
+    UpdateReport(UpdateReport),
+    InstalledSoftware(InstalledSoftware),
+    UpdateEvent(UpdateEvent),
+}
+
+impl Upstream {
+    // A newer message on the same topic replaces an undelivered older one.
+    fn topic(&self) -> String {
+        match *self {
+            Upstream::UpdateReport(ref report) => format!("report-{}", report.update_id),
+            Upstream::InstalledSoftware(_)     => "installed".to_string(),
+            Upstream::UpdateEvent(ref event)   => format!("event-{}-{:?}-{}", event.update_id, event.state, event.timestamp),
+        }
+    }
+}
+
+/// A queued message, with the key the server uses to recognize retries.
+#[derive(RustcEncodable, RustcDecodable, Debug, Clone, PartialEq, Eq)]
+pub struct OutboxEntry {
+    pub seq:        u64,
+    pub key:        String,
+    pub attempts:   u32,
+    pub last_error: Option<String>,
+    pub message:    Upstream,
+}
+
+
+/// The queue, with an entry per file in `dir`, delivered in order.
+pub struct Outbox {
+    dir: PathBuf,
+}
+
+impl Outbox {
+    pub fn new(dir: &str) -> Outbox {
+        Outbox { dir: PathBuf::from(dir) }
+    }
+
+    /// Queue a message, dropping any undelivered message it replaces. The
+    /// same message queued again keeps its place and key.
+    pub fn enqueue(&self, message: Upstream) -> Result<OutboxEntry, Error> {
+        let entries = try!(self.entries());
+        let topic   = message.topic();
+        for entry in &entries {
+            if entry.message == message {
+                return Ok(entry.clone());
+            } else if entry.message.topic() == topic {
+                try!(self.remove(entry));
+            }
+        }
+
+        let entry = OutboxEntry {
+            seq:        entries.last().map(|entry| entry.seq + 1).unwrap_or(1),
+            key:        try!(generate_uuid()),
+            attempts:   0,
+            last_error: None,
+            message:    message,
+        };
+        try!(self.save(&entry));
+        Ok(entry)
+    }
+
+    /// The undelivered messages, oldest first.
+    pub fn entries(&self) -> Result<Vec<OutboxEntry>, Error> {
+        if !self.dir.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut entries = Vec::new();
+        for file in try!(fs::read_dir(&self.dir)) {
+            let path = try!(file).path();
+            if path.extension().map(|ext| ext != "json").unwrap_or(true) {
+                continue;
+            }
+            let mut raw = String::new();
+            try!(try!(File::open(&path)).read_to_string(&mut raw));
+            match json::decode::<OutboxEntry>(&raw) {
+                Ok(entry) => entries.push(entry),
+                Err(err)  => error!("Skipping unreadable outbox entry {:?}: {}", path, err)
+            }
+        }
+        entries.sort_by_key(|entry| entry.seq);
+        Ok(entries)
+    }
+
+    pub fn delivered(&self, entry: &OutboxEntry) -> Result<(), Error> {
+        self.remove(entry)
+    }
+
+    /// Record a failed delivery, keeping the message for the next attempt.
+    pub fn failed(&self, entry: &OutboxEntry, err: &Error) -> Result<(), Error> {
+        let mut entry = entry.clone();
+        entry.attempts += 1;
+        entry.last_error = Some(format!("{}", err));
+        self.save(&entry)
+    }
+
+    /// Set aside a message the server keeps rejecting, so it no longer holds
+    /// up the queue. It is kept in the `failed` subdirectory for inspection.
+    pub fn dead_letter(&self, entry: &OutboxEntry, err: &Error) -> Result<(), Error> {
+        let mut entry = entry.clone();
+        entry.attempts += 1;
+        entry.last_error = Some(format!("{}", err));
+        try!(fs::create_dir_all(self.dir.join("failed")));
+        try!(self.save(&entry));
+        Ok(try!(fs::rename(self.path(&entry), self.dir.join("failed").join(format!("{:020}.json", entry.seq)))))
+    }
+
+    fn save(&self, entry: &OutboxEntry) -> Result<(), Error> {
+        try!(fs::create_dir_all(&self.dir));
+        let tmp = self.dir.join(format!("{:020}.tmp", entry.seq));
+        try!(try!(File::create(&tmp)).write_all(try!(json::encode(entry)).as_bytes()));
+        Ok(try!(fs::rename(tmp, self.path(entry))))
+    }
+
+    fn remove(&self, entry: &OutboxEntry) -> Result<(), Error> {
+        Ok(try!(fs::remove_file(self.path(entry))))
+    }
+
+    fn path(&self, entry: &OutboxEntry) -> PathBuf {
+        self.dir.join(format!("{:020}.json", entry.seq))
+    }
+}
+
+
+/// Sends every request with the idempotency key of an outbox entry, if any.
+pub struct KeyedClient<'c> {
+    pub client: &'c HttpClient,
+    pub key:    Option<&'c str>,
+}
+
+impl<'c> HttpClient for KeyedClient<'c> {
+    fn chan_request(&self, mut req: HttpRequest, resp_tx: Sender<HttpResponse>) {
+        req.idempotency_key = self.key.map(|key| key.to_string());
+        self.client.chan_request(req, resp_tx)
+    }
+
+    fn is_testing(&self) -> bool {
+        self.client.is_testing()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use super::*;
+    use datatype::{Error, UpdateReport, UpdateResultCode};
+    use datatype::report::InstalledSoftware;
+    use remote::dw::random_string;
+
+
+    fn report(id: &str, code: UpdateResultCode) -> Upstream {
+        Upstream::UpdateReport(UpdateReport::single(id.to_string(), code, "".to_string()))
+    }
+
+    #[test]
+    fn queues_and_replaces_messages() {
+        let dir    = format!("/tmp/sota-outbox-{}", random_string(8));
+        let outbox = Outbox::new(&dir);
+
+        let first = outbox.enqueue(report("1", UpdateResultCode::GENERAL_ERROR)).unwrap();
+        let sw    = InstalledSoftware { packages: Vec::new(), firmware: Vec::new() };
+        outbox.enqueue(Upstream::InstalledSoftware(sw.clone())).unwrap();
+        assert_eq!(outbox.enqueue(report("1", UpdateResultCode::GENERAL_ERROR)).unwrap(), first);
+        assert_eq!(outbox.entries().unwrap().len(), 2);
+
+        outbox.failed(&first, &Error::ClientError("offline".to_string())).unwrap();
+        let retried = outbox.entries().unwrap().remove(0);
+        assert_eq!((retried.attempts, retried.key.clone()), (1, first.key.clone()));
+
+        let newer = outbox.enqueue(report("1", UpdateResultCode::OK)).unwrap();
+        let queued = outbox.entries().unwrap();
+        assert_eq!(queued.iter().map(|entry| entry.seq).collect::<Vec<_>>(), vec![2, 3]);
+        assert!(newer.key != first.key);
+        assert_eq!(queued[0].message, Upstream::InstalledSoftware(sw));
+
+        outbox.dead_letter(&queued[0], &Error::RequestRejected("400".to_string())).unwrap();
+        outbox.delivered(&queued[1]).unwrap();
+        assert!(outbox.entries().unwrap().is_empty());
+        assert!(Path::new(&format!("{}/failed/{:020}.json", dir, queued[0].seq)).exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
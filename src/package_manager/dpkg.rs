@@ -1,7 +1,7 @@
 use std::process::Command;
 
 use datatype::{Error, Package, UpdateResultCode};
-use package_manager::package_manager::{InstallOutcome, parse_package};
+use package_manager::package_manager::{InstallOutcome, InstallProgress, OutputLine, parse_package, run_with_progress};
 
 
 pub fn installed_packages() -> Result<Vec<Package>, Error> {
@@ -21,13 +21,12 @@ pub fn installed_packages() -> Result<Vec<Package>, Error> {
         })
 }
 
-pub fn install_package(path: &str) -> Result<InstallOutcome, InstallOutcome> {
-    let output = try!(Command::new("dpkg").arg("-E").arg("-i").arg(path)
-        .output()
-        .map_err(|e| (UpdateResultCode::GENERAL_ERROR, format!("{:?}", e))));
-
-    let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
-    let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+pub fn install_package(path: &str, progress: InstallProgress) -> Result<InstallOutcome, InstallOutcome> {
+    let mut cmd = Command::new("dpkg");
+    cmd.arg("--status-fd").arg("1").arg("-E").arg("-i").arg(path);
+    let output = try!(run_with_progress(&mut cmd, parse_status, progress));
+    let stdout = output.stdout;
+    let stderr = output.stderr;
 
     match output.status.code() {
         Some(0) => {
@@ -43,3 +42,38 @@ pub fn install_package(path: &str) -> Result<InstallOutcome, InstallOutcome> {
         }
     }
 }
+
+// The `--status-fd` lines are mixed into stdout, as `status: <pkg>: <state>`
+// for each change of the package state and `processing: <step>: <pkg>`.
+fn parse_status(line: &str) -> OutputLine {
+    if line.starts_with("processing: ") {
+        return OutputLine::Ignored;
+    } else if !line.starts_with("status: ") {
+        return OutputLine::Output;
+    }
+
+    match line.rsplit(": ").next().map(|state| state.trim()) {
+        Some("half-installed")  => OutputLine::Progress(25, "unpacking".to_string()),
+        Some("unpacked")        => OutputLine::Progress(50, "unpacked".to_string()),
+        Some("half-configured") => OutputLine::Progress(75, "configuring".to_string()),
+        Some("installed")       => OutputLine::Progress(100, "installed".to_string()),
+        _ => OutputLine::Ignored
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::parse_status;
+    use package_manager::package_manager::OutputLine;
+
+
+    #[test]
+    fn parses_status_lines() {
+        assert_eq!(parse_status("status: app: half-installed"), OutputLine::Progress(25, "unpacking".to_string()));
+        assert_eq!(parse_status("status: app: installed"), OutputLine::Progress(100, "installed".to_string()));
+        assert_eq!(parse_status("status: app: not-installed"), OutputLine::Ignored);
+        assert_eq!(parse_status("processing: configure: app"), OutputLine::Ignored);
+        assert_eq!(parse_status("Setting up app (1.0) ..."), OutputLine::Output);
+    }
+}
@@ -1,6 +1,9 @@
 use rustc_serialize::{Decoder, Decodable};
 use std::env::temp_dir;
+use std::io::{BufRead, BufReader, Read};
+use std::process::{Command, ExitStatus, Stdio};
 use std::str::FromStr;
+use std::thread;
 
 use datatype::{Error, Package, UpdateResultCode};
 use package_manager::{dpkg, rpm, tpm, otb};
@@ -9,6 +12,9 @@ use tempfile::NamedTempFile;
 
 pub type InstallOutcome = (UpdateResultCode, String);
 
+/// Called with the percentage done and the current step of an install.
+pub type InstallProgress<'p> = &'p Fn(u8, &str);
+
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub enum PackageManager {
     Dpkg,
@@ -36,10 +42,12 @@ impl PackageManager {
         }
     }
 
-    pub fn install_package(&self, path: &str) -> Result<InstallOutcome, InstallOutcome> {
+    /// Install a package, passing the progress to `progress` when the
+    /// package manager reports it.
+    pub fn install_package(&self, path: &str, progress: InstallProgress) -> Result<InstallOutcome, InstallOutcome> {
         match *self {
-            PackageManager::Dpkg => dpkg::install_package(path),
-            PackageManager::Rpm  => rpm::install_package(path),
+            PackageManager::Dpkg => dpkg::install_package(path, progress),
+            PackageManager::Rpm  => rpm::install_package(path, progress),
             PackageManager::File { ref filename, succeeds } => tpm::install_package(filename, path, succeeds),
             PackageManager::OstreeBasic { ref repodir } => otb::install_package(repodir, path),
         }
@@ -78,10 +86,60 @@ impl FromStr for PackageManager {
 
 impl Decodable for PackageManager {
     fn decode<D: Decoder>(d: &mut D) -> Result<PackageManager, D::Error> {
-        d.read_str().and_then(|s| Ok(s.parse::<PackageManager>().expect("couldn't parse PackageManager")))
+        d.read_str().and_then(|s| s.parse::<PackageManager>().map_err(|err| d.error(&format!("{}", err))))
     }
 }
 
+/// A line of package manager output.
+#[derive(Debug, PartialEq, Eq)]
+pub enum OutputLine {
+    Output,
+    Progress(u8, String),
+    Ignored,
+}
+
+/// The result of `run_with_progress`, with the progress lines left out of
+/// `stdout`.
+pub struct ProgressOutput {
+    pub status: ExitStatus,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+/// Run an install command, passing each line of its output to `parse` as it
+/// is written and reporting the progress lines to `progress`.
+pub fn run_with_progress(cmd: &mut Command, parse: fn(&str) -> OutputLine, progress: InstallProgress)
+                         -> Result<ProgressOutput, InstallOutcome> {
+    let mut child = try!(cmd.stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()
+        .map_err(|e| (UpdateResultCode::GENERAL_ERROR, format!("{:?}", e))));
+
+    // read stderr alongside so that neither pipe fills up
+    let mut stderr = child.stderr.take().expect("piped stderr");
+    let errors = thread::spawn(move || {
+        let mut buf = Vec::new();
+        let _ = stderr.read_to_end(&mut buf);
+        buf
+    });
+
+    let mut stdout = String::new();
+    for line in BufReader::new(child.stdout.take().expect("piped stdout")).split(b'\n') {
+        let line = String::from_utf8_lossy(&try!(line.map_err(|e| (UpdateResultCode::GENERAL_ERROR, format!("{:?}", e)))))
+            .into_owned();
+        match parse(&line) {
+            OutputLine::Output => {
+                stdout.push_str(&line);
+                stdout.push('\n');
+            }
+            OutputLine::Progress(percent, step) => progress(percent, &step),
+            OutputLine::Ignored => ()
+        }
+    }
+
+    let status = try!(child.wait().map_err(|e| (UpdateResultCode::GENERAL_ERROR, format!("{:?}", e))));
+    let stderr = errors.join().unwrap_or(Vec::new());
+    Ok(ProgressOutput { status: status, stdout: stdout, stderr: String::from_utf8_lossy(&stderr).into_owned() })
+}
+
 pub fn parse_package(line: &str) -> Result<Package, Error> {
     match line.splitn(2, ' ').collect::<Vec<_>>() {
         ref parts if parts.len() == 2 => {
@@ -1,7 +1,7 @@
 use std::process::Command;
 
 use datatype::{Error, Package, UpdateResultCode};
-use package_manager::package_manager::{InstallOutcome, parse_package};
+use package_manager::package_manager::{InstallOutcome, InstallProgress, OutputLine, parse_package, run_with_progress};
 
 
 pub fn installed_packages() -> Result<Vec<Package>, Error> {
@@ -21,13 +21,12 @@ pub fn installed_packages() -> Result<Vec<Package>, Error> {
         })
 }
 
-pub fn install_package(path: &str) -> Result<InstallOutcome, InstallOutcome> {
-    let output = try!(Command::new("rpm").arg("-Uvh").arg("--force").arg(path)
-        .output()
-        .map_err(|e| (UpdateResultCode::GENERAL_ERROR, format!("{:?}", e))));
-
-    let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
-    let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+pub fn install_package(path: &str, progress: InstallProgress) -> Result<InstallOutcome, InstallOutcome> {
+    let mut cmd = Command::new("rpm");
+    cmd.arg("-Uv").arg("--percent").arg("--force").arg(path);
+    let output = try!(run_with_progress(&mut cmd, parse_percent, progress));
+    let stdout = output.stdout;
+    let stderr = output.stderr;
 
     match output.status.code() {
         Some(0) => Ok((UpdateResultCode::OK, stdout)),
@@ -41,3 +40,30 @@ pub fn install_package(path: &str) -> Result<InstallOutcome, InstallOutcome> {
         }
     }
 }
+
+// With `--percent` rpm writes `%% <percent>` lines instead of hash marks.
+fn parse_percent(line: &str) -> OutputLine {
+    if !line.starts_with("%%") {
+        return OutputLine::Output;
+    }
+    match line[2..].trim().parse::<f64>() {
+        Ok(percent) => OutputLine::Progress(percent.max(0.0).min(100.0) as u8, "installing".to_string()),
+        Err(_)      => OutputLine::Ignored
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::parse_percent;
+    use package_manager::package_manager::OutputLine;
+
+
+    #[test]
+    fn parses_percent_lines() {
+        assert_eq!(parse_percent("%% 42.857143"), OutputLine::Progress(42, "installing".to_string()));
+        assert_eq!(parse_percent("%% 100.000000"), OutputLine::Progress(100, "installing".to_string()));
+        assert_eq!(parse_percent("%% app-1.0"), OutputLine::Ignored);
+        assert_eq!(parse_percent("Preparing packages..."), OutputLine::Output);
+    }
+}
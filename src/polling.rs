@@ -0,0 +1,210 @@
+//! Schedules the polls for pending updates. The interpreter records the
+//! outcome of each poll, and the poller waits as long as `next_delay` says
+//! before asking again.
+
+use rand;
+use rand::Rng;
+use std::cmp;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+use datatype::{Error, OtaConfig, UpdateRequestId};
+
+
+#[derive(Clone, Default)]
+pub struct Polling {
+    state: Arc<Mutex<PollState>>,
+}
+
+#[derive(Default)]
+struct PollState {
+    failures:    u32,
+    retry_after: Option<u64>,
+    hint:        Option<u64>,
+    in_flight:   HashSet<UpdateRequestId>,
+    pushing:     bool,
+    queued:      bool,
+}
+
+impl Polling {
+    pub fn new() -> Polling {
+        Polling::default()
+    }
+
+    /// Record a successful poll, with the server's hint of when to poll next.
+    pub fn succeeded(&self, hint: Option<u64>) {
+        let mut state = self.state.lock().unwrap();
+        state.failures    = 0;
+        state.retry_after = None;
+        state.hint        = hint;
+    }
+
+    /// Mark a poll as sent to the interpreter, unless the last one is still
+    /// waiting there, e.g. behind a running install, so that polls don't
+    /// pile up. Returns whether the poll should be sent.
+    pub fn queue_poll(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let queue     = !state.queued;
+        state.queued  = true;
+        queue
+    }
+
+    /// Record that the interpreter picked up a poll.
+    pub fn poll_started(&self) {
+        self.state.lock().unwrap().queued = false;
+    }
+
+    /// Record that an update was accepted. The client is busy until every
+    /// accepted update has finished.
+    pub fn update_started(&self, id: &UpdateRequestId) {
+        self.state.lock().unwrap().in_flight.insert(id.clone());
+    }
+
+    /// Record that an update was accepted, until the returned guard is
+    /// dropped, so that it is released on every path out of an install.
+    pub fn track_update(&self, id: &UpdateRequestId) -> UpdateGuard {
+        self.update_started(id);
+        UpdateGuard { polling: self.clone(), id: Some(id.clone()) }
+    }
+
+    pub fn update_finished(&self, id: &UpdateRequestId) {
+        self.state.lock().unwrap().in_flight.remove(id);
+    }
+
+    pub fn is_in_flight(&self, id: &UpdateRequestId) -> bool {
+        self.state.lock().unwrap().in_flight.contains(id)
+    }
+
+    /// Record whether the server can push notifications to the client, in
+    /// which case polling only serves as a fallback.
+    pub fn set_pushing(&self, pushing: bool) {
+        self.state.lock().unwrap().pushing = pushing;
+    }
+
+    pub fn is_pushing(&self) -> bool {
+        self.state.lock().unwrap().pushing
+    }
+
+    /// Record a failed poll. An `Error::RetryAfter` sets the delay before the
+    /// next one rather than backing off.
+    pub fn failed(&self, err: &Error) {
+        let mut state = self.state.lock().unwrap();
+        state.failures    = state.failures.saturating_add(1);
+        state.retry_after = match *err {
+            Error::RetryAfter(secs) => Some(secs),
+            _                       => None
+        };
+    }
+
+    /// The seconds to wait before the next poll, given a `jitter` between 0
+    /// and 1. Failures back off exponentially from `polling_interval` up to
+    /// `max_polling_backoff`, waiting a random time between half and all of
+    /// the backoff, while successful polls vary by up to 10%. A `Retry-After`
+    /// is also capped by `max_polling_backoff`. While updates are pushed, the
+    /// client polls every `max_polling_backoff` seconds.
+    pub fn next_delay(&self, config: &OtaConfig, jitter: f64) -> u64 {
+        let state = self.state.lock().unwrap();
+        if let Some(secs) = state.retry_after {
+            return cmp::max(cmp::min(secs, config.max_polling_backoff), 1);
+        }
+
+        let delay = if state.failures > 0 {
+            let shift   = cmp::min(state.failures, 32);
+            let backoff = cmp::min(config.polling_interval.saturating_mul(1 << shift), config.max_polling_backoff);
+            backoff as f64 * (0.5 + jitter / 2.0)
+        } else if !state.in_flight.is_empty() {
+            config.busy_polling_interval as f64
+        } else if state.pushing {
+            config.max_polling_backoff as f64 * (0.9 + jitter / 5.0)
+        } else {
+            let interval = cmp::min(state.hint.unwrap_or(config.polling_interval), config.max_polling_backoff);
+            interval as f64 * (0.9 + jitter / 5.0)
+        };
+        cmp::max(delay.round() as u64, 1)
+    }
+}
+
+/// Keeps an update in flight while it is alive.
+pub struct UpdateGuard {
+    polling: Polling,
+    id:      Option<UpdateRequestId>,
+}
+
+impl UpdateGuard {
+    /// Leave the update in flight after the guard is dropped, for a download
+    /// that finishes in the background and calls `update_finished` itself.
+    pub fn keep(mut self) {
+        self.id = None;
+    }
+}
+
+impl Drop for UpdateGuard {
+    fn drop(&mut self) {
+        if let Some(ref id) = self.id {
+            self.polling.update_finished(id);
+        }
+    }
+}
+
+/// A random number between 0 and 1, so that clients don't poll in lockstep.
+pub fn jitter() -> f64 {
+    rand::thread_rng().gen::<f64>()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use datatype::{Error, OtaConfig};
+
+
+    #[test]
+    fn backs_off_after_failures() {
+        let polling = Polling::new();
+        let config  = OtaConfig::default();
+        assert_eq!(polling.next_delay(&config, 0.5), 10);
+
+        polling.failed(&Error::ClientError("down".to_string()));
+        assert_eq!(polling.next_delay(&config, 0.0), 10);
+        assert_eq!(polling.next_delay(&config, 1.0), 20);
+        for _ in 0..20 {
+            polling.failed(&Error::ClientError("down".to_string()));
+        }
+        assert_eq!(polling.next_delay(&config, 1.0), config.max_polling_backoff);
+        polling.failed(&Error::RetryAfter(120));
+        assert_eq!(polling.next_delay(&config, 1.0), 120);
+        polling.failed(&Error::RetryAfter(86400));
+        assert_eq!(polling.next_delay(&config, 1.0), config.max_polling_backoff);
+
+        polling.succeeded(Some(300));
+        assert_eq!(polling.next_delay(&config, 0.5), 300);
+        polling.update_started(&"1".to_string());
+        assert_eq!(polling.next_delay(&config, 0.5), config.busy_polling_interval);
+        polling.update_finished(&"1".to_string());
+        polling.succeeded(None);
+        polling.set_pushing(true);
+        assert_eq!(polling.next_delay(&config, 0.5), config.max_polling_backoff);
+    }
+
+    #[test]
+    fn guards_release_updates() {
+        let polling = Polling::new();
+        {
+            let _update = polling.track_update(&"1".to_string());
+            assert!(polling.is_in_flight(&"1".to_string()));
+        }
+        assert!(!polling.is_in_flight(&"1".to_string()));
+
+        polling.track_update(&"2".to_string()).keep();
+        assert!(polling.is_in_flight(&"2".to_string()));
+    }
+
+    #[test]
+    fn polls_dont_pile_up() {
+        let polling = Polling::new();
+        assert!(polling.queue_poll());
+        assert!(!polling.queue_poll());
+        polling.poll_started();
+        assert!(polling.queue_poll());
+    }
+}
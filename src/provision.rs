@@ -0,0 +1,306 @@
+use rustc_serialize::json;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use datatype::{AuthConfig, Config, Error, Method};
+use credentials;
+use credentials::KeySource;
+use datatype::config::{CredentialsFile, write_credentials, write_private_file};
+use http_client::{HttpClient, HttpRequest};
+
+
+#[derive(RustcEncodable)]
+#[allow(non_snake_case)]
+struct DeviceRegistration<'a> {
+    deviceUuid:   &'a str,
+    deviceId:     &'a str,
+    deviceName:   &'a str,
+    deviceType:   &'a str,
+    provisionKey: &'a str,
+}
+
+/// The registry responds with either OAuth2 client credentials or a client
+/// certificate and private key in PEM format.
+#[derive(RustcDecodable, Debug, PartialEq, Eq)]
+pub struct Registration {
+    pub client_id:   Option<String>,
+    pub secret:      Option<String>,
+    pub certificate: Option<String>,
+    pub private_key: Option<String>,
+}
+
+
+/// Register this device with the registry from the `[provision]` config section
+/// then persist the issued credentials into the `credentials_file`, updating
+/// `config` in place so the client can start without re-reading it.
+pub fn provision(config: &mut Config, client: &HttpClient) -> Result<(), Error> {
+    let prov_cfg = config.provision.clone().expect("trying to provision without provision config");
+    let auth_cfg = config.auth.clone().expect("trying to provision without auth config");
+
+    if config.device.uuid.is_empty() {
+        config.device.uuid = try!(generate_uuid());
+        info!("Generated device UUID: {}", config.device.uuid);
+    }
+
+    let body = try!(json::encode(&DeviceRegistration {
+        deviceUuid:   &config.device.uuid,
+        deviceId:     &config.device.vin,
+        deviceName:   &config.device.vin,
+        deviceType:   "Vehicle",
+        provisionKey: &prov_cfg.provision_key,
+    }));
+    let resp_rx = client.send_request(HttpRequest {
+        method:          Method::Post,
+        url:             try!(prov_cfg.server.join("/api/v1/devices")),
+        body:            Some(body.into_bytes()),
+        idempotency_key: None,
+        progress:        None,
+        range:           None,
+    });
+    let data = try!(resp_rx.recv().expect("no provision response received"));
+    let text = try!(String::from_utf8(data));
+    let reg  = try!(json::decode::<Registration>(&text));
+
+    let creds_file = auth_cfg.credentials_file.clone();
+    let creds_path = Path::new(&creds_file);
+    let creds_cfg  = auth_cfg.credentials_key.clone();
+    let creds_key  = creds_cfg.as_ref().map(|key| key as &KeySource);
+    match reg {
+        Registration { client_id: Some(client_id), secret: Some(secret), .. } => {
+            let credentials = CredentialsFile { client_id: client_id, secret: secret };
+            try!(write_credentials(creds_path, Some(&credentials), Some(&config.device.uuid), creds_key));
+            info!("Provisioned device {} with client credentials", config.device.uuid);
+            config.auth = Some(AuthConfig {
+                client_id: credentials.client_id,
+                secret:    credentials.secret,
+                ..auth_cfg
+            });
+        }
+
+        Registration { certificate: Some(cert), private_key: Some(key), .. } => {
+            let tls_cfg = try!(config.tls.clone().ok_or(Error::ParseError("certificate provisioning requires a tls section".to_string())));
+            match (tls_cfg.cert_file, tls_cfg.key_file) {
+                (Some(cert_file), Some(key_file)) => {
+                    try!(write_private_file(Path::new(&cert_file), cert.as_bytes()));
+                    let key = match tls_cfg.credentials_key.as_ref().map(|key| key as &KeySource).or(creds_key) {
+                        Some(source) => try!(credentials::encrypt(source, key.as_bytes())).into_bytes(),
+                        None         => key.into_bytes()
+                    };
+                    try!(write_private_file(Path::new(&key_file), &key));
+                }
+                _ => return Err(Error::ParseError("certificate provisioning requires tls cert_file and key_file".to_string()))
+            }
+            try!(write_credentials(creds_path, None, Some(&config.device.uuid), creds_key));
+            info!("Provisioned device {} with a client certificate", config.device.uuid);
+            config.auth = None;
+        }
+
+        _ => return Err(Error::ClientError("registration returned neither credentials nor a certificate".to_string()))
+    }
+
+    Ok(())
+}
+
+/// Generate a random (version 4) UUID.
+pub fn generate_uuid() -> Result<String, Error> {
+    let mut bytes = [0u8; 16];
+    try!(try!(File::open("/dev/urandom")).read_exact(&mut bytes));
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+
+    let hex = bytes.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+    Ok(format!("{}-{}-{}-{}-{}", &hex[0..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..32]))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use rustc_serialize::json::Json;
+    use std::fs::File;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+    use tempfile::NamedTempFile;
+
+    use super::*;
+    use credentials;
+    use credentials::CredentialsKey;
+    use datatype::{Auth, AuthConfig, Config, ProvisionConfig, TlsConfig, Url};
+    use datatype::config::parse_config;
+    use http_client::{AuthClient, TestHttpClient};
+
+
+    fn test_config(creds_file: &str) -> Config {
+        let mut config        = Config::default();
+        let mut auth          = AuthConfig::default();
+        auth.credentials_file = creds_file.to_string();
+        config.auth           = Some(auth);
+        config.provision      = Some(ProvisionConfig::default());
+        config.device.uuid    = "".to_string();
+        config
+    }
+
+    fn temp_path() -> String {
+        let file = NamedTempFile::new().unwrap();
+        file.path().to_str().unwrap().to_string()
+    }
+
+    fn read_file(path: &str) -> String {
+        let mut text = String::new();
+        File::open(path).unwrap().read_to_string(&mut text).unwrap();
+        text
+    }
+
+    #[test]
+    fn test_generate_uuid() {
+        let uuid = generate_uuid().unwrap();
+        assert_eq!(uuid.len(), 36);
+        assert_eq!(&uuid[14..15], "4");
+        assert!(uuid != generate_uuid().unwrap());
+    }
+
+    #[test]
+    fn test_provision_credentials() {
+        let path       = temp_path();
+        let mut config = test_config(&path);
+        assert!(config.needs_provisioning());
+
+        let reply  = r#"{"client_id": "new-id", "secret": "new-secret"}"#;
+        let client = TestHttpClient::from(vec![reply.to_string()]);
+        provision(&mut config, &client).unwrap();
+        assert!(!config.needs_provisioning());
+
+        let auth = config.auth.clone().unwrap();
+        assert_eq!(auth.client_id, "new-id".to_string());
+        assert_eq!(auth.secret, "new-secret".to_string());
+        assert_eq!(config.device.uuid.len(), 36);
+
+        let persisted = format!(r#"
+            [auth]
+            server = "http://127.0.0.1:9000"
+            client_id = "client-id"
+            secret = "secret"
+            credentials_file = "{}"
+
+            [device]
+            uuid = ""
+            vin = "V1234567890123456"
+
+            [gateway]
+            console = false
+            http = false
+            websocket = true
+
+            [ota]
+            server = "http://127.0.0.1:8080"
+            polling_interval = 10
+            packages_dir = "/tmp/"
+            package_manager = "dpkg"
+            "#, path);
+        let reloaded = parse_config(&persisted).unwrap();
+        assert_eq!(reloaded.auth.unwrap().client_id, "new-id".to_string());
+        assert_eq!(reloaded.device.uuid, config.device.uuid);
+    }
+
+    #[test]
+    fn test_provision_against_a_registry() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address  = listener.local_addr().unwrap();
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader      = BufReader::new(stream.try_clone().unwrap());
+            let mut request     = String::new();
+            let mut length      = 0;
+            reader.read_line(&mut request).unwrap();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line.trim().is_empty() {
+                    break;
+                } else if line.to_lowercase().starts_with("content-length:") {
+                    length = line[15..].trim().parse().unwrap();
+                }
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+
+            let reply = r#"{"client_id": "issued-id", "secret": "issued-secret"}"#;
+            write!(stream, "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}", reply.len(), reply).unwrap();
+            tx.send((request.trim().to_string(), String::from_utf8(body).unwrap())).unwrap();
+        });
+
+        let path         = temp_path();
+        let mut config   = test_config(&path);
+        let mut prov     = ProvisionConfig::default();
+        prov.server      = Url::parse(&format!("http://{}", address)).unwrap();
+        config.provision = Some(prov);
+        provision(&mut config, &AuthClient::new(Auth::None)).unwrap();
+
+        let (request, body) = rx.recv().unwrap();
+        assert_eq!(request, "POST /api/v1/devices HTTP/1.1".to_string());
+        let json = Json::from_str(&body).unwrap();
+        let reg  = json.as_object().unwrap();
+        assert_eq!(reg.get("deviceUuid").and_then(|v| v.as_string()), Some(config.device.uuid.as_str()));
+        assert_eq!(reg.get("deviceId").and_then(|v| v.as_string()), Some("V1234567890123456"));
+        assert_eq!(reg.get("deviceType").and_then(|v| v.as_string()), Some("Vehicle"));
+        assert_eq!(reg.get("provisionKey").and_then(|v| v.as_string()), Some("provision-key"));
+
+        let stored = read_file(&path);
+        assert!(stored.contains("client_id = \"issued-id\""));
+        assert!(stored.contains("secret = \"issued-secret\""));
+        assert!(stored.contains(&format!("uuid = \"{}\"", config.device.uuid)));
+        assert_eq!(config.auth.unwrap().client_id, "issued-id".to_string());
+    }
+
+    #[test]
+    fn test_provision_certificate() {
+        let path       = temp_path();
+        let mut config = test_config(&path);
+        let mut tls    = TlsConfig::default();
+        tls.cert_file  = Some(temp_path());
+        tls.key_file   = Some(temp_path());
+        config.tls     = Some(tls.clone());
+
+        let reply  = r#"{"certificate": "CERT", "private_key": "KEY"}"#;
+        let client = TestHttpClient::from(vec![reply.to_string()]);
+        provision(&mut config, &client).unwrap();
+
+        assert_eq!(config.auth, None);
+        assert_eq!(read_file(&tls.cert_file.unwrap()), "CERT".to_string());
+        assert_eq!(read_file(&tls.key_file.unwrap()), "KEY".to_string());
+        assert!(read_file(&path).contains(&config.device.uuid));
+    }
+
+    #[test]
+    fn test_provision_encrypted_private_key() {
+        let mut secret = NamedTempFile::new().unwrap();
+        secret.write_all(b"machine secret").unwrap();
+        let key        = CredentialsKey::File(secret.path().to_str().unwrap().to_string());
+        let mut config      = test_config(&temp_path());
+        let mut tls         = TlsConfig::default();
+        tls.cert_file       = Some(temp_path());
+        tls.key_file        = Some(temp_path());
+        tls.credentials_key = Some(key.clone());
+        config.tls          = Some(tls.clone());
+
+        let reply  = r#"{"certificate": "CERT", "private_key": "KEY"}"#;
+        let client = TestHttpClient::from(vec![reply.to_string()]);
+        provision(&mut config, &client).unwrap();
+
+        let stored = read_file(&tls.key_file.unwrap());
+        assert!(credentials::is_encrypted(&stored));
+        assert_eq!(credentials::decrypt(&key, &stored).unwrap(), b"KEY".to_vec());
+        assert_eq!(read_file(&tls.cert_file.unwrap()), "CERT".to_string());
+    }
+
+    #[test]
+    fn test_provision_empty_registration() {
+        let mut config = test_config(&temp_path());
+        let client     = TestHttpClient::from(vec!["{}".to_string()]);
+        assert_eq!(format!("{}", provision(&mut config, &client).unwrap_err()),
+                   "Http client error: registration returned neither credentials nor a certificate");
+    }
+}
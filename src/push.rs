@@ -0,0 +1,128 @@
+//! An optional channel for the OTA server to push commands to the client,
+//! by HTTPS long-polling. Each message holds one or more commands in the same
+//! text form as the console gateway, one per line.
+
+use chan::Sender;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use aborts::Aborts;
+use datatype::{Command, Error, Method, PushConfig};
+use http_client::{HttpClient, HttpRequest};
+use interpreter::Global;
+use polling::Polling;
+
+
+/// Forwards pushed commands to the global interpreter.
+#[derive(Clone)]
+pub struct Notifier {
+    pub gtx:     Sender<Global>,
+    pub polling: Polling,
+    pub aborts:  Aborts,
+}
+
+impl Notifier {
+    /// Catch up on anything missed while the channel was down.
+    fn connected(&self) {
+        info!("Push channel connected");
+        self.polling.set_pushing(true);
+        self.gtx.send(Global { command: Command::GetPendingUpdates, response_tx: None });
+    }
+
+    // Only the commands that ask the client to check in with the server are
+    // taken from it.
+    fn notify(&self, text: &str) {
+        for line in text.lines().filter(|line| !line.trim().is_empty()) {
+            match line.parse::<Command>() {
+                Ok(cmd @ Command::GetPendingUpdates) => {
+                    info!("Pushed command: {:?}", cmd);
+                    self.gtx.send(Global { command: cmd, response_tx: None });
+                }
+
+                Ok(Command::AbortDownload(id)) => {
+                    info!("Pushed abort of {}", id);
+                    self.aborts.request(&id);
+                    self.gtx.send(Global { command: Command::AbortDownload(id), response_tx: None });
+                }
+
+                Ok(_)    => error!("Ignoring pushed command: {}", line),
+                Err(err) => error!("Couldn't parse pushed command: {}", err)
+            }
+        }
+    }
+}
+
+
+/// Keep the push channel open, reconnecting `retry_delay` seconds after it
+/// goes down. Polling takes over in the meantime.
+pub fn run(config: &PushConfig, client: &HttpClient, notifier: Notifier) {
+    loop {
+        let outcome = long_poll(config, client, &notifier);
+        notifier.polling.set_pushing(false);
+        match outcome {
+            Ok(_)    => info!("Push channel closed"),
+            Err(err) => error!("Push channel down: {}", err)
+        }
+        thread::sleep(Duration::from_secs(config.retry_delay));
+    }
+}
+
+// The server holds each request until it has commands to push, or answers
+// with an empty body before the `timeout` the client is configured with. An empty answer
+// that comes back at once means the server isn't holding requests, so the
+// next one waits `retry_delay` seconds rather than spinning.
+fn long_poll(config: &PushConfig, client: &HttpClient, notifier: &Notifier) -> Result<(), Error> {
+    loop {
+        let started = Instant::now();
+        let resp_rx = client.send_request(HttpRequest {
+            method:          Method::Get,
+            url:             config.server.clone(),
+            body:            None,
+            idempotency_key: None,
+            progress:        None,
+            range:           None,
+        });
+        let data = try!(resp_rx.recv().expect("no push response received"));
+        if !notifier.polling.is_pushing() {
+            notifier.connected();
+        }
+        let text = try!(String::from_utf8(data));
+        if text.trim().is_empty() && started.elapsed() < Duration::from_secs(1) {
+            thread::sleep(Duration::from_secs(config.retry_delay));
+        }
+        notifier.notify(&text);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use chan;
+    use std::time::{Duration, Instant};
+
+    use super::*;
+    use aborts::Aborts;
+    use datatype::{Command, PushConfig, Url};
+    use http_client::TestHttpClient;
+    use interpreter::Global;
+    use polling::Polling;
+
+
+    #[test]
+    fn long_poll_forwards_commands() {
+        let (gtx, grx) = chan::async::<Global>();
+        let notifier   = Notifier { gtx: gtx, polling: Polling::new(), aborts: Aborts::new() };
+        let config     = PushConfig { server: Url::parse("https://127.0.0.1:8080/push").unwrap(), retry_delay: 1, timeout: 60 };
+        let client     = TestHttpClient::from(vec!["".to_string(), "GetPendingUpdates\nabort 1\nShutdown\nInstallBundle /tmp".to_string()]);
+
+        let started = Instant::now();
+        assert!(long_poll(&config, &client, &notifier).is_err());
+        assert!(started.elapsed() >= Duration::from_secs(1));
+        assert!(notifier.polling.is_pushing());
+        assert!(notifier.aborts.is_requested(&"1".to_string()));
+        let commands = (0..3).map(|_| grx.recv().unwrap().command).collect::<Vec<_>>();
+        assert_eq!(commands, vec![Command::GetPendingUpdates,
+                                  Command::GetPendingUpdates,
+                                  Command::AbortDownload("1".to_string())]);
+    }
+}
@@ -1,25 +1,32 @@
 //! Handles caching and storage on disk for in-progress transfers and the assembly and verification
 //! of finished transfers
+//!
+//! Chunks are written straight to their offset in a sparse part file, which becomes the package
+//! once complete, so a transfer never needs more disk space than the package itself.
 
+use std::cmp;
 use std::fs;
-use std::fs::{OpenOptions, DirEntry, File};
+use std::fs::{OpenOptions, File};
 use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::mem;
 use std::path::PathBuf;
 use std::vec::Vec;
-use std::str::FromStr;
 
 use time;
-
 #[cfg(test)] use rand;
 #[cfg(test)] use rand::Rng;
-#[cfg(test)] use test_library::PathPrefix;
 
 use crypto::sha1::Sha1;
+use crypto::sha2::Sha256;
 use crypto::digest::Digest;
 
 use rustc_serialize::base64::FromBase64;
+use rustc_serialize::json;
 
 use datatype::UpdateId;
+use polling::Polling;
+use shutdown::{Shutdown, WorkGuard};
 
 /// TODO: Remove this macro and use proper error handling
 /// Try to unwrap or log the error and run the second argument
@@ -40,64 +47,219 @@ macro_rules! try_or {
     }
 }
 
+/// How often the missing chunks of a stalled transfer are requested again before the transfer
+/// is dropped.
+pub const MAX_RESENDS: u32 = 3;
+
+/// The size of the blocks read when hashing a package from disk.
+const READ_BLOCK_SIZE: usize = 64 * 1024;
+
+/// The hash algorithms a checksum can be given in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    Sha1,
+    Sha256
+}
+
+impl HashAlgorithm {
+    fn hasher(&self) -> Box<Digest + Send> {
+        match *self {
+            HashAlgorithm::Sha1   => Box::new(Sha1::new()),
+            HashAlgorithm::Sha256 => Box::new(Sha256::new()),
+        }
+    }
+}
+
+/// Parse a checksum, either tagged with its algorithm as `sha256:<hex>` or `sha1:<hex>`, or as
+/// plain hex where the algorithm is inferred from the length. Returns the algorithm and the
+/// lowercase hex digest.
+///
+/// # Arguments
+/// * `checksum`: The checksum to parse.
+pub fn parse_checksum(checksum: &str) -> Result<(HashAlgorithm, String), String> {
+    let (tag, hex) = match checksum.find(':') {
+        Some(n) => (Some(&checksum[..n]), &checksum[n+1..]),
+        None    => (None, checksum)
+    };
+    if !hex.chars().all(|c| c.is_digit(16)) {
+        return Err(format!("Invalid checksum: {}", checksum));
+    }
+
+    match (tag, hex.len()) {
+        (Some("sha1"), 40)   | (None, 40) => Ok((HashAlgorithm::Sha1, hex.to_lowercase())),
+        (Some("sha256"), 64) | (None, 64) => Ok((HashAlgorithm::Sha256, hex.to_lowercase())),
+        _ => Err(format!("Invalid checksum: {}", checksum))
+    }
+}
+
+/// Check `data` against a checksum as accepted by [`parse_checksum`](fn.parse_checksum.html).
+/// Returns a `String` detailing the mismatch on failure.
+fn verify_checksum(data: &[u8], checksum: &str) -> Result<(), String> {
+    let (algorithm, expected) = try!(parse_checksum(checksum));
+    let mut hasher = algorithm.hasher();
+    hasher.input(data);
+    let hash = hasher.result_str();
+    if hash == expected {
+        Ok(())
+    } else {
+        Err(format!("Checksum mismatch, expected {} but got {}", expected, hash))
+    }
+}
+
+/// The outcome of writing a chunk to a `Transfer`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChunkStatus {
+    /// The chunk was stored.
+    Written,
+    /// The chunk had already been stored and was ignored.
+    Duplicate,
+    /// The last chunk arrived before the chunk size was known, and is held in memory until it
+    /// can be placed.
+    Pending
+}
+
+/// A bitmap of the chunks a `Transfer` has received.
+#[derive(RustcDecodable, RustcEncodable, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ChunkBitmap {
+    bits: Vec<u8>
+}
+
+impl ChunkBitmap {
+    pub fn new() -> ChunkBitmap {
+        ChunkBitmap::default()
+    }
+
+    pub fn contains(&self, index: u64) -> bool {
+        self.bits.get((index / 8) as usize).map_or(false, |byte| byte & (1u8 << (index % 8)) != 0)
+    }
+
+    pub fn insert(&mut self, index: u64) {
+        let byte = (index / 8) as usize;
+        if byte >= self.bits.len() {
+            self.bits.resize(byte + 1, 0);
+        }
+        self.bits[byte] |= 1u8 << (index % 8);
+    }
+
+    /// Returns the indices in the bitmap in ascending order.
+    pub fn indices(&self) -> Vec<u64> {
+        (0..self.bits.len() as u64 * 8).filter(|&index| self.contains(index)).collect()
+    }
+}
+
+/// The part of a `Transfer` saved to disk, so it can resume after a client restart.
+#[derive(RustcDecodable, RustcEncodable)]
+struct TransferState {
+    update_id: UpdateId,
+    checksum: String,
+    chunkscount: u64,
+    chunk_size: u64,
+    size: u64,
+    received: ChunkBitmap
+}
+
 /// Type for storing the metadata of a in-progress transfer, which is defined as one package.
-/// Will clear out the chunks on disk when freed.
+/// Will clear out the partial package on disk when freed.
 pub struct Transfer {
     pub update_id: UpdateId,
-    /// SHA1 checksum of the fully assembled package.
+    /// Checksum of the fully assembled package, as accepted by
+    /// [`parse_checksum`](fn.parse_checksum.html).
     pub checksum: String,
-    /// `Vector` of transferred chunks.
-    pub transferred_chunks: Vec<u64>,
-    /// Path to the directory, where chunks will be cached and finished packages will be stored.
+    /// The amount of chunks in this transfer, or 0 when unknown.
+    pub chunkscount: u64,
+    /// The size of every chunk but the last, or 0 until it's known from a received chunk.
+    pub chunk_size: u64,
+    /// The size of the package, as far as known from the chunks received.
+    pub size: u64,
+    /// The chunks received so far.
+    pub received: ChunkBitmap,
+    /// Path to the directory, where the partial and finished packages will be stored.
     pub prefix_dir: String,
     /// Timestamp, when the last chunk was received. Given as a unix epoch timestamp.
-    pub last_chunk_received: i64
+    pub last_chunk_received: i64,
+    /// How often missing chunks were requested again since the last chunk was received.
+    pub resends: u32,
+    /// The chunks that might be the last one, held until the chunk size is known.
+    pending: Vec<(u64, Vec<u8>)>,
+    /// The held chunks that turned out not to fit the chunk size, to be requested again.
+    dropped: Vec<u64>,
+    /// Hash of the first `hashed` bytes of the package, fed as chunks arrive in order.
+    hasher: Option<(HashAlgorithm, Box<Digest + Send>)>,
+    hashed: u64,
+    /// Keeps a shutdown waiting until the transfer ends.
+    work: Option<WorkGuard>
 }
 
 impl Transfer {
     /// Return a new `Transfer`
     ///
     /// # Arguments
-    /// * `prefix`: Path where transferred chunks and assembled package will be stored.
+    /// * `prefix`: Path where the partial and assembled package will be stored.
     /// * `package`: [`PackageId`](../message/struct.PackageId.html) of this transfer.
-    /// * `checksum`: SHA1 checksum of the fully assembled package.
-    pub fn new(prefix: String, id: UpdateId, checksum: String)
+    /// * `checksum`: Checksum of the fully assembled package.
+    /// * `chunkscount`: The amount of chunks this transfer will have.
+    pub fn new(prefix: String, id: UpdateId, checksum: String, chunkscount: u64)
         -> Transfer {
+        let hasher = parse_checksum(&checksum).ok().map(|(algorithm, _)| (algorithm, algorithm.hasher()));
         Transfer {
             update_id: id,
             checksum: checksum,
-            transferred_chunks: Vec::new(),
+            chunkscount: chunkscount,
+            chunk_size: 0,
+            size: 0,
+            received: ChunkBitmap::new(),
             prefix_dir: prefix,
-            last_chunk_received: time::get_time().sec
+            last_chunk_received: time::get_time().sec,
+            resends: 0,
+            pending: Vec::new(),
+            dropped: Vec::new(),
+            hasher: hasher,
+            hashed: 0,
+            work: None
         }
     }
 
+    /// Restore a `Transfer` from its saved state file. The chunks are forgotten if the partial
+    /// package is gone.
+    ///
+    /// # Arguments
+    /// * `prefix`: Path where the partial and assembled package are stored.
+    /// * `path`: Path to the state file.
+    fn restore(prefix: String, path: &PathBuf) -> Result<Transfer, String> {
+        let mut file = try!(File::open(path).map_err(|e| format!("Couldn't open file: {}", e)));
+        let mut text = String::new();
+        try!(file.read_to_string(&mut text).map_err(|e| format!("Couldn't read file: {}", e)));
+        let state = try!(json::decode::<TransferState>(&text)
+                         .map_err(|e| format!("Couldn't parse transfer state: {}", e)));
+
+        let mut transfer = Transfer::new(prefix, state.update_id, state.checksum, state.chunkscount);
+        if transfer.get_part_path().is_file() {
+            transfer.chunk_size = state.chunk_size;
+            transfer.size = state.size;
+            transfer.received = state.received;
+            try!(transfer.hash_forward());
+        }
+        Ok(transfer)
+    }
+
     /// Create a transfer with empty values. To be used in tests.
     ///
     /// # Arguments
-    /// * `prefix`: Path where transferred chunks and assembled package will be stored. This should
-    ///   be a temporary directory for tests.
+    /// * `prefix`: Path where the partial and assembled package will be stored. This should be a
+    ///   temporary directory for tests.
     #[cfg(test)]
-    pub fn new_test(prefix: &PathPrefix) -> Transfer {
-        Transfer {
-            update_id: UpdateId::new(),
-            checksum: "".to_string(),
-            transferred_chunks: Vec::new(),
-            prefix_dir: prefix.to_string(),
-            last_chunk_received: time::get_time().sec
-        }
+    pub fn new_test(prefix: &str) -> Transfer {
+        Transfer::new(prefix.to_string(), UpdateId::new(), "".to_string(), 0)
     }
 
-    /// Randomize a existing transfer, by creating a random
-    /// [`PackageId`](../message/struct.PackageId.html). Returns the created `PackageId`, so it can
-    /// be used in assertions.
+    /// Randomize a existing transfer, by creating a random `UpdateId`. Returns the created
+    /// `UpdateId`, so it can be used in assertions.
     ///
     /// # Arguments
-    /// * `i`: Size of the name and version strings.
+    /// * `i`: Length of the `UpdateId`.
     #[cfg(test)]
     pub fn randomize(&mut self, i: usize) -> UpdateId {
-        let update_id = rand::thread_rng()
-            .gen_ascii_chars().take(i).collect::<String>();
+        let update_id = random_string(i);
 
         trace!("Testing with:");
         trace!("  update_id: {}", update_id);
@@ -105,41 +267,156 @@ impl Transfer {
         update_id
     }
 
-    /// Write a transferred chunk to disk. Returns false and logs an error if something goes wrong.
+    /// Returns the indices of the chunks received so far.
+    pub fn transferred_chunks(&self) -> Vec<u64> {
+        self.received.indices()
+    }
+
+    /// Returns the indices of the chunks that have not been received yet. When the amount of
+    /// chunks is unknown, only the held chunks that were dropped are known to be missing.
+    pub fn missing_chunks(&self) -> Vec<u64> {
+        let mut missing = (0..self.chunkscount)
+            .filter(|&index| !self.received.contains(index))
+            .collect::<Vec<_>>();
+        for &index in &self.dropped {
+            if !self.received.contains(index) && !missing.contains(&index) {
+                missing.push(index);
+            }
+        }
+        missing.sort();
+        missing
+    }
+
+    /// Write a transferred chunk to its offset in the partial package and save the transfer
+    /// state. A chunk that was already stored is ignored, so resent chunks are harmless. Returns a
+    /// `String` with a error message if the chunk can't be decoded, doesn't match its hash or
+    /// size, or can't be written.
+    ///
+    /// Every chunk but the last must have the same size. When the amount of chunks is unknown,
+    /// a shorter chunk is taken to be the last.
     ///
     /// # Arguments
     /// * `msg`: Base64 encoded data of this chunk.
     /// * `index`: Index of this chunk
+    /// * `hash`: Optional checksum of the decoded chunk data.
     pub fn write_chunk(&mut self,
                        msg: &str,
-                       index: u64) -> bool {
-        let success = msg.from_base64().map_err(|e| {
-            error!("Could not decode chunk {} for update_id {}", index, self.update_id);
-            error!("{}", e)
-        }).and_then(|msg| self.get_chunk_path(index).map_err(|e| {
-            error!("Could not get path for chunk {}", index);
-            error!("{}", e)
-        }).map(|path| {
-            trace!("Saving chunk to {}", path.display());
-            if write_new_file(&path, &msg) {
-                self.transferred_chunks.push(index);
-                self.transferred_chunks.sort();
-                self.transferred_chunks.dedup();
-                true
-            } else {
-                error!("Couldn't write chunk {} for update_id {}", index, self.update_id);
-                false
+                       index: u64,
+                       hash: Option<&str>) -> Result<ChunkStatus, String> {
+        self.last_chunk_received = time::get_time().sec;
+        self.resends = 0;
+        if self.received.contains(index) || self.pending.iter().any(|&(held, _)| held == index) {
+            debug!("Ignoring duplicate chunk {} for update_id {}", index, self.update_id);
+            return Ok(ChunkStatus::Duplicate);
+        }
+        if self.chunkscount > 0 && index >= self.chunkscount {
+            return Err(format!("Chunk {} out of range for update_id {}", index, self.update_id));
+        }
+
+        let data = try!(msg.from_base64().map_err(|e| {
+            format!("Could not decode chunk {} for update_id {}: {}", index, self.update_id, e)
+        }));
+        if let Some(hash) = hash {
+            try!(verify_checksum(&data, hash).map_err(|e| {
+                format!("Corrupt chunk {} for update_id {}: {}", index, self.update_id, e)
+            }));
+        }
+
+        if self.chunk_size == 0 {
+            // Only a chunk that isn't the last gives the chunk size. Without a count that is the
+            // first chunk, which is only the last when it's the only one. An empty only chunk
+            // is an empty package.
+            let maybe_last = match self.chunkscount {
+                0     => index > 0,
+                count => count > 1 && index == count - 1,
+            };
+            if maybe_last {
+                debug!("Holding chunk {} for update_id {} until the chunk size is known",
+                       index, self.update_id);
+                self.pending.push((index, data));
+                return Ok(ChunkStatus::Pending);
+            } else if data.is_empty() && self.chunkscount != 1 {
+                return Err(format!("Empty chunk {} for update_id {}", index, self.update_id));
             }
-        })).unwrap_or(false);
+            self.chunk_size = data.len() as u64;
+            try!(self.preallocate());
+        }
 
-        self.last_chunk_received = time::get_time().sec;
-        success
+        try!(self.store_chunk(index, &data));
+        for (held, data) in mem::replace(&mut self.pending, Vec::new()) {
+            if let Err(err) = self.store_chunk(held, &data) {
+                error!("{}", err);
+                self.dropped.push(held);
+            }
+        }
+        let _ = self.save_state().map_err(|e| error!("Couldn't save transfer state: {}", e));
+        Ok(ChunkStatus::Written)
+    }
+
+    /// Check the size of a chunk, write it at its offset and feed the package hash if it follows
+    /// on from the data hashed so far.
+    fn store_chunk(&mut self, index: u64, data: &[u8]) -> Result<(), String> {
+        let len = data.len() as u64;
+        let is_last = self.chunkscount == 0 || index == self.chunkscount - 1;
+        if len > self.chunk_size || (!is_last && len != self.chunk_size) {
+            return Err(format!("Chunk {} for update_id {} has {} bytes, expected {}",
+                               index, self.update_id, len, self.chunk_size));
+        }
+
+        let offset = index * self.chunk_size;
+        let mut file = try!(self.open_part_file());
+        trace!("Writing chunk {} to offset {}", index, offset);
+        try!(file.seek(SeekFrom::Start(offset))
+             .map_err(|e| format!("Couldn't seek to chunk {}: {}", index, e)));
+        try!(file.write_all(data)
+             .map_err(|e| format!("Couldn't write chunk {} for update_id {}: {}", index, self.update_id, e)));
+
+        self.received.insert(index);
+        self.size = cmp::max(self.size, offset + len);
+        if offset == self.hashed {
+            if let Some((_, ref mut hasher)) = self.hasher {
+                hasher.input(data);
+            }
+            self.hashed += len;
+        }
+        self.hash_forward()
+    }
+
+    /// Feed the package hash with the received chunks that now follow on from the data hashed so
+    /// far, reading them back from disk.
+    fn hash_forward(&mut self) -> Result<(), String> {
+        if self.hasher.is_none() || self.chunk_size == 0 {
+            return Ok(());
+        }
+        while self.hashed % self.chunk_size == 0 && self.received.contains(self.hashed / self.chunk_size) {
+            let len = cmp::min(self.chunk_size, self.size - self.hashed);
+            let data = try!(self.read_part(self.hashed, len));
+            if let Some((_, ref mut hasher)) = self.hasher {
+                hasher.input(&data);
+            }
+            self.hashed += len;
+            if len < self.chunk_size {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Size the partial package for all chunks once the chunk size is known. The file stays
+    /// sparse until the chunks are written.
+    fn preallocate(&self) -> Result<(), String> {
+        if self.chunkscount == 0 {
+            return Ok(());
+        }
+        let file = try!(self.open_part_file());
+        file.set_len(self.chunkscount * self.chunk_size)
+            .map_err(|e| format!("Couldn't preallocate update_id {}: {}", self.update_id, e))
     }
 
     /// Assemble the transferred chunks to a package and verify it with the provided checksum.
     /// Returns `false` and prints a error message if either the package can't be assembled or the
     /// checksum doesn't match.
-    pub fn assemble_package(&self) -> Result<PathBuf, String> {
+    pub fn assemble_package(&mut self) -> Result<PathBuf, String> {
         trace!("Finalizing package {}", self.update_id);
         self.assemble_chunks().
             and_then(|_| {
@@ -151,106 +428,114 @@ impl Transfer {
             })
     }
 
-    /// Collect all chunks and concatenate them into one file. Returns a `String` with a error
-    /// message, should something go wrong.
+    /// Trim the partial package to its final size and move it to the package path. Returns a
+    /// `String` with a error message, should something go wrong.
     fn assemble_chunks(&self) -> Result<(), String> {
-        let package_path = try!(self.get_package_path());
-
-        trace!("Saving update_id {} to {}", self.update_id, package_path.display());
-
-        let mut file = try!(OpenOptions::new()
-                               .write(true).append(true)
-                               .create(true).truncate(true)
-                               .open(package_path)
-                               .map_err(|x| format!("Couldn't open file: {}", x)));
-
-        let path: PathBuf = try!(self.get_chunk_dir());
-
-        // Make sure all indices are valid and sort them
-        let mut indices = Vec::new();
-        for entry in try!(read_dir(&path)) {
-            let entry = try!(entry.map_err(|x| format!("No entries: {}", x)));
-            indices.push(try!(parse_index(entry)));
+        let missing = self.missing_chunks();
+        if !missing.is_empty() {
+            return Err(format!("Chunks {:?} missing for update_id {}", missing, self.update_id));
         }
-        indices.sort();
 
-        // Append indices to the final file
-        for index in indices {
-            try!(self.copy_chunk(&path, index, &mut file));
-        }
-        Ok(())
-    }
+        let package_path = try!(self.get_package_path());
+        trace!("Saving update_id {} to {}", self.update_id, package_path.display());
 
-    /// Read a chunk file file and append it to a package file. Returns a `String` with a error
-    /// message should something go wrong.
-    ///
-    /// # Arguments
-    /// * `path`: Pointer to a [`PathBuf`]
-    ///   (https://doc.rust-lang.org/stable/std/path/struct.PathBuf.html) where the chunks are
-    ///   cached.
-    /// * `index`: Index of the chunk to append.
-    /// * `file`: Pointer to a `File` where the chunk should be appended. Should be created with
-    ///   `OpenOptions` and the append only option. See the documentation for [`OpenOptions`]
-    ///   (https://doc.rust-lang.org/stable/std/fs/struct.OpenOptions.html), [`File`]
-    ///   (https://doc.rust-lang.org/stable/std/fs/struct.File.html), and the implementation of
-    ///   [`assemble_chunks`](#method.assemble_chunks) for details.
-    fn copy_chunk(&self, path: &PathBuf, index: u64, file: &mut File)
-        -> Result<(), String> {
-        let name = index.to_string();
-        let mut chunk_path = path.clone();
-        chunk_path.push(&name);
-        let mut chunk =
-            try!(OpenOptions::new().open(chunk_path)
-                 .map_err(|x| format!("Couldn't open file: {}", x)));
-
-        let mut buf = Vec::new();
-        try!(chunk.read_to_end(&mut buf)
-             .map_err(|x| format!("Couldn't read file {}: {}", name, x)));
-        try!(file.write(&mut buf)
-             .map_err(|x| format!("Couldn't write chunk {} to file {}: {}",
-                                  name, self.update_id, x)));
-
-        trace!("Wrote chunk {} to update_id {}", name, self.update_id);
-        Ok(())
+        let file = try!(self.open_part_file());
+        try!(file.set_len(self.size).map_err(|e| format!("Couldn't truncate file: {}", e)));
+        fs::rename(self.get_part_path(), &package_path)
+            .map_err(|e| format!("Couldn't move package to {}: {}", package_path.display(), e))
     }
 
     /// Verify the checksum of this transfer. Assumes the package was already assembled. Prints a
     /// error message showing the mismatched checksums and returns false on errors.
-    fn checksum(&self) -> bool {
-        let path = try_or!(self.get_package_path(), return false);
-        let mut file = try_or!(OpenOptions::new().open(path), return false);
-        let mut data = Vec::new();
-
-        // TODO: avoid reading in the whole file at once
-        try_or!(file.read_to_end(&mut data), return false);
+    fn checksum(&mut self) -> bool {
+        let (algorithm, expected) = try_or!(parse_checksum(&self.checksum), return false);
+        let hash = try_or!(self.package_hash(algorithm), return false);
 
-        let mut hasher = Sha1::new();
-        hasher.input(&data);
-        let hash = hasher.result_str();
-
-        if hash == self.checksum {
+        if hash == expected {
             true
         } else {
             error!("Checksums didn't match for update_id {}", self.update_id);
-            error!("    Expected: {}", self.checksum);
+            error!("    Expected: {}", expected);
             error!("    Got: {}", hash);
             false
         }
     }
 
-    /// Get the full path for the specified chunk index. Returns a
-    /// [`PathBuf`](https://doc.rust-lang.org/stable/std/path/struct.PathBuf.html) on success or a
-    /// `String` on errors detailing what went wrong.
-    ///
-    /// # Arguments
-    /// * `index`: The index for which the path should be constructed
-    fn get_chunk_path(&self, index: u64) -> Result<PathBuf, String> {
-        let mut path = try!(self.get_chunk_dir());
-        let filename = index.to_string();
+    /// Returns the hash of the assembled package. Uses the hash fed as chunks arrived when it
+    /// covers the whole package, and reads the package back from disk otherwise.
+    fn package_hash(&mut self, algorithm: HashAlgorithm) -> Result<String, String> {
+        if self.hashed == self.size {
+            if let Some((ref hashed_with, ref mut hasher)) = self.hasher {
+                if *hashed_with == algorithm {
+                    return Ok(hasher.result_str());
+                }
+            }
+        }
 
-        trace!("Using filename {}", filename);
-        path.push(filename);
-        Ok(path)
+        let path = try!(self.get_package_path());
+        let mut file = try!(File::open(path).map_err(|e| format!("Couldn't open file: {}", e)));
+        let mut hasher = algorithm.hasher();
+        let mut buf = vec![0; READ_BLOCK_SIZE];
+        loop {
+            match try!(file.read(&mut buf).map_err(|e| format!("Couldn't read file: {}", e))) {
+                0 => break,
+                n => hasher.input(&buf[..n])
+            }
+        }
+        Ok(hasher.result_str())
+    }
+
+    /// Save the transfer state next to the partial package. Returns a `String` with a error
+    /// message should something go wrong.
+    fn save_state(&self) -> Result<(), String> {
+        let state = TransferState {
+            update_id: self.update_id.clone(),
+            checksum: self.checksum.clone(),
+            chunkscount: self.chunkscount,
+            chunk_size: self.chunk_size,
+            size: self.size,
+            received: self.received.clone()
+        };
+        let text = try!(json::encode(&state).map_err(|e| format!("{}", e)));
+        let path = self.get_state_path();
+        let tmp  = path.with_extension("json.tmp");
+        if !write_new_file(&tmp, &text.into_bytes()) {
+            return Err(format!("Couldn't write {}", tmp.display()));
+        }
+        fs::rename(&tmp, &path).map_err(|e| format!("Couldn't rename {}: {}", tmp.display(), e))
+    }
+
+    /// Open the partial package for reading and writing, creating it if needed.
+    fn open_part_file(&self) -> Result<File, String> {
+        let path = self.get_part_path();
+        try!(self.get_download_dir());
+        OpenOptions::new().read(true).write(true).create(true).open(&path)
+            .map_err(|e| format!("Couldn't open file {}: {}", path.display(), e))
+    }
+
+    /// Read `len` bytes at `offset` from the partial package.
+    fn read_part(&self, offset: u64, len: u64) -> Result<Vec<u8>, String> {
+        let mut file = try!(self.open_part_file());
+        let mut data = vec![0; len as usize];
+        try!(file.seek(SeekFrom::Start(offset)).map_err(|e| format!("Couldn't seek: {}", e)));
+        try!(file.read_exact(&mut data).map_err(|e| format!("Couldn't read file: {}", e)));
+        Ok(data)
+    }
+
+    /// Get the path of the partial package, where chunks are written as they arrive.
+    fn get_part_path(&self) -> PathBuf {
+        let mut path = PathBuf::from(&self.prefix_dir);
+        path.push("downloads");
+        path.push(format!("{}.part", self.update_id));
+        path
+    }
+
+    /// Get the path of the file, where the state of this `Transfer` is saved.
+    fn get_state_path(&self) -> PathBuf {
+        let mut path = PathBuf::from(&self.prefix_dir);
+        path.push("downloads");
+        path.push(format!("{}.json", self.update_id));
+        path
     }
 
     /// Get the full path for the package of this `Transfer`. Returns a
@@ -262,17 +547,16 @@ impl Transfer {
         Ok(path)
     }
 
-    /// Get the directory, where this `Transfer` caches chunks. Returns a
+    /// Get the directory, where in-progress transfers are stored. Returns a
     /// [`PathBuf`](https://doc.rust-lang.org/stable/std/path/struct.PathBuf.html) on success or a
     /// `String` on errors detailing what went wrong.
-    fn get_chunk_dir(&self) -> Result<PathBuf, String> {
+    fn get_download_dir(&self) -> Result<PathBuf, String> {
         let mut path = PathBuf::from(&self.prefix_dir);
         path.push("downloads");
-        path.push(format!("{}", self.update_id));
 
         fs::create_dir_all(&path).map_err(|e| {
             let path_str = path.to_str().unwrap_or("unknown");
-            format!("Couldn't create chunk dir at '{}': {}", path_str, e)
+            format!("Couldn't create download dir at '{}': {}", path_str, e)
         }).map(|_| path)
     }
 
@@ -291,22 +575,12 @@ impl Transfer {
 }
 
 impl Drop for Transfer {
-    /// When a `Transfer` is freed it will also clear out the associated chunk cache on disk.
+    /// When a `Transfer` is freed it will also clear out the partial package and saved state on
+    /// disk.
     fn drop(&mut self) {
-        let dir = try_or!(self.get_chunk_dir(), return);
         trace!("Dropping transfer for package {}", self.update_id);
-
-        for entry in try_or!(read_dir(&dir), return) {
-            let entry = try_or!(entry, continue);
-            let _ = entry.file_name().into_string().map_err(|_|
-                error!("Found a malformed entry!")
-            ).map(|name| {
-                trace!("Dropping chunk file {}", name);
-                try_or!(fs::remove_file(entry.path()), return);
-            });
-        }
-
-        try_or!(fs::remove_dir(dir), return);
+        let _ = fs::remove_file(self.get_state_path());
+        let _ = fs::remove_file(self.get_part_path());
     }
 }
 
@@ -329,26 +603,10 @@ fn write_new_file(path: &PathBuf, data: &Vec<u8>) -> bool {
     true
 }
 
-/// Read the contents of a directory. Returns a
-/// [`ReadDir`](https://doc.rust-lang.org/stable/std/fs/struct.ReadDir.html) iterator on success or
-/// a `String` with a detailed error message on failure.
-fn read_dir(path: &PathBuf) -> Result<fs::ReadDir, String> {
-    fs::read_dir(path).map_err(|e| {
-        let path_str = path.to_str().unwrap_or("unknown");
-        format!("Couldn't read dir at '{}': {}", path_str, e)
-    })
-}
-
-/// Parse a [`DirEntry`](https://doc.rust-lang.org/stable/std/fs/struct.DirEntry.html) to a `u64`.
-/// Returns the parsed number on success or a `String` with a detailed error message on failure.
-///
-/// # Arguments
-/// * `entry`: `DirEntry` to be parsed.
-fn parse_index(entry: DirEntry) -> Result<u64, String> {
-    let name = entry.file_name().into_string()
-        .unwrap_or("unknown".to_string());
-    u64::from_str(&name)
-        .map_err(|_| "Couldn't parse chunk index from filename".to_string())
+/// Generate a random alphanumeric string of length `len`. To be used in tests.
+#[cfg(test)]
+pub fn random_string(len: usize) -> String {
+    rand::thread_rng().gen_ascii_chars().take(len).collect()
 }
 
 use std::collections::HashMap;
@@ -357,14 +615,37 @@ use std::collections::HashMap;
 /// [`Transfer`](../persistence/struct.Transfer.html)s.
 pub struct Transfers {
     items: HashMap<UpdateId, Transfer>,
-    storage_dir: String
+    storage_dir: String,
+    /// Releases the accepted updates whose transfer fails.
+    polling: Polling,
+    /// Waits for the transfers in progress before the client exits.
+    shutdown: Shutdown
 }
 
 impl Transfers {
-    pub fn new(dir: String) -> Transfers {
+    /// Create a new `Transfers`, resuming the transfers whose state was saved in `dir`.
+    pub fn new(dir: String, polling: Polling, shutdown: Shutdown) -> Transfers {
+        let mut items = HashMap::new();
+        let downloads = PathBuf::from(&dir).join("downloads");
+        if let Ok(entries) = fs::read_dir(&downloads) {
+            for entry in entries {
+                let path = try_or!(entry, continue).path();
+                if path.extension().map_or(true, |ext| ext != "json") {
+                    continue;
+                }
+                let mut transfer = try_or!(Transfer::restore(dir.clone(), &path), continue);
+                transfer.work = shutdown.begin();
+                info!("Resuming transfer for update_id {} with {} chunks",
+                      transfer.update_id, transfer.transferred_chunks().len());
+                items.insert(transfer.update_id.clone(), transfer);
+            }
+        }
+
         Transfers {
-            items: HashMap::new(),
-            storage_dir: dir
+            items: items,
+            storage_dir: dir,
+            polling: polling,
+            shutdown: shutdown
         }
     }
 
@@ -376,10 +657,28 @@ impl Transfers {
         self.items.get_mut(pkg)
     }
 
-    pub fn push(&mut self, pkg: UpdateId, cksum: String) {
-        self.items.insert(
-            pkg.clone(),
-            Transfer::new(self.storage_dir.to_string(), pkg, cksum));
+    /// Start a transfer, or resume it when a transfer with the same checksum is already in
+    /// progress. Returns the chunks that were already transferred, or `None` when a new transfer
+    /// can't start as the client is shutting down.
+    pub fn push(&mut self, pkg: UpdateId, cksum: String, chunkscount: u64) -> Option<Vec<u64>> {
+        if let Some(transfer) = self.items.get_mut(&pkg) {
+            if transfer.checksum == cksum {
+                transfer.chunkscount = chunkscount;
+                transfer.last_chunk_received = time::get_time().sec;
+                return Some(transfer.transferred_chunks());
+            }
+        }
+
+        let work = match self.shutdown.begin() {
+            Some(work) => work,
+            None => return None
+        };
+        // drop a stale transfer first, as it clears the chunk directory on disk
+        self.items.remove(&pkg);
+        let mut transfer = Transfer::new(self.storage_dir.to_string(), pkg.clone(), cksum, chunkscount);
+        transfer.work = Some(work);
+        self.items.insert(pkg, transfer);
+        Some(Vec::new())
     }
 
     #[cfg(test)]
@@ -396,19 +695,47 @@ impl Transfers {
         self.items.remove(pkg);
     }
 
+    /// Drop a transfer that can't complete, so that its update is no longer in flight.
+    pub fn fail(&mut self, pkg: &UpdateId) {
+        self.items.remove(pkg);
+        self.polling.update_finished(pkg);
+    }
+
     pub fn clear(&mut self) {
-        self.items.clear();
+        for (id, _) in self.items.drain() {
+            self.polling.update_finished(&id);
+        }
     }
 
-    pub fn prune(&mut self, now: i64, timeout: i64) {
-        self.items.iter()
-            .filter(|&(_, v)| now - v.last_chunk_received > timeout)
-            .map(|(k, _)| k.clone())
-            .collect::<Vec<UpdateId>>()
-            .iter().map(|k| {
-                self.items.remove(k);
-                info!("Transfer for update_id {} timed out after {} ms", k, timeout)})
-            .collect::<Vec<()>>();
+    /// Find the transfers that received no chunk for `timeout` seconds. Returns the missing
+    /// chunks of each, to be requested again. Transfers that are still stalled after
+    /// `MAX_RESENDS` requests, or that don't know which chunks are missing, are dropped.
+    ///
+    /// # Arguments
+    /// * `now`: The current unix epoch timestamp.
+    /// * `timeout`: The timeout in seconds.
+    pub fn stalled(&mut self, now: i64, timeout: i64) -> Vec<(UpdateId, Vec<u64>)> {
+        let mut resend = Vec::new();
+        let mut expired = Vec::new();
+        for (id, transfer) in self.items.iter_mut() {
+            if now - transfer.last_chunk_received <= timeout {
+                continue;
+            }
+            let missing = transfer.missing_chunks();
+            if missing.is_empty() || transfer.resends >= MAX_RESENDS {
+                expired.push(id.clone());
+            } else {
+                transfer.resends += 1;
+                transfer.last_chunk_received = now;
+                resend.push((id.clone(), missing));
+            }
+        }
+
+        for id in expired {
+            self.fail(&id);
+            info!("Transfer for update_id {} timed out after {} s", id, timeout);
+        }
+        resend
     }
 }
 
@@ -416,62 +743,90 @@ impl Transfers {
 #[cfg(test)]
 mod test {
     use super::*;
-    use test_library::*;
 
-    use std::path::PathBuf;
+    use std::env;
+    use std::fmt;
+    use std::path::{Path, PathBuf};
     use std::fs;
-    use std::fs::OpenOptions;
+    use std::fs::File;
     use std::io::prelude::*;
+    use std::time::Duration;
 
-    use rand;
-    use rand::Rng;
     use rustc_serialize::base64;
     use rustc_serialize::base64::ToBase64;
+    use time;
 
-    fn create_tmp_directories(prefix: &PathPrefix) {
-        for i in 1..20 {
-            let mut transfer = Transfer::new_test(prefix);
-            let update_id = transfer.randomize(i);
-            let chunk_dir: PathBuf = transfer.get_chunk_dir().unwrap();
-            let path = format!("{}/downloads/{}", prefix, update_id);
-            assert_eq!(chunk_dir.to_str().unwrap(), path);
+    use polling::Polling;
+    use shutdown::Shutdown;
 
-            let path = PathBuf::from(path);
-            // This also makes sure it's a directory
-            let dir = fs::read_dir(&path).unwrap();
+    /// A unique temporary directory, removed when dropped.
+    struct PathPrefix(String);
 
-            for _ in dir {
-                panic!("Found non-empty directory!");
-            }
+    impl PathPrefix {
+        fn new() -> PathPrefix {
+            let mut path = env::temp_dir();
+            path.push(format!("ota-dw-{}-{}", time::precise_time_ns(), random_string(8)));
+            PathPrefix(path.to_str().unwrap().to_string())
+        }
+    }
+
+    impl fmt::Display for PathPrefix {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "{}", self.0)
+        }
+    }
+
+    impl Drop for PathPrefix {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
         }
     }
 
+    fn encode(data: &str) -> String {
+        data.as_bytes().to_base64(base64::STANDARD)
+    }
+
+    fn read_file<P: AsRef<Path>>(path: P) -> Vec<u8> {
+        let mut data = Vec::new();
+        File::open(path).unwrap().read_to_end(&mut data).unwrap();
+        data
+    }
+
     #[test]
-    fn it_creates_a_tmp_directory() {
-        test_init!();
+    fn it_writes_chunks_to_a_part_file() {
         let prefix = PathPrefix::new();
-        create_tmp_directories(&prefix);
+        for i in 1..20 {
+            let mut transfer = Transfer::new_test(&prefix.0);
+            let update_id = transfer.randomize(i);
+            let data = random_string(i);
+            transfer.write_chunk(&encode(&data), 0, None).unwrap();
+
+            let path = format!("{}/downloads/{}.part", prefix, update_id);
+            assert_eq!(transfer.get_part_path().to_str().unwrap(), path);
+            assert_eq!(read_file(&path), data.into_bytes());
+        }
     }
 
     #[test]
-    fn it_cleans_up_the_tmp_directories() {
-        test_init!();
+    fn it_cleans_up_the_part_files() {
         let prefix = PathPrefix::new();
-        create_tmp_directories(&prefix);
-        let path = PathBuf::from(format!("{}/downloads/", prefix));
-        let dir = fs::read_dir(&path).unwrap();
+        for i in 1..20 {
+            let mut transfer = Transfer::new_test(&prefix.0);
+            transfer.randomize(i);
+            transfer.write_chunk(&encode("test\n"), 0, None).unwrap();
+        }
 
-        for _ in dir {
+        let path = PathBuf::from(format!("{}/downloads/", prefix));
+        for _ in fs::read_dir(&path).unwrap() {
             panic!("Found non-empty directory!");
         }
     }
 
     #[test]
     fn it_creates_a_persistent_directory_per_package() {
-        test_init!();
         let prefix = PathPrefix::new();
         for i in 1..20 {
-            let mut transfer = Transfer::new_test(&prefix);
+            let mut transfer = Transfer::new_test(&prefix.0);
             let update_id = transfer.randomize(i);
 
             let chunk_dir: PathBuf = transfer.get_package_path().unwrap();
@@ -480,95 +835,85 @@ mod test {
         }
     }
 
-    macro_rules! assert_chunk_written {
-        ($transfer:ident,
-         $prefix:ident,
-         $update_id:ident,
-         $index:ident,
-         $data:ident) => {{
-            trace!("Testing with: {}", $data);
-
-            let b64_data = $data.as_bytes().to_base64(
-                base64::Config {
-                    char_set: base64::CharacterSet::UrlSafe,
-                    newline: base64::Newline::LF,
-                    pad: true,
-                    line_length: None
-                });
-
-            trace!("Encoded as: {}", b64_data);
-
-            $transfer.write_chunk(&b64_data, $index as u64);
-
-            let path = format!("{}/downloads/{}/{}", $prefix, $update_id, $index);
-
-            trace!("Expecting file at: {}", path);
-
-            let mut from_disk = Vec::new();
-            OpenOptions::new()
-                .open(PathBuf::from(path))
-                .unwrap()
-                .read_to_end(&mut from_disk)
-                .unwrap();
+    #[test]
+    fn it_writes_chunks_at_their_offset() {
+        let prefix = PathPrefix::new();
+        let mut transfer = Transfer::new(prefix.0.clone(), "offsets".to_string(), "".to_string(), 3);
+        assert_eq!(transfer.write_chunk(&encode("cc"), 2, None), Ok(ChunkStatus::Pending));
+        assert_eq!(transfer.write_chunk(&encode("bbbb"), 1, None), Ok(ChunkStatus::Written));
+        assert_eq!(transfer.transferred_chunks(), vec![1, 2]);
+
+        let part = read_file(format!("{}/downloads/offsets.part", prefix));
+        assert_eq!(part.len(), 12);
+        assert_eq!(&part[4..10], b"bbbbcc");
+
+        transfer.write_chunk(&encode("aaaa"), 0, None).unwrap();
+        transfer.assemble_chunks().unwrap();
+        assert_eq!(read_file(format!("{}/packages/offsets.spkg", prefix)), b"aaaabbbbcc".to_vec());
+        assert!(!Path::new(&format!("{}/downloads/offsets.part", prefix)).exists());
+    }
 
-            assert_eq!($data.into_bytes(), from_disk);
-        }}
+    #[test]
+    fn it_holds_chunks_until_the_chunk_size_is_known() {
+        let prefix = PathPrefix::new();
+        let mut transfer = Transfer::new(prefix.0.clone(), "uncounted".to_string(), "".to_string(), 0);
+        assert_eq!(transfer.write_chunk(&encode("cc"), 2, None), Ok(ChunkStatus::Pending));
+        assert_eq!(transfer.write_chunk(&encode("bbbb"), 1, None), Ok(ChunkStatus::Pending));
+        assert_eq!(transfer.write_chunk(&encode("aaaa"), 0, None), Ok(ChunkStatus::Written));
+        assert_eq!(transfer.chunk_size, 4);
+        assert_eq!(transfer.transferred_chunks(), vec![0, 1, 2]);
+
+        transfer.assemble_chunks().unwrap();
+        assert_eq!(read_file(format!("{}/packages/uncounted.spkg", prefix)), b"aaaabbbbcc".to_vec());
     }
 
     #[test]
-    fn it_writes_decoded_data_to_disk() {
-        test_init!();
+    fn it_assembles_an_empty_only_chunk() {
         let prefix = PathPrefix::new();
-        for i in 1..20 {
-            let mut transfer = Transfer::new_test(&prefix);
-            let update_id = transfer.randomize(i);
-            for i in 1..20 {
-                let data = rand::thread_rng()
-                    .gen_ascii_chars().take(i).collect::<String>();
-                assert_chunk_written!(transfer, prefix, update_id, i, data);
-            }
-        }
+        let mut transfer = Transfer::new(prefix.0.clone(), "empty".to_string(), "".to_string(), 1);
+        assert_eq!(transfer.write_chunk("", 0, None), Ok(ChunkStatus::Written));
+        assert!(transfer.missing_chunks().is_empty());
+
+        transfer.assemble_chunks().unwrap();
+        assert_eq!(read_file(format!("{}/packages/empty.spkg", prefix)), Vec::new());
     }
 
     #[test]
     fn it_correctly_assembles_stored_chunks() {
-        test_init!();
         let prefix = PathPrefix::new();
-        for i in 1..20 {
-            let mut transfer = Transfer::new_test(&prefix);
-            let update_id = transfer.randomize(i);
-            let mut full_data = String::new();
-            for i in 1..20 {
-                let data = rand::thread_rng()
-                    .gen_ascii_chars().take(i).collect::<String>();
-                full_data.push_str(&data);
-
-                assert_chunk_written!(transfer, prefix, update_id, i, data);
+        for count in 1..20 {
+            let mut transfer = Transfer::new(prefix.0.clone(), random_string(10), "".to_string(), count);
+            let chunks = (0..count).map(|i| random_string(if i == count - 1 { 3 } else { 5 })).collect::<Vec<_>>();
+            for i in (0..count).rev() {
+                transfer.write_chunk(&encode(&chunks[i as usize]), i, None).unwrap();
             }
-
             transfer.assemble_chunks().unwrap();
 
-            let path = format!("{}/packages/{}.spkg", prefix, update_id);
-
-            trace!("Expecting assembled file at: {}", path);
-
-            let mut from_disk = Vec::new();
-            OpenOptions::new()
-                .open(PathBuf::from(path))
-                .unwrap()
-                .read_to_end(&mut from_disk)
-                .unwrap();
+            let path = format!("{}/packages/{}.spkg", prefix, transfer.update_id);
+            assert_eq!(read_file(path), chunks.concat().into_bytes());
+        }
+    }
 
-            assert_eq!(full_data.into_bytes(), from_disk);
+    #[test]
+    fn it_hashes_chunks_as_they_arrive() {
+        let prefix = PathPrefix::new();
+        let checksum = "sha256:f2ca1bb6c7e907d06dafe4687e579fce76b37e4e93b7605022da52e6ccc26fd2";
+        for order in vec![vec![0, 1, 2], vec![1, 0, 2], vec![2, 1, 0]] {
+            let mut transfer = Transfer::new(prefix.0.clone(), random_string(10), checksum.to_string(), 3);
+            for index in order {
+                let chunk = ["te", "st", "\n"][index as usize];
+                transfer.write_chunk(&encode(chunk), index, None).unwrap();
+            }
+            assert_eq!(transfer.hashed, 5);
+            assert!(transfer.assemble_package().is_ok());
         }
     }
 
     fn checksum_matching(data: String, checksum: String) -> bool {
             let prefix = PathPrefix::new();
-            let mut transfer = Transfer::new_test(&prefix);
-            let update_id = transfer.randomize(20);
-            let index = 0;
-            assert_chunk_written!(transfer, prefix, update_id, index, data);
+            let mut transfer = Transfer::new_test(&prefix.0);
+            transfer.randomize(20);
+            transfer.write_chunk(&encode(&data), 0, None).unwrap();
             transfer.assemble_chunks().unwrap();
 
             transfer.checksum = checksum;
@@ -577,22 +922,114 @@ mod test {
 
     #[test]
     fn it_returns_true_for_correct_checksums() {
-        test_init!();
         assert!(checksum_matching("test\n".to_string(),
         "4e1243bd22c66e76c2ba9eddc1f91394e57f9f83".to_string()));
     }
 
     #[test]
     fn it_returns_false_for_incorrect_checksums() {
-        test_init!();
         assert!(!checksum_matching("test\n".to_string(),
         "fa7c4d75bae3a641d1f9ab5df028175bfb8a69ca".to_string()));
     }
 
     #[test]
     fn it_returns_false_for_invalid_checksums() {
-        test_init!();
         assert!(!checksum_matching("test\n".to_string(),
         "invalid".to_string()));
     }
+
+    #[test]
+    fn it_accepts_tagged_sha256_checksums() {
+        assert!(checksum_matching("test\n".to_string(),
+        "sha256:f2ca1bb6c7e907d06dafe4687e579fce76b37e4e93b7605022da52e6ccc26fd2".to_string()));
+        assert!(!checksum_matching("test\n".to_string(),
+        "sha1:f2ca1bb6c7e907d06dafe4687e579fce76b37e4e93b7605022da52e6ccc26fd2".to_string()));
+    }
+
+    #[test]
+    fn it_ignores_duplicate_and_corrupt_chunks() {
+        let prefix = PathPrefix::new();
+        let mut transfer = Transfer::new(prefix.0.clone(), "dup".to_string(), "".to_string(), 2);
+        let hash = "sha1:4e1243bd22c66e76c2ba9eddc1f91394e57f9f83";
+        assert_eq!(transfer.write_chunk("dGVzdAo=", 0, Some(hash)), Ok(ChunkStatus::Written));
+        assert_eq!(transfer.write_chunk("b3RoZXIK", 0, None), Ok(ChunkStatus::Duplicate));
+        assert!(transfer.write_chunk("b3RoZXIK", 1, Some(hash)).is_err());
+        assert!(transfer.write_chunk("dGVzdAo=", 2, None).is_err());
+        assert_eq!(transfer.transferred_chunks(), vec![0]);
+        assert_eq!(transfer.missing_chunks(), vec![1]);
+    }
+
+    #[test]
+    fn it_resumes_transfers_after_a_restart() {
+        let prefix = PathPrefix::new();
+        {
+            let mut transfers = Transfers::new(prefix.0.clone(), Polling::new(), Shutdown::new());
+            assert_eq!(transfers.push("upd".to_string(), "sum".to_string(), 3), Some(Vec::new()));
+            transfers.get_mut(&"upd".to_string()).unwrap().write_chunk("dGVzdAo=", 0, None).unwrap();
+            // the client exits without freeing its transfers
+            ::std::mem::forget(transfers);
+        }
+
+        let mut transfers = Transfers::new(prefix.0.clone(), Polling::new(), Shutdown::new());
+        assert_eq!(transfers.get(&"upd".to_string()).unwrap().transferred_chunks(), vec![0]);
+        assert_eq!(transfers.push("upd".to_string(), "sum".to_string(), 3), Some(vec![0]));
+        assert_eq!(transfers.push("upd".to_string(), "other".to_string(), 3), Some(Vec::new()));
+    }
+
+    #[test]
+    fn shutdown_waits_for_transfers() {
+        let prefix = PathPrefix::new();
+        let shutdown = Shutdown::new();
+        let mut transfers = Transfers::new(prefix.0.clone(), Polling::new(), shutdown.clone());
+        transfers.push("upd".to_string(), "sum".to_string(), 2).unwrap();
+
+        shutdown.request();
+        assert!(!shutdown.wait_idle(Duration::from_millis(10)));
+        assert_eq!(transfers.push("other".to_string(), "sum".to_string(), 2), None);
+        assert_eq!(transfers.push("upd".to_string(), "sum".to_string(), 2), Some(Vec::new()));
+        transfers.remove(&"upd".to_string());
+        assert!(shutdown.wait_idle(Duration::from_millis(10)));
+    }
+
+    #[test]
+    fn it_requests_missing_chunks_before_timing_out() {
+        let prefix = PathPrefix::new();
+        let polling = Polling::new();
+        polling.update_started(&"upd".to_string());
+        let mut transfers = Transfers::new(prefix.0.clone(), polling.clone(), Shutdown::new());
+        transfers.push("upd".to_string(), "sum".to_string(), 2);
+        transfers.get_mut(&"upd".to_string()).unwrap().write_chunk("dGVzdAo=", 0, None).unwrap();
+
+        let mut now = time::get_time().sec;
+        assert!(transfers.stalled(now, 10).is_empty());
+        for _ in 0..MAX_RESENDS {
+            now += 11;
+            assert_eq!(transfers.stalled(now, 10), vec![("upd".to_string(), vec![1])]);
+        }
+        now += 11;
+        assert!(transfers.stalled(now, 10).is_empty());
+        assert!(transfers.is_empty());
+        assert!(!polling.is_in_flight(&"upd".to_string()));
+    }
+
+    #[test]
+    fn it_requests_dropped_chunks_again() {
+        let prefix = PathPrefix::new();
+        let mut transfers = Transfers::new(prefix.0.clone(), Polling::new(), Shutdown::new());
+        transfers.push("upd".to_string(), "sum".to_string(), 0);
+        {
+            let transfer = transfers.get_mut(&"upd".to_string()).unwrap();
+            assert_eq!(transfer.write_chunk(&encode("cccccc"), 1, None), Ok(ChunkStatus::Pending));
+            assert_eq!(transfer.write_chunk(&encode("aaaa"), 0, None), Ok(ChunkStatus::Written));
+            assert_eq!(transfer.transferred_chunks(), vec![0]);
+            assert_eq!(transfer.missing_chunks(), vec![1]);
+            assert!(transfer.assemble_chunks().is_err());
+        }
+
+        let now = time::get_time().sec + 11;
+        assert_eq!(transfers.stalled(now, 10), vec![("upd".to_string(), vec![1])]);
+        let transfer = transfers.get_mut(&"upd".to_string()).unwrap();
+        assert_eq!(transfer.write_chunk(&encode("cc"), 1, None), Ok(ChunkStatus::Written));
+        assert!(transfer.missing_chunks().is_empty());
+    }
 }
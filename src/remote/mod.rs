@@ -1,4 +1,4 @@
-mod dw;
+pub mod dw;
 mod jsonrpc;
 mod parm;
 pub mod rvi;
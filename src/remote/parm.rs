@@ -11,7 +11,8 @@ use std::sync::Mutex;
 pub enum Error {
     UnknownPackage,
     IoFailure,
-    SendFailure
+    SendFailure,
+    ShuttingDown
 }
 pub type Result =  result::Result<Option<Event>, Error>;
 
@@ -55,7 +56,8 @@ pub struct StartParams {
     pub update_id: UpdateId,
     /// The amount of chunks this `Transfer` will have.
     pub chunkscount: u64,
-    /// The SHA1 checksum of the assembled package.
+    /// The checksum of the assembled package, tagged as `sha256:<hex>` or `sha1:<hex>`. Untagged
+    /// checksums are taken as SHA1.
     pub checksum: String
 }
 
@@ -68,11 +70,17 @@ impl ParamHandler for StartParams {
 
         info!("Starting transfer for update_id {}", self.update_id);
 
-        transfers.push(self.update_id.clone(), self.checksum.clone());
+        let chunks = match transfers.push(self.update_id.clone(), self.checksum.clone(), self.chunkscount) {
+            Some(chunks) => chunks,
+            None => {
+                info!("Shutting down, refusing transfer for update_id {}", self.update_id);
+                return Err(Error::ShuttingDown);
+            }
+        };
         services.send_chunk_received(
             ChunkReceived {
                 update_id: self.update_id.clone(),
-                chunks: Vec::new(),
+                chunks: chunks,
                 vin: services.vin.clone() })
             .map_err(|e| {
                 error!("Error on sending start ACK: {}", e);
@@ -92,6 +100,18 @@ pub struct ChunkReceived {
     pub vin: String
 }
 
+/// Encodes the "Resend Chunks" message, asking the server to send missing or corrupt chunks
+/// again.
+#[derive(RustcEncodable)]
+pub struct ResendChunks {
+    /// The transfer to which the chunks belong.
+    pub update_id: UpdateId,
+    /// The chunks to send again.
+    pub chunks: Vec<u64>,
+    /// The VIN of this device.
+    pub vin: String
+}
+
 
 /// Type for messages transferring single chunks.
 #[derive(RustcDecodable)]
@@ -101,7 +121,9 @@ pub struct ChunkParams {
     /// The data of the transferred chunk.
     pub bytes: String,
     /// The index of this chunk.
-    pub index: u64
+    pub index: u64,
+    /// Optional checksum of the decoded chunk data, in the same format as the package checksum.
+    pub hash: Option<String>
 }
 
 impl ParamHandler for ChunkParams {
@@ -111,19 +133,32 @@ impl ParamHandler for ChunkParams {
         let services = services.lock().unwrap();
         let mut transfers = transfers.lock().unwrap();
         transfers.get_mut(&self.update_id).map(|t| {
-            if t.write_chunk(&self.bytes, self.index) {
-                info!("Wrote chunk {} for package {}", self.index, self.update_id);
-                services.send_chunk_received(
-                    ChunkReceived {
-                        update_id: self.update_id.clone(),
-                        chunks: t.transferred_chunks.clone(),
-                        vin: services.vin.clone() })
-                    .map_err(|e| {
-                        error!("Error on sending ChunkReceived: {}", e);
-                        Error::SendFailure })
-                    .map(|_| None)
-            } else {
-                Err(Error::IoFailure)
+            match t.write_chunk(&self.bytes, self.index, self.hash.as_ref().map(|h| h.as_str())) {
+                Ok(status) => {
+                    info!("{:?} chunk {} for package {}", status, self.index, self.update_id);
+                    services.send_chunk_received(
+                        ChunkReceived {
+                            update_id: self.update_id.clone(),
+                            chunks: t.transferred_chunks(),
+                            vin: services.vin.clone() })
+                        .map_err(|e| {
+                            error!("Error on sending ChunkReceived: {}", e);
+                            Error::SendFailure })
+                        .map(|_| None)
+                }
+
+                Err(e) => {
+                    error!("{}", e);
+                    services.send_resend_chunks(
+                        ResendChunks {
+                            update_id: self.update_id.clone(),
+                            chunks: vec![self.index],
+                            vin: services.vin.clone() })
+                        .map_err(|e| {
+                            error!("Error on sending ResendChunks: {}", e);
+                            Error::SendFailure })
+                        .map(|_| None)
+                }
             }
         }).unwrap_or_else(|| {
             error!("Couldn't find transfer for update_id {}", self.update_id);
@@ -143,36 +178,66 @@ pub struct FinishParams {
 
 impl ParamHandler for FinishParams {
     fn handle(&self,
-              _: &Mutex<RemoteServices>,
+              services: &Mutex<RemoteServices>,
               transfers: &Mutex<Transfers>) -> Result {
+        let services = services.lock().unwrap();
         let mut transfers = transfers.lock().unwrap();
-        transfers.get(&self.update_id).ok_or(Error::UnknownPackage)
+        let missing = try!(transfers.get(&self.update_id).ok_or(Error::UnknownPackage)).missing_chunks();
+        if !missing.is_empty() {
+            info!("Transfer of {} is missing chunks {:?}", self.update_id, missing);
+            return services.send_resend_chunks(
+                ResendChunks {
+                    update_id: self.update_id.clone(),
+                    chunks: missing,
+                    vin: services.vin.clone() })
+                .map_err(|e| {
+                    error!("Error on sending ResendChunks: {}", e);
+                    Error::SendFailure })
+                .map(|_| None);
+        }
+
+        let path = match transfers.get_mut(&self.update_id).ok_or(Error::UnknownPackage)
             .and_then(|t| {
                 t.assemble_package().map_err(|_| Error::IoFailure) })
             .and_then(|p| {
-                p.into_os_string().into_string().map_err(|_| Error::IoFailure) })
-            .map(|p| {
-                transfers.remove(&self.update_id);
-                info!("Finished transfer of {}", self.update_id);
-                Some(Event::DownloadComplete(DownloadComplete {
-                    update_id: self.update_id.clone(),
-                    update_image: p,
-                    signature: self.signature.clone() })) })
+                p.into_os_string().into_string().map_err(|_| Error::IoFailure) }) {
+            Ok(path) => path,
+            Err(err) => {
+                error!("Couldn't assemble the package of {}: {:?}", self.update_id, err);
+                transfers.fail(&self.update_id);
+                return Err(err);
+            }
+        };
+        transfers.remove(&self.update_id);
+        info!("Finished transfer of {}", self.update_id);
+        Ok(Some(Event::DownloadComplete(DownloadComplete {
+            update_id: self.update_id.clone(),
+            update_image: path,
+            signature: self.signature.clone() })))
     }
 }
 
 
-/// Type for "Abort Transfer" messages.
+/// Type for "Abort Transfer" messages. Aborts every transfer when no
+/// `update_id` is given.
 #[derive(RustcDecodable)]
-pub struct AbortParams;
+pub struct AbortParams {
+    pub update_id: Option<UpdateId>
+}
 
 impl ParamHandler for AbortParams {
     fn handle(&self,
               _: &Mutex<RemoteServices>,
               transfers: &Mutex<Transfers>) -> Result {
         let mut transfers = transfers.lock().unwrap();
-        transfers.clear();
-        Ok(None)
+        match self.update_id {
+            // the interpreter cancels the transfer and reports it
+            Some(ref id) => Ok(Some(Event::AbortRequested(id.clone()))),
+            None => {
+                transfers.clear();
+                Ok(None)
+            }
+        }
     }
 }
 
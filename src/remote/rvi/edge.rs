@@ -1,12 +1,18 @@
 //! Implements the RVI facing webservice.
 
-use std::io::{Read, Write};
-use std::thread;
-use hyper::Server;
+use std::{io, mem, thread};
+use std::io::{ErrorKind, Write};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use hyper::{Decoder, Encoder, Next, Server};
+use hyper::header::{ContentLength, ContentType};
+use hyper::mime::{Mime, TopLevel, SubLevel};
+use hyper::net::HttpStream;
 use hyper::server::{Handler, Request, Response};
 use rustc_serialize::json;
 use rustc_serialize::json::Json;
-use url::Url;
+
+use datatype::{Error, Url};
 
 use remote::jsonrpc;
 use remote::jsonrpc::{OkResponse, ErrResponse};
@@ -71,30 +77,28 @@ impl<H: ServiceHandler + 'static> ServiceEdge<H> {
 
     /// Starts the service edge.
     ///
-    /// It binds on the provided `host:port` combination, registers all services and then waits for
-    /// incoming RVI messages. On incoming messages it forks another thread and passes the message
-    /// to the provided `Handler`. For details about how to implement a `Handler` see the
-    /// [`hyper`](../../hyper/index.html) documentation and the [reference
-    /// implementation](../handler/index.html).
-    ///
-    /// Panics if it can't reach or register in RVI.
+    /// It binds on the provided `host:port` combination, then registers all services in a
+    /// separate thread and waits for incoming RVI messages. Incoming messages are passed to the
+    /// provided `ServiceHandler`. Returns the address the edge is listening on.
     ///
-    /// # Arguments
-    /// * `h`: The `Handler` all messages are passed to.
-    /// * `s`: A `Vector` of service strings to register in RVI.
-    pub fn start(self) {
-        /* TODO: Re-implement with async hyper
-        let addr = (self.edge_url.host().unwrap().to_string(), self.edge_url.port().unwrap());
-
-        self.hdlr.register_services(|s| self.register_service(s));
-        thread::spawn(move || {
-            Server::http(((&*addr.0, addr.1))).and_then(|srv| {
-                info!("Ready to accept connections.");
-                srv.handle(self) })
-                .map_err(|e| error!("Couldn't start server\n{}", e))
-                .unwrap()
-        });
-        */
+    /// Registration panics in its own thread if it can't reach or register in RVI.
+    pub fn start(self) -> Result<SocketAddr, Error> {
+        let host = try!(self.edge_url.0.host_str().map(|host| host.to_string())
+                        .ok_or_else(|| Error::ParseError(format!("no host in edge URL: {}", self.edge_url.to_string()))));
+        let port = self.edge_url.0.port_or_known_default().unwrap_or(80);
+        let addr = try!(format!("{}:{}", host, port).parse::<SocketAddr>()
+                        .map_err(|e| Error::ParseError(format!("invalid edge address: {}", e))));
+
+        let edge   = Arc::new(self);
+        let server = try!(Server::http(&addr).map_err(|e| Error::RviError(format!("couldn't start edge: {}", e))));
+        let handler_edge   = edge.clone();
+        let (addr, server) = try!(server.handle(move |_| EdgeHandler::new(handler_edge.clone()))
+                                  .map_err(|e| Error::RviError(format!("couldn't start edge: {}", e))));
+        thread::spawn(move || server.run());
+        info!("RVI edge listening on http://{}", addr);
+
+        thread::spawn(move || edge.hdlr.register_services(|s| edge.register_service(s)));
+        Ok(addr)
     }
 
     /// Try to parse the type of a message and forward it to the appropriate message handler.
@@ -137,32 +141,79 @@ impl<H: ServiceHandler + 'static> ServiceEdge<H> {
     }
 }
 
-/* TODO: Re-implement with async hyper
-impl<H: ServiceHandler + 'static> Handler for ServiceEdge<H> {
-    fn handle(&self, mut req: Request, resp: Response) {
-        let mut rbody = String::new();
-        try_or!(req.read_to_string(&mut rbody), return);
-        debug!(">>> Received Message: {}", rbody);
-        let mut resp = try_or!(resp.start(), return);
-
-        macro_rules! send_response {
-            ($rtype:ty, $resp:ident) => {
-                match json::encode::<$rtype>(&$resp) {
-                    Ok(decoded_msg) => {
-                        try_or!(resp.write_all(decoded_msg.as_bytes()), return);
-                        debug!("<<< Sent Response: {}", decoded_msg);
-                    },
-                    Err(p) => { error!("{}", p); }
-                }
-            };
+/// Handles a single incoming RVI request on the service edge.
+struct EdgeHandler<H: ServiceHandler + 'static> {
+    edge:      Arc<ServiceEdge<H>>,
+    req_body:  Vec<u8>,
+    resp_body: Vec<u8>,
+    written:   usize,
+}
+
+impl<H: ServiceHandler + 'static> EdgeHandler<H> {
+    fn new(edge: Arc<ServiceEdge<H>>) -> EdgeHandler<H> {
+        EdgeHandler {
+            edge:      edge,
+            req_body:  Vec::new(),
+            resp_body: Vec::new(),
+            written:   0,
+        }
+    }
+
+    fn handle_request(&mut self) -> Next {
+        let body = mem::replace(&mut self.req_body, Vec::new());
+        let body = String::from_utf8_lossy(&body);
+        debug!(">>> Received Message: {}", body);
+
+        let encoded = match self.edge.handle_message(&body) {
+            Ok(msg)  => json::encode::<OkResponse<i32>>(&msg),
+            Err(msg) => json::encode::<ErrResponse>(&msg),
+        };
+        match encoded {
+            Ok(resp) => {
+                debug!("<<< Sent Response: {}", resp);
+                self.resp_body = resp.into_bytes();
+            }
+            Err(err) => error!("{}", err)
         }
+        Next::write()
+    }
+}
+
+impl<H: ServiceHandler + 'static> Handler<HttpStream> for EdgeHandler<H> {
+    fn on_request(&mut self, _: Request) -> Next {
+        Next::read()
+    }
 
-        match self.handle_message(&rbody) {
-            Ok(msg) => send_response!(OkResponse<i32>, msg),
-            Err(msg) => send_response!(ErrResponse, msg)
+    fn on_request_readable(&mut self, transport: &mut Decoder<HttpStream>) -> Next {
+        match io::copy(transport, &mut self.req_body) {
+            Ok(0) => self.handle_request(),
+            Ok(_) => Next::read(),
+            Err(ref err) if err.kind() == ErrorKind::WouldBlock => Next::read(),
+            Err(err) => {
+                error!("unable to read RVI request: {}", err);
+                Next::remove()
+            }
         }
+    }
+
+    fn on_response(&mut self, resp: &mut Response) -> Next {
+        let mut headers = resp.headers_mut();
+        headers.set(ContentType(Mime(TopLevel::Application, SubLevel::Json, vec![])));
+        headers.set(ContentLength(self.resp_body.len() as u64));
+        if self.resp_body.is_empty() { Next::end() } else { Next::write() }
+    }
 
-        try_or!(resp.end(), return);
+    fn on_response_writable(&mut self, transport: &mut Encoder<HttpStream>) -> Next {
+        match transport.write(&self.resp_body[self.written..]) {
+            Ok(n) => {
+                self.written += n;
+                if self.written < self.resp_body.len() { Next::write() } else { Next::end() }
+            }
+            Err(ref err) if err.kind() == ErrorKind::WouldBlock => Next::write(),
+            Err(err) => {
+                error!("unable to write RVI response: {}", err);
+                Next::remove()
+            }
+        }
     }
 }
-*/
@@ -1,12 +1,9 @@
 //! Helper functions for sending messages to RVI.
 
-use std::io::Read;
-use hyper::Client;
-use hyper::header::ContentType;
-use hyper::mime::{Mime, TopLevel, SubLevel};
 use rustc_serialize::{json, Encodable};
-use url::Url;
 
+use datatype::{Auth, Method, Url};
+use http_client::{AuthClient, HttpClient, HttpRequest};
 use remote::jsonrpc;
 use remote::rvi::message::RVIMessage;
 
@@ -19,27 +16,23 @@ use remote::rvi::message::RVIMessage;
 /// * `url`: The full URL where RVI can be reached.
 /// * `b`: The object to encode and send to RVI.
 pub fn send<E: Encodable>(url: Url, b: &E) -> Result<String, String> {
-    /* TODO: Re-implement with async hyper
-    let client = Client::new();
-
-    let mut resp = try!(json::encode(b)
-        .map_err(|e| format!("{}", e))
-        .and_then(|j| {
-            debug!("<<< Sent Message: {}", j);
-            client.post(url)
-                .header(ContentType(Mime(TopLevel::Application, SubLevel::Json, vec![])))
-                .body(&j)
-                .send()
-                .map_err(|e| format!("{}", e))
-        }));
-
-    let mut rbody = String::new();
-    try!(resp.read_to_string(&mut rbody)
-         .map_err(|e| format!("{}", e)));
+    let body = try!(json::encode(b).map_err(|e| format!("{}", e)));
+    debug!("<<< Sent Message: {}", body);
+
+    let client  = AuthClient::new(Auth::None);
+    let resp_rx = client.send_request(HttpRequest {
+        method:          Method::Post,
+        url:             url,
+        body:            Some(body.into_bytes()),
+        idempotency_key: None,
+        progress:        None,
+        range:           None,
+    });
+    let data  = try!(try!(resp_rx.recv().ok_or("no response from RVI".to_string()))
+                     .map_err(|e| format!("{}", e)));
+    let rbody = try!(String::from_utf8(data).map_err(|e| format!("{}", e)));
     debug!(">>> Received Response: {}", rbody);
     Ok(rbody)
-    */
-    Ok("".into())
 }
 
 /// Prepare a message and send it to RVI. Returns the full response from RVI on success or a error
@@ -56,7 +49,6 @@ pub fn send<E: Encodable>(url: Url, b: &E) -> Result<String, String> {
 /// * `url`: The full URL where RVI can be reached.
 /// * `b`: The object to wrap into a RVI Message, encode and send to RVI.
 /// * `addr`: The full RVI address (service URL) where this message should be sent to.
-#[cfg(not(test))]
 pub fn send_message<E: Encodable>(url: Url, b: E, addr: &str) -> Result<String, String> {
     let mut params = Vec::new();
     params.push(b);
@@ -65,10 +57,3 @@ pub fn send_message<E: Encodable>(url: Url, b: E, addr: &str) -> Result<String,
     send(url, &json_rpc)
 }
 
-#[cfg(test)]
-use std::fmt::Display;
-
-#[cfg(test)]
-pub fn send_message<U: IntoUrl + Display, E: Encodable>(url: U, _: E, addr: &str) -> Result<String, String> {
-    Ok(format!("Faked sending to RVI: {}, {}", url, addr))
-}
@@ -11,15 +11,16 @@ use std::time::Duration;
 use chan::Sender;
 use rustc_serialize::{json, Decodable};
 use time;
-use url::Url;
 
-use datatype::{Event, UpdateId};
+use datatype::{Event, UpdateId, Url};
 use datatype::report::{UpdateReport, InstalledSoftware};
-use datatype::config::ClientConfiguration;
+use datatype::config::RviConfig;
+use polling::Polling;
+use shutdown::Shutdown;
 
 use super::upstream::Upstream;
 
-use super::parm::{NotifyParams, StartParams, ChunkParams, ChunkReceived, FinishParams};
+use super::parm::{NotifyParams, StartParams, ChunkParams, ChunkReceived, FinishParams, ResendChunks};
 use super::parm::{ReportParams, AbortParams, ParamHandler};
 use super::dw::Transfers;
 
@@ -71,7 +72,9 @@ pub struct BackendServices {
     /// URL for the "Installation Report" call.
     pub report: String,
     /// URL for the "Get All Packages" call.
-    pub packages: String
+    pub packages: String,
+    /// URL for the "Resend Chunks" call, if the server supports it.
+    pub resend: Option<String>
 }
 
 #[derive(RustcEncodable, Clone)]
@@ -118,6 +121,12 @@ impl RemoteServices {
             .and_then(|ref svcs| rvi::send_message(self.url.clone(), m, &svcs.ack))
     }
 
+    pub fn send_resend_chunks(&self, m: ResendChunks) -> Result<String, String> {
+        self.svcs.iter().next().ok_or(format!("RemoteServices not set"))
+            .and_then(|ref svcs| svcs.resend.clone().ok_or(format!("Server doesn't accept resend requests")))
+            .and_then(|ref resend| rvi::send_message(self.url.clone(), m, resend))
+    }
+
     fn make_start_download(&self, id: UpdateId) -> StartDownload {
         StartDownload {
             vin: self.vin.clone(),
@@ -170,28 +179,32 @@ pub struct ServiceHandler {
     transfers: Arc<Mutex<Transfers>>,
     /// The service URLs that the SOTA server advertised.
     remote_services: Arc<Mutex<RemoteServices>>,
-    /// The full `Configuration` of sota_client.
-    conf: ClientConfiguration
+    /// The `[rvi]` section of the client config.
+    conf: RviConfig
 }
 
 impl ServiceHandler {
     /// Create a new `ServiceHandler`.
     ///
     /// # Arguments
-    /// * `transfers`: A `Transfers` object to store the in-progress `Transfer`s.
     /// * `sender`: A `Sender` to call back into the `main_loop`.
-    /// * `url`: The full URL, where RVI can be reached.
-    /// * `c`: The full `Configuration` of sota_client.
+    /// * `r`: The service URLs of the SOTA server, shared with the interpreter.
+    /// * `c`: The `[rvi]` section of the client config.
+    /// * `p`: The polling state, which keeps an accepted update in flight until its transfer ends.
+    /// * `s`: The shutdown state, which waits for the transfers in progress.
     pub fn new(sender: Sender<Event>,
                r: Arc<Mutex<RemoteServices>>,
-               c: ClientConfiguration) -> ServiceHandler {
-        let transfers = Arc::new(Mutex::new(Transfers::new(c.storage_dir.clone())));
+               c: RviConfig,
+               p: Polling,
+               s: Shutdown) -> ServiceHandler {
+        let transfers = Arc::new(Mutex::new(Transfers::new(c.storage_dir.clone(), p, s)));
         let tc = transfers.clone();
+        let rc = r.clone();
         c.timeout
             .map(|t| {
-                let _ = thread::spawn(move || ServiceHandler::start_timer(tc.deref(), t));
+                let _ = thread::spawn(move || ServiceHandler::start_timer(tc.deref(), rc.deref(), t));
                 info!("Transfers timeout after {}", t)})
-            .unwrap_or(info!("No timeout configured, transfers will never time out."));
+            .unwrap_or_else(|| info!("No timeout configured, transfers will never time out."));
 
         ServiceHandler {
             sender: Mutex::new(sender),
@@ -201,19 +214,35 @@ impl ServiceHandler {
         }
     }
 
-    /// Starts a infinite loop to expire timed out transfers. Checks once a second for timed out
-    /// transfers.
+    /// The in-progress transfers, shared so that a download can be aborted.
+    pub fn transfers(&self) -> Arc<Mutex<Transfers>> {
+        self.transfers.clone()
+    }
+
+    /// Starts a infinite loop to handle stalled transfers. Checks once a second for transfers
+    /// that timed out, and asks the server to resend their missing chunks.
     ///
     /// # Arguments
     /// * `transfers`: Pointer to a `Transfers` object, that stores the transfers to be checked for
     ///   expired timeouts.
+    /// * `services`: Pointer to the `RemoteServices` used to request missing chunks.
     /// * `timeout`: The timeout in seconds.
     pub fn start_timer(transfers: &Mutex<Transfers>,
+                       services: &Mutex<RemoteServices>,
                        timeout: i64) {
         loop {
             thread::sleep(Duration::from_secs(1));
-            let mut transfers = transfers.lock().unwrap();
-            transfers.prune(time::get_time().sec, timeout);
+            let stalled = transfers.lock().unwrap().stalled(time::get_time().sec, timeout);
+            let services = services.lock().unwrap();
+            for (update_id, chunks) in stalled {
+                info!("Requesting chunks {:?} of {} again", chunks, update_id);
+                let _ = services.send_resend_chunks(
+                    ResendChunks {
+                        update_id: update_id,
+                        chunks: chunks,
+                        vin: services.vin.clone() })
+                    .map_err(|e| error!("Error on sending ResendChunks: {}", e));
+            }
         }
     }
 
@@ -274,3 +303,141 @@ impl rvi::ServiceHandler for ServiceHandler {
         remote_svcs.set_remote(svcs.get_vin(self.conf.vin_match), svcs);
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use chan;
+    use chan::{Receiver, Sender};
+    use rustc_serialize::json::Json;
+    use std::env;
+    use std::fs;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use time;
+
+    use super::*;
+    use datatype::{Event, Url};
+    use datatype::config::RviConfig;
+    use datatype::update_request::DownloadComplete;
+    use polling::Polling;
+    use shutdown::Shutdown;
+    use remote::rvi;
+    use remote::rvi::ServiceEdge;
+    use remote::upstream::Upstream;
+
+
+    fn read_request(stream: &mut TcpStream) -> String {
+        let mut reader = BufReader::new(stream);
+        let mut length = 0;
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let line = line.trim_right().to_lowercase();
+            if line.is_empty() {
+                break;
+            } else if line.starts_with("content-length:") {
+                length = line["content-length:".len()..].trim().parse().unwrap();
+            }
+        }
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body).unwrap();
+        String::from_utf8(body).unwrap()
+    }
+
+    /// Answers calls from the client like an RVI node, forwarding each request.
+    fn mock_rvi_node(listener: TcpListener, tx: Sender<Json>) {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let req    = Json::from_str(&read_request(&mut stream)).unwrap();
+            let id     = req.find("id").and_then(|id| id.as_u64()).unwrap();
+            let result = match req.find("method").and_then(|m| m.as_string()) {
+                Some("register_service") => {
+                    let svc = req.find_path(&["params", "service"]).and_then(|s| s.as_string()).unwrap();
+                    format!(r#"{{"status":0,"service":"genivi.org/vin/VIN123{}"}}"#, svc)
+                }
+                _ => "null".to_string()
+            };
+            let resp = format!(r#"{{"jsonrpc":"2.0","id":{},"result":{}}}"#, id, result);
+            write!(stream, "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                   resp.len(), resp).unwrap();
+            tx.send(req);
+        }
+    }
+
+    fn send_to_edge(edge: &Url, service: &str, params: &str) {
+        let msg = format!(r#"{{"jsonrpc":"2.0","id":{},"method":"message","params":{{"service_name":"{}","parameters":[{}]}}}}"#,
+                          time::precise_time_ns(), service, params);
+        rvi::send(edge.clone(), &Json::from_str(&msg).unwrap()).unwrap();
+    }
+
+    fn expect_message(rx: &Receiver<Json>, service: &str) -> Json {
+        let req = rx.recv().unwrap();
+        assert_eq!(req.find_path(&["params", "service_name"]).and_then(|s| s.as_string()), Some(service));
+        req.find_path(&["params", "parameters"]).and_then(|p| p.as_array()).unwrap()[0].clone()
+    }
+
+    #[test]
+    fn rvi_end_to_end() {
+        let listener  = TcpListener::bind("127.0.0.1:0").unwrap();
+        let node_addr = listener.local_addr().unwrap();
+        let (ntx, nrx) = chan::async::<Json>();
+        thread::spawn(move || mock_rvi_node(listener, ntx));
+
+        let edge_port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let mut storage = env::temp_dir();
+        storage.push(format!("ota-rvi-{}", time::precise_time_ns()));
+        let storage = storage.to_str().unwrap().to_string();
+
+        let config = RviConfig {
+            client:      Url::parse(&format!("http://{}", node_addr)).unwrap(),
+            edge:        format!("127.0.0.1:{}", edge_port),
+            storage_dir: storage.clone(),
+            timeout:     None,
+            vin_match:   2,
+        };
+        let edge_url    = config.edge_url().unwrap();
+        let (etx, erx)  = chan::async::<Event>();
+        let remote_svcs = Arc::new(Mutex::new(RemoteServices::new(config.client.clone())));
+        let handler     = ServiceHandler::new(etx, remote_svcs.clone(), config.clone(), Polling::new(), Shutdown::new());
+        ServiceEdge::new(config.client.clone(), edge_url.clone(), handler).start().unwrap();
+        for _ in 0..6 {
+            assert_eq!(nrx.recv().unwrap().find("method").and_then(|m| m.as_string()), Some("register_service"));
+        }
+        assert_eq!(remote_svcs.lock().unwrap().vin, "VIN123");
+
+        send_to_edge(&edge_url, "/sota/notify", r#"{
+            "update_available": {"update_id": "upd1", "signature": "sig", "description": "test",
+                                 "request_confirmation": false, "size": 5},
+            "services": {"start": "genivi.org/backend/sota/start", "ack": "genivi.org/backend/sota/ack",
+                         "report": "genivi.org/backend/sota/report", "packages": "genivi.org/backend/sota/packages"}
+        }"#);
+        match erx.recv().unwrap() {
+            Event::UpdateAvailable(avail) => assert_eq!(avail.update_id, "upd1"),
+            event => panic!("unexpected event: {:?}", event)
+        }
+
+        remote_svcs.lock().unwrap().send_start_download("upd1".to_string()).unwrap();
+        let start = expect_message(&nrx, "genivi.org/backend/sota/start");
+        assert_eq!(start.find("update_id").and_then(|id| id.as_string()), Some("upd1"));
+
+        send_to_edge(&edge_url, "/sota/start",
+                     r#"{"update_id": "upd1", "chunkscount": 1, "checksum": "4e1243bd22c66e76c2ba9eddc1f91394e57f9f83"}"#);
+        let ack = expect_message(&nrx, "genivi.org/backend/sota/ack");
+        assert_eq!(ack.find("chunks").and_then(|c| c.as_array()).map(|c| c.len()), Some(0));
+
+        send_to_edge(&edge_url, "/sota/chunk", r#"{"update_id": "upd1", "bytes": "dGVzdAo=", "index": 0}"#);
+        let ack = expect_message(&nrx, "genivi.org/backend/sota/ack");
+        assert_eq!(ack.find("chunks"), Some(&Json::from_str("[0]").unwrap()));
+
+        send_to_edge(&edge_url, "/sota/finish", r#"{"update_id": "upd1", "signature": "sig"}"#);
+        assert_eq!(erx.recv().unwrap(), Event::DownloadComplete(DownloadComplete {
+            update_id:    "upd1".to_string(),
+            update_image: format!("{}/packages/upd1.spkg", storage),
+            signature:    "sig".to_string(),
+        }));
+        let _ = fs::remove_dir_all(&storage);
+    }
+}
@@ -0,0 +1,228 @@
+use rustc_serialize::json;
+use std::fs;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use datatype::{Error, SecondaryConfig, UpdateRequestId, UpdateResultCode};
+use datatype::report::InstalledFirmware;
+
+
+/// How long a secondary may take to flash an image when no `timeout` is set.
+pub const DEFAULT_TIMEOUT: u64 = 600;
+
+/// How long a secondary may take to answer a query, such as for its firmware.
+pub const QUERY_TIMEOUT: u64 = 10;
+
+/// Installs images on a secondary ECU on behalf of the primary.
+pub trait SecondaryLoader {
+    /// Flash the verified `image` of an update, returning the result code and
+    /// output of the operation.
+    fn install(&self, id: &UpdateRequestId, image: &Path) -> Result<(UpdateResultCode, String), Error>;
+
+    /// The firmware currently running on the secondary.
+    fn installed_firmware(&self) -> Result<InstalledFirmware, Error>;
+}
+
+impl SecondaryConfig {
+    /// The loader for the secondary `module`.
+    pub fn loader(&self, module: &str) -> Box<SecondaryLoader> {
+        Box::new(SocketLoader {
+            module:        module.to_string(),
+            socket:        self.socket.clone(),
+            timeout:       Duration::from_secs(self.timeout.unwrap_or(DEFAULT_TIMEOUT)),
+            query_timeout: Duration::from_secs(QUERY_TIMEOUT),
+        })
+    }
+}
+
+
+/// Talks to a loader process over a local socket, one request per connection.
+///
+/// The primary sends `install <update_id> <image path>`, with the path quoted
+/// as a JSON string, and the loader answers with an `UpdateResultCode` name
+/// followed by its output, e.g. `OK flashed`. For `firmware` it answers with
+/// `<firmware_id> [last_modified]` within the shorter `query_timeout`.
+pub struct SocketLoader {
+    pub module:        String,
+    pub socket:        String,
+    pub timeout:       Duration,
+    pub query_timeout: Duration,
+}
+
+impl SocketLoader {
+    fn request(&self, line: &str, timeout: Duration) -> Result<String, Error> {
+        let mut stream = try!(UnixStream::connect(&self.socket));
+        try!(stream.set_read_timeout(Some(timeout)));
+        try!(stream.write_all(format!("{}\n", line).as_bytes()));
+
+        let mut reply = String::new();
+        try!(BufReader::new(stream).read_line(&mut reply));
+        if reply.trim().is_empty() {
+            return Err(Error::ClientError(format!("No reply from the {} loader", self.module)));
+        }
+        Ok(reply.trim().to_string())
+    }
+}
+
+impl SecondaryLoader for SocketLoader {
+    fn install(&self, id: &UpdateRequestId, image: &Path) -> Result<(UpdateResultCode, String), Error> {
+        let path  = try!(image.to_str().ok_or_else(|| Error::ParseError(format!("Path is not valid UTF-8: {:?}", image))));
+        let reply = try!(self.request(&format!("install {} {}", id, try!(json::encode(&path))), self.timeout));
+        let mut parts = reply.splitn(2, ' ');
+        let name = parts.next().unwrap_or("");
+        let code = try!(json::decode::<UpdateResultCode>(&format!("\"{}\"", name)).map_err(|_| {
+            Error::ParseError(format!("Unknown result code from the {} loader: {}", self.module, reply))
+        }));
+        Ok((code, parts.next().unwrap_or("").to_string()))
+    }
+
+    fn installed_firmware(&self) -> Result<InstalledFirmware, Error> {
+        let reply = try!(self.request("firmware", self.query_timeout));
+        let parts = reply.split_whitespace().collect::<Vec<_>>();
+        let last_modified = match parts.get(1) {
+            Some(time) => try!(time.parse::<u64>().map_err(|_| {
+                Error::ParseError(format!("Couldn't parse firmware time: {}", reply))
+            })),
+            None => 0
+        };
+
+        match parts.len() {
+            1 | 2 => Ok(InstalledFirmware {
+                module:        self.module.clone(),
+                firmware_id:   parts[0].to_string(),
+                last_modified: last_modified,
+            }),
+            _ => Err(Error::ParseError(format!("Couldn't parse firmware: {}", reply)))
+        }
+    }
+}
+
+
+
+/// Remembers the secondaries that each update targets until it has been
+/// installed, as an `<update_id>.targets` file in the packages directory, so
+/// that a restart doesn't install them on this host instead.
+pub struct TargetStore {
+    dir: PathBuf,
+}
+
+impl TargetStore {
+    pub fn new(packages_dir: &str) -> TargetStore {
+        TargetStore { dir: PathBuf::from(packages_dir) }
+    }
+
+    pub fn save(&self, id: &UpdateRequestId, targets: &[String]) -> Result<(), Error> {
+        try!(fs::create_dir_all(&self.dir));
+        let mut file = try!(File::create(self.path(id)));
+        Ok(try!(file.write_all(try!(json::encode(&targets)).as_bytes())))
+    }
+
+    pub fn load(&self, id: &UpdateRequestId) -> Option<Vec<String>> {
+        let mut text = String::new();
+        match File::open(self.path(id)).and_then(|mut file| file.read_to_string(&mut text)) {
+            Ok(_)  => json::decode(&text).map_err(|err| error!("Couldn't read the targets of {}: {}", id, err)).ok(),
+            Err(_) => None
+        }
+    }
+
+    pub fn remove(&self, id: &UpdateRequestId) {
+        let _ = fs::remove_file(self.path(id));
+    }
+
+    fn path(&self, id: &UpdateRequestId) -> PathBuf {
+        self.dir.join(format!("{}.targets", id))
+    }
+}
+
+
+#[cfg(test)]
+pub mod test_loader {
+    use std::fs;
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::unix::net::UnixListener;
+    use std::thread;
+
+    use remote::dw::random_string;
+
+
+    /// Serve a loader socket that answers `install` requests with `result`
+    /// and reports `firmware_id`, returning the socket path.
+    pub fn serve(result: &'static str, firmware_id: &'static str) -> String {
+        let path = format!("/tmp/sota-secondary-{}.sock", random_string(8));
+        let _    = fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+
+        thread::spawn(move || for stream in listener.incoming() {
+            let mut stream  = stream.unwrap();
+            let mut request = String::new();
+            BufReader::new(stream.try_clone().unwrap()).read_line(&mut request).unwrap();
+            let reply = if request.starts_with("install ") { result } else { firmware_id };
+            stream.write_all(format!("{}\n", reply).as_bytes()).unwrap();
+        });
+        path
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::unix::net::UnixListener;
+    use std::path::Path;
+    use std::thread;
+
+    use super::*;
+    use super::test_loader::serve;
+    use datatype::{SecondaryConfig, UpdateResultCode};
+    use remote::dw::random_string;
+
+
+    #[test]
+    fn quotes_image_paths() {
+        let path     = format!("/tmp/sota-secondary-{}.sock", random_string(8));
+        let listener = UnixListener::bind(&path).unwrap();
+        let config   = SecondaryConfig { socket: path.clone(), timeout: Some(5), hardware_id: None };
+        let request  = thread::spawn(move || {
+            let mut stream  = listener.accept().unwrap().0;
+            let mut request = String::new();
+            BufReader::new(stream.try_clone().unwrap()).read_line(&mut request).unwrap();
+            stream.write_all(b"OK flashed\n").unwrap();
+            request
+        });
+
+        config.loader("brakes").install(&"1".to_string(), Path::new("/tmp/my \"images\"/brakes.img")).unwrap();
+        assert_eq!(request.join().unwrap(), "install 1 \"/tmp/my \\\"images\\\"/brakes.img\"\n".to_string());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn stores_targets() {
+        let dir   = format!("/tmp/sota-targets-{}", random_string(8));
+        let store = TargetStore::new(&dir);
+        let id    = "1".to_string();
+        assert_eq!(store.load(&id), None);
+        store.save(&id, &["brakes".to_string()]).unwrap();
+        assert_eq!(TargetStore::new(&dir).load(&id), Some(vec!["brakes".to_string()]));
+        store.remove(&id);
+        assert_eq!(store.load(&id), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn socket_loader() {
+        let config = SecondaryConfig { socket: serve("FLASH_FAILED bad block", "2.1 40"), timeout: Some(5), hardware_id: None };
+        let loader = config.loader("brakes");
+
+        let (code, output) = loader.install(&"1".to_string(), Path::new("/tmp/brakes.img")).unwrap();
+        assert_eq!(code, UpdateResultCode::FLASH_FAILED);
+        assert_eq!(output, "bad block".to_string());
+        let firmware = loader.installed_firmware().unwrap();
+        assert_eq!((firmware.module, firmware.firmware_id, firmware.last_modified), ("brakes".to_string(), "2.1".to_string(), 40));
+
+        let missing = SecondaryConfig { socket: "/tmp/sota-no-such-loader.sock".to_string(), timeout: None, hardware_id: None };
+        assert!(missing.loader("doors").installed_firmware().is_err());
+    }
+}
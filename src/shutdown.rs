@@ -0,0 +1,126 @@
+//! Coordinates an orderly shutdown. Once a shutdown is requested no new work
+//! is accepted, and the process may exit when the work in flight has drained.
+
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+
+#[derive(Default)]
+struct State {
+    requested: bool,
+    busy:      usize,
+}
+
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    state: Arc<(Mutex<State>, Condvar)>,
+}
+
+impl Shutdown {
+    pub fn new() -> Shutdown {
+        Shutdown::default()
+    }
+
+    /// Stop accepting new work and wake any thread waiting for the request.
+    pub fn request(&self) {
+        let &(ref lock, ref cvar) = &*self.state;
+        lock.lock().unwrap().requested = true;
+        cvar.notify_all();
+    }
+
+    pub fn is_requested(&self) -> bool {
+        let &(ref lock, _) = &*self.state;
+        lock.lock().unwrap().requested
+    }
+
+    /// Register a unit of work that a shutdown should wait for, which lasts
+    /// until the returned guard is dropped. Returns `None` once a shutdown has
+    /// been requested.
+    pub fn begin(&self) -> Option<WorkGuard> {
+        let &(ref lock, _) = &*self.state;
+        let mut state = lock.lock().unwrap();
+        if state.requested {
+            None
+        } else {
+            state.busy += 1;
+            Some(WorkGuard { shutdown: self.clone() })
+        }
+    }
+
+    /// Block until a shutdown is requested.
+    pub fn wait_requested(&self) {
+        let &(ref lock, ref cvar) = &*self.state;
+        let mut state = lock.lock().unwrap();
+        while !state.requested {
+            state = cvar.wait(state).unwrap();
+        }
+    }
+
+    /// Wait up to `timeout` for the work in flight to finish, returning
+    /// `false` if some work had to be abandoned.
+    pub fn wait_idle(&self, timeout: Duration) -> bool {
+        let &(ref lock, ref cvar) = &*self.state;
+        let deadline  = Instant::now() + timeout;
+        let mut state = lock.lock().unwrap();
+        while state.busy > 0 {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            state = cvar.wait_timeout(state, deadline - now).unwrap().0;
+        }
+        true
+    }
+
+    fn finish(&self) {
+        let &(ref lock, ref cvar) = &*self.state;
+        lock.lock().unwrap().busy -= 1;
+        cvar.notify_all();
+    }
+}
+
+
+/// Marks work in flight until dropped.
+pub struct WorkGuard {
+    shutdown: Shutdown,
+}
+
+impl Drop for WorkGuard {
+    fn drop(&mut self) {
+        self.shutdown.finish();
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+
+
+    #[test]
+    fn refuses_work_after_request() {
+        let shutdown = Shutdown::new();
+        assert!(shutdown.begin().is_some());
+        shutdown.request();
+        assert!(shutdown.is_requested());
+        assert!(shutdown.begin().is_none());
+        assert!(shutdown.wait_idle(Duration::from_millis(10)));
+    }
+
+    #[test]
+    fn waits_for_work_in_flight() {
+        let shutdown = Shutdown::new();
+        let guard    = shutdown.begin().unwrap();
+        shutdown.request();
+        assert!(!shutdown.wait_idle(Duration::from_millis(10)));
+
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            drop(guard);
+        });
+        assert!(shutdown.wait_idle(Duration::from_secs(5)));
+    }
+}
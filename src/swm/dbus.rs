@@ -5,6 +5,10 @@ use toml::{decode, Table, Value};
 static MISSING_ARG: &'static str = "Error.MissingArgument";
 /// DBus error string to indicate a malformed argument.
 static MALFORMED_ARG: &'static str = "Error.MalformedArgument";
+/// DBus error string to indicate an unknown interface.
+static UNKNOWN_INTERFACE: &'static str = "org.freedesktop.DBus.Error.UnknownInterface";
+/// DBus error string to indicate an unknown property.
+static UNKNOWN_PROPERTY: &'static str = "org.freedesktop.DBus.Error.UnknownProperty";
 
 /// Format a DBus error message indicating a missing argument.
 pub fn missing_arg() -> (&'static str, String) {
@@ -16,6 +20,16 @@ pub fn malformed_arg() -> (&'static str, String) {
     (MALFORMED_ARG, "Malformed argument".to_string())
 }
 
+/// Format a DBus error message indicating an unknown interface.
+pub fn unknown_interface(name: &str) -> (&'static str, String) {
+    (UNKNOWN_INTERFACE, format!("Unknown interface: {}", name))
+}
+
+/// Format a DBus error message indicating an unknown property.
+pub fn unknown_property(name: &str) -> (&'static str, String) {
+    (UNKNOWN_PROPERTY, format!("Unknown property: {}", name))
+}
+
 
 struct DecodableValue(Value);
 
@@ -1,63 +1,112 @@
-use std::sync::{Arc, Mutex};
 use chan::Sender;
+use std::path::PathBuf;
 
-use datatype::{Command, Event};
-use datatype::Command::*;
-
+use datatype::{Command, Config, DBusConfig, Event, UpdateReport, UpdateResultCode};
+use http_client::HttpClient;
 use interpreter::Interpreter;
-use interaction_library::gateway::Interpret;
+use swm::swlm;
+use uptane;
 
-pub struct SwmEventInterpreter;
 
-use datatype::config::DBusConfiguration;
-use swm::swlm;
+/// Replaces the `EventInterpreter` when a software loading manager is
+/// available over D-Bus. Updates are announced to the manager, which starts
+/// their download and installs them, and it provides the installed software.
+/// Downloads are verified against the Uptane metadata, when configured, before
+/// they are handed over.
+pub struct SwmEventInterpreter {
+    pub config:        DBusConfig,
+    pub client_config: Config,
+    pub http_client:   Box<HttpClient>,
+}
 
 impl Interpreter<Event, Command> for SwmEventInterpreter {
     fn interpret(&mut self, event: Event, ctx: &Sender<Command>) {
-        let cfg = DBusConfiguration::default();
         info!("Event interpreter: {:?}", event);
         match event {
-            Event::UpdateAvailable(e) => {
-                swlm::send_update_available(&cfg, e);
+            Event::NotAuthenticated => {
+                debug!("Trying to authenticate again...");
+                ctx.send(Command::Authenticate(None));
             }
-            Event::DownloadComplete(e) => {
-                swlm::send_download_complete(&cfg, e);
+
+            Event::UpdateAvailable(avail) => {
+                let _ = swlm::send_update_available(&self.config, avail)
+                    .map_err(|err| error!("Couldn't announce the update: {}", err));
             }
-            Event::GetInstalledSoftware(e) => {
-                let _ = swlm::send_get_installed_software(&cfg, e)
-                    .map(|e| ctx.send(Command::ReportInstalledSoftware(e)));
+
+            Event::DownloadComplete(dl) => {
+                let path = PathBuf::from(&dl.update_image);
+                match uptane::verify_update(&self.client_config, self.http_client.as_ref(), &dl.update_id, &path) {
+                    Ok(_) => {
+                        let _ = swlm::send_download_complete(&self.config, dl)
+                            .map_err(|err| error!("Couldn't hand over the download: {}", err));
+                    }
+
+                    Err(err) => {
+                        error!("Download of {} failed verification: {}", dl.update_id, err);
+                        let report = UpdateReport::single(dl.update_id, UpdateResultCode::VALIDATION_FAILED, format!("{}", err));
+                        ctx.send(Command::UpdateReport(report));
+                    }
+                }
+            }
+
+            Event::AbortRequested(id) => {
+                ctx.send(Command::AbortDownload(id));
             }
-            _ => {}
+
+            Event::GetInstalledSoftware(get) => {
+                match swlm::send_get_installed_software(&self.config, get.clone()) {
+                    Ok(sw)   => ctx.send(Command::ReportInstalledSoftware(sw)),
+                    Err(err) => {
+                        error!("Couldn't get the installed software, reporting the local inventory: {}", err);
+                        ctx.send(Command::SendInstalledSoftware(get));
+                    }
+                }
+            }
+
+            _ => ()
         }
     }
 }
 
 
-use remote::upstream::Upstream;
-pub struct UpstreamInterpreter<U> where U: Upstream {
-    pub upstream: Arc<Mutex<U>>
-}
-
-pub type Global = Interpret<Command, Event>;
+#[cfg(test)]
+mod tests {
+    use chan;
 
-impl<U> Interpreter<Global, Event> for UpstreamInterpreter<U> where U: Upstream{
-    fn interpret(&mut self, w: Global, _global_tx: &Sender<Event>) {
+    use super::*;
+    use datatype::{Command, Config, DBusConfig, Event, UpdateReport, UpdateResultCode, UptaneConfig, Url};
+    use datatype::update_request::DownloadComplete;
+    use http_client::TestHttpClient;
+    use interpreter::Interpreter;
 
-        info!("UpstreamInterpreter: {:?}", w.command);
-        match w.command {
-            AcceptUpdates(ids) => {
-                let _ = self.upstream.lock().unwrap().send_start_download(ids[0].clone());
-            }
 
-            UpdateReport(report) => {
-                let _ = self.upstream.lock().unwrap().send_update_report(report);
-            }
+    #[test]
+    fn reports_unverified_downloads() {
+        let mut config = Config::default();
+        config.uptane  = Some(UptaneConfig {
+            director_server: Url::parse("http://127.0.0.1:8090").unwrap(),
+            image_server:    Url::parse("http://127.0.0.1:8091").unwrap(),
+            metadata_dir:    "/tmp/sota-swm-no-metadata".to_string(),
+            hardware_id:     "tcu".to_string(),
+        });
+        let mut swm = SwmEventInterpreter {
+            config:        DBusConfig::default(),
+            client_config: config,
+            http_client:   Box::new(TestHttpClient::new()),
+        };
 
-            ReportInstalledSoftware(installed) => {
-                let _ = self.upstream.lock().unwrap().send_installed_software(installed);
+        let (ctx, crx) = chan::sync(1);
+        swm.interpret(Event::DownloadComplete(DownloadComplete {
+            update_id:    "update-1".to_string(),
+            update_image: "/tmp/sota-swm-no-image".to_string(),
+            signature:    "".to_string(),
+        }), &ctx);
+        match crx.recv().unwrap() {
+            Command::UpdateReport(UpdateReport { ref update_id, ref operation_results }) => {
+                assert_eq!(update_id, "update-1");
+                assert_eq!(operation_results[0].result_code, UpdateResultCode::VALIDATION_FAILED);
             }
-
-            _ => {}
+            cmd => panic!("expected an update report, got {:?}", cmd)
         }
     }
 }
@@ -1,4 +1,5 @@
 pub mod dbus;
 pub mod sc;
+pub mod status;
 pub mod swlm;
 pub mod interpreter;
@@ -1,20 +1,28 @@
 //! Receiving side of the DBus interface.
 
+use std::cell::RefCell;
 use std::fmt::Debug;
 use std::sync::{Arc, Mutex};
-use chan::Sender;
+use std::sync::atomic::{AtomicBool, Ordering};
+use chan::{Receiver, Sender};
+use time;
 
-use dbus::{Connection, NameFlag, BusType, ConnectionItem, Message, FromMessageItem};
+use dbus::{Connection, NameFlag, BusType, ConnectionItem, Message, MessageItem, FromMessageItem};
 use dbus::obj::*;
 
 use rustc_serialize::{Decodable, Encodable};
 
-use datatype::config::DBusConfiguration;
+use datatype::{DBusConfig, Error, Event};
 use datatype::command::Command;
 use datatype::report::{UpdateReport, OperationResults};
-use interaction_library::gateway::{Gateway, Interpret};
+use interaction_library::gateway::Interpret;
 
 use super::dbus::*;
+use super::status::{ClientStatus, PROPERTIES};
+
+
+/// The standard interface for reading D-Bus properties.
+static PROPERTIES_INTERFACE: &'static str = "org.freedesktop.DBus.Properties";
 
 
 /// Encodes the state that is needed to accept incoming DBus messages.
@@ -22,7 +30,7 @@ pub struct SotaC<C, E>
     where C: Decodable + Send + Clone + Debug + 'static,
           E: Encodable + Send + Clone + Debug + 'static {
     /// The configuration for the DBus interface.
-    config: DBusConfiguration,
+    config: DBusConfig,
     /// A sender to forward incoming messages.
     sender: Arc<Mutex<Sender<Interpret<C, E>>>>,
 }
@@ -34,50 +42,13 @@ impl<E> SotaC<Command, E>
     /// # Arguments
     /// * `c`: The configuration for the DBus interface.
     /// * `s`: A sender to forward incoming messages.
-    pub fn new(c: DBusConfiguration, tx: Sender<Interpret<Command, E>>) -> SotaC<Command, E> {
+    pub fn new(c: DBusConfig, tx: Sender<Interpret<Command, E>>) -> SotaC<Command, E> {
         SotaC {
             config: c,
             sender: Arc::new(Mutex::new(tx)),
         }
     }
 
-    /// Start the listener. It will register in DBus according to the configuration, wait for
-    /// incoming messages and forward them via the internal `Sender`.
-    pub fn start(&self) {
-        let conn = Connection::get_private(BusType::Session).unwrap();
-        conn.register_name(&self.config.name, NameFlag::ReplaceExisting as u32).unwrap();
-
-        let initiate_download = Method::new(
-            "initiateDownload",
-            vec!(Argument::new("update_id", "s")),
-            vec!(),
-            Box::new(|msg| self.handle_initiate_download(msg)));
-        let abort_download = Method::new(
-            "abortDownload",
-            vec!(Argument::new("update_id", "s")),
-            vec!(),
-            Box::new(|msg| self.handle_abort_download(msg)));
-        let update_report = Method::new(
-            "updateReport",
-            vec!(Argument::new("update_id", "s"), Argument::new("operations_results", "aa{sv}")),
-            vec!(),
-            Box::new(|msg| self.handle_update_report(msg)));
-        let interface = Interface::new(vec!(initiate_download, abort_download, update_report), vec!(), vec!());
-
-        let mut object_path = ObjectPath::new(&conn, &self.config.path, true);
-        object_path.insert_interface(&self.config.interface, interface);
-        object_path.set_registered(true).unwrap();
-
-        for n in conn.iter(1000) {
-            match n {
-                ConnectionItem::MethodCall(mut m) => {
-                    object_path.handle_message(&mut m);
-                },
-                _ => {}
-            }
-        }
-    }
-
     /// Handles incoming "Initiate Download" messages.
     ///
     /// Parses the message and forwards it to the internal `Sender`.
@@ -102,13 +73,10 @@ impl<E> SotaC<Command, E>
         trace!("sender: {:?}", sender);
         trace!("msg: {:?}", msg);
 
-        // TODO: Implement feature
-        /*
         let mut args = msg.get_items().into_iter();
         let arg = try!(args.next().ok_or(missing_arg()));
         let update_id: &String = try!(FromMessageItem::from(&arg).or(Err(malformed_arg())));
         self.send(Command::AbortDownload(update_id.clone()));
-        */
 
         Ok(vec!())
     }
@@ -137,17 +105,162 @@ impl<E> SotaC<Command, E>
     }
 }
 
-fn get_sender(msg: &Message) -> Option<String> {
-    msg.sender().map(|s| s.to_string())
-}
+impl SotaC<Command, Event> {
+    /// Start the listener. It will register in DBus according to the configuration, wait for
+    /// incoming messages and forward them via the internal `Sender`. Returns once `stop` is set,
+    /// after releasing the bus name, or if the registration fails.
+    ///
+    /// The client status is tracked from `events` and exported as read-only properties, with a
+    /// `PropertiesChanged` signal for each change and an `UpdateStateChanged` signal for each
+    /// update state change.
+    pub fn start(&self, events: Receiver<Event>, stop: &AtomicBool) -> Result<(), Error> {
+        let conn = try!(Connection::get_private(BusType::Session));
+        try!(conn.register_name(&self.config.name, NameFlag::ReplaceExisting as u32));
+        let status = RefCell::new(ClientStatus::new());
 
+        let initiate_download = Method::new(
+            "initiateDownload",
+            vec!(Argument::new("update_id", "s")),
+            vec!(),
+            Box::new(|msg| self.handle_initiate_download(msg)));
+        let abort_download = Method::new(
+            "abortDownload",
+            vec!(Argument::new("update_id", "s")),
+            vec!(),
+            Box::new(|msg| self.handle_abort_download(msg)));
+        let update_report = Method::new(
+            "updateReport",
+            vec!(Argument::new("update_id", "s"), Argument::new("operations_results", "aa{sv}")),
+            vec!(),
+            Box::new(|msg| self.handle_update_report(msg)));
+        let update_state_changed = Signal::new(
+            "UpdateStateChanged",
+            vec!(Argument::new("update_id", "s"), Argument::new("state", "s")));
+        let interface = Interface::new(vec!(initiate_download, abort_download, update_report),
+                                       vec!(), vec!(update_state_changed));
+
+        let get = Method::new(
+            "Get",
+            vec!(Argument::new("interface_name", "s"), Argument::new("property_name", "s")),
+            vec!(Argument::new("value", "v")),
+            Box::new(|msg| self.handle_get_property(msg, &status.borrow())));
+        let get_all = Method::new(
+            "GetAll",
+            vec!(Argument::new("interface_name", "s")),
+            vec!(Argument::new("properties", "a{sv}")),
+            Box::new(|msg| self.handle_get_all_properties(msg, &status.borrow())));
+        let properties_changed = Signal::new(
+            "PropertiesChanged",
+            vec!(Argument::new("interface_name", "s"), Argument::new("changed_properties", "a{sv}"),
+                 Argument::new("invalidated_properties", "as")));
+        let properties = Interface::new(vec!(get, get_all), vec!(), vec!(properties_changed));
+
+        let mut object_path = ObjectPath::new(&conn, &self.config.path, true);
+        object_path.insert_interface(&self.config.interface, interface);
+        object_path.insert_interface(PROPERTIES_INTERFACE, properties);
+        try!(object_path.set_registered(true));
+        info!("Listening on D-Bus as {}", self.config.name);
+
+        for n in conn.iter(100) {
+            if stop.load(Ordering::SeqCst) {
+                break;
+            }
+            match n {
+                ConnectionItem::MethodCall(mut m) => {
+                    object_path.handle_message(&mut m);
+                },
+                _ => {}
+            }
+            while let Some(event) = next_event(&events) {
+                self.publish(&conn, &mut status.borrow_mut(), event);
+            }
+        }
+
+        try!(object_path.set_registered(false));
+        try!(conn.release_name(&self.config.name));
+        info!("Released the D-Bus name {}", self.config.name);
+        Ok(())
+    }
+
+    /// Updates the client status from an event and emits the resulting signals.
+    fn publish(&self, conn: &Connection, status: &mut ClientStatus, event: Event) {
+        let changed = status.update(&event, time::get_time().sec);
+        if !changed.is_empty() {
+            let entries = changed.iter()
+                .filter_map(|name| status.property(name).map(|value| property_entry(name, value)))
+                .collect();
+            let args = [
+                MessageItem::Str(self.config.interface.clone()),
+                MessageItem::new_array(entries).expect("changed properties"),
+                MessageItem::Array(Vec::new(), "as".into())];
+            self.emit(conn, PROPERTIES_INTERFACE, "PropertiesChanged", &args);
+        }
+
+        if let Event::UpdateStateChanged(id, state) = event {
+            let args = [MessageItem::Str(id), MessageItem::Str(format!("{:?}", state))];
+            self.emit(conn, &self.config.interface, "UpdateStateChanged", &args);
+        }
+    }
 
+    fn emit(&self, conn: &Connection, interface: &str, name: &str, args: &[MessageItem]) {
+        match Message::new_signal(&self.config.path, interface, name) {
+            Ok(mut signal) => {
+                signal.append_items(args);
+                let _ = conn.send(signal).map_err(|_| error!("Couldn't send the {} signal", name));
+            }
+            Err(err) => error!("Couldn't create the {} signal: {}", name, err)
+        }
+    }
+
+    fn handle_get_property(&self, msg: &mut Message, status: &ClientStatus) -> MethodResult {
+        let mut args = msg.get_items().into_iter();
+        let arg = try!(args.next().ok_or(missing_arg()));
+        let interface: &String = try!(FromMessageItem::from(&arg).or(Err(malformed_arg())));
+        try!(self.check_interface(interface));
+
+        let arg = try!(args.next().ok_or(missing_arg()));
+        let name: &String = try!(FromMessageItem::from(&arg).or(Err(malformed_arg())));
+        let value = try!(status.property(name).ok_or(unknown_property(name)));
+        Ok(vec!(MessageItem::Variant(Box::new(value))))
+    }
+
+    fn handle_get_all_properties(&self, msg: &mut Message, status: &ClientStatus) -> MethodResult {
+        let mut args = msg.get_items().into_iter();
+        let arg = try!(args.next().ok_or(missing_arg()));
+        let interface: &String = try!(FromMessageItem::from(&arg).or(Err(malformed_arg())));
+        try!(self.check_interface(interface));
 
-impl<E> Gateway<Command, E> for SotaC<Command, E>
-    where E: Encodable + Send + Clone + Debug + 'static,
-{
-    fn new(tx: Sender<Interpret<Command, E>>) -> Result<Self, String> {
-        Ok(SotaC::new(DBusConfiguration::default(), tx))
+        let entries = PROPERTIES.iter()
+            .filter_map(|name| status.property(name).map(|value| property_entry(name, value)))
+            .collect();
+        Ok(vec!(MessageItem::new_array(entries).expect("client properties")))
     }
+
+    fn check_interface(&self, interface: &str) -> Result<(), (&'static str, String)> {
+        if interface == self.config.interface {
+            Ok(())
+        } else {
+            Err(unknown_interface(interface))
+        }
+    }
+}
+
+/// Take the next event without blocking.
+fn next_event(events: &Receiver<Event>) -> Option<Event> {
+    let mut next = None;
+    chan_select! {
+        default => (),
+        events.recv() -> event => next = event,
+    }
+    next
+}
+
+fn property_entry(name: &str, value: MessageItem) -> MessageItem {
+    MessageItem::DictEntry(Box::new(MessageItem::Str(name.to_string())),
+                           Box::new(MessageItem::Variant(Box::new(value))))
+}
+
+fn get_sender(msg: &Message) -> Option<String> {
+    msg.sender().map(|s| s.to_string())
 }
 
@@ -0,0 +1,118 @@
+//! The client status that is exported as D-Bus properties.
+
+use std::collections::HashSet;
+
+use dbus::MessageItem;
+
+use datatype::{Event, UpdateRequestId, UpdateState};
+
+
+/// The names of the exported properties.
+pub const PROPERTIES: &'static [&'static str] = &["Authenticated", "CurrentUpdate", "PendingUpdates", "LastSync"];
+
+/// Tracks the client status from the events it emits.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ClientStatus {
+    pub authenticated:  bool,
+    pub current_update: Option<UpdateRequestId>,
+    pub pending:        HashSet<UpdateRequestId>,
+    pub last_sync:      Option<i64>,
+}
+
+impl ClientStatus {
+    pub fn new() -> ClientStatus {
+        ClientStatus::default()
+    }
+
+    /// Apply an event received at `now`, returning the names of the
+    /// properties that changed.
+    pub fn update(&mut self, event: &Event, now: i64) -> Vec<&'static str> {
+        let before = self.clone();
+        match *event {
+            Event::Authenticated    => self.authenticated = true,
+            Event::NotAuthenticated => self.authenticated = false,
+
+            Event::FoundPendingUpdates(ref ids) => {
+                self.pending   = ids.iter().cloned().collect();
+                self.last_sync = Some(now);
+            }
+
+            Event::UpdateAvailable(ref avail) => {
+                self.pending.insert(avail.update_id.clone());
+                self.last_sync = Some(now);
+            }
+
+            Event::UpdateStateChanged(ref id, ref state) => match *state {
+                UpdateState::Downloading |
+                UpdateState::Installing  => self.current_update = Some(id.clone()),
+                UpdateState::Installed   |
+                UpdateState::Failed      |
+                UpdateState::Cancelled   => self.finish(id),
+            },
+
+            Event::UpdateErrored(ref id, _) => self.finish(id),
+
+            _ => ()
+        }
+
+        let mut changed = Vec::new();
+        if self.authenticated != before.authenticated {
+            changed.push("Authenticated");
+        }
+        if self.current_update != before.current_update {
+            changed.push("CurrentUpdate");
+        }
+        if self.pending.len() != before.pending.len() {
+            changed.push("PendingUpdates");
+        }
+        if self.last_sync != before.last_sync {
+            changed.push("LastSync");
+        }
+        changed
+    }
+
+    /// The D-Bus value of a property. An empty `CurrentUpdate` means no update
+    /// is in progress, and a `LastSync` of 0 that the client hasn't synced yet.
+    pub fn property(&self, name: &str) -> Option<MessageItem> {
+        match name {
+            "Authenticated"  => Some(MessageItem::Bool(self.authenticated)),
+            "CurrentUpdate"  => Some(MessageItem::Str(self.current_update.clone().unwrap_or(String::new()))),
+            "PendingUpdates" => Some(MessageItem::UInt32(self.pending.len() as u32)),
+            "LastSync"       => Some(MessageItem::Int64(self.last_sync.unwrap_or(0))),
+            _                => None
+        }
+    }
+
+    fn finish(&mut self, id: &UpdateRequestId) {
+        self.pending.remove(id);
+        if self.current_update.as_ref() == Some(id) {
+            self.current_update = None;
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use datatype::{Event, UpdateState};
+
+
+    #[test]
+    fn tracks_update_progress() {
+        let mut status = ClientStatus::new();
+        let ids        = vec!["1".to_string(), "2".to_string()];
+        assert_eq!(status.update(&Event::Authenticated, 10), vec!["Authenticated"]);
+        assert_eq!(status.update(&Event::FoundPendingUpdates(ids), 20), vec!["PendingUpdates", "LastSync"]);
+
+        let downloading = Event::UpdateStateChanged("1".to_string(), UpdateState::Downloading);
+        assert_eq!(status.update(&downloading, 30), vec!["CurrentUpdate"]);
+        assert_eq!(status.current_update, Some("1".to_string()));
+        assert!(status.update(&Event::Ok, 40).is_empty());
+
+        let installed = Event::UpdateStateChanged("1".to_string(), UpdateState::Installed);
+        assert_eq!(status.update(&installed, 50), vec!["CurrentUpdate", "PendingUpdates"]);
+        assert_eq!(status.property("PendingUpdates"), Some(MessageItem::UInt32(1)));
+        assert_eq!(status.property("LastSync"), Some(MessageItem::Int64(20)));
+    }
+}
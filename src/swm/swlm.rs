@@ -4,60 +4,63 @@ use std::convert::From;
 
 use dbus::{Connection, BusType, MessageItem, Message, FromMessageItem};
 
-use datatype::config::DBusConfiguration;
+use datatype::{DBusConfig, Error};
 use datatype::update_request::{UpdateAvailable, DownloadComplete, GetInstalledSoftware};
 use datatype::report::{InstalledFirmwares, InstalledPackages, InstalledSoftware};
 
-pub fn send_update_available(config: &DBusConfiguration, e: UpdateAvailable) {
+pub fn send_update_available(config: &DBusConfig, e: UpdateAvailable) -> Result<(), Error> {
     let args = [
         MessageItem::from(e.update_id),
         MessageItem::from(e.signature),
         MessageItem::from(e.description),
         MessageItem::from(e.request_confirmation)];
-    let mut message = Message::new_method_call(
-        &config.software_manager, &config.software_manager_path,
-        &config.software_manager, "updateAvailable").unwrap();
-    message.append_items(&args);
-
-    let conn = Connection::get_private(BusType::Session).unwrap();
-    let _ = conn.send(message)
-        .map_err(|_| error!("Couldn't forward message to D-Bus"));
+    send(config, "updateAvailable", &args)
 }
 
-pub fn send_download_complete(config: &DBusConfiguration, e: DownloadComplete) {
+pub fn send_download_complete(config: &DBusConfig, e: DownloadComplete) -> Result<(), Error> {
     let args = [
         MessageItem::from(e.update_image),
         MessageItem::from(e.signature)];
-    let mut message = Message::new_method_call(
-        &config.software_manager, &config.software_manager_path,
-        &config.software_manager, "downloadComplete").unwrap();
-    message.append_items(&args);
-
-    let conn = Connection::get_private(BusType::Session).unwrap();
-    let _ = conn.send(message)
-        .map_err(|_| error!("Couldn't forward message to D-Bus"));
+    send(config, "downloadComplete", &args)
 }
 
-pub fn send_get_installed_software(config: &DBusConfiguration, e: GetInstalledSoftware)
-    -> Result<InstalledSoftware, ()> {
+pub fn send_get_installed_software(config: &DBusConfig, e: GetInstalledSoftware)
+    -> Result<InstalledSoftware, Error> {
     let args = [
         MessageItem::from(e.include_packages),
         MessageItem::from(e.include_module_firmware)];
-    let mut message = Message::new_method_call(
-        &config.software_manager, &config.software_manager_path,
-        &config.software_manager, "getInstalledPackages").unwrap();
-    message.append_items(&args);
+    let message = try!(method_call(config, "getInstalledPackages", &args));
 
-    let conn = Connection::get_private(BusType::Session).unwrap();
-    let msg = conn.send_with_reply_and_block(message, config.timeout).unwrap();
+    let conn = try!(Connection::get_private(BusType::Session));
+    let msg = try!(conn.send_with_reply_and_block(message, config.timeout_ms()));
 
     let mut args = msg.get_items().into_iter();
-    let arg = try!(args.next().ok_or(()));
-    let installed_packages: InstalledPackages = try!(FromMessageItem::from(&arg));
+    let arg = try!(args.next().ok_or(reply_error("missing installed packages")));
+    let installed_packages: InstalledPackages = try!(FromMessageItem::from(&arg)
+        .or(Err(reply_error("malformed installed packages"))));
 
-    let arg = try!(args.next().ok_or(()));
-    let installed_firmware: InstalledFirmwares = try!(FromMessageItem::from(&arg));
+    let arg = try!(args.next().ok_or(reply_error("missing installed firmware")));
+    let installed_firmware: InstalledFirmwares = try!(FromMessageItem::from(&arg)
+        .or(Err(reply_error("malformed installed firmware"))));
 
     Ok(InstalledSoftware::new(installed_packages, installed_firmware))
 }
 
+fn method_call(config: &DBusConfig, method: &str, args: &[MessageItem]) -> Result<Message, Error> {
+    let mut message = try!(Message::new_method_call(
+        &config.software_manager, &config.software_manager_path,
+        &config.software_manager, method).map_err(Error::DBusError));
+    message.append_items(args);
+    Ok(message)
+}
+
+fn send(config: &DBusConfig, method: &str, args: &[MessageItem]) -> Result<(), Error> {
+    let message = try!(method_call(config, method, args));
+    let conn = try!(Connection::get_private(BusType::Session));
+    try!(conn.send(message).map_err(|_| Error::DBusError(format!("couldn't send {}", method))));
+    Ok(())
+}
+
+fn reply_error(msg: &str) -> Error {
+    Error::DBusError(format!("getInstalledPackages reply: {}", msg))
+}
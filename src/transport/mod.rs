@@ -0,0 +1,11 @@
+pub use self::mqtt::MqttTransport;
+pub use self::ota::OtaTransport;
+pub use self::rvi::RviTransport;
+pub use self::test_transport::TestTransport;
+pub use self::transport::Transport;
+
+pub mod mqtt;
+pub mod ota;
+pub mod rvi;
+pub mod test_transport;
+pub mod transport;
@@ -0,0 +1,167 @@
+use chan::Sender;
+use rustc_serialize::Encodable;
+use rustc_serialize::json;
+use rustc_serialize::json::Json;
+use std::path::PathBuf;
+use std::thread;
+
+use datatype::{Config, Error, Event, PendingUpdates, UpdateReport, UpdateRequestId};
+use datatype::report::{InstalledSoftware, UpdateEvent, UpdateReportWithDevice};
+use datatype::update_request::UpdateAvailable;
+use http_client::HttpClient;
+use mqtt::{Incoming, Mqtt};
+use polling::Polling;
+use transport::{OtaTransport, Transport};
+
+
+/// Talks to the OTA server through an MQTT broker, under the device topic
+/// `<topic_prefix>/<device uuid>`. Reports are published to `<topic>/reports`
+/// and `<topic>/installed`, update events to `<topic>/events`, and
+/// `UpdateAvailable` notifications arrive on `<topic>/updates`. Packages are
+/// still fetched from the OTA server.
+pub struct MqttTransport {
+    mqtt:  Mqtt,
+    topic: String,
+    ota:   OtaTransport,
+}
+
+impl MqttTransport {
+    /// Connect to the broker from the `[mqtt]` section, forwarding update
+    /// notifications to `etx`. Polling falls back to `max_polling_backoff`
+    /// while connected.
+    pub fn new(config: &Config, etx: Sender<Event>, polling: Polling) -> Result<MqttTransport, Error> {
+        let mqtt_cfg  = try!(config.mqtt.as_ref().ok_or(Error::MqttError("no [mqtt] section".to_string())));
+        let topic     = mqtt_cfg.device_topic(&config.device);
+        let client_id = mqtt_cfg.client_id.clone().unwrap_or(config.device.uuid.clone());
+        let (mqtt, incoming) = try!(Mqtt::start(mqtt_cfg, config.tls.as_ref(), &client_id, &topic,
+                                                &format!("{}/updates", topic)));
+
+        thread::spawn(move || for msg in incoming {
+            match msg {
+                Incoming::Connected    => polling.set_pushing(true),
+                Incoming::Disconnected => polling.set_pushing(false),
+                Incoming::Message(publish) => {
+                    let update = String::from_utf8(publish.payload).map_err(Error::from)
+                        .and_then(|text| json::decode::<UpdateAvailable>(&text).map_err(Error::from));
+                    match update {
+                        Ok(update) => etx.send(Event::UpdateAvailable(update)),
+                        Err(err)   => error!("Ignoring MQTT message on {}: {}", publish.topic, err)
+                    }
+                }
+            }
+        });
+
+        Ok(MqttTransport { mqtt: mqtt, topic: topic, ota: OtaTransport })
+    }
+}
+
+impl Transport for MqttTransport {
+    fn get_pending_updates(&mut self, config: &Config, client: &HttpClient) -> Result<PendingUpdates, Error> {
+        self.ota.get_pending_updates(config, client)
+    }
+
+    fn download_update(&mut self, config: &Config, client: &HttpClient, id: &UpdateRequestId, etx: &Sender<Event>)
+                       -> Result<Option<PathBuf>, Error> {
+        self.ota.download_update(config, client, id, etx)
+    }
+
+    fn abort_download(&mut self, config: &Config, client: &HttpClient, id: &UpdateRequestId) -> Result<(), Error> {
+        self.ota.abort_download(config, client, id)
+    }
+
+    fn send_update_report(&mut self, config: &Config, _: &HttpClient, report: &UpdateReport, key: Option<&str>)
+                          -> Result<(), Error> {
+        let body = try!(keyed_body(&UpdateReportWithDevice::new(&config.device.uuid, report), key));
+        self.mqtt.publish(&format!("{}/reports", self.topic), body)
+    }
+
+    fn send_installed_software(&mut self, _: &Config, _: &HttpClient, sw: &InstalledSoftware, key: Option<&str>)
+                               -> Result<(), Error> {
+        let body = try!(keyed_body(sw, key));
+        self.mqtt.publish(&format!("{}/installed", self.topic), body)
+    }
+
+    fn send_update_event(&mut self, _: &Config, _: &HttpClient, event: &UpdateEvent, key: Option<&str>)
+                         -> Result<(), Error> {
+        let body = try!(keyed_body(event, key));
+        self.mqtt.publish(&format!("{}/events", self.topic), body)
+    }
+}
+
+// MQTT 3.1.1 has no message headers, so the outbox key is added to the JSON
+// object as `idempotency_key`.
+fn keyed_body<T: Encodable>(message: &T, key: Option<&str>) -> Result<Vec<u8>, Error> {
+    let mut body = try!(Json::from_str(&try!(json::encode(message)))
+                        .map_err(|err| Error::ParseError(format!("{}", err))));
+    if let (Some(key), Some(object)) = (key, body.as_object_mut()) {
+        object.insert("idempotency_key".to_string(), Json::String(key.to_string()));
+    }
+    Ok(body.to_string().into_bytes())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use chan;
+    use rustc_serialize::json;
+
+    use super::*;
+    use datatype::{Config, Event, UpdateReport, UpdateResultCode};
+    use datatype::config::parse_config;
+    use datatype::report::{OperationResult, OperationResults};
+    use datatype::update_request::UpdateAvailable;
+    use http_client::TestHttpClient;
+    use mqtt::client::test_broker;
+    use mqtt::packet::{Packet, Publish};
+    use polling::Polling;
+    use transport::Transport;
+
+
+    #[test]
+    fn publishes_reports_and_forwards_updates() {
+        let (broker, received, to_client) = test_broker::serve();
+        let config: Config = parse_config(&format!("[device]\nuuid = \"car\"\n[mqtt]\nbroker = \"{}\"\n", broker)).unwrap();
+        let (etx, erx) = chan::async::<Event>();
+        let polling    = Polling::new();
+        let mut mqtt   = MqttTransport::new(&config, etx, polling.clone()).unwrap();
+
+        match received.recv().unwrap() {
+            Packet::Connect(connect) => {
+                assert_eq!((connect.client_id.as_str(), connect.clean_session), ("car", false));
+                assert_eq!(connect.will, Some(Publish::new("devices/car/status", b"offline".to_vec(), 1, true)));
+            }
+            packet => panic!("expected CONNECT, got {:?}", packet)
+        }
+        assert!(match received.recv().unwrap() { Packet::Publish(ref p) => p.payload == b"online", _ => false });
+        match received.recv().unwrap() {
+            Packet::Subscribe { topics, .. } => assert_eq!(topics, vec![("devices/car/updates".to_string(), 1)]),
+            packet => panic!("expected SUBSCRIBE, got {:?}", packet)
+        }
+
+        let update = UpdateAvailable {
+            update_id:            "1".to_string(),
+            signature:            "".to_string(),
+            description:          "new firmware".to_string(),
+            request_confirmation: false,
+            size:                 10,
+        };
+        let payload = json::encode(&update).unwrap().into_bytes();
+        to_client.send(Publish::new("devices/car/updates", payload, 1, false));
+        assert_eq!(erx.recv(), Some(Event::UpdateAvailable(update)));
+        assert!(polling.is_pushing());
+        assert_eq!(received.recv(), Some(Packet::PubAck(1000)));
+
+        let result = OperationResult { id: "1".to_string(), result_code: UpdateResultCode::OK, result_text: "".to_string() };
+        let report = UpdateReport::new("1".to_string(), OperationResults(vec![result]));
+        mqtt.send_update_report(&config, &TestHttpClient::new(), &report, Some("key-1")).unwrap();
+        match received.recv().unwrap() {
+            Packet::Publish(publish) => {
+                assert_eq!(publish.topic, "devices/car/reports".to_string());
+                let payload = String::from_utf8(publish.payload).unwrap();
+                assert!(payload.contains("\"device\":\"car\""));
+                assert!(payload.contains("\"idempotency_key\":\"key-1\""));
+            }
+            packet => panic!("expected PUBLISH, got {:?}", packet)
+        }
+    }
+}
@@ -0,0 +1,51 @@
+use chan::Sender;
+use std::fs;
+use std::path::PathBuf;
+
+use datatype::{Config, Error, Event, PendingUpdates, UpdateReport, UpdateRequestId};
+use datatype::report::{InstalledSoftware, UpdateEvent};
+use http_client::HttpClient;
+use ota_plus::OTA;
+use outbox::KeyedClient;
+use transport::Transport;
+
+
+/// Talks to the OTA server over HTTP.
+pub struct OtaTransport;
+
+impl Transport for OtaTransport {
+    fn get_pending_updates(&mut self, config: &Config, client: &HttpClient)
+                           -> Result<PendingUpdates, Error> {
+        OTA::new(config, client).get_package_updates()
+    }
+
+    fn download_update(&mut self, config: &Config, client: &HttpClient, id: &UpdateRequestId, etx: &Sender<Event>)
+                       -> Result<Option<PathBuf>, Error> {
+        OTA::new(config, client).download_package_update(id, etx).map(Some)
+    }
+
+    // Downloads run to completion, so only the package is left to remove.
+    fn abort_download(&mut self, config: &Config, client: &HttpClient, id: &UpdateRequestId)
+                      -> Result<(), Error> {
+        let path = OTA::new(config, client).package_path(id);
+        if path.exists() {
+            try!(fs::remove_file(&path));
+        }
+        Ok(())
+    }
+
+    fn send_update_report(&mut self, config: &Config, client: &HttpClient, report: &UpdateReport, key: Option<&str>)
+                          -> Result<(), Error> {
+        OTA::new(config, &KeyedClient { client: client, key: key }).send_install_report(report)
+    }
+
+    fn send_installed_software(&mut self, config: &Config, client: &HttpClient, sw: &InstalledSoftware,
+                               key: Option<&str>) -> Result<(), Error> {
+        OTA::new(config, &KeyedClient { client: client, key: key }).send_installed_software(sw)
+    }
+
+    fn send_update_event(&mut self, config: &Config, client: &HttpClient, event: &UpdateEvent, key: Option<&str>)
+                         -> Result<(), Error> {
+        OTA::new(config, &KeyedClient { client: client, key: key }).send_update_event(event)
+    }
+}
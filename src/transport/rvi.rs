@@ -0,0 +1,61 @@
+use chan::Sender;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use datatype::{Config, Error, Event, PendingUpdates, UpdateReport, UpdateRequestId};
+use datatype::report::{InstalledSoftware, UpdateEvent};
+use http_client::HttpClient;
+use remote::dw::Transfers;
+use remote::svc::RemoteServices;
+use remote::upstream::Upstream;
+use transport::Transport;
+
+
+/// Talks to the SOTA server through an RVI node. Updates are pushed to the
+/// service edge, so downloads complete asynchronously. RVI has no service for
+/// update events or idempotency keys, so events are dropped and keys ignored.
+pub struct RviTransport {
+    services:  Arc<Mutex<RemoteServices>>,
+    transfers: Arc<Mutex<Transfers>>,
+}
+
+impl RviTransport {
+    pub fn new(services: Arc<Mutex<RemoteServices>>, transfers: Arc<Mutex<Transfers>>) -> RviTransport {
+        RviTransport { services: services, transfers: transfers }
+    }
+}
+
+impl Transport for RviTransport {
+    fn get_pending_updates(&mut self, _: &Config, _: &HttpClient) -> Result<PendingUpdates, Error> {
+        debug!("Updates are pushed over RVI");
+        Ok(PendingUpdates::default())
+    }
+
+    fn download_update(&mut self, _: &Config, _: &HttpClient, id: &UpdateRequestId, _: &Sender<Event>)
+                       -> Result<Option<PathBuf>, Error> {
+        try!(self.services.lock().unwrap().send_start_download(id.clone()).map_err(Error::RviError));
+        Ok(None)
+    }
+
+    // Dropping the transfer removes its partial package and saved state.
+    fn abort_download(&mut self, _: &Config, _: &HttpClient, id: &UpdateRequestId) -> Result<(), Error> {
+        self.transfers.lock().unwrap().remove(id);
+        Ok(())
+    }
+
+    fn send_update_report(&mut self, _: &Config, _: &HttpClient, report: &UpdateReport, _: Option<&str>)
+                          -> Result<(), Error> {
+        self.services.lock().unwrap().send_update_report(report.clone()).map(|_| ()).map_err(Error::RviError)
+    }
+
+    fn send_installed_software(&mut self, _: &Config, _: &HttpClient, sw: &InstalledSoftware, _: Option<&str>)
+                               -> Result<(), Error> {
+        self.services.lock().unwrap().send_installed_software(sw.clone()).map(|_| ()).map_err(Error::RviError)
+    }
+
+    fn send_update_event(&mut self, _: &Config, _: &HttpClient, event: &UpdateEvent, _: Option<&str>)
+                         -> Result<(), Error> {
+        debug!("Not sending update event over RVI: {:?}", event);
+        Ok(())
+    }
+}
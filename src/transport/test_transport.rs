@@ -0,0 +1,70 @@
+use chan::Sender;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use datatype::{Config, Error, Event, PendingUpdateRequest, PendingUpdates, UpdateReport, UpdateRequestId};
+use datatype::report::{InstalledSoftware, UpdateEvent};
+use http_client::HttpClient;
+use transport::Transport;
+
+
+/// An in-memory `Transport` that serves a fixed list of pending updates and
+/// records everything sent back to it.
+///
+/// A downloaded update resolves to the path `"<name> <version>"` of its
+/// package, which the `File` package manager then lists as installed.
+#[derive(Clone, Default)]
+pub struct TestTransport {
+    pub pending:   Vec<PendingUpdateRequest>,
+    pub reports:   Arc<Mutex<Vec<UpdateReport>>>,
+    pub inventory: Arc<Mutex<Vec<InstalledSoftware>>>,
+    pub events:    Arc<Mutex<Vec<UpdateEvent>>>,
+    pub aborted:   Arc<Mutex<Vec<UpdateRequestId>>>,
+}
+
+impl TestTransport {
+    pub fn new() -> TestTransport {
+        TestTransport::default()
+    }
+
+    pub fn from(pending: Vec<PendingUpdateRequest>) -> TestTransport {
+        TestTransport { pending: pending, ..TestTransport::default() }
+    }
+}
+
+impl Transport for TestTransport {
+    fn get_pending_updates(&mut self, _: &Config, _: &HttpClient) -> Result<PendingUpdates, Error> {
+        Ok(PendingUpdates { updates: self.pending.clone(), poll_interval: None })
+    }
+
+    fn download_update(&mut self, _: &Config, _: &HttpClient, id: &UpdateRequestId, _: &Sender<Event>)
+                       -> Result<Option<PathBuf>, Error> {
+        match self.pending.iter().find(|update| update.requestId == *id) {
+            Some(update) => Ok(Some(PathBuf::from(format!("{}", update.packageId)))),
+            None         => Err(Error::ClientError(format!("unknown update: {}", id)))
+        }
+    }
+
+    fn abort_download(&mut self, _: &Config, _: &HttpClient, id: &UpdateRequestId) -> Result<(), Error> {
+        self.aborted.lock().unwrap().push(id.clone());
+        Ok(())
+    }
+
+    fn send_update_report(&mut self, _: &Config, _: &HttpClient, report: &UpdateReport, _: Option<&str>)
+                          -> Result<(), Error> {
+        self.reports.lock().unwrap().push(report.clone());
+        Ok(())
+    }
+
+    fn send_installed_software(&mut self, _: &Config, _: &HttpClient, sw: &InstalledSoftware, _: Option<&str>)
+                               -> Result<(), Error> {
+        self.inventory.lock().unwrap().push(sw.clone());
+        Ok(())
+    }
+
+    fn send_update_event(&mut self, _: &Config, _: &HttpClient, event: &UpdateEvent, _: Option<&str>)
+                         -> Result<(), Error> {
+        self.events.lock().unwrap().push(event.clone());
+        Ok(())
+    }
+}
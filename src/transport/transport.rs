@@ -0,0 +1,42 @@
+use chan::Sender;
+use std::path::PathBuf;
+
+use datatype::{Config, Error, Event, PendingUpdates, UpdateReport, UpdateRequestId};
+use datatype::report::{InstalledSoftware, UpdateEvent};
+use http_client::HttpClient;
+
+
+/// A backend that delivers updates to the client and receives its reports,
+/// such as the OTA server over HTTP or a SOTA server over RVI.
+///
+/// Each call is passed the current config and HTTP client, as the config may
+/// be reloaded and the client is replaced when the access token is renewed.
+pub trait Transport: Send {
+    /// Fetch the pending updates. Backends that push updates to the client
+    /// return an empty list.
+    fn get_pending_updates(&mut self, config: &Config, client: &HttpClient)
+                           -> Result<PendingUpdates, Error>;
+
+    /// Download an update, returning the path of the package. Returns `None`
+    /// when the download completes later with an `Event::DownloadComplete`.
+    /// Backends that can follow the download send `Event::DownloadProgress`
+    /// events to `etx`.
+    fn download_update(&mut self, config: &Config, client: &HttpClient, id: &UpdateRequestId, etx: &Sender<Event>)
+                       -> Result<Option<PathBuf>, Error>;
+
+    /// Cancel the download of an update and remove whatever was downloaded.
+    fn abort_download(&mut self, config: &Config, client: &HttpClient, id: &UpdateRequestId)
+                      -> Result<(), Error>;
+
+    /// Send a report. A message from the outbox is sent with the same `key`
+    /// on every attempt, so that the backend can drop the ones it already
+    /// received.
+    fn send_update_report(&mut self, config: &Config, client: &HttpClient, report: &UpdateReport, key: Option<&str>)
+                          -> Result<(), Error>;
+
+    fn send_installed_software(&mut self, config: &Config, client: &HttpClient, sw: &InstalledSoftware,
+                               key: Option<&str>) -> Result<(), Error>;
+
+    fn send_update_event(&mut self, config: &Config, client: &HttpClient, event: &UpdateEvent, key: Option<&str>)
+                         -> Result<(), Error>;
+}
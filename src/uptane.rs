@@ -0,0 +1,742 @@
+//! Uptane metadata verification.
+//!
+//! Both the director and the image repository serve TUF metadata for the
+//! `root`, `timestamp`, `snapshot` and `targets` roles as `<role>.json`, and
+//! every version of root as `<version>.root.json`. Each role is signed over the canonical JSON of its `signed` object by a threshold
+//! of the ed25519 keys that root assigns to it, each identified by the sha256
+//! of its own canonical JSON. Verified metadata is persisted under
+//! `metadata_dir`, which must be provisioned with each repository's initial
+//! `root.json`. A rotated root is only trusted once it is signed by both the
+//! keys of the root before it and its own.
+
+use crypto::digest::Digest;
+use crypto::ed25519;
+use crypto::sha2::Sha256;
+use rustc_serialize::{Decodable, json};
+use rustc_serialize::hex::FromHex;
+use rustc_serialize::json::Json;
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use time;
+
+use datatype::{Config, Error, Method, UpdateRequestId, UptaneConfig, Url};
+use http_client::{HttpClient, HttpRequest};
+use secondary::TargetStore;
+
+
+#[derive(RustcDecodable, Clone, Debug)]
+struct Signature {
+    keyid: String,
+    sig:   String,
+}
+
+#[derive(RustcDecodable, Clone, Debug)]
+struct Key {
+    keytype: String,
+    keyval:  KeyValue,
+}
+
+#[derive(RustcDecodable, Clone, Debug)]
+struct KeyValue {
+    public: String,
+}
+
+#[derive(RustcDecodable, Clone, Debug)]
+struct RoleKeys {
+    keyids:    Vec<String>,
+    threshold: usize,
+}
+
+#[derive(RustcDecodable, Clone, Debug)]
+struct Root {
+    version: u64,
+    expires: String,
+    keys:    BTreeMap<String, Key>,
+    roles:   BTreeMap<String, RoleKeys>,
+}
+
+#[derive(RustcDecodable, Clone, Debug)]
+struct MetaVersion {
+    version: u64,
+}
+
+/// The `timestamp` and `snapshot` roles, which pin the version of the next role.
+#[derive(RustcDecodable, Clone, Debug)]
+struct Meta {
+    version: u64,
+    expires: String,
+    meta:    BTreeMap<String, MetaVersion>,
+}
+
+#[derive(RustcDecodable, Clone, Debug)]
+struct Targets {
+    version: u64,
+    expires: String,
+    targets: BTreeMap<String, Target>,
+}
+
+/// An image described by the targets metadata.
+#[derive(RustcDecodable, Clone, Debug, PartialEq, Eq)]
+pub struct Target {
+    pub length: u64,
+    pub hashes: BTreeMap<String, String>,
+    pub custom: Option<TargetCustom>,
+}
+
+#[derive(RustcDecodable, Clone, Debug, PartialEq, Eq)]
+#[allow(non_snake_case)]
+pub struct TargetCustom {
+    pub hardwareId: Option<String>,
+}
+
+impl Target {
+    fn sha256(&self) -> Option<&String> {
+        self.hashes.get("sha256")
+    }
+
+    fn hardware_id(&self) -> Option<&String> {
+        self.custom.as_ref().and_then(|custom| custom.hardwareId.as_ref())
+    }
+}
+
+
+/// Update and verify the metadata of both repositories, returning the
+/// director's targets for any of the `hardware_ids`. Each must be described
+/// identically by the image repository.
+pub fn verified_targets(config: &UptaneConfig, client: &HttpClient, hardware_ids: &[String], now: i64)
+                        -> Result<BTreeMap<String, Target>, Error> {
+    let director = try!(Repository::new("director", &config.director_server, &config.metadata_dir).update(client, now));
+    let images   = try!(Repository::new("image", &config.image_server, &config.metadata_dir).update(client, now));
+
+    let mut targets = BTreeMap::new();
+    for (name, target) in director {
+        if !target.hardware_id().map_or(false, |id| hardware_ids.contains(id)) {
+            debug!("Skipping target {} for other hardware: {:?}", name, target.hardware_id());
+            continue;
+        }
+        match images.get(&name) {
+            Some(image) if image.length == target.length && target.sha256().is_some()
+                && image.sha256() == target.sha256() => { targets.insert(name, target); }
+            _ => return Err(Error::UptaneError(format!("director target {} doesn't match the image repository", name)))
+        }
+    }
+    Ok(targets)
+}
+
+/// Check that the image at `path` is the target named by the director for
+/// each of the `hardware_ids`, returning the names of those targets.
+pub fn verify_image(targets: &BTreeMap<String, Target>, hardware_ids: &[String], path: &Path)
+                    -> Result<Vec<String>, Error> {
+    if hardware_ids.is_empty() {
+        return Err(Error::UptaneError(format!("no hardware id to verify {:?} for", path)));
+    }
+    let (length, hash) = try!(digest(path));
+
+    hardware_ids.iter().map(|hardware_id| {
+        let mut named = targets.iter().filter(|&(_, target)| target.hardware_id() == Some(hardware_id));
+        match (named.next(), named.next()) {
+            (Some((name, target)), None) => {
+                if target.length == length && target.sha256() == Some(&hash) {
+                    Ok(name.clone())
+                } else {
+                    Err(Error::UptaneError(format!("{:?} doesn't match target {} for {}", path, name, hardware_id)))
+                }
+            }
+            (None, _)    => Err(Error::UptaneError(format!("director names no target for {}", hardware_id))),
+            (Some(_), _) => Err(Error::UptaneError(format!("director names more than one target for {}", hardware_id)))
+        }
+    }).collect()
+}
+
+/// Verify the downloaded image of an update for any of the `hardware_ids`,
+/// removing it if it doesn't match.
+pub fn verify_download(config: &UptaneConfig, client: &HttpClient, hardware_ids: &[String], path: &Path)
+                       -> Result<(), Error> {
+    let verified = verified_targets(config, client, hardware_ids, time::get_time().sec)
+        .and_then(|targets| verify_image(&targets, hardware_ids, path));
+    match verified {
+        Ok(names) => {
+            info!("Download {:?} matches verified targets {}", path, names.join(", "));
+            Ok(())
+        }
+
+        Err(err) => {
+            let _ = fs::remove_file(path);
+            Err(err)
+        }
+    }
+}
+
+/// Verify the downloaded image of update `id` for the hardware it will be
+/// installed on: each of its target secondaries, or else this host. Does
+/// nothing unless Uptane is configured.
+pub fn verify_update(config: &Config, client: &HttpClient, id: &UpdateRequestId, path: &Path) -> Result<(), Error> {
+    let uptane = match config.uptane {
+        Some(ref uptane) => uptane,
+        None => return Ok(())
+    };
+    let hardware_ids = match TargetStore::new(&config.ota.packages_dir).load(id) {
+        Some(ref targets) if !targets.is_empty() => targets.iter().map(|module| {
+            config.secondary.get(module)
+                .and_then(|secondary| secondary.hardware_id.clone())
+                .unwrap_or_else(|| module.clone())
+        }).collect::<Vec<_>>(),
+        _ => vec![uptane.hardware_id.clone()]
+    };
+    verify_download(uptane, client, &hardware_ids, path)
+}
+
+
+struct Repository<'u> {
+    name:   &'static str,
+    server: &'u Url,
+    dir:    PathBuf,
+}
+
+impl<'u> Repository<'u> {
+    fn new(name: &'static str, server: &'u Url, metadata_dir: &str) -> Repository<'u> {
+        Repository { name: name, server: server, dir: Path::new(metadata_dir).join(name) }
+    }
+
+    // Follow each rotation of root in turn, then fetch the metadata of each
+    // role, only storing it (including a rotated root) once every role has
+    // been verified.
+    fn update(&self, client: &HttpClient, now: i64) -> Result<BTreeMap<String, Target>, Error> {
+        let trusted_root = try!(self.trusted("root").ok_or_else(|| {
+            Error::UptaneError(format!("{}: no trusted root.json in {:?}", self.name, self.dir))
+        }));
+        let mut root    = try!(self.self_signed_root(&trusted_root));
+        let mut rotated = None;
+        loop {
+            // the repository rejects a version it doesn't have yet
+            let raw_root = match self.fetch(client, &format!("{}.root", root.version + 1)) {
+                Ok(raw_root) => raw_root,
+                Err(Error::RequestRejected(_)) => break,
+                Err(err) => return Err(err)
+            };
+            root    = try!(self.rotate(&root, &raw_root));
+            rotated = Some(raw_root);
+        }
+        try!(self.check_expiry("root", &root.expires, now));
+
+        let raw_timestamp = try!(self.fetch(client, "timestamp"));
+        let timestamp: Meta = try!(decode(try!(verify_signed(&raw_timestamp, "timestamp", &root))));
+        try!(self.check_version("timestamp", timestamp.version, self.trusted_version("timestamp")));
+        try!(self.check_expiry("timestamp", &timestamp.expires, now));
+
+        let raw_snapshot = try!(self.fetch(client, "snapshot"));
+        let snapshot: Meta = try!(decode(try!(verify_signed(&raw_snapshot, "snapshot", &root))));
+        try!(self.check_pinned("snapshot", snapshot.version, &timestamp));
+        try!(self.check_version("snapshot", snapshot.version, self.trusted_version("snapshot")));
+        try!(self.check_expiry("snapshot", &snapshot.expires, now));
+
+        let raw_targets = try!(self.fetch(client, "targets"));
+        let targets: Targets = try!(decode(try!(verify_signed(&raw_targets, "targets", &root))));
+        try!(self.check_pinned("targets", targets.version, &snapshot));
+        try!(self.check_version("targets", targets.version, self.trusted_version("targets")));
+        try!(self.check_expiry("targets", &targets.expires, now));
+
+        if let Some(ref raw_root) = rotated {
+            try!(self.store("root", raw_root));
+        }
+        try!(self.store("timestamp", &raw_timestamp));
+        try!(self.store("snapshot", &raw_snapshot));
+        try!(self.store("targets", &raw_targets));
+        Ok(targets.targets)
+    }
+
+    fn self_signed_root(&self, raw: &str) -> Result<Root, Error> {
+        let json = try!(parse_json(raw));
+        let signed = try!(json.find("signed").cloned().ok_or_else(|| {
+            Error::UptaneError(format!("{}: root.json has no signed object", self.name))
+        }));
+        try!(check_keyids(&signed));
+        let root: Root = try!(decode(signed));
+        try!(verify_signed(raw, "root", &root));
+        Ok(root)
+    }
+
+    // Verify the next version of root with the keys of both the current root
+    // and itself.
+    fn rotate(&self, root: &Root, raw: &str) -> Result<Root, Error> {
+        try!(verify_signed(raw, "root", root));
+        let next = try!(self.self_signed_root(raw));
+        if next.version != root.version + 1 {
+            return Err(Error::UptaneError(format!("{}: expected root version {}, got {}",
+                                                  self.name, root.version + 1, next.version)));
+        }
+        info!("{}: rotating to root version {}", self.name, next.version);
+        Ok(next)
+    }
+
+    fn check_version(&self, role: &str, version: u64, trusted: Option<u64>) -> Result<(), Error> {
+        match trusted {
+            Some(trusted) if version < trusted => {
+                Err(Error::UptaneError(format!("{}: {} version {} rolls back trusted version {}",
+                                               self.name, role, version, trusted)))
+            }
+            _ => Ok(())
+        }
+    }
+
+    fn check_pinned(&self, role: &str, version: u64, pinned_by: &Meta) -> Result<(), Error> {
+        match pinned_by.meta.get(&format!("{}.json", role)) {
+            Some(pinned) if pinned.version == version => Ok(()),
+            Some(pinned) => Err(Error::UptaneError(format!("{}: {} version {} isn't the expected version {}",
+                                                           self.name, role, version, pinned.version))),
+            None => Err(Error::UptaneError(format!("{}: no version listed for {}", self.name, role)))
+        }
+    }
+
+    fn check_expiry(&self, role: &str, expires: &str, now: i64) -> Result<(), Error> {
+        let tm = try!(time::strptime(expires, "%Y-%m-%dT%H:%M:%SZ").map_err(|err| {
+            Error::UptaneError(format!("{}: {} has an invalid expiry {}: {}", self.name, role, expires, err))
+        }));
+        if tm.to_timespec().sec <= now {
+            Err(Error::UptaneError(format!("{}: {} expired at {}", self.name, role, expires)))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn fetch(&self, client: &HttpClient, role: &str) -> Result<String, Error> {
+        let resp_rx = client.send_request(HttpRequest {
+            method:          Method::Get,
+            url:             try!(self.server.join(&format!("{}.json", role))),
+            body:            None,
+            idempotency_key: None,
+            progress:        None,
+            range:           None,
+        });
+        let data = try!(resp_rx.recv().expect("no uptane metadata response received"));
+        Ok(try!(String::from_utf8(data)))
+    }
+
+    fn trusted(&self, role: &str) -> Option<String> {
+        let mut file = match File::open(self.dir.join(format!("{}.json", role))) {
+            Ok(file) => file,
+            Err(_)   => return None
+        };
+        let mut text = String::new();
+        file.read_to_string(&mut text).ok().map(|_| text)
+    }
+
+    fn trusted_version(&self, role: &str) -> Option<u64> {
+        self.trusted(role)
+            .and_then(|raw| Json::from_str(&raw).ok())
+            .and_then(|json| json.find_path(&["signed", "version"]).and_then(|version| version.as_u64()))
+    }
+
+    fn store(&self, role: &str, raw: &str) -> Result<(), Error> {
+        try!(fs::create_dir_all(&self.dir));
+        let mut file = try!(File::create(self.dir.join(format!("{}.json", role))));
+        Ok(try!(file.write_all(raw.as_bytes())))
+    }
+}
+
+
+/// Check the signatures on the metadata of `role`, returning its signed object.
+fn verify_signed(raw: &str, role: &str, root: &Root) -> Result<Json, Error> {
+    let json   = try!(parse_json(raw));
+    let signed = try!(json.find("signed").cloned().ok_or_else(|| {
+        Error::UptaneError(format!("{}.json has no signed object", role))
+    }));
+    let signatures: Vec<Signature> = try!(decode(try!(json.find("signatures").cloned().ok_or_else(|| {
+        Error::UptaneError(format!("{}.json has no signatures", role))
+    }))));
+
+    match signed.find("_type").and_then(|kind| kind.as_string()) {
+        Some(kind) if kind.to_lowercase() == role => (),
+        kind => return Err(Error::UptaneError(format!("expected {} metadata, got {:?}", role, kind)))
+    }
+
+    let keys = try!(root.roles.get(role).ok_or_else(|| {
+        Error::UptaneError(format!("root has no keys for {}", role))
+    }));
+    let message   = try!(canonical_json(&signed));
+    let mut valid = HashSet::new();
+    for signature in signatures {
+        if !keys.keyids.contains(&signature.keyid) {
+            continue;
+        }
+        let verified = root.keys.get(&signature.keyid)
+            .map(|key| key_verifies(key, message.as_bytes(), &signature.sig))
+            .unwrap_or(false);
+        if verified {
+            valid.insert(signature.keyid);
+        }
+    }
+
+    if keys.threshold == 0 || valid.len() < keys.threshold {
+        Err(Error::UptaneError(format!("{} is signed by {} of {} required keys", role, valid.len(), keys.threshold)))
+    } else {
+        Ok(signed)
+    }
+}
+
+/// Check that each key of a root is listed under the sha256 of its canonical
+/// JSON.
+fn check_keyids(signed: &Json) -> Result<(), Error> {
+    let keys = try!(signed.find("keys").and_then(|keys| keys.as_object()).ok_or_else(|| {
+        Error::UptaneError("root has no keys".to_string())
+    }));
+    for (keyid, key) in keys {
+        let mut hasher = Sha256::new();
+        hasher.input(try!(canonical_json(key)).as_bytes());
+        if hasher.result_str() != *keyid {
+            return Err(Error::UptaneError(format!("root key {} doesn't match its hash", keyid)));
+        }
+    }
+    Ok(())
+}
+
+/// Encode `json` without whitespace, with sorted object keys and with only
+/// `"` and `\` escaped in strings. Floats have no canonical form.
+fn canonical_json(json: &Json) -> Result<String, Error> {
+    let mut out = String::new();
+    try!(write_canonical(json, &mut out));
+    Ok(out)
+}
+
+fn write_canonical(json: &Json, out: &mut String) -> Result<(), Error> {
+    match *json {
+        Json::Null           => out.push_str("null"),
+        Json::Boolean(value) => out.push_str(if value { "true" } else { "false" }),
+        Json::I64(value)     => out.push_str(&value.to_string()),
+        Json::U64(value)     => out.push_str(&value.to_string()),
+        Json::F64(value)     => return Err(Error::UptaneError(format!("no canonical form for {}", value))),
+        Json::String(ref text) => write_canonical_string(text, out),
+
+        Json::Array(ref items) => {
+            out.push('[');
+            for (n, item) in items.iter().enumerate() {
+                if n > 0 { out.push(','); }
+                try!(write_canonical(item, out));
+            }
+            out.push(']');
+        }
+
+        // a `BTreeMap` iterates its keys in sorted order
+        Json::Object(ref object) => {
+            out.push('{');
+            for (n, (key, value)) in object.iter().enumerate() {
+                if n > 0 { out.push(','); }
+                write_canonical_string(key, out);
+                out.push(':');
+                try!(write_canonical(value, out));
+            }
+            out.push('}');
+        }
+    }
+    Ok(())
+}
+
+fn write_canonical_string(text: &str, out: &mut String) {
+    out.push('"');
+    for c in text.chars() {
+        if c == '"' || c == '\\' {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out.push('"');
+}
+
+fn key_verifies(key: &Key, message: &[u8], signature: &str) -> bool {
+    if key.keytype != "ed25519" {
+        return false;
+    }
+    match (key.keyval.public.from_hex(), signature.from_hex()) {
+        (Ok(ref public), Ok(ref sig)) if public.len() == 32 && sig.len() == 64 => ed25519::verify(message, public, sig),
+        _ => false
+    }
+}
+
+/// The length and sha256 of the file at `path`.
+fn digest(path: &Path) -> Result<(u64, String), Error> {
+    let mut file   = try!(File::open(path));
+    let mut buf    = vec![0; 64 * 1024];
+    let mut hasher = Sha256::new();
+    let mut length = 0;
+    loop {
+        match try!(file.read(&mut buf)) {
+            0 => break,
+            n => {
+                hasher.input(&buf[..n]);
+                length += n as u64;
+            }
+        }
+    }
+    Ok((length, hasher.result_str()))
+}
+
+fn parse_json(raw: &str) -> Result<Json, Error> {
+    Json::from_str(raw).map_err(|err| Error::UptaneError(format!("invalid metadata: {}", err)))
+}
+
+fn decode<T: Decodable>(json: Json) -> Result<T, Error> {
+    Ok(try!(T::decode(&mut json::Decoder::new(json))))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crypto::digest::Digest;
+    use crypto::ed25519;
+    use crypto::sha2::Sha256;
+    use rustc_serialize::hex::ToHex;
+    use rustc_serialize::json::Json;
+    use std::collections::BTreeMap;
+    use std::fs;
+    use std::fs::File;
+    use std::io::prelude::*;
+    use std::path::Path;
+
+    use super::*;
+    use datatype::{Error, UptaneConfig, Url};
+    use http_client::{HttpResponse, TestHttpClient};
+    use remote::dw::random_string;
+
+
+    const EXPIRES: &'static str = "2100-01-01T00:00:00Z";
+
+    fn key(public: &[u8]) -> String {
+        format!(r#"{{"keytype":"ed25519","keyval":{{"public":"{}"}}}}"#, public.to_hex())
+    }
+
+    fn keyid(public: &[u8]) -> String {
+        let mut hasher = Sha256::new();
+        hasher.input(key(public).as_bytes());
+        hasher.result_str()
+    }
+
+    fn sign(signed: Json, secret: &[u8]) -> String {
+        sign_all(signed, &[secret])
+    }
+
+    // The secret half of an ed25519 keypair ends with the public key.
+    fn sign_all(signed: Json, secrets: &[&[u8]]) -> String {
+        let message = canonical_json(&signed).unwrap();
+        let sigs = secrets.iter().map(|secret| {
+            let sig = ed25519::signature(message.as_bytes(), secret).to_hex();
+            format!(r#"{{"keyid":"{}","sig":"{}"}}"#, keyid(&secret[32..]), sig)
+        }).collect::<Vec<_>>().join(",");
+        format!(r#"{{"signatures":[{}],"signed":{}}}"#, sigs, signed)
+    }
+
+    fn signed(text: &str) -> Json {
+        Json::from_str(text).unwrap()
+    }
+
+    fn root(public: &[u8], version: u64) -> Json {
+        let roles = ["root", "timestamp", "snapshot", "targets"].iter()
+            .map(|role| format!(r#""{}":{{"keyids":["{}"],"threshold":1}}"#, role, keyid(public)))
+            .collect::<Vec<_>>().join(",");
+        signed(&format!(r#"{{"_type":"Root","version":{},"expires":"{}","roles":{{{}}},"keys":{{"{}":{}}}}}"#,
+                        version, EXPIRES, roles, keyid(public), key(public)))
+    }
+
+    fn meta(kind: &str, pins: &str, version: u64, expires: &str) -> Json {
+        signed(&format!(r#"{{"_type":"{}","version":{},"expires":"{}","meta":{{"{}.json":{{"version":1}}}}}}"#,
+                        kind, version, expires, pins))
+    }
+
+    fn sha256(image: &[u8]) -> String {
+        let mut hasher = Sha256::new();
+        hasher.input(image);
+        hasher.result_str()
+    }
+
+    fn targets(image: &[u8], hardware_id: &str) -> Json {
+        signed(&format!(r#"{{"_type":"Targets","version":1,"expires":"{}","targets":{{"ecu.img":
+                          {{"length":{},"hashes":{{"sha256":"{}"}},"custom":{{"hardwareId":"{}"}}}}}}}}"#,
+                        EXPIRES, image.len(), sha256(image), hardware_id))
+    }
+
+    fn target(image: &[u8], hardware_id: &str) -> Target {
+        let mut hashes = BTreeMap::new();
+        hashes.insert("sha256".to_string(), sha256(image));
+        Target {
+            length: image.len() as u64,
+            hashes: hashes,
+            custom: Some(TargetCustom { hardwareId: Some(hardware_id.to_string()) }),
+        }
+    }
+
+    fn reply(body: String) -> HttpResponse {
+        Ok(body.into_bytes())
+    }
+
+    fn not_found() -> HttpResponse {
+        Err(Error::RequestRejected("404".to_string()))
+    }
+
+    // The replies for one repository, in the order they are requested.
+    fn repository(secret: &[u8], public: &[u8], timestamp: Json, target: Json) -> Vec<HttpResponse> {
+        rotated(secret, public, 1, timestamp, target)
+    }
+
+    // Each root after the first up to `version`, then the other roles.
+    fn rotated(secret: &[u8], public: &[u8], version: u64, timestamp: Json, target: Json) -> Vec<HttpResponse> {
+        let mut replies = (2..version + 1).map(|n| reply(sign(root(public, n), secret))).collect::<Vec<_>>();
+        replies.push(not_found());
+        replies.extend(roles(secret, timestamp, target));
+        replies
+    }
+
+    fn roles(secret: &[u8], timestamp: Json, target: Json) -> Vec<HttpResponse> {
+        vec![reply(sign(timestamp, secret)),
+             reply(sign(meta("Snapshot", "targets", 1, EXPIRES), secret)),
+             reply(sign(target, secret))]
+    }
+
+    fn setup(secret: &[u8], public: &[u8]) -> UptaneConfig {
+        let dir = format!("/tmp/sota-uptane-{}", random_string(8));
+        for repo in &["director", "image"] {
+            fs::create_dir_all(format!("{}/{}", dir, repo)).unwrap();
+            File::create(format!("{}/{}/root.json", dir, repo)).unwrap()
+                .write_all(sign(root(public, 1), secret).as_bytes()).unwrap();
+        }
+        UptaneConfig {
+            director_server: Url::parse("http://127.0.0.1:8090").unwrap(),
+            image_server:    Url::parse("http://127.0.0.1:8091").unwrap(),
+            metadata_dir:    dir,
+            hardware_id:     "tcu".to_string(),
+        }
+    }
+
+    fn client(director: Vec<HttpResponse>, image: Vec<HttpResponse>) -> TestHttpClient {
+        let mut replies = director;
+        replies.extend(image);
+        replies.reverse();
+        TestHttpClient::from_responses(replies)
+    }
+
+    fn hardware(ids: &[&str]) -> Vec<String> {
+        ids.iter().map(|id| id.to_string()).collect()
+    }
+
+    #[test]
+    fn encodes_canonical_json() {
+        let json = Json::from_str("{\"b\": \"tab\\t \\\"quoted\\\" \\u00e9\", \"a\": [1, -2, true, null]}").unwrap();
+        assert_eq!(canonical_json(&json).unwrap(), "{\"a\":[1,-2,true,null],\"b\":\"tab\t \\\"quoted\\\" \u{e9}\"}");
+        assert!(canonical_json(&Json::from_str("[1.5]").unwrap()).is_err());
+    }
+
+    #[test]
+    fn verifies_both_repositories() {
+        let (secret, public) = ed25519::keypair(&[7; 32]);
+        let config = setup(&secret, &public);
+        let image  = b"ecu firmware";
+        let tcu    = hardware(&["tcu"]);
+        let fresh  = || repository(&secret, &public, meta("Timestamp", "snapshot", 2, EXPIRES), targets(image, "tcu"));
+
+        let found = verified_targets(&config, &client(fresh(), fresh()), &tcu, 0).unwrap();
+        assert_eq!(found.keys().collect::<Vec<_>>(), vec!["ecu.img"]);
+        let path = format!("{}/ecu.img", config.metadata_dir);
+        File::create(&path).unwrap().write_all(image).unwrap();
+        assert_eq!(verify_image(&found, &tcu, Path::new(&path)).unwrap(), vec!["ecu.img".to_string()]);
+        File::create(&path).unwrap().write_all(b"tampered").unwrap();
+        assert!(verify_image(&found, &tcu, Path::new(&path)).is_err());
+
+        // rolled back, expired, mismatched and badly signed metadata
+        let old = repository(&secret, &public, meta("Timestamp", "snapshot", 1, EXPIRES), targets(image, "tcu"));
+        assert!(verified_targets(&config, &client(old, fresh()), &tcu, 0).is_err());
+        let expired = repository(&secret, &public, meta("Timestamp", "snapshot", 2, "2000-01-01T00:00:00Z"), targets(image, "tcu"));
+        assert!(verified_targets(&config, &client(expired, fresh()), &tcu, 0).is_err());
+        let other = repository(&secret, &public, meta("Timestamp", "snapshot", 2, EXPIRES), targets(b"other", "tcu"));
+        assert!(verified_targets(&config, &client(fresh(), other), &tcu, 0).is_err());
+        let (forged, _) = ed25519::keypair(&[8; 32]);
+        let unsigned = repository(&forged, &public, meta("Timestamp", "snapshot", 2, EXPIRES), targets(image, "tcu"));
+        assert!(verified_targets(&config, &client(unsigned, fresh()), &tcu, 0).is_err());
+        fs::remove_dir_all(&config.metadata_dir).unwrap();
+    }
+
+    #[test]
+    fn selects_targets_by_hardware_id() {
+        let (secret, public) = ed25519::keypair(&[7; 32]);
+        let config = setup(&secret, &public);
+        let fresh  = || repository(&secret, &public, meta("Timestamp", "snapshot", 1, EXPIRES), targets(b"brakes", "abs"));
+
+        let found = verified_targets(&config, &client(fresh(), fresh()), &hardware(&["tcu", "abs"]), 0).unwrap();
+        assert_eq!(found.keys().collect::<Vec<_>>(), vec!["ecu.img"]);
+        assert!(verified_targets(&config, &client(fresh(), fresh()), &hardware(&["tcu"]), 0).unwrap().is_empty());
+        fs::remove_dir_all(&config.metadata_dir).unwrap();
+    }
+
+    #[test]
+    fn stores_a_rotated_root_once_verified() {
+        let (secret, public) = ed25519::keypair(&[7; 32]);
+        let config  = setup(&secret, &public);
+        let tcu     = hardware(&["tcu"]);
+        let expired = rotated(&secret, &public, 2, meta("Timestamp", "snapshot", 1, "2000-01-01T00:00:00Z"), targets(b"img", "tcu"));
+        let fresh   = || rotated(&secret, &public, 2, meta("Timestamp", "snapshot", 1, EXPIRES), targets(b"img", "tcu"));
+        let trusted = || Repository::new("director", &config.director_server, &config.metadata_dir).trusted_version("root");
+
+        assert!(verified_targets(&config, &client(expired, fresh()), &tcu, 0).is_err());
+        assert_eq!(trusted(), Some(1));
+        verified_targets(&config, &client(fresh(), fresh()), &tcu, 0).unwrap();
+        assert_eq!(trusted(), Some(2));
+        fs::remove_dir_all(&config.metadata_dir).unwrap();
+    }
+
+    #[test]
+    fn follows_root_rotations_in_order() {
+        let keys = (1..4).map(|n| ed25519::keypair(&[n; 32])).collect::<Vec<_>>();
+        let (ref first, _)  = keys[0];
+        let (ref last, _)   = keys[2];
+        let config  = setup(first, &keys[0].1);
+        let tcu     = hardware(&["tcu"]);
+        let fresh   = || meta("Timestamp", "snapshot", 1, EXPIRES);
+        let trusted = || Repository::new("director", &config.director_server, &config.metadata_dir).trusted_version("root");
+        // version n is signed by the keys of versions n-1 and n
+        let chain = |signers: &[(usize, usize)]| signers.iter().enumerate().map(|(n, &(old, new))| {
+            reply(sign_all(root(&keys[n + 1].1, n as u64 + 2), &[&keys[old].0, &keys[new].0]))
+        }).collect::<Vec<_>>();
+        let director = |roots: Vec<HttpResponse>| {
+            let mut replies = roots;
+            replies.push(not_found());
+            replies.extend(roles(last, fresh(), targets(b"img", "tcu")));
+            replies
+        };
+        let images = || repository(first, &keys[0].1, fresh(), targets(b"img", "tcu"));
+
+        // skipping a version, or signed without the previous root's keys
+        let skipped = vec![reply(sign_all(root(&keys[2].1, 3), &[first, last]))];
+        assert!(verified_targets(&config, &client(director(skipped), images()), &tcu, 0).is_err());
+        assert!(verified_targets(&config, &client(director(chain(&[(0, 1), (0, 2)])), images()), &tcu, 0).is_err());
+        assert_eq!(trusted(), Some(1));
+
+        verified_targets(&config, &client(director(chain(&[(0, 1), (1, 2)])), images()), &tcu, 0).unwrap();
+        assert_eq!(trusted(), Some(3));
+        fs::remove_dir_all(&config.metadata_dir).unwrap();
+    }
+
+    #[test]
+    fn verifies_the_target_named_for_the_hardware() {
+        let path = format!("/tmp/sota-uptane-{}.img", random_string(8));
+        File::create(&path).unwrap().write_all(b"brakes").unwrap();
+        let mut targets = BTreeMap::new();
+        targets.insert("tcu.img".to_string(), target(b"firmware", "tcu"));
+        targets.insert("abs.img".to_string(), target(b"brakes", "abs"));
+
+        assert_eq!(verify_image(&targets, &hardware(&["abs"]), Path::new(&path)).unwrap(), vec!["abs.img".to_string()]);
+        assert!(verify_image(&targets, &hardware(&["tcu"]), Path::new(&path)).is_err());
+        assert!(verify_image(&targets, &hardware(&["abs", "tcu"]), Path::new(&path)).is_err());
+        assert!(verify_image(&targets, &hardware(&["bcm"]), Path::new(&path)).is_err());
+        assert!(verify_image(&targets, &[], Path::new(&path)).is_err());
+        targets.insert("abs-old.img".to_string(), target(b"old brakes", "abs"));
+        assert!(verify_image(&targets, &hardware(&["abs"]), Path::new(&path)).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rejects_misnamed_keys() {
+        let (secret, public) = ed25519::keypair(&[7; 32]);
+        let json = Json::from_str(&sign(root(&public, 1), &secret).replace(&keyid(&public), "k1")).unwrap();
+        assert!(check_keyids(json.find("signed").unwrap()).is_err());
+    }
+}
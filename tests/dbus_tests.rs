@@ -0,0 +1,221 @@
+extern crate chan;
+extern crate dbus;
+extern crate libotaplus;
+extern crate tempfile;
+
+use chan::Sender;
+use dbus::{BusType, Connection, ConnectionItem, Message, MessageItem, NameFlag};
+use dbus::obj::{Argument, Interface, Method, ObjectPath};
+use std::env;
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::process::{Child, Command, Stdio};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+use tempfile::NamedTempFile;
+
+use libotaplus::datatype::{Command as ClientCommand, DBusConfig, Event, UpdateState};
+use libotaplus::datatype::update_request::{GetInstalledSoftware, UpdateAvailable};
+use libotaplus::interaction_library::gateway::Interpret;
+use libotaplus::swm::sc::SotaC;
+use libotaplus::swm::swlm;
+
+
+const BUS_CONFIG: &'static str = r#"<!DOCTYPE busconfig PUBLIC "-//freedesktop//DTD D-Bus Bus Configuration 1.0//EN"
+ "http://www.freedesktop.org/standards/dbus/1.0/busconfig.dtd">
+<busconfig>
+  <type>session</type>
+  <listen>unix:tmpdir=/tmp</listen>
+  <policy context="default">
+    <allow send_destination="*"/>
+    <allow own="*"/>
+  </policy>
+</busconfig>
+"#;
+
+/// A `dbus-daemon` that serves as the session bus until dropped.
+struct PrivateBus {
+    daemon: Child,
+    _config: NamedTempFile,
+}
+
+impl PrivateBus {
+    /// Returns `None` when `dbus-daemon` isn't installed.
+    fn start() -> Option<PrivateBus> {
+        let mut config = NamedTempFile::new().unwrap();
+        config.write_all(BUS_CONFIG.as_bytes()).unwrap();
+        let spawned = Command::new("dbus-daemon")
+            .arg(format!("--config-file={}", config.path().display()))
+            .args(&["--nofork", "--print-address"])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn();
+        let mut daemon = match spawned {
+            Ok(daemon) => daemon,
+            Err(ref err) if err.kind() == ErrorKind::NotFound => return None,
+            Err(err) => panic!("couldn't start dbus-daemon: {}", err)
+        };
+
+        let mut address = String::new();
+        BufReader::new(daemon.stdout.take().unwrap()).read_line(&mut address).unwrap();
+        env::set_var("DBUS_SESSION_BUS_ADDRESS", address.trim());
+        Some(PrivateBus { daemon: daemon, _config: config })
+    }
+}
+
+impl Drop for PrivateBus {
+    fn drop(&mut self) {
+        let _ = self.daemon.kill();
+        let _ = self.daemon.wait();
+    }
+}
+
+/// Retry `f` until it succeeds, giving a service time to claim its name.
+fn eventually<T, E, F: Fn() -> Result<T, E>>(f: F) -> T {
+    for _ in 0..50 {
+        match f() {
+            Ok(t)  => return t,
+            Err(_) => thread::sleep(Duration::from_millis(100))
+        }
+    }
+    panic!("service didn't come up on the bus");
+}
+
+fn dict(entries: Vec<(&str, MessageItem)>) -> MessageItem {
+    MessageItem::new_array(entries.into_iter().map(|(key, value)| {
+        MessageItem::DictEntry(Box::new(MessageItem::Str(key.to_string())),
+                               Box::new(MessageItem::Variant(Box::new(value))))
+    }).collect()).unwrap()
+}
+
+/// Serves the software loading manager's methods, forwarding announced
+/// update ids over `announced`.
+fn mock_software_manager(cfg: DBusConfig, announced: Sender<String>) {
+    let conn = Connection::get_private(BusType::Session).unwrap();
+    conn.register_name(&cfg.software_manager, NameFlag::ReplaceExisting as u32).unwrap();
+
+    let get_installed = Method::new(
+        "getInstalledPackages",
+        vec!(Argument::new("include_packages", "b"), Argument::new("include_module_firmware", "b")),
+        vec!(Argument::new("packages", "aa{sv}"), Argument::new("firmware", "aa{sv}")),
+        Box::new(|_| {
+            let package = dict(vec![
+                ("package_id", MessageItem::Str("vim-7.4".to_string())),
+                ("name", MessageItem::Str("vim".to_string())),
+                ("description", MessageItem::Str("editor".to_string())),
+                ("last_modified", MessageItem::UInt64(1)),
+            ]);
+            let firmware = dict(vec![
+                ("module", MessageItem::Str("ecu".to_string())),
+                ("firmware_id", MessageItem::Str("fw-1".to_string())),
+                ("last_modified", MessageItem::UInt64(2)),
+            ]);
+            Ok(vec!(MessageItem::new_array(vec![package]).unwrap(),
+                    MessageItem::new_array(vec![firmware]).unwrap()))
+        }));
+    let update_available = Method::new(
+        "updateAvailable",
+        vec!(Argument::new("update_id", "s"), Argument::new("signature", "s"),
+             Argument::new("description", "s"), Argument::new("request_confirmation", "b")),
+        vec!(),
+        Box::new(|msg| {
+            if let Some(&MessageItem::Str(ref id)) = msg.get_items().first() {
+                announced.send(id.clone());
+            }
+            Ok(vec!())
+        }));
+    let interface = Interface::new(vec!(get_installed, update_available), vec!(), vec!());
+
+    let mut object_path = ObjectPath::new(&conn, &cfg.software_manager_path, true);
+    object_path.insert_interface(&cfg.software_manager, interface);
+    object_path.set_registered(true).unwrap();
+    for item in conn.iter(100) {
+        if let ConnectionItem::MethodCall(mut msg) = item {
+            object_path.handle_message(&mut msg);
+        }
+    }
+}
+
+fn check_gateway(cfg: &DBusConfig) {
+    let (itx, irx) = chan::async::<Interpret<ClientCommand, Event>>();
+    let (etx, erx) = chan::async::<Event>();
+    let gateway    = cfg.clone();
+    let stop       = Arc::new(AtomicBool::new(false));
+    let stopped    = stop.clone();
+    let handle     = thread::spawn(move || SotaC::new(gateway, itx).start(erx, &stopped).unwrap());
+
+    let conn = Connection::get_private(BusType::Session).unwrap();
+    let call = |method: &str, args: &[MessageItem]| {
+        let mut msg = Message::new_method_call(&cfg.name, &cfg.path, &cfg.interface, method).unwrap();
+        msg.append_items(args);
+        conn.send_with_reply_and_block(msg, 2000)
+    };
+
+    eventually(|| call("initiateDownload", &[MessageItem::Str("1".to_string())]));
+    assert_eq!(irx.recv().unwrap().command, ClientCommand::AcceptUpdates(vec!["1".to_string()]));
+    call("abortDownload", &[MessageItem::Str("1".to_string())]).unwrap();
+    assert_eq!(irx.recv().unwrap().command, ClientCommand::AbortDownload("1".to_string()));
+    assert!(call("initiateDownload", &[]).is_err());
+
+    conn.add_match(&format!("type='signal',interface='{}'", cfg.interface)).unwrap();
+    etx.send(Event::FoundPendingUpdates(vec!["1".to_string()]));
+    etx.send(Event::UpdateStateChanged("1".to_string(), UpdateState::Downloading));
+    let signal = conn.iter(100).filter_map(|item| match item {
+        ConnectionItem::Signal(msg) => Some(msg.get_items()),
+        _                           => None
+    }).next().unwrap();
+    assert_eq!(signal, vec![MessageItem::Str("1".to_string()), MessageItem::Str("Downloading".to_string())]);
+
+    let get = |name: &str| {
+        let mut msg = Message::new_method_call(&cfg.name, &cfg.path, "org.freedesktop.DBus.Properties", "Get").unwrap();
+        msg.append_items(&[MessageItem::Str(cfg.interface.clone()), MessageItem::Str(name.to_string())]);
+        conn.send_with_reply_and_block(msg, 2000).unwrap().get_items()
+    };
+    assert_eq!(get("PendingUpdates"), vec![MessageItem::Variant(Box::new(MessageItem::UInt32(1)))]);
+    assert_eq!(get("CurrentUpdate"), vec![MessageItem::Variant(Box::new(MessageItem::Str("1".to_string())))]);
+
+    // stopping releases the name
+    stop.store(true, Ordering::SeqCst);
+    handle.join().unwrap();
+    assert!(call("abortDownload", &[MessageItem::Str("1".to_string())]).is_err());
+}
+
+fn check_software_manager(cfg: &DBusConfig) {
+    let (atx, arx) = chan::async::<String>();
+    let manager    = cfg.clone();
+    thread::spawn(move || mock_software_manager(manager, atx));
+
+    let get = GetInstalledSoftware { include_packages: true, include_module_firmware: true };
+    let sw  = eventually(|| swlm::send_get_installed_software(cfg, get.clone()));
+    assert_eq!(sw.packages[0].package_id, "vim-7.4");
+    assert_eq!(sw.firmware[0].firmware_id, "fw-1");
+
+    swlm::send_update_available(cfg, UpdateAvailable {
+        update_id:            "2".to_string(),
+        signature:            "sig".to_string(),
+        description:          "update".to_string(),
+        request_confirmation: true,
+        size:                 10,
+    }).unwrap();
+    assert_eq!(arx.recv().unwrap(), "2".to_string());
+
+    let mut missing = cfg.clone();
+    missing.software_manager = "org.genivi.Missing".to_string();
+    assert!(swlm::send_get_installed_software(&missing, get).is_err());
+}
+
+
+// The session bus address is process-wide, so the checks share one test.
+// It is skipped where dbus-daemon isn't installed.
+#[test]
+fn dbus_session() {
+    let _bus = match PrivateBus::start() {
+        Some(bus) => bus,
+        None => return println!("Skipping the D-Bus session test: dbus-daemon isn't installed")
+    };
+    let mut cfg = DBusConfig::default();
+    cfg.timeout = 2;
+    check_gateway(&cfg);
+    check_software_manager(&cfg);
+}
@@ -42,6 +42,7 @@ fn help() {
 Options:
     -h, --help          print this help menu
         --config PATH   change config path
+        --print-config  print the effective configuration and exit
         --auth-server URL
                         change the auth server URL
         --auth-client-id ID
@@ -72,9 +73,22 @@ fn bad_ota_server_url() {
 }
 
 #[test]
-fn bad_section() {
-    assert_eq!(client_with_config(&[""], "[foo]\n"),
-               "parse_section, invalid section: device\n")
+fn bad_values() {
+    assert_eq!(client_with_config(&[""], "[ota]\npolling_interval = 0\npackages_dir = \"\"\n"),
+               "Invalid config:\n  ota.polling_interval: must be greater than zero\n  ota.packages_dir: must not be empty\n")
+}
+
+#[test]
+fn print_config() {
+    let mut creds = NamedTempFile::new().unwrap();
+    let _         = creds.write_all(b"[auth]\nclient_id = \"stored-id\"\nsecret = \"hunter2\"\n").unwrap();
+    let cfg       = format!("[auth]\nserver = \"http://127.0.0.1:9000\"\nclient_id = \"id\"\nsecret = \"s\"\ncredentials_file = \"{}\"\n",
+                            creds.path().display());
+    let output    = client_with_config(&["--print-config", "--device-vin", "V42"], &cfg);
+    assert!(output.contains("vin = \"V42\""));
+    assert!(output.contains("client_id = \"stored-id\""));
+    assert!(output.contains("<redacted>"));
+    assert!(!output.contains("hunter2"));
 }
 
 #[test]